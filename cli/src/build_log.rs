@@ -0,0 +1,217 @@
+//! The `--log-file` of `trunk build`: a copy of everything the build prints, with timestamps.
+//! Also tags each line the build prints with `--log-prefix` and its topic, e.g.
+//! `trunk: config: warn: Trunk.toml not found`.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use anyhow::Context;
+
+static LOG_FILE: OnceLock<Mutex<LogFile>> = OnceLock::new();
+
+/// `--log-prefix`, unset outside of `trunk build`
+static PREFIX: OnceLock<String> = OnceLock::new();
+
+/// Whether the next text printed to stdout and stderr starts a new line, and so needs a tag
+static STDOUT_AT_LINE_START: Mutex<bool> = Mutex::new(true);
+static STDERR_AT_LINE_START: Mutex<bool> = Mutex::new(true);
+
+/// Tags every line printed from now on with `prefix`, followed by its topic. An empty prefix
+/// leaves lines untagged. Only the first prefix set is used
+pub fn set_prefix(prefix: &str) {
+    let _ = PREFIX.set(prefix.to_string());
+}
+
+/// The topic of a line printed from `module`, or logged with `module` as its target: `config`
+/// for reading Trunk.toml and the control file, `build` for everything else
+pub fn topic(module: &str) -> &'static str {
+    let module = module.strip_prefix("pg_trunk::").unwrap_or(module);
+    match module {
+        "config" | "trunk_toml" | "control_file" => "config",
+        _ => "build",
+    }
+}
+
+/// The tag of lines about `topic`, e.g. `trunk: build: `, or nothing if no prefix is set
+pub fn tag(topic: &str) -> String {
+    match PREFIX.get() {
+        Some(prefix) if !prefix.is_empty() => format!("{prefix}{topic}: "),
+        _ => String::new(),
+    }
+}
+
+/// `text` with `tag` at the start of each line that isn't empty. `at_line_start` says whether
+/// `text` starts a line, and is updated for the text that follows
+fn tag_lines(tag: &str, text: &str, at_line_start: &mut bool) -> String {
+    if tag.is_empty() {
+        *at_line_start = text.ends_with('\n');
+        return text.to_string();
+    }
+    let mut tagged = String::with_capacity(text.len());
+    for piece in text.split_inclusive('\n') {
+        if *at_line_start && piece != "\n" {
+            tagged.push_str(tag);
+        }
+        tagged.push_str(piece);
+        *at_line_start = piece.ends_with('\n');
+    }
+
+    tagged
+}
+
+/// `text` about `topic` with each line that isn't empty tagged
+pub fn tag_each_line(topic: &str, text: &str) -> String {
+    tag_lines(&tag(topic), text, &mut true)
+}
+
+/// Prints `text` from `module` to stdout, or stderr, with each line tagged, and writes it to
+/// the log file
+pub fn print(module: &str, text: &str, stderr: bool) {
+    let at_line_start = if stderr {
+        &STDERR_AT_LINE_START
+    } else {
+        &STDOUT_AT_LINE_START
+    };
+    let mut at_line_start = at_line_start.lock().unwrap_or_else(|err| err.into_inner());
+    let text = tag_lines(&tag(topic(module)), text, &mut at_line_start);
+    if stderr {
+        eprint!("{text}");
+    } else {
+        print!("{text}");
+    }
+    record(&text);
+}
+
+struct LogFile {
+    file: File,
+    /// Whether the next text written starts a new line, and so needs a timestamp
+    at_line_start: bool,
+}
+
+impl LogFile {
+    fn write(&mut self, text: &str) {
+        for piece in text.split_inclusive('\n') {
+            if self.at_line_start {
+                let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+                let _ = write!(self.file, "{timestamp} ");
+            }
+            // Failing to write the log must not fail the build
+            let _ = self.file.write_all(piece.as_bytes());
+            self.at_line_start = piece.ends_with('\n');
+        }
+    }
+}
+
+/// Starts copying the build's output to the file at `path`, truncating it, and writes `header`
+/// at its top.
+pub fn init(path: &Path, header: &str) -> Result<(), anyhow::Error> {
+    let file = File::create(path)
+        .with_context(|| format!("Failed to create log file {}", path.display()))?;
+    let mut log_file = LogFile {
+        file,
+        at_line_start: true,
+    };
+    log_file.write(header);
+
+    if LOG_FILE.set(Mutex::new(log_file)).is_err() {
+        anyhow::bail!("The log file was already set");
+    }
+
+    Ok(())
+}
+
+/// Writes `text` to the log file, if there is one
+pub fn record(text: &str) {
+    if let Some(log_file) = LOG_FILE.get() {
+        if let Ok(mut log_file) = log_file.lock() {
+            log_file.write(text);
+        }
+    }
+}
+
+/// Like `println!`, tagging the line and also writing it to the log file
+macro_rules! tee_println {
+    () => {
+        $crate::build_log::tee_println!("")
+    };
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        $crate::build_log::print(module_path!(), &format!("{line}\n"), false);
+    }};
+}
+
+/// Like `print!`, tagging the lines it starts and also writing the text to the log file
+macro_rules! tee_print {
+    ($($arg:tt)*) => {{
+        let text = format!($($arg)*);
+        $crate::build_log::print(module_path!(), &text, false);
+    }};
+}
+
+/// Like `eprintln!`, tagging the line and also writing it to the log file
+macro_rules! tee_eprintln {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        $crate::build_log::print(module_path!(), &format!("{line}\n"), true);
+    }};
+}
+
+pub(crate) use {tee_eprintln, tee_print, tee_println};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_each_line() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut log_file = LogFile {
+            file: file.reopen().unwrap(),
+            at_line_start: true,
+        };
+
+        log_file.write("first line\nsecond ");
+        log_file.write("line\n");
+        log_file.write("third line\n");
+
+        let contents = std::fs::read_to_string(file.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, expected) in lines
+            .iter()
+            .zip(["first line", "second line", "third line"])
+        {
+            let (timestamp, text) = line.split_once(' ').unwrap();
+            assert!(humantime::parse_rfc3339(timestamp).is_ok(), "{timestamp}");
+            assert_eq!(text, expected);
+        }
+    }
+
+    #[test]
+    fn tags_each_line() {
+        let mut at_line_start = true;
+        let tag = "trunk: build: ";
+        assert_eq!(
+            tag_lines(tag, "Step 1/9 : FROM postgres\n\nStep ", &mut at_line_start),
+            "trunk: build: Step 1/9 : FROM postgres\n\ntrunk: build: Step "
+        );
+        assert!(!at_line_start);
+        // The rest of a line isn't tagged again
+        assert_eq!(
+            tag_lines(tag, "2/9 : RUN make\n", &mut at_line_start),
+            "2/9 : RUN make\n"
+        );
+        assert!(at_line_start);
+        assert_eq!(
+            tag_lines("", "untagged\n", &mut at_line_start),
+            "untagged\n"
+        );
+
+        assert_eq!(topic("pg_trunk::trunk_toml"), "config");
+        assert_eq!(topic("config"), "config");
+        assert_eq!(topic("pg_trunk::commands::generic_build"), "build");
+    }
+}
@@ -0,0 +1,165 @@
+//! Reads a short changelog from the git history of the build path, for `--changelog-from-git`.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+
+/// At most this many commits are recorded, so that a first release doesn't carry the whole history
+pub const MAX_CHANGELOG_COMMITS: usize = 50;
+
+/// The commits since the previous release, recorded in manifest.json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Changelog {
+    /// The latest tag before `HEAD`, or none if the repository has no tags, in which case
+    /// the commits are the most recent ones
+    pub since_tag: Option<String>,
+    /// Subjects of the commits, newest first, leaving out merges
+    pub commits: Vec<String>,
+    /// Whether there were more than [`MAX_CHANGELOG_COMMITS`] commits
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+fn git(repo: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Collects the subjects of the commits that touched `repo` since its latest tag
+pub fn read_git_changelog(repo: &Path) -> anyhow::Result<Changelog> {
+    git(repo, &["rev-parse", "--is-inside-work-tree"])
+        .with_context(|| format!("{} is not in a git repository", repo.display()))?;
+    // Fails when no tag is reachable from HEAD
+    let since_tag = git(repo, &["describe", "--tags", "--abbrev=0", "HEAD"])
+        .ok()
+        .map(|tag| tag.trim().to_string());
+
+    let limit = format!("--max-count={}", MAX_CHANGELOG_COMMITS + 1);
+    let range = match &since_tag {
+        Some(tag) => format!("{tag}..HEAD"),
+        None => "HEAD".to_string(),
+    };
+    let log = git(
+        repo,
+        &[
+            "log",
+            "--no-merges",
+            "--format=%s",
+            &limit,
+            &range,
+            "--",
+            ".",
+        ],
+    )?;
+    let mut commits: Vec<String> = log.lines().map(ToString::to_string).collect();
+    let truncated = commits.len() > MAX_CHANGELOG_COMMITS;
+    commits.truncate(MAX_CHANGELOG_COMMITS);
+
+    Ok(Changelog {
+        since_tag,
+        commits,
+        truncated,
+    })
+}
+
+/// The commit the sources in `repo` were built from
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct GitRevision {
+    pub commit: String,
+    /// Whether files under `repo` differ from the commit, including untracked ones
+    pub dirty: bool,
+}
+
+/// Reads the commit checked out in `repo`, and whether the files under it have changes
+pub fn read_git_revision(repo: &Path) -> anyhow::Result<GitRevision> {
+    let commit = git(repo, &["rev-parse", "HEAD"])
+        .with_context(|| format!("{} is not in a git repository", repo.display()))?;
+    let status = git(repo, &["status", "--porcelain", "--", "."])?;
+
+    Ok(GitRevision {
+        commit: commit.trim().to_string(),
+        dirty: !status.trim().is_empty(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_git(repo: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(repo)
+            .args([
+                "-c",
+                "user.name=trunk",
+                "-c",
+                "user.email=trunk@example.com",
+                "-c",
+                "commit.gpgsign=false",
+                "-c",
+                "tag.gpgsign=false",
+            ])
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    fn commit(repo: &Path, subject: &str) {
+        std::fs::write(repo.join("CHANGES"), subject).unwrap();
+        run_git(repo, &["add", "CHANGES"]);
+        run_git(repo, &["commit", "--quiet", "-m", subject]);
+    }
+
+    #[test]
+    fn reads_the_checked_out_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_git_revision(dir.path()).is_err());
+
+        run_git(dir.path(), &["init", "--quiet"]);
+        commit(dir.path(), "Initial commit");
+        let revision = read_git_revision(dir.path()).unwrap();
+        assert_eq!(revision.commit.len(), 40, "{revision:?}");
+        assert!(!revision.dirty);
+
+        std::fs::write(dir.path().join("Makefile"), "install:\n").unwrap();
+        assert!(read_git_revision(dir.path()).unwrap().dirty);
+    }
+
+    #[test]
+    fn reads_commits_since_the_latest_tag() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_git_changelog(dir.path()).is_err());
+
+        run_git(dir.path(), &["init", "--quiet"]);
+        commit(dir.path(), "Initial commit");
+        let changelog = read_git_changelog(dir.path()).unwrap();
+        assert_eq!(changelog.since_tag, None);
+        assert_eq!(changelog.commits, ["Initial commit"]);
+
+        run_git(dir.path(), &["tag", "v1.0.0"]);
+        for subject in ["Fix a crash", "Add a function"] {
+            commit(dir.path(), subject);
+        }
+        let changelog = read_git_changelog(dir.path()).unwrap();
+        assert_eq!(changelog.since_tag.as_deref(), Some("v1.0.0"));
+        assert_eq!(changelog.commits, ["Add a function", "Fix a crash"]);
+        assert!(!changelog.truncated);
+    }
+}
@@ -1,27 +1,111 @@
 use super::SubCommand;
-use crate::commands::generic_build::build_generic;
-use crate::commands::pgrx::build_pgrx;
-use crate::config::{self, ExtensionConfiguration, LoadableLibrary};
-use crate::trunk_toml::{cli_or_trunk, cli_or_trunk_opt, SystemDependencies};
-use anyhow::anyhow;
+use crate::build_log::{self, tee_print, tee_println};
+use crate::changelog::{read_git_changelog, read_git_revision};
+use crate::commands::categories::VALID_CATEGORY_SLUGS;
+use crate::commands::clean::format_size;
+use crate::commands::compare::compare_archives;
+use crate::commands::containers::{
+    check_dockerfile, dockerfile_stages, dockerfile_up_to_stage, parse_memory, parse_step_timeout,
+    BuilderKind, ImageBuildOptions, NoInstall, Strip,
+};
+use crate::commands::context_checksum::context_checksum;
+use crate::commands::context_filter::{parse_context_glob, ContextFilter};
+use crate::commands::encryption::{
+    encrypt_artifact, encrypt_file, encrypted_path, find_age, validate_encryption,
+};
+use crate::commands::generic_build::{
+    build_generic, bundled_builder, parse_entrypoint, staged_dockerfile, validate_install_prefixes,
+    validate_install_user, validate_writable_paths, InstallHooks, BUNDLED_BUILDERS,
+    DEFAULT_BUILDER,
+};
+use crate::commands::git_versions::{
+    checkout_ref, read_version_refs, summary, version_from_ref, VersionResults,
+};
+use crate::commands::pgrx::{
+    build_pgrx, cargo_pgrx_flags, copy_packaged_files_command, depends_on_pgrx, read_cargo_toml,
+    validate_rust_toolchain, CargoPackage,
+};
+use crate::commands::report::BuildReport;
+use crate::commands::runtime_trace;
+use crate::commands::signing::{find_signing_tool, sign_artifact, validate_signing};
+use crate::commands::verify_archive::read_verified_archive;
+use crate::commands::workspace::{
+    build_order, discover_extensions, extension_requires, repository_root,
+};
+use crate::config::{self, ControlFields, ExtensionConfiguration, LoadableLibrary, Preload};
+use crate::labels::{parse_label, read_label_file, validate_label_key};
+use crate::manifest::{
+    ExtensionKind, Manifest, SupportedPgVersions, MANIFEST_VERSION, OLDEST_MANIFEST_VERSION,
+};
+use crate::timings::BuildTimings;
+use crate::trunk_toml::{
+    resolve_cli_env_or_trunk, resolve_cli_env_or_trunk_opt, resolve_cli_or_trunk,
+    resolve_cli_or_trunk_opt, resolve_env, resolve_flag, resolve_trunk_flag, validate_base_image,
+    validate_platform, ConfigOverride, Resolved, Source, Sources, SystemDependencies, TrunkToml,
+};
+use crate::warnings::{warn_or_fail, WarningCategory};
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use clap::Args;
+use clap::{Args, FromArgMatches, ValueEnum};
 use log::{info, warn};
 use slicedisplay::SliceDisplay;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, BufRead, Read, Seek};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
 use tokio_task_manager::Task;
 use toml::Table;
 
+pub use crate::commands::containers::{NoExtensionFilesError, PullPolicy};
+pub use crate::commands::generic_build::{CompilerFlags, InstallLayout};
+pub use crate::commands::output_layout::OutputLayout;
+pub use crate::commands::pgrx::{CargoFeatures, CargoProfile};
+pub use crate::commands::registry_auth::RegistryAuth;
+pub use crate::commands::signing::SigningTool;
+
 #[derive(Args)]
 pub struct BuildCommand {
     /// The file path of the extension to build
     #[arg(short = 'p', long = "path", default_value = ".")]
     path: String,
+    /// Build from the sources in this .tar.gz or .tar archive instead of a directory
+    #[arg(long = "source-tarball", conflicts_with = "path")]
+    source_tarball: Option<PathBuf>,
+    /// Build from a .tar.gz or .tar build context streamed on stdin instead of a directory
+    #[arg(
+        long = "context-from-stdin",
+        conflicts_with_all = ["path", "source_tarball", "shell_in"]
+    )]
+    context_from_stdin: bool,
+    /// Where to extract --source-tarball or --context-from-stdin, and to check out each ref of
+    /// --versions. Defaults to TMPDIR, or the system's temporary directory
+    #[arg(long = "temp-dir")]
+    temp_dir: Option<PathBuf>,
+    /// Leave files matching this glob, relative to --path, out of the build context. Repeatable
+    #[arg(long = "context-exclude", value_name = "GLOB")]
+    context_exclude: Vec<String>,
+    /// Send files matching this glob, relative to --path, even if --context-exclude matches them.
+    /// Repeatable
+    #[arg(
+        long = "context-include",
+        value_name = "GLOB",
+        requires = "context_exclude"
+    )]
+    context_include: Vec<String>,
     #[arg(short = 'o', long = "output-path")]
     output_path: Option<String>,
+    /// How archives are arranged under --output-path: flat, nested or registry. Defaults to flat
+    #[arg(long = "output-layout", value_enum)]
+    output_layout: Option<OutputLayout>,
+    /// Fail unless --output-path or TRUNK_OUTPUT_PATH is given, instead of writing to .trunk
+    #[arg(long = "no-default-output")]
+    no_default_output: bool,
     #[arg(short = 'v', long = "version")]
     version: Option<String>,
     #[arg(short = 'n', long = "name")]
@@ -36,19 +120,325 @@ pub struct BuildCommand {
     platform: Option<String>,
     #[arg(short = 'd', long = "dockerfile")]
     dockerfile_path: Option<String>,
+    /// The bundled Dockerfile to build a generic extension with, instead of a custom --dockerfile.
+    /// Defaults to `generic`. See --list-builders
+    #[arg(long = "builder", conflicts_with = "dockerfile_path")]
+    builder: Option<String>,
+    /// List the bundled Dockerfiles that --builder can select, then exit
+    #[arg(long = "list-builders")]
+    list_builders: bool,
+    /// Command run before the build command, as its own cached image layer
+    #[arg(long = "configure-command")]
+    configure_command: Option<String>,
+    /// Command that compiles the extension, as its own cached image layer.
+    /// Replaces the `make` step of the default Dockerfile
+    #[arg(long = "build-command")]
+    build_command: Option<String>,
+    /// CFLAGS of the compile and install steps, recorded in the manifest. Generic builds only
+    #[arg(long = "cflags", allow_hyphen_values = true)]
+    cflags: Option<String>,
+    /// CXXFLAGS of the compile and install steps, recorded in the manifest. Generic builds only
+    #[arg(long = "cxxflags", allow_hyphen_values = true)]
+    cxxflags: Option<String>,
     #[arg(short = 'i', long = "install-command")]
     install_command: Option<String>,
+    /// Command to run in the build container right before the install command, with the same
+    /// entrypoint, environment, directory and user. Generic builds only
+    #[arg(long = "before-install")]
+    before_install: Option<String>,
+    /// Command to run in the build container right after the install command, with the same
+    /// entrypoint, environment, directory and user. Generic builds only
+    #[arg(long = "after-install")]
+    after_install: Option<String>,
+    /// Run the install command as this user[:group], by name or numeric id, instead of root.
+    /// Packaged files keep the ownership it gives them. Generic builds only
+    #[arg(long = "user")]
+    user: Option<String>,
+    /// Fail if the install command changes files outside pg_config's prefix, the install
+    /// prefixes, the directory it runs from and /tmp. Generic builds only
+    #[arg(long = "check-install-writes")]
+    check_install_writes: bool,
+    /// With --check-install-writes, another absolute path the install command may write to.
+    /// Can be repeated
+    #[arg(long = "writable-path", requires = "check_install_writes")]
+    writable_path: Vec<String>,
+    /// Command line to run the install command with instead of /bin/sh -c, e.g. "/bin/bash -lc",
+    /// for images whose shell setup the install command relies on. Generic builds only
+    #[arg(long = "entrypoint")]
+    entrypoint: Option<String>,
+    /// The directory, relative to --path, that contains the extension's sources.
+    /// The install command is run from this directory. Defaults to the root of the build context.
+    #[arg(long = "extension-dir")]
+    extension_dir: Option<String>,
+    /// The Cargo.toml to detect pgrx in and to read the name and version from, relative to the
+    /// current directory. Defaults to the Cargo.toml in --extension-dir, or else in --path
+    #[arg(long = "cargo-manifest")]
+    cargo_manifest: Option<PathBuf>,
+    /// Build the builder image with BuildKit
+    #[arg(long = "buildkit", overrides_with = "no_buildkit")]
+    buildkit: bool,
+    /// Build the builder image with the classic builder. This is the default
+    #[arg(long = "no-buildkit", overrides_with = "buildkit")]
+    no_buildkit: bool,
+    /// The image to build on, instead of the builder's default. It may be a local image that was never pushed to a registry
+    #[arg(long = "base-image")]
+    base_image: Option<String>,
+    /// Number of CPUs the build can use, e.g. 1.5. Unlimited by default
+    #[arg(long = "cpus")]
+    cpus: Option<f64>,
+    /// Directory, relative to `pg_config --prefix`, where the install command puts shared libraries.
+    /// Defaults to `pg_config --pkglibdir`
+    #[arg(long = "lib-dir")]
+    lib_dir: Option<String>,
+    /// Directory, relative to `pg_config --prefix`, where the install command puts SQL scripts.
+    /// Defaults to the extension directory of `pg_config --sharedir`
+    #[arg(long = "sql-dir")]
+    sql_dir: Option<String>,
+    /// Directory, relative to `pg_config --prefix`, where the install command puts the control file.
+    /// Defaults to the extension directory of `pg_config --sharedir`
+    #[arg(long = "control-dir")]
+    control_dir: Option<String>,
+    /// Comma-separated absolute prefixes the install command installs to besides `pg_config --prefix`,
+    /// e.g. /opt/ext. Their files are captured as if installed under `pg_config --prefix`. Generic builds only
+    #[arg(long = "prefix", value_delimiter = ',')]
+    prefix: Option<Vec<String>>,
+    /// Memory limit of the build, e.g. 512m or 2g. Unlimited by default
+    #[arg(long = "memory")]
+    memory: Option<String>,
+    /// Fail the build when a single step, such as a Dockerfile step or the install command, runs
+    /// longer than this, e.g. 90s or 10m. Unlimited by default
+    #[arg(long = "step-timeout")]
+    step_timeout: Option<String>,
+    /// Fail the build on warnings of these comma-separated categories instead of printing them,
+    /// e.g. missing-install-command,control-mismatch
+    #[arg(long = "fail-on-warn", value_enum, value_delimiter = ',')]
+    fail_on_warn: Vec<WarningCategory>,
+    /// When to pull the base images of the builder image. Defaults to `missing`
+    #[arg(long = "pull", value_enum)]
+    pull: Option<PullPolicy>,
+    /// Build without network access. Base images are not pulled unless --pull=missing is also given
+    #[arg(long = "offline")]
+    offline: bool,
+    /// Build even if the base images don't list the requested --platform
+    #[arg(long = "skip-platform-check")]
+    skip_platform_check: bool,
+    /// Package shared libraries built for another architecture than --platform, with a warning
+    #[arg(long = "allow-arch-mismatch")]
+    allow_arch_mismatch: bool,
+    /// Package the extension even if its control file's default_version differs from --version
+    #[arg(long = "no-version-check")]
+    no_version_check: bool,
+    /// Docker-style config.json with the credentials for pulling base images, instead of those of `docker login`
+    #[arg(long = "registry-auth-file")]
+    registry_auth_file: Option<PathBuf>,
+    /// Stage of a multi-stage custom Dockerfile to build up to, instead of the last one. Generic builds only
+    #[arg(long = "target")]
+    target: Option<String>,
+    /// The file extension of the produced archive. The archive is a gzipped tarball regardless
+    #[arg(long = "artifact-suffix", default_value = ".tar.gz")]
+    artifact_suffix: String,
+    /// The octal file mode of the written archive and its signatures, e.g. 0640
+    #[arg(long = "artifact-mode", default_value = "0644")]
+    artifact_mode: String,
+    /// The manifest version of the archive. Older versions are laid out for older consumers
+    #[arg(long = "format-version", default_value_t = MANIFEST_VERSION)]
+    format_version: i32,
+    /// Sign the archive with this tool, writing a detached signature next to it
+    #[arg(long = "sign", value_enum)]
+    sign: Option<SigningTool>,
+    /// The key to sign with. Required by minisign; cosign signs keyless through Sigstore without one
+    #[arg(long = "sign-key", requires = "sign")]
+    sign_key: Option<PathBuf>,
+    /// Encrypt the archive, and the --keep-debug archive, with age, writing <archive>.age and a
+    /// sidecar <archive>.age.json that records the scheme. The unencrypted archive never
+    /// reaches the output directory, and signatures are of the encrypted one
+    #[arg(long = "encrypt")]
+    encrypt: bool,
+    /// The file of age recipients to encrypt the archive to. Required by --encrypt
+    #[arg(long = "encrypt-key", value_name = "PATH")]
+    encrypt_key: Option<PathBuf>,
+    /// An earlier archive of the extension to compare the new one with, printing the files that
+    /// were added, removed or changed
+    #[arg(long = "compare", conflicts_with = "no_install")]
+    compare: Option<PathBuf>,
+    /// Also write a JSON report of the build to this file: the digests of the archive, its files
+    /// and its signatures, the toolchain, the git commit, the changelog, labels and timings
+    #[arg(long = "report", conflicts_with = "no_install")]
+    report: Option<PathBuf>,
+    /// Skip the build if the output directory already has an intact archive of this extension,
+    /// version and Postgres version, as an interrupted run of a build matrix leaves behind
+    #[arg(
+        long = "resume",
+        overrides_with = "force",
+        conflicts_with = "no_install"
+    )]
+    resume: bool,
+    /// Rebuild even if an earlier archive is present, overriding an earlier --resume
+    #[arg(long = "force", overrides_with = "resume")]
+    force: bool,
+    /// Fail instead of overwriting the archive if the output directory already has one with its
+    /// name, e.g. to guard against rebuilding a released version
+    #[arg(long = "fail-if-exists", conflicts_with_all = ["resume", "no_install"])]
+    fail_if_exists: bool,
+    /// Package the extension even if the install command installed no control, SQL or library files
+    #[arg(long = "allow-missing-control", visible_alias = "allow-empty")]
+    allow_missing_control: bool,
+    /// Record the SHA-256 digest of every packaged file in manifest.json
+    #[arg(long = "file-digests")]
+    file_digests: bool,
+    /// Strip the debug symbols from the packaged shared libraries, with `strip` in the builder image
+    #[arg(long = "strip")]
+    strip: bool,
+    /// With --strip, keep the debug symbols in a companion <archive>-debug archive
+    #[arg(long = "keep-debug", requires = "strip")]
+    keep_debug: bool,
+    /// Record the subjects of the commits since the latest git tag in manifest.json
+    #[arg(long = "changelog-from-git")]
+    changelog_from_git: bool,
+    /// A provenance label recorded in manifest.json, as KEY=VALUE. Can be repeated
+    #[arg(long = "label", value_parser = parse_label)]
+    label: Vec<(String, String)>,
+    /// A file of KEY=VALUE labels, one per line, with blank lines and # comments ignored.
+    /// --label overrides the labels in it, and they override [build.labels] in Trunk.toml
+    #[arg(long = "label-file")]
+    label_file: Option<PathBuf>,
+    /// Comma-separated registry categories to record in the manifest, instead of the
+    /// `categories` of Trunk.toml
+    #[arg(long = "categories", value_delimiter = ',')]
+    categories: Option<Vec<String>>,
+    /// Don't run the commands from the [hooks] table of Trunk.toml
+    #[arg(long = "no-hooks")]
+    no_hooks: bool,
+    /// First build the extensions in the same repository that this one requires, in dependency
+    /// order. Required extensions that aren't in the repository are skipped
+    #[arg(
+        long = "with-deps",
+        conflicts_with_all = ["source_tarball", "context_from_stdin", "no_install"]
+    )]
+    with_deps: bool,
+    /// Build each of these git refs of the repository at --path, such as the tags of past
+    /// releases, one after another. Each is labeled with the version its sources give, or else
+    /// the version the ref names, e.g. 1.2.0 for v1.2.0
+    #[arg(
+        long = "versions",
+        value_name = "REF",
+        value_delimiter = ',',
+        group = "version_refs",
+        conflicts_with_all = ["version", "source_tarball", "context_from_stdin", "no_install", "with_deps"]
+    )]
+    versions: Vec<String>,
+    /// Build the git refs listed in this file, one per line, as with --versions
+    #[arg(
+        long = "versions-file",
+        value_name = "PATH",
+        group = "version_refs",
+        conflicts_with_all = ["version", "source_tarball", "context_from_stdin", "no_install", "with_deps"]
+    )]
+    versions_file: Option<PathBuf>,
+    /// With --versions, carry on building the remaining refs after one fails
+    #[arg(long = "keep-going", requires = "version_refs")]
+    keep_going: bool,
+    /// Only warn, instead of failing, if the extension name is not a legal unquoted Postgres identifier
+    #[arg(long = "allow-unusual-name")]
+    allow_unusual_name: bool,
+    /// Comma-separated Cargo features to enable when packaging a pgrx extension
+    #[arg(long = "cargo-features", value_delimiter = ',')]
+    cargo_features: Option<Vec<String>>,
+    /// Disable the default Cargo features when packaging a pgrx extension
+    #[arg(long = "no-default-features")]
+    no_default_features: bool,
+    /// Enable all Cargo features when packaging a pgrx extension
+    #[arg(long = "all-features")]
+    all_features: bool,
+    /// The Cargo profile to package a pgrx extension with. Defaults to `release`
+    #[arg(long = "profile", value_enum)]
+    profile: Option<CargoProfile>,
+    /// Merge the values of [profile.<name>] in Trunk.toml over its [extension] and [build]
+    #[arg(long = "profile-name")]
+    profile_name: Option<String>,
+    /// Set the Trunk.toml key at a dotted path, such as build.cpus=2, over Trunk.toml, its
+    /// profiles and its platform overrides. Can be repeated
+    #[arg(long = "config-override", value_name = "KEY=VALUE")]
+    config_override: Vec<ConfigOverride>,
+    /// The Rust toolchain to package a pgrx extension with, such as 1.79.0 or nightly-2024-06-01.
+    /// Defaults to the one pinned by a rust-toolchain.toml in the sources, if any
+    #[arg(long = "rust-toolchain")]
+    rust_toolchain: Option<String>,
+    /// Build as a pgrx extension even if Cargo.toml doesn't list pgrx in its [dependencies]
+    #[arg(long = "force-pgrx")]
+    force_pgrx: bool,
+    /// Print every resolved build setting along with where its value came from, then exit without building
+    #[arg(long = "explain")]
+    explain: bool,
+    /// Print the resolved settings as a Trunk.toml to commit, then exit without building
+    #[arg(long = "print-settings-toml", conflicts_with = "explain")]
+    print_settings_toml: bool,
+    /// Print the commands the install step would run, one per line, then exit without building
+    #[arg(
+        long = "print-install-command",
+        conflicts_with_all = ["explain", "print_settings_toml"]
+    )]
+    print_install_command: bool,
+    /// Print a checksum of the build context and the resolved settings, for a CI cache key,
+    /// then exit without building
+    #[arg(
+        long = "print-context-checksum",
+        conflicts_with_all = ["explain", "print_settings_toml", "print_install_command"]
+    )]
+    print_context_checksum: bool,
+    /// Also write the full build output, with timestamps, to this file
+    #[arg(long = "log-file")]
+    log_file: Option<PathBuf>,
+    /// Start each line the build prints with this, followed by its topic (`build: ` or
+    /// `config: `). An empty prefix prints the lines untagged
+    #[arg(long = "log-prefix", default_value = "trunk: ")]
+    log_prefix: String,
+    /// Print each call made to the container runtime to stderr, as the docker or podman command
+    /// line that does the same, with the values of secret-looking variables redacted
+    #[arg(long = "trace-docker")]
+    trace_docker: bool,
+    /// Build the builder image, then stop without running the install command or writing an archive
+    #[arg(long = "no-install", conflicts_with_all = ["test", "sign", "integration_test"])]
+    no_install: bool,
+    /// With --no-install, open an interactive shell in a container of the builder image
+    #[arg(long = "shell-in", requires = "no_install")]
+    shell_in: bool,
     /// Run this extension's integration tests after building, if any are found
     #[clap(long, short, action)]
     test: bool,
+    /// Once packaged, install the archive into a fresh Postgres in a container of the builder
+    /// image and run this SQL file against it with psql, failing on the first error
+    #[arg(long = "integration-test", value_name = "SQL_FILE")]
+    integration_test: Option<PathBuf>,
+    /// Skip the integration test set by `integration_test` in Trunk.toml
+    #[arg(long = "no-integration-test", conflicts_with = "integration_test")]
+    no_integration_test: bool,
     /// The PostgreSQL version to build this extension against. Experimental for versions other than Postgres 15.
     #[clap(default_value = "15", long, action)]
     pg_version: u8,
 }
 
+/// Everything a build needs. `trunk build` resolves these from its flags, the environment and
+/// Trunk.toml; to build programmatically, use [`BuildSettings::builder`] and [`build`].
 pub struct BuildSettings {
     pub path: String,
+    /// The archive `path` was extracted from, if building from a source tarball
+    pub source_tarball: Option<PathBuf>,
+    /// Whether `path` was extracted from a tar read from stdin
+    pub context_from_stdin: bool,
+    /// Where `source_tarball` or the context from stdin is extracted, if not the system's temporary
+    /// directory. Falls back to
+    /// the system's temporary directory if it isn't writable or lacks the space
+    pub temp_dir: Option<PathBuf>,
+    /// Files of the build context matching these globs aren't sent to the container runtime,
+    /// unless they match `context_include`
+    pub context_exclude: Vec<glob::Pattern>,
+    pub context_include: Vec<glob::Pattern>,
     pub output_path: String,
+    /// How archives are arranged under `output_path`
+    pub output_layout: OutputLayout,
+    /// Whether the output path must be given rather than defaulting to `.trunk`
+    pub require_explicit_output: bool,
     pub version: Option<String>,
     pub name: Option<String>,
     pub extension_name: Option<String>,
@@ -56,260 +446,4389 @@ pub struct BuildSettings {
     pub configurations: Option<Vec<ExtensionConfiguration>>,
     pub system_dependencies: Option<SystemDependencies>,
     pub glob_patterns_to_include: Vec<glob::Pattern>,
+    /// Installed files to package, relative to `pg_config --prefix`, from `capture_globs`
+    pub capture_globs: Vec<glob::Pattern>,
     pub platform: Option<String>,
     pub dockerfile_path: Option<String>,
+    /// Bundled Dockerfile of generic builds without a `dockerfile_path`, `generic` unless set
+    pub builder: Option<String>,
+    /// Stage of the custom Dockerfile to build up to
+    pub target: Option<String>,
+    /// Runs as an image layer before `build_command`
+    pub configure_command: Option<String>,
+    /// Runs as an image layer before the install command
+    pub build_command: Option<String>,
+    /// `CFLAGS` and `CXXFLAGS` of the compile and install steps of generic builds
+    pub compiler_flags: CompilerFlags,
     pub install_command: Option<String>,
+    /// Command run in the build container right before `install_command`
+    pub before_install: Option<String>,
+    /// Command run in the build container right after `install_command`
+    pub after_install: Option<String>,
+    /// `user[:group]` the install command runs as, root unless set
+    pub install_user: Option<String>,
+    /// Whether the install command may only change its prefixes and `writable_paths`
+    pub check_install_writes: bool,
+    /// Other paths the install command may change with `check_install_writes`
+    pub writable_paths: Vec<String>,
+    /// Argv the install command is appended to, `/bin/sh -c` unless set
+    pub entrypoint: Option<Vec<String>>,
+    pub extension_dir: Option<String>,
+    /// The Cargo.toml read instead of the one in the extension's directory
+    pub cargo_manifest: Option<PathBuf>,
+    /// Where generic builds install files, if not the default layout
+    pub install_layout: InstallLayout,
+    /// Files, relative to `path`, copied into the archive as-is
+    pub included_files: Vec<String>,
+    /// Appended to the artifact's file name, e.g. `.tar.gz`
+    pub artifact_suffix: String,
+    /// File mode of the archive and its signatures, `0o644` unless set
+    pub artifact_mode: u32,
+    /// The `manifest_version` to write, and lay the archive out for
+    pub format_version: i32,
+    /// Signs the archive once it's packaged
+    pub sign: Option<SigningTool>,
+    pub sign_key: Option<PathBuf>,
+    /// Encrypts the archive and its debug symbols to the age recipients in `encrypt_key`, before
+    /// they're signed
+    pub encrypt: bool,
+    pub encrypt_key: Option<PathBuf>,
+    /// An earlier archive to compare the new one with once it's built
+    pub compare: Option<PathBuf>,
+    /// Where to write the JSON report of the build, if anywhere
+    pub report: Option<PathBuf>,
+    /// Whether to keep an intact archive left in the output directory by an earlier build,
+    /// rather than building it again
+    pub resume: bool,
+    /// Whether to fail rather than overwrite an archive already in the output directory
+    pub fail_if_exists: bool,
+    /// Whether to package a build that installed no extension files
+    pub allow_missing_control: bool,
+    /// Whether manifest.json records the digest of each packaged file
+    pub file_digests: bool,
+    /// Whether the debug symbols of the packaged shared libraries are stripped
+    pub strip: bool,
+    /// Whether stripped debug symbols are packaged in a companion archive
+    pub keep_debug: bool,
+    /// Whether manifest.json records the commits since the latest git tag of `path`
+    pub changelog_from_git: bool,
+    /// Provenance labels recorded in manifest.json
+    pub labels: BTreeMap<String, String>,
+    /// The file the labels were read from, if any
+    pub label_file: Option<PathBuf>,
+    /// Registry categories recorded in manifest.json, such as `analytics`
+    pub categories: Vec<String>,
+    /// Command that transforms manifest.json before it's packaged, from `[hooks]` in Trunk.toml
+    pub manifest_transform: Option<String>,
+    /// Whether to skip the `[hooks]` commands
+    pub no_hooks: bool,
+    /// Whether an extension name that needs quoting in SQL is only warned about
+    pub allow_unusual_name: bool,
+    /// Features `cargo pgrx package` builds with
+    pub cargo_features: CargoFeatures,
+    /// Cargo profile of pgrx builds, release unless set
+    pub profile: Option<CargoProfile>,
+    /// Rust toolchain of pgrx builds, the one pinned in the sources or the builder's unless set
+    pub rust_toolchain: Option<String>,
+    /// Whether to build as a pgrx extension without detecting pgrx in Cargo.toml
+    pub force_pgrx: bool,
+    pub buildkit: bool,
+    pub pull: PullPolicy,
+    pub offline: bool,
+    /// Set to stop after building the builder image, for debugging. Only `trunk build` sets it
+    pub no_install: Option<NoInstall>,
+    /// Whether to build without checking that the base images provide `platform`
+    pub skip_platform_check: bool,
+    /// Whether to only warn when a shared library's ELF header names another architecture than
+    /// `platform`
+    pub allow_arch_mismatch: bool,
+    /// Whether to package a generic build whose control file has another `default_version` than
+    /// `version`
+    pub no_version_check: bool,
+    /// Credentials for pulling base images, read from `--registry-auth-file`
+    pub registry_auth: Option<RegistryAuth>,
+    pub base_image: Option<String>,
+    pub cpus: Option<f64>,
+    /// Memory limit, in bytes
+    pub memory: Option<u64>,
+    /// How long a single build step may run
+    pub step_timeout: Option<Duration>,
+    /// The categories of warnings that fail the build
+    pub fail_on_warn: Vec<WarningCategory>,
     pub should_test: bool,
+    /// SQL file run against the installed archive once it is packaged, see `--integration-test`
+    pub integration_test: Option<PathBuf>,
+    /// Whether `--no-integration-test` skipped the integration test
+    pub no_integration_test: bool,
     pub loadable_libraries: Option<Vec<LoadableLibrary>>,
+    /// Libraries to record in `loadable_libraries` as needing `shared_preload_libraries`, from
+    /// `preload` in `[extension]`
+    pub preload: Option<Preload>,
+    /// `kind` in `[extension]`: whether this is an extension or a loadable module without a
+    /// control file
+    pub kind: ExtensionKind,
+    /// Control file fields from `[extension.control]`, recorded in the manifest
+    pub control: Option<ControlFields>,
     pub pg_version: u8,
+    /// The Postgres versions the extension declares support for, recorded in the manifest
+    pub supported_pg_versions: SupportedPgVersions,
+    /// Where each resolved setting came from
+    pub sources: Sources,
+    /// The Trunk.toml that was read, before platform overrides, for `--print-settings-toml`
+    trunk_toml: Option<Table>,
+    /// With `encrypt`, the output directory the encrypted archive is moved to, while `output_path`
+    /// is the private directory the archive is built in
+    encrypted_output_path: Option<PathBuf>,
+    /// Holds the sources extracted from `source_tarball`, removed when the settings are dropped
+    source_dir: Option<TempDir>,
 }
 
-impl BuildCommand {
-    fn settings(&self) -> Result<BuildSettings, anyhow::Error> {
-        // path cannot be set from Trunk.toml, since --path can also
-        // be used to specify the path to the directory that includes a
-        // Trunk.toml file.
-        let build_path = self.path.clone();
-        let trunkfile_path = Path::new(&build_path).join("Trunk.toml");
-        let trunk_toml = match File::open(trunkfile_path) {
-            Ok(file) => Some(config::parse_trunk_toml(file)?),
-            Err(_e) => {
-                warn!("Trunk.toml not found");
+/// What a build produced
+#[derive(Debug)]
+pub struct BuildOutput {
+    /// The packaged archive, in the output directory. With `--encrypt`, the encrypted archive
+    pub artifact_path: PathBuf,
+    /// The manifest.json packaged in the archive
+    pub manifest: Manifest,
+    /// Time spent in each phase of the build
+    pub timings: BuildTimings,
+    /// Detached signatures written next to the archive, if it was signed
+    pub signatures: Vec<PathBuf>,
+    /// The companion archive of the libraries' debug symbols, with `--keep-debug`. Encrypted
+    /// with `--encrypt`
+    pub debug_symbols_path: Option<PathBuf>,
+    /// The sidecar recording how the archive was encrypted, with `--encrypt`
+    pub encryption_sidecar: Option<PathBuf>,
+}
 
-                None
-            }
-        };
+/// Builds [`BuildSettings`] with the same defaults as `trunk build`, without reading Trunk.toml
+/// or the environment.
+pub struct BuildSettingsBuilder {
+    settings: BuildSettings,
+    output_path: Option<String>,
+    pull: Option<PullPolicy>,
+    registry_auth_file: Option<PathBuf>,
+}
 
-        // If output_path is not specified, default to .trunk directory in
-        // the directory specified by --path
-        let output_path = self.output_path.clone();
-        let output_path = match output_path {
-            Some(output_path) => output_path,
-            None => {
-                let output_path = Path::new(&build_path).join(".trunk");
-                output_path
-                    .to_str()
-                    .expect("Failed trying to specify a subdirectory .trunk of the --path argument")
-                    .to_string()
-            }
-        };
+impl BuildSettingsBuilder {
+    /// The directory of the extension to build, also used as the Docker build context
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            settings: BuildSettings {
+                path: path.into(),
+                source_tarball: None,
+                context_from_stdin: false,
+                temp_dir: None,
+                context_exclude: Vec::new(),
+                context_include: Vec::new(),
+                output_path: String::new(),
+                output_layout: OutputLayout::Flat,
+                require_explicit_output: false,
+                version: None,
+                name: None,
+                extension_name: None,
+                extension_dependencies: None,
+                configurations: None,
+                system_dependencies: None,
+                glob_patterns_to_include: Vec::new(),
+                capture_globs: Vec::new(),
+                platform: None,
+                dockerfile_path: None,
+                builder: None,
+                target: None,
+                configure_command: None,
+                build_command: None,
+                compiler_flags: CompilerFlags::default(),
+                install_command: None,
+                before_install: None,
+                after_install: None,
+                install_user: None,
+                check_install_writes: false,
+                writable_paths: Vec::new(),
+                entrypoint: None,
+                extension_dir: None,
+                cargo_manifest: None,
+                install_layout: InstallLayout::default(),
+                included_files: Vec::new(),
+                artifact_suffix: ".tar.gz".to_string(),
+                artifact_mode: DEFAULT_ARTIFACT_MODE,
+                format_version: MANIFEST_VERSION,
+                sign: None,
+                sign_key: None,
+                encrypt: false,
+                encrypt_key: None,
+                compare: None,
+                report: None,
+                resume: false,
+                fail_if_exists: false,
+                allow_missing_control: false,
+                file_digests: false,
+                strip: false,
+                keep_debug: false,
+                changelog_from_git: false,
+                labels: BTreeMap::new(),
+                label_file: None,
+                categories: Vec::new(),
+                manifest_transform: None,
+                no_hooks: false,
+                allow_unusual_name: false,
+                cargo_features: CargoFeatures::default(),
+                profile: None,
+                rust_toolchain: None,
+                force_pgrx: false,
+                buildkit: false,
+                pull: PullPolicy::default(),
+                offline: false,
+                no_install: None,
+                skip_platform_check: false,
+                allow_arch_mismatch: false,
+                no_version_check: false,
+                registry_auth: None,
+                base_image: None,
+                cpus: None,
+                memory: None,
+                step_timeout: None,
+                fail_on_warn: vec![],
+                should_test: false,
+                integration_test: None,
+                no_integration_test: false,
+                loadable_libraries: None,
+                preload: None,
+                kind: ExtensionKind::default(),
+                control: None,
+                pg_version: 15,
+                supported_pg_versions: SupportedPgVersions::default(),
+                sources: Sources::default(),
+                trunk_toml: None,
+                encrypted_output_path: None,
+                source_dir: None,
+            },
+            output_path: None,
+            pull: None,
+            registry_auth_file: None,
+        }
+    }
 
-        let name = cli_or_trunk(&self.name, |toml| &toml.extension.name, &trunk_toml);
+    /// Build from the sources in this archive, ignoring the path given to [`Self::new`]
+    pub fn source_tarball(mut self, source_tarball: impl Into<PathBuf>) -> Self {
+        self.settings.source_tarball = Some(source_tarball.into());
+        self
+    }
 
-        let loadable_libraries = trunk_toml
-            .as_ref()
-            .and_then(|toml| toml.extension.loadable_libraries.as_ref())
-            .cloned();
+    /// Build from a tar of the build context read from stdin, see `--context-from-stdin`
+    pub fn context_from_stdin(mut self, context_from_stdin: bool) -> Self {
+        self.settings.context_from_stdin = context_from_stdin;
+        self
+    }
 
-        let extension_name = cli_or_trunk_opt(
-            &self.extension_name,
-            |toml| &toml.extension.extension_name,
-            &trunk_toml,
-        );
+    /// Where to extract the source tarball or the context from stdin, instead of the system's
+    /// temporary directory
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.settings.temp_dir = Some(temp_dir.into());
+        self
+    }
 
-        let extension_dependencies = cli_or_trunk_opt(
-            &self.extension_dependencies,
-            |toml| &toml.extension.extension_dependencies,
-            &trunk_toml,
-        );
+    /// Files of the build context that aren't sent to the container runtime, as globs relative to
+    /// the context
+    pub fn context_exclude(mut self, patterns: Vec<glob::Pattern>) -> Self {
+        self.settings.context_exclude = patterns;
+        self
+    }
 
-        let version = cli_or_trunk(&self.version, |toml| &toml.extension.version, &trunk_toml);
+    /// Files excluded with [`Self::context_exclude`] that are sent all the same
+    pub fn context_include(mut self, patterns: Vec<glob::Pattern>) -> Self {
+        self.settings.context_include = patterns;
+        self
+    }
 
-        let platform = cli_or_trunk(&self.platform, |toml| &toml.build.platform, &trunk_toml);
+    /// Defaults to the `.trunk` directory in the extension's directory, or in the current
+    /// directory when building from a source tarball
+    pub fn output_path(mut self, output_path: impl Into<String>) -> Self {
+        self.output_path = Some(output_path.into());
+        self
+    }
 
-        let install_command = cli_or_trunk_opt(
-            &self.install_command,
-            |toml| &toml.build.install_command,
-            &trunk_toml,
-        );
+    /// Defaults to [`OutputLayout::Flat`]. The other layouts require a platform
+    pub fn output_layout(mut self, output_layout: OutputLayout) -> Self {
+        self.settings.output_layout = output_layout;
+        self
+    }
 
-        let glob_patterns_to_include = trunk_toml
-            .as_ref()
-            .map(|toml| toml.build.build_glob_patterns())
-            .transpose()?
-            .unwrap_or(Vec::new());
+    /// Fail to build the settings unless `output_path` is set, instead of defaulting to `.trunk`
+    pub fn require_explicit_output(mut self, require_explicit_output: bool) -> Self {
+        self.settings.require_explicit_output = require_explicit_output;
+        self
+    }
 
-        warn!(
-            "will include files matching {}",
-            glob_patterns_to_include.display()
-        );
+    /// Taken from Cargo.toml if not set, which pgrx extensions always have
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.settings.name = Some(name.into());
+        self
+    }
 
-        let configurations = trunk_toml
-            .as_ref()
-            .and_then(|toml| toml.extension.configurations.as_ref())
-            .cloned();
+    /// Taken from Cargo.toml if not set, which pgrx extensions always have
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.settings.version = Some(version.into());
+        self
+    }
 
-        let system_dependencies = trunk_toml
-            .as_ref()
-            .and_then(|toml| toml.dependencies.as_ref())
-            .cloned();
+    pub fn extension_name(mut self, extension_name: impl Into<String>) -> Self {
+        self.settings.extension_name = Some(extension_name.into());
+        self
+    }
 
-        // Dockerfile is handled slightly differently in Trunk.toml as the CLI.
-        // On CLI, the argument is --dockerfile_path, and it means the path relative
-        // to the current working directory where the command line argument is executed.
-        // In Trunk.toml, the field is called "dockerfile", and it means the file relative
-        // to the Trunk.toml file.
-        let dockerfile_path = self.dockerfile_path.clone().or_else(|| {
-            let dockerfile = &trunk_toml?.build.dockerfile?;
-
-            Some(
-                Path::new(&build_path)
-                    .join(dockerfile)
-                    .to_string_lossy()
-                    .into(),
-            )
-        });
+    pub fn extension_dependencies(mut self, extension_dependencies: Vec<String>) -> Self {
+        self.settings.extension_dependencies = Some(extension_dependencies);
+        self
+    }
 
-        Ok(BuildSettings {
-            path: build_path,
-            output_path,
-            version,
-            name,
-            extension_name,
-            extension_dependencies,
-            system_dependencies,
-            glob_patterns_to_include,
-            platform,
-            dockerfile_path,
-            install_command,
-            should_test: self.test,
-            configurations,
-            loadable_libraries,
-            pg_version: self.pg_version,
-        })
+    pub fn configurations(mut self, configurations: Vec<ExtensionConfiguration>) -> Self {
+        self.settings.configurations = Some(configurations);
+        self
     }
-}
 
-fn get_dockerfile(path: Option<String>) -> Result<String, std::io::Error> {
-    if let Some(dockerfile_path) = path {
-        info!("Using Dockerfile at {}", &dockerfile_path);
-        fs::read_to_string(dockerfile_path.as_str())
-    } else {
-        Ok(include_str!("./builders/Dockerfile.generic").to_string())
+    pub fn loadable_libraries(mut self, loadable_libraries: Vec<LoadableLibrary>) -> Self {
+        self.settings.loadable_libraries = Some(loadable_libraries);
+        self
     }
-}
 
-#[async_trait]
-impl SubCommand for BuildCommand {
-    async fn execute(&self, task: Task) -> Result<(), anyhow::Error> {
-        let build_settings = self.settings()?;
-        info!("Building from path {}", build_settings.path);
-        let path = Path::new(&build_settings.path);
+    /// Which of the extension's libraries must be in `shared_preload_libraries`, see `preload`
+    /// in Trunk.toml
+    pub fn preload(mut self, preload: Preload) -> Self {
+        self.settings.preload = Some(preload);
+        self
+    }
 
-        if path.join("Cargo.toml").exists() {
-            let cargo_toml: Table =
-                toml::from_str(&std::fs::read_to_string(path.join("Cargo.toml")).unwrap()).unwrap();
-            let dependencies = cargo_toml.get("dependencies").unwrap().as_table().unwrap();
-            if dependencies.contains_key("pgrx") {
-                info!("Detected that we are building a pgrx extension");
-                // if user provides name, check that it matches Cargo.toml name
-                if build_settings.name.is_some() {
-                    let package = cargo_toml.get("package");
-                    let cargo_name = package.unwrap().get("name");
-                    if build_settings.name
-                        != Some(cargo_name.unwrap().as_str().unwrap().to_string())
-                    {
-                        return Err(anyhow!(
-                            "User-provided name must match name in Cargo.toml\n \
-                             User-provided name: {}\n \
-                             Cargo.toml name: {}\n\
-                            ",
-                            build_settings.name.unwrap(),
-                            cargo_name.unwrap().as_str().unwrap().to_string()
-                        ));
-                    }
-                }
-                // if user provides version, check that it matches Cargo.toml version
-                if build_settings.version.is_some() {
-                    let package = cargo_toml.get("package");
-                    let cargo_version = package.unwrap().get("version");
-                    if build_settings.version
-                        != Some(cargo_version.unwrap().as_str().unwrap().to_string())
-                    {
-                        return Err(anyhow!(
-                            "User-provided version must match version in Cargo.toml\n \
-                             User-provided version: {}\n \
-                             Cargo.toml version: {}\n\
-                            ",
-                            build_settings.version.unwrap(),
-                            cargo_version.unwrap().as_str().unwrap().to_string()
-                        ));
-                    }
-                }
+    /// Package a loadable module without a control file, see `kind` in Trunk.toml
+    pub fn kind(mut self, kind: ExtensionKind) -> Self {
+        self.settings.kind = kind;
+        self
+    }
 
-                build_pgrx(
-                    build_settings.dockerfile_path.clone(),
-                    build_settings.platform.clone(),
-                    path,
-                    &build_settings.output_path,
-                    build_settings.extension_name,
-                    build_settings.extension_dependencies,
-                    cargo_toml,
-                    build_settings.system_dependencies,
-                    build_settings.glob_patterns_to_include,
-                    build_settings.configurations,
-                    build_settings.loadable_libraries,
-                    build_settings.pg_version,
-                    task,
-                )
-                .await?;
-                return Ok(());
+    pub fn control(mut self, control: ControlFields) -> Self {
+        self.settings.control = Some(control);
+        self
+    }
+
+    pub fn system_dependencies(mut self, system_dependencies: SystemDependencies) -> Self {
+        self.settings.system_dependencies = Some(system_dependencies);
+        self
+    }
+
+    /// Extra files installed by the build to package, like `include` in Trunk.toml
+    pub fn include(mut self, patterns: Vec<glob::Pattern>) -> Self {
+        self.settings.glob_patterns_to_include = patterns;
+        self
+    }
+
+    /// Installed files to package, relative to `pg_config --prefix`, like `capture_globs` in
+    /// Trunk.toml
+    pub fn capture_globs(mut self, patterns: Vec<glob::Pattern>) -> Self {
+        self.settings.capture_globs = patterns;
+        self
+    }
+
+    pub fn platform(mut self, platform: impl Into<String>) -> Self {
+        self.settings.platform = Some(platform.into());
+        self
+    }
+
+    /// Relative to the current directory. Defaults to the builder's own Dockerfile
+    pub fn dockerfile_path(mut self, dockerfile_path: impl Into<String>) -> Self {
+        self.settings.dockerfile_path = Some(dockerfile_path.into());
+        self
+    }
+
+    /// The bundled Dockerfile to build with, unless [`Self::dockerfile_path`] is set
+    pub fn builder(mut self, builder: impl Into<String>) -> Self {
+        self.settings.builder = Some(builder.into());
+        self
+    }
+
+    /// Stage of the custom Dockerfile to build up to. Requires [`Self::dockerfile_path`]
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.settings.target = Some(target.into());
+        self
+    }
+
+    /// Run before the build command, in a separate image layer
+    pub fn configure_command(mut self, configure_command: impl Into<String>) -> Self {
+        self.settings.configure_command = Some(configure_command.into());
+        self
+    }
+
+    /// Compiles the extension in a separate image layer, instead of the default Dockerfile's `make`
+    pub fn build_command(mut self, build_command: impl Into<String>) -> Self {
+        self.settings.build_command = Some(build_command.into());
+        self
+    }
+
+    /// `CFLAGS` of the compile and install steps of generic builds
+    pub fn cflags(mut self, cflags: impl Into<String>) -> Self {
+        self.settings.compiler_flags.cflags = Some(cflags.into());
+        self
+    }
+
+    /// `CXXFLAGS` of the compile and install steps of generic builds
+    pub fn cxxflags(mut self, cxxflags: impl Into<String>) -> Self {
+        self.settings.compiler_flags.cxxflags = Some(cxxflags.into());
+        self
+    }
+
+    /// Defaults to `make install`
+    pub fn install_command(mut self, install_command: impl Into<String>) -> Self {
+        self.settings.install_command = Some(install_command.into());
+        self
+    }
+
+    /// Command to run in the build container right before the install command
+    pub fn before_install(mut self, before_install: impl Into<String>) -> Self {
+        self.settings.before_install = Some(before_install.into());
+        self
+    }
+
+    /// Command to run in the build container right after the install command
+    pub fn after_install(mut self, after_install: impl Into<String>) -> Self {
+        self.settings.after_install = Some(after_install.into());
+        self
+    }
+
+    /// `user[:group]` to run the install command as, instead of root
+    pub fn install_user(mut self, install_user: impl Into<String>) -> Self {
+        self.settings.install_user = Some(install_user.into());
+        self
+    }
+
+    /// Fail if the install command changes files outside its prefixes, the directory it runs from,
+    /// /tmp and `writable_paths`, see `--check-install-writes`
+    pub fn check_install_writes(mut self, check_install_writes: bool) -> Self {
+        self.settings.check_install_writes = check_install_writes;
+        self
+    }
+
+    /// Other absolute paths the install command may change with [`Self::check_install_writes`]
+    pub fn writable_paths(mut self, writable_paths: Vec<String>) -> Self {
+        self.settings.writable_paths = writable_paths;
+        self
+    }
+
+    /// The program and arguments the install command is passed to, instead of `/bin/sh -c`
+    pub fn entrypoint<S: Into<String>>(mut self, entrypoint: impl IntoIterator<Item = S>) -> Self {
+        self.settings.entrypoint = Some(entrypoint.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Relative to the extension's directory, see `--extension-dir`
+    pub fn extension_dir(mut self, extension_dir: impl Into<String>) -> Self {
+        self.settings.extension_dir = Some(extension_dir.into());
+        self
+    }
+
+    /// The Cargo.toml to detect pgrx in, see `--cargo-manifest`
+    pub fn cargo_manifest(mut self, cargo_manifest: impl Into<PathBuf>) -> Self {
+        self.settings.cargo_manifest = Some(cargo_manifest.into());
+        self
+    }
+
+    pub fn install_layout(mut self, install_layout: InstallLayout) -> Self {
+        self.settings.install_layout = install_layout;
+        self
+    }
+
+    /// Files relative to the extension's directory, like `include_files` in Trunk.toml
+    pub fn included_files(mut self, included_files: Vec<String>) -> Self {
+        self.settings.included_files = included_files;
+        self
+    }
+
+    pub fn artifact_suffix(mut self, artifact_suffix: impl Into<String>) -> Self {
+        self.settings.artifact_suffix = artifact_suffix.into();
+        self
+    }
+
+    /// The file mode of the archive and its signatures, such as `0o640`
+    pub fn artifact_mode(mut self, artifact_mode: u32) -> Self {
+        self.settings.artifact_mode = artifact_mode;
+        self
+    }
+
+    pub fn format_version(mut self, format_version: i32) -> Self {
+        self.settings.format_version = format_version;
+        self
+    }
+
+    /// Compare the new archive with an earlier one once it's built
+    pub fn compare(mut self, previous_archive: impl Into<PathBuf>) -> Self {
+        self.settings.compare = Some(previous_archive.into());
+        self
+    }
+
+    /// Write a JSON report of the build to `path` once it's built, see `--report`
+    pub fn report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.settings.report = Some(path.into());
+        self
+    }
+
+    /// Keep an intact archive that an earlier build left in the output directory
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.settings.resume = resume;
+        self
+    }
+
+    /// Fail instead of overwriting an archive already in the output directory
+    pub fn fail_if_exists(mut self, fail_if_exists: bool) -> Self {
+        self.settings.fail_if_exists = fail_if_exists;
+        self
+    }
+
+    /// Sign the archive with `tool`, using `key` if given. minisign always needs a key
+    pub fn sign(mut self, tool: SigningTool, key: Option<PathBuf>) -> Self {
+        self.settings.sign = Some(tool);
+        self.settings.sign_key = key;
+        self
+    }
+
+    /// Encrypt the archive to the age recipients in the file `recipients` once it's packaged
+    pub fn encrypt(mut self, recipients: PathBuf) -> Self {
+        self.settings.encrypt = true;
+        self.settings.encrypt_key = Some(recipients);
+        self
+    }
+
+    /// Package the extension even if the build installed no control, SQL or library files
+    pub fn allow_missing_control(mut self, allow_missing_control: bool) -> Self {
+        self.settings.allow_missing_control = allow_missing_control;
+        self
+    }
+
+    /// Record the SHA-256 digest of every packaged file in manifest.json
+    pub fn file_digests(mut self, file_digests: bool) -> Self {
+        self.settings.file_digests = file_digests;
+        self
+    }
+
+    /// Strip the debug symbols from the packaged shared libraries, see `--strip`
+    pub fn strip(mut self, strip: bool) -> Self {
+        self.settings.strip = strip;
+        self
+    }
+
+    /// Package the symbols [`Self::strip`] removes in a companion archive, see `--keep-debug`
+    pub fn keep_debug(mut self, keep_debug: bool) -> Self {
+        self.settings.keep_debug = keep_debug;
+        self
+    }
+
+    pub fn changelog_from_git(mut self, changelog_from_git: bool) -> Self {
+        self.settings.changelog_from_git = changelog_from_git;
+        self
+    }
+
+    /// Records a provenance label in manifest.json, see `--label`
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.settings.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Reads labels from a file of `KEY=VALUE` lines, see `--label-file`. Labels set with
+    /// [`Self::label`] take precedence
+    pub fn label_file(mut self, label_file: impl Into<PathBuf>) -> Self {
+        self.settings.label_file = Some(label_file.into());
+        self
+    }
+
+    /// Registry categories to record in manifest.json. Categories the registry doesn't know are
+    /// warned about
+    pub fn categories(mut self, categories: Vec<String>) -> Self {
+        self.settings.categories = categories;
+        self
+    }
+
+    /// Pipe manifest.json through `command` before packaging it, see `[hooks] manifest_transform`
+    pub fn manifest_transform(mut self, command: impl Into<String>) -> Self {
+        self.settings.manifest_transform = Some(command.into());
+        self
+    }
+
+    /// Don't run the `[hooks]` commands
+    pub fn no_hooks(mut self, no_hooks: bool) -> Self {
+        self.settings.no_hooks = no_hooks;
+        self
+    }
+
+    /// Only warn if the extension name is not a legal unquoted Postgres identifier
+    pub fn allow_unusual_name(mut self, allow_unusual_name: bool) -> Self {
+        self.settings.allow_unusual_name = allow_unusual_name;
+        self
+    }
+
+    /// Cargo features to enable when packaging a pgrx extension
+    pub fn cargo_features(mut self, features: Vec<String>) -> Self {
+        self.settings.cargo_features.features = features;
+        self
+    }
+
+    pub fn no_default_features(mut self, no_default_features: bool) -> Self {
+        self.settings.cargo_features.no_default_features = no_default_features;
+        self
+    }
+
+    pub fn all_features(mut self, all_features: bool) -> Self {
+        self.settings.cargo_features.all_features = all_features;
+        self
+    }
+
+    /// The Cargo profile to package a pgrx extension with
+    pub fn profile(mut self, profile: CargoProfile) -> Self {
+        self.settings.profile = Some(profile);
+        self
+    }
+
+    /// The Rust toolchain to package a pgrx extension with, installed with rustup in the builder
+    pub fn rust_toolchain(mut self, rust_toolchain: impl Into<String>) -> Self {
+        self.settings.rust_toolchain = Some(rust_toolchain.into());
+        self
+    }
+
+    /// Build as a pgrx extension even if pgrx isn't found in Cargo.toml's `[dependencies]`
+    pub fn force_pgrx(mut self, force_pgrx: bool) -> Self {
+        self.settings.force_pgrx = force_pgrx;
+        self
+    }
+
+    pub fn buildkit(mut self, buildkit: bool) -> Self {
+        self.settings.buildkit = buildkit;
+        self
+    }
+
+    /// Defaults to [`PullPolicy::Missing`], or [`PullPolicy::Never`] for offline builds
+    pub fn pull(mut self, pull: PullPolicy) -> Self {
+        self.pull = Some(pull);
+        self
+    }
+
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.settings.offline = offline;
+        self
+    }
+
+    /// Build without checking that the base images provide the requested platform
+    pub fn skip_platform_check(mut self, skip_platform_check: bool) -> Self {
+        self.settings.skip_platform_check = skip_platform_check;
+        self
+    }
+
+    /// Package shared libraries built for another architecture than the requested platform
+    pub fn allow_arch_mismatch(mut self, allow_arch_mismatch: bool) -> Self {
+        self.settings.allow_arch_mismatch = allow_arch_mismatch;
+        self
+    }
+
+    /// Package the extension even if its control file's `default_version` differs from `version`
+    pub fn no_version_check(mut self, no_version_check: bool) -> Self {
+        self.settings.no_version_check = no_version_check;
+        self
+    }
+
+    /// Docker-style `config.json` with the credentials for pulling base images
+    pub fn registry_auth_file(mut self, registry_auth_file: impl Into<PathBuf>) -> Self {
+        self.registry_auth_file = Some(registry_auth_file.into());
+        self
+    }
+
+    pub fn base_image(mut self, base_image: impl Into<String>) -> Self {
+        self.settings.base_image = Some(base_image.into());
+        self
+    }
+
+    pub fn cpus(mut self, cpus: f64) -> Self {
+        self.settings.cpus = Some(cpus);
+        self
+    }
+
+    /// Memory limit, in bytes
+    pub fn memory(mut self, memory: u64) -> Self {
+        self.settings.memory = Some(memory);
+        self
+    }
+
+    /// Fail the build on warnings of these categories, see `--fail-on-warn`
+    pub fn fail_on_warn(mut self, fail_on_warn: Vec<WarningCategory>) -> Self {
+        self.settings.fail_on_warn = fail_on_warn;
+        self
+    }
+
+    /// Fail the build when a single step runs longer than this
+    pub fn step_timeout(mut self, step_timeout: Duration) -> Self {
+        self.settings.step_timeout = Some(step_timeout);
+        self
+    }
+
+    /// Run the extension's regression tests after installing it
+    pub fn should_test(mut self, should_test: bool) -> Self {
+        self.settings.should_test = should_test;
+        self
+    }
+
+    /// SQL file to run against the archive installed into a fresh Postgres, once it is packaged
+    pub fn integration_test(mut self, sql_file: impl Into<PathBuf>) -> Self {
+        self.settings.integration_test = Some(sql_file.into());
+        self
+    }
+
+    pub fn pg_version(mut self, pg_version: u8) -> Self {
+        self.settings.pg_version = pg_version;
+        self
+    }
+
+    /// The oldest Postgres major version the extension supports, like `min_pg_version` in Trunk.toml
+    pub fn min_pg_version(mut self, min_pg_version: u8) -> Self {
+        self.settings.supported_pg_versions.min = Some(min_pg_version);
+        self
+    }
+
+    /// The newest Postgres major version the extension supports
+    pub fn max_pg_version(mut self, max_pg_version: u8) -> Self {
+        self.settings.supported_pg_versions.max = Some(max_pg_version);
+        self
+    }
+
+    /// Checks the settings the same way `trunk build` checks its flags
+    pub fn build(self) -> Result<BuildSettings, anyhow::Error> {
+        let mut settings = self.settings;
+
+        let extracted = match (&settings.source_tarball, settings.context_from_stdin) {
+            (Some(_), true) => {
+                return Err(anyhow!(
+                    "A source tarball and a context from stdin can't be built at the same time"
+                ));
             }
+            (Some(source_tarball), false) => Some(extract_source_tarball(
+                source_tarball,
+                settings.temp_dir.as_deref(),
+            )?),
+            (None, true) => Some(extract_stdin_context(settings.temp_dir.as_deref())?),
+            (None, false) => None,
+        };
+        if let Some((source_dir, root)) = extracted {
+            settings.path = root.to_string_lossy().into_owned();
+            settings.source_dir = Some(source_dir);
         }
+        settings.output_path = match self.output_path {
+            Some(output_path) => output_path,
+            None if settings.require_explicit_output => {
+                return Err(anyhow!(
+                    "No output path was given, and the settings require an explicit one"
+                ));
+            }
+            None if settings.source_dir.is_some() => ".trunk".to_string(),
+            None => Path::new(&settings.path)
+                .join(".trunk")
+                .to_string_lossy()
+                .into_owned(),
+        };
+        settings.pull = match self.pull {
+            Some(PullPolicy::Always) if settings.offline => {
+                return Err(anyhow!("--pull=always cannot be used with --offline"));
+            }
+            Some(pull) => pull,
+            None if settings.offline => PullPolicy::Never,
+            None => PullPolicy::default(),
+        };
 
-        // Check if version or name are missing
-        if build_settings.version.is_none() || build_settings.name.is_none() {
+        validate_pg_version(settings.pg_version)?;
+        settings.supported_pg_versions.validate()?;
+        if let Some(extension_dir) = &settings.extension_dir {
+            validate_extension_dir(Path::new(&settings.path), extension_dir)?;
+        }
+        if let Some(cargo_manifest) = &settings.cargo_manifest {
+            validate_cargo_manifest(cargo_manifest)?;
+        }
+        for included_file in &settings.included_files {
+            validate_included_file(Path::new(&settings.path), included_file)?;
+        }
+        settings
+            .output_layout
+            .validate(settings.platform.as_deref())?;
+        validate_artifact_suffix(&settings.artifact_suffix)?;
+        validate_artifact_mode(settings.artifact_mode)?;
+        validate_format_version(settings.format_version)?;
+        validate_kind(settings.kind, settings.control.as_ref())?;
+        for key in settings.labels.keys() {
+            validate_label_key(key).map_err(|err| anyhow!("Invalid label: {err}"))?;
+        }
+        if let Some(label_file) = &settings.label_file {
+            for (key, value) in read_label_file(label_file)? {
+                settings.labels.entry(key).or_insert(value);
+            }
+        }
+        validate_signing(settings.sign, settings.sign_key.as_deref())?;
+        validate_encryption(
+            settings.encrypt,
+            settings.encrypt_key.as_deref(),
+            settings.output_layout,
+            settings.resume,
+        )?;
+        if let Some(previous_archive) = &settings.compare {
+            validate_compare(previous_archive)?;
+        }
+        if let Some(install_user) = &settings.install_user {
+            validate_install_user(install_user)?;
+        }
+        validate_install_prefixes(&settings.install_layout.prefixes)?;
+        validate_writable_paths(&settings.writable_paths)?;
+        if settings.entrypoint.as_ref().is_some_and(Vec::is_empty) {
             return Err(anyhow!(
-                "--version and --name are required unless building a PGRX extension"
+                "The entrypoint must name a program to run the install command with"
             ));
         }
+        if settings.keep_debug && !settings.strip {
+            return Err(anyhow!("keep_debug only applies when strip is set"));
+        }
+        if let Some(name) = settings.extension_name.as_ref().or(settings.name.as_ref()) {
+            validate_extension_name(name, settings.allow_unusual_name)?;
+        }
+        if let Some(cpus) = settings.cpus {
+            validate_cpus(cpus)?;
+        }
+        settings.cargo_features.validate()?;
+        if let Some(rust_toolchain) = &settings.rust_toolchain {
+            validate_rust_toolchain(rust_toolchain)?;
+        }
+        if settings.target.is_some() && settings.dockerfile_path.is_none() {
+            return Err(target_requires_dockerfile());
+        }
+        if let Some(builder) = &settings.builder {
+            validate_builder(builder, settings.dockerfile_path.is_some())?;
+        }
+        if let Some(registry_auth_file) = &self.registry_auth_file {
+            settings.registry_auth = Some(RegistryAuth::load(registry_auth_file)?);
+        }
 
-        let dockerfile: String = get_dockerfile(build_settings.dockerfile_path.clone()).unwrap();
+        Ok(settings)
+    }
+}
 
-        let processed_install_command = build_settings
-            .install_command
-            .as_ref()
-            .map(|command| process_install_command(command, build_settings.pg_version));
+impl BuildSettings {
+    /// Starts building settings for the extension at `path`
+    pub fn builder(path: impl Into<String>) -> BuildSettingsBuilder {
+        BuildSettingsBuilder::new(path)
+    }
 
-        let mut install_command_split: Vec<&str> = vec![];
-        if let Some(install_command) = processed_install_command.as_deref() {
-            install_command_split.push("/bin/sh");
-            install_command_split.push("-c");
-            install_command_split.push(install_command);
-        } else {
-            warn!("Install command is not specified, guessing the command is 'make install'");
-            install_command_split = vec!["make", "install"];
+    fn image_build_options(&self) -> ImageBuildOptions {
+        ImageBuildOptions {
+            buildkit: self.buildkit,
+            pull: self.pull,
+            offline: self.offline,
+            base_image: self.base_image.clone(),
+            cpus: self.cpus,
+            memory: self.memory,
+            registry_auth: self.registry_auth.clone(),
+            skip_platform_check: self.skip_platform_check,
+            step_timeout: self.step_timeout,
+            context_filter: self.context_filter(),
         }
-        info!(
-            "Using install command {}",
-            install_command_split.clone().join(" ")
-        );
-
-        let dockerfile = dockerfile.as_str();
-        build_generic(
-            dockerfile,
-            build_settings.platform.clone(),
-            install_command_split,
-            path,
-            &build_settings.output_path,
-            build_settings.name.clone().unwrap().as_str(),
-            build_settings.extension_name,
-            build_settings.extension_dependencies,
-            build_settings.system_dependencies,
-            build_settings.version.clone().unwrap().as_str(),
-            build_settings.glob_patterns_to_include,
-            task,
-            build_settings.should_test,
-            build_settings.configurations,
-            build_settings.loadable_libraries,
-            build_settings.pg_version,
-        )
-        .await?;
-        return Ok(());
     }
-}
 
-fn process_install_command(install_command: &str, pg_version: u8) -> Cow<'_, str> {
+    fn context_filter(&self) -> ContextFilter {
+        ContextFilter {
+            exclude: self.context_exclude.clone(),
+            include: self.context_include.clone(),
+        }
+    }
+
+    /// How the packaged shared libraries are stripped, if they are
+    fn strip(&self) -> Option<Strip> {
+        match (self.strip, self.keep_debug) {
+            (false, _) => None,
+            (true, false) => Some(Strip::Discard),
+            (true, true) => Some(Strip::KeepDebug),
+        }
+    }
+
+    /// Every setting, in a stable order, as `(setting, value as JSON, flag, Trunk.toml key)`
+    fn settings_table(&self) -> Vec<ExplainedSetting> {
+        fn json<T: serde::Serialize>(value: &T) -> String {
+            serde_json::to_string(value).unwrap_or_default()
+        }
+
+        let context_exclude: Vec<&str> = self
+            .context_exclude
+            .iter()
+            .map(glob::Pattern::as_str)
+            .collect();
+        let context_include: Vec<&str> = self
+            .context_include
+            .iter()
+            .map(glob::Pattern::as_str)
+            .collect();
+        let include: Vec<&str> = self
+            .glob_patterns_to_include
+            .iter()
+            .map(glob::Pattern::as_str)
+            .collect();
+        let capture_globs: Vec<&str> = self
+            .capture_globs
+            .iter()
+            .map(glob::Pattern::as_str)
+            .collect();
+        let pull = self
+            .pull
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+
+        // (setting, value, flag, Trunk.toml key)
+        let settings = [
+            (
+                "path",
+                json(&self.path),
+                Some(if self.source_tarball.is_some() {
+                    "--source-tarball"
+                } else if self.context_from_stdin {
+                    "--context-from-stdin"
+                } else {
+                    "--path"
+                }),
+                None,
+            ),
+            (
+                "source_tarball",
+                json(&self.source_tarball),
+                Some("--source-tarball"),
+                None,
+            ),
+            (
+                "context_from_stdin",
+                json(&self.context_from_stdin),
+                Some("--context-from-stdin"),
+                None,
+            ),
+            ("temp_dir", json(&self.temp_dir), Some("--temp-dir"), None),
+            (
+                "context_exclude",
+                json(&context_exclude),
+                Some("--context-exclude"),
+                None,
+            ),
+            (
+                "context_include",
+                json(&context_include),
+                Some("--context-include"),
+                None,
+            ),
+            (
+                "output_path",
+                json(&self.output_path),
+                Some("--output-path"),
+                None,
+            ),
+            (
+                "output_layout",
+                json(&self.output_layout),
+                Some("--output-layout"),
+                None,
+            ),
+            (
+                "require_explicit_output",
+                json(&self.require_explicit_output),
+                Some("--no-default-output"),
+                Some("build.require_explicit_output"),
+            ),
+            (
+                "name",
+                json(&self.name),
+                Some("--name"),
+                Some("extension.name"),
+            ),
+            (
+                "version",
+                json(&self.version),
+                Some("--version"),
+                Some("extension.version"),
+            ),
+            (
+                "extension_name",
+                json(&self.extension_name),
+                Some("--extension_name"),
+                Some("extension.extension_name"),
+            ),
+            (
+                "extension_dependencies",
+                json(&self.extension_dependencies),
+                Some("--extension_dependencies"),
+                Some("extension.extension_dependencies"),
+            ),
+            (
+                "configurations",
+                json(&self.configurations),
+                None,
+                Some("extension.configurations"),
+            ),
+            (
+                "loadable_libraries",
+                json(&self.loadable_libraries),
+                None,
+                Some("extension.loadable_libraries"),
+            ),
+            (
+                "preload",
+                json(&self.preload),
+                None,
+                Some("extension.preload"),
+            ),
+            ("kind", json(&self.kind), None, Some("extension.kind")),
+            (
+                "control",
+                json(&self.control),
+                None,
+                Some("extension.control"),
+            ),
+            (
+                "system_dependencies",
+                json(&self.system_dependencies),
+                None,
+                Some("dependencies"),
+            ),
+            ("include", json(&include), None, Some("build.include")),
+            (
+                "include_files",
+                json(&self.included_files),
+                None,
+                Some("build.include_files"),
+            ),
+            (
+                "capture_globs",
+                json(&capture_globs),
+                None,
+                Some("build.capture_globs"),
+            ),
+            (
+                "platform",
+                json(&self.platform),
+                Some("--platform"),
+                Some("build.platform"),
+            ),
+            (
+                "dockerfile_path",
+                json(&self.dockerfile_path),
+                Some("--dockerfile"),
+                Some("build.dockerfile"),
+            ),
+            (
+                "builder",
+                json(&self.builder),
+                Some("--builder"),
+                Some("build.builder"),
+            ),
+            ("target", json(&self.target), Some("--target"), None),
+            (
+                "configure_command",
+                json(&self.configure_command),
+                Some("--configure-command"),
+                Some("build.configure_command"),
+            ),
+            (
+                "build_command",
+                json(&self.build_command),
+                Some("--build-command"),
+                Some("build.build_command"),
+            ),
+            (
+                "cflags",
+                json(&self.compiler_flags.cflags),
+                Some("--cflags"),
+                Some("build.cflags"),
+            ),
+            (
+                "cxxflags",
+                json(&self.compiler_flags.cxxflags),
+                Some("--cxxflags"),
+                Some("build.cxxflags"),
+            ),
+            (
+                "install_command",
+                json(&self.install_command),
+                Some("--install-command"),
+                Some("build.install_command"),
+            ),
+            (
+                "before_install",
+                json(&self.before_install),
+                Some("--before-install"),
+                Some("build.before_install"),
+            ),
+            (
+                "after_install",
+                json(&self.after_install),
+                Some("--after-install"),
+                Some("build.after_install"),
+            ),
+            (
+                "install_user",
+                json(&self.install_user),
+                Some("--user"),
+                Some("build.user"),
+            ),
+            (
+                "check_install_writes",
+                json(&self.check_install_writes),
+                Some("--check-install-writes"),
+                None,
+            ),
+            (
+                "writable_paths",
+                json(&self.writable_paths),
+                Some("--writable-path"),
+                None,
+            ),
+            (
+                "entrypoint",
+                json(&self.entrypoint),
+                Some("--entrypoint"),
+                Some("build.entrypoint"),
+            ),
+            (
+                "extension_dir",
+                json(&self.extension_dir),
+                Some("--extension-dir"),
+                Some("build.extension_dir"),
+            ),
+            (
+                "cargo_manifest",
+                json(&self.cargo_manifest),
+                Some("--cargo-manifest"),
+                None,
+            ),
+            (
+                "lib_dir",
+                json(&self.install_layout.lib_dir),
+                Some("--lib-dir"),
+                Some("build.lib_dir"),
+            ),
+            (
+                "sql_dir",
+                json(&self.install_layout.sql_dir),
+                Some("--sql-dir"),
+                Some("build.sql_dir"),
+            ),
+            (
+                "control_dir",
+                json(&self.install_layout.control_dir),
+                Some("--control-dir"),
+                Some("build.control_dir"),
+            ),
+            (
+                "install_prefixes",
+                json(&self.install_layout.prefixes),
+                Some("--prefix"),
+                Some("build.prefix"),
+            ),
+            (
+                "base_image",
+                json(&self.base_image),
+                Some("--base-image"),
+                Some("build.base_image"),
+            ),
+            (
+                "artifact_suffix",
+                json(&self.artifact_suffix),
+                Some("--artifact-suffix"),
+                None,
+            ),
+            (
+                "artifact_mode",
+                json(&format!("{:04o}", self.artifact_mode)),
+                Some("--artifact-mode"),
+                None,
+            ),
+            (
+                "format_version",
+                json(&self.format_version),
+                Some("--format-version"),
+                None,
+            ),
+            ("sign", json(&self.sign), Some("--sign"), None),
+            ("sign_key", json(&self.sign_key), Some("--sign-key"), None),
+            ("encrypt", json(&self.encrypt), Some("--encrypt"), None),
+            (
+                "encrypt_key",
+                json(&self.encrypt_key),
+                Some("--encrypt-key"),
+                None,
+            ),
+            ("compare", json(&self.compare), Some("--compare"), None),
+            ("report", json(&self.report), Some("--report"), None),
+            ("resume", json(&self.resume), Some("--resume"), None),
+            (
+                "fail_if_exists",
+                json(&self.fail_if_exists),
+                Some("--fail-if-exists"),
+                None,
+            ),
+            (
+                "buildkit",
+                json(&self.buildkit),
+                Some("--buildkit/--no-buildkit"),
+                None,
+            ),
+            ("cpus", json(&self.cpus), Some("--cpus"), Some("build.cpus")),
+            (
+                "memory",
+                json(&self.memory),
+                Some("--memory"),
+                Some("build.memory"),
+            ),
+            (
+                "step_timeout",
+                json(
+                    &self
+                        .step_timeout
+                        .map(|timeout| humantime::format_duration(timeout).to_string()),
+                ),
+                Some("--step-timeout"),
+                Some("build.step_timeout"),
+            ),
+            (
+                "fail_on_warn",
+                json(&self.fail_on_warn),
+                Some("--fail-on-warn"),
+                None,
+            ),
+            ("pull", json(&pull), Some("--pull"), None),
+            ("offline", json(&self.offline), Some("--offline"), None),
+            (
+                "no_install",
+                json(&self.no_install.is_some()),
+                Some("--no-install"),
+                None,
+            ),
+            (
+                "shell_in",
+                json(&(self.no_install == Some(NoInstall::Shell))),
+                Some("--shell-in"),
+                None,
+            ),
+            (
+                "skip_platform_check",
+                json(&self.skip_platform_check),
+                Some("--skip-platform-check"),
+                None,
+            ),
+            (
+                "allow_arch_mismatch",
+                json(&self.allow_arch_mismatch),
+                Some("--allow-arch-mismatch"),
+                None,
+            ),
+            (
+                "no_version_check",
+                json(&self.no_version_check),
+                Some("--no-version-check"),
+                None,
+            ),
+            (
+                "registry_auth_file",
+                json(&self.registry_auth.as_ref().map(RegistryAuth::path)),
+                Some("--registry-auth-file"),
+                Some("publish.auth_file"),
+            ),
+            (
+                "allow_missing_control",
+                json(&self.allow_missing_control),
+                Some("--allow-missing-control"),
+                None,
+            ),
+            (
+                "file_digests",
+                json(&self.file_digests),
+                Some("--file-digests"),
+                None,
+            ),
+            ("strip", json(&self.strip), Some("--strip"), None),
+            (
+                "keep_debug",
+                json(&self.keep_debug),
+                Some("--keep-debug"),
+                None,
+            ),
+            (
+                "changelog_from_git",
+                json(&self.changelog_from_git),
+                Some("--changelog-from-git"),
+                None,
+            ),
+            (
+                "labels",
+                json(&self.labels),
+                Some("--label"),
+                Some("build.labels"),
+            ),
+            (
+                "label_file",
+                json(&self.label_file),
+                Some("--label-file"),
+                None,
+            ),
+            (
+                "categories",
+                json(&self.categories),
+                Some("--categories"),
+                Some("extension.categories"),
+            ),
+            (
+                "manifest_transform",
+                json(&self.manifest_transform),
+                None,
+                Some("hooks.manifest_transform"),
+            ),
+            ("no_hooks", json(&self.no_hooks), Some("--no-hooks"), None),
+            (
+                "allow_unusual_name",
+                json(&self.allow_unusual_name),
+                Some("--allow-unusual-name"),
+                None,
+            ),
+            (
+                "cargo_features",
+                json(&self.cargo_features.features),
+                Some("--cargo-features"),
+                Some("build.pgrx.features"),
+            ),
+            (
+                "no_default_features",
+                json(&self.cargo_features.no_default_features),
+                Some("--no-default-features"),
+                Some("build.pgrx.no_default_features"),
+            ),
+            (
+                "all_features",
+                json(&self.cargo_features.all_features),
+                Some("--all-features"),
+                Some("build.pgrx.all_features"),
+            ),
+            (
+                "profile",
+                json(&self.profile),
+                Some("--profile"),
+                Some("build.pgrx.profile"),
+            ),
+            (
+                "rust_toolchain",
+                json(&self.rust_toolchain),
+                Some("--rust-toolchain"),
+                Some("build.pgrx.rust_toolchain"),
+            ),
+            (
+                "force_pgrx",
+                json(&self.force_pgrx),
+                Some("--force-pgrx"),
+                None,
+            ),
+            ("should_test", json(&self.should_test), Some("--test"), None),
+            (
+                "integration_test",
+                json(&self.integration_test),
+                Some("--integration-test"),
+                Some("build.integration_test"),
+            ),
+            (
+                "no_integration_test",
+                json(&self.no_integration_test),
+                Some("--no-integration-test"),
+                None,
+            ),
+            (
+                "pg_version",
+                json(&self.pg_version),
+                Some("--pg-version"),
+                None,
+            ),
+            (
+                "min_pg_version",
+                json(&self.supported_pg_versions.min),
+                None,
+                Some("extension.min_pg_version"),
+            ),
+            (
+                "max_pg_version",
+                json(&self.supported_pg_versions.max),
+                None,
+                Some("extension.max_pg_version"),
+            ),
+        ];
+
+        settings.into()
+    }
+
+    /// Fails if a setting overridden with `--config-override` is also given as a flag or an
+    /// environment variable, either of which would otherwise be used instead of the override
+    fn check_config_overrides(&self) -> Result<(), anyhow::Error> {
+        for (setting, _, flag, toml_key) in self.settings_table() {
+            let Some(toml_key) = toml_key else {
+                continue;
+            };
+            let Some(override_key) = self.sources.override_key(toml_key) else {
+                continue;
+            };
+            let given = match self.sources.get(setting) {
+                Some(Source::Cli) => format!("{} was given too", flag.unwrap_or(setting)),
+                Some(Source::CliFlag(flag)) => format!("{flag} was given too"),
+                Some(Source::Env(env_var)) => format!("{env_var} is set too"),
+                _ => continue,
+            };
+            return Err(anyhow!(
+                "--config-override {override_key} sets {setting}, but {given}. Only give one"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Lists every setting, in a stable order, with its value and where the value came from
+    fn explain(&self) -> String {
+        let mut explained = String::new();
+        for (setting, value, flag, toml_key) in self.settings_table() {
+            let source = match self.sources.get(setting) {
+                Some(Source::Cli) => format!("flag {}", flag.unwrap_or(setting)),
+                Some(Source::CliFlag(flag)) => format!("flag {flag}"),
+                Some(Source::Env(env_var)) => format!("environment variable {env_var}"),
+                Some(Source::TrunkToml) => {
+                    let default_toml_key = toml_key;
+                    let toml_key = self
+                        .sources
+                        .toml_key(setting)
+                        .or(toml_key)
+                        .unwrap_or(setting);
+                    let override_key = self
+                        .sources
+                        .override_key(toml_key)
+                        .or_else(|| self.sources.override_key(default_toml_key?));
+                    match override_key {
+                        Some(override_key) => format!("flag --config-override {override_key}"),
+                        None => {
+                            let profile_key = self.sources.profile_key(toml_key);
+                            format!("Trunk.toml {}", profile_key.as_deref().unwrap_or(toml_key))
+                        }
+                    }
+                }
+                Some(Source::CargoToml) => format!("Cargo.toml package.{setting}"),
+                Some(Source::Default) => "default".to_string(),
+                None => "not set".to_string(),
+            };
+            explained.push_str(&format!("{setting} = {value}  # {source}\n"));
+        }
+
+        explained
+    }
+
+    /// A digest of the build context, the settings that shape the build and a custom Dockerfile,
+    /// for `--print-context-checksum`
+    fn context_checksum(&self) -> Result<String, anyhow::Error> {
+        let settings: Vec<(&str, String)> = self
+            .settings_table()
+            .into_iter()
+            .filter(|(setting, ..)| !LOCATION_SETTINGS.contains(setting))
+            .map(|(setting, value, ..)| (setting, value))
+            .collect();
+        let dockerfile = match &self.dockerfile_path {
+            Some(dockerfile_path) => Some(
+                fs::read_to_string(dockerfile_path)
+                    .with_context(|| format!("Failed to read the Dockerfile {dockerfile_path}"))?,
+            ),
+            None => None,
+        };
+
+        context_checksum(
+            Path::new(&self.path),
+            &self.context_filter(),
+            &settings,
+            dockerfile.as_deref(),
+        )
+    }
+
+    /// The resolved settings as a Trunk.toml, for `--print-settings-toml`: the Trunk.toml that
+    /// was read, with every setting that has a non-empty value written over it
+    fn settings_toml(&self) -> Result<String, anyhow::Error> {
+        fn set(table: &mut Table, key: &str, value: toml::Value) {
+            let (parents, key) = key.rsplit_once('.').unwrap_or(("", key));
+            let table = parents.split('.').filter(|parent| !parent.is_empty()).fold(
+                table,
+                |table, parent| {
+                    let entry = table
+                        .entry(parent)
+                        .or_insert_with(|| toml::Value::Table(Table::new()));
+                    if !entry.is_table() {
+                        *entry = toml::Value::Table(Table::new());
+                    }
+                    entry.as_table_mut().expect("just made a table")
+                },
+            );
+            table.insert(key.to_string(), value);
+        }
+
+        let mut table = self.trunk_toml.clone().unwrap_or_default();
+        for (setting, value, _, toml_key) in self.settings_table() {
+            let Some(toml_key) = toml_key else {
+                continue;
+            };
+            let is_default = matches!(self.sources.get(setting), Some(Source::Default));
+            match json_to_toml(serde_json::from_str(&value)?) {
+                Some(toml::Value::Array(array)) if array.is_empty() => {}
+                Some(toml::Value::Table(table)) if table.is_empty() => {}
+                // Flags left off don't need spelling out
+                Some(toml::Value::Boolean(false)) if is_default => {}
+                Some(value) => set(&mut table, toml_key, value),
+                None => {}
+            }
+        }
+        // Settings whose resolved form differs from how Trunk.toml spells them
+        if let Some(memory) = self.memory {
+            set(
+                &mut table,
+                "build.memory",
+                toml::Value::String(format_memory(memory)),
+            );
+        }
+        if let Some(entrypoint) = &self.entrypoint {
+            let entrypoint = shlex::try_join(entrypoint.iter().map(String::as_str))
+                .context("The entrypoint can't be written as a single command")?;
+            set(
+                &mut table,
+                "build.entrypoint",
+                toml::Value::String(entrypoint),
+            );
+        }
+        // Relative to the current directory once resolved, but to Trunk.toml in the file
+        if let Some(dockerfile) = &self.dockerfile_path {
+            let dockerfile = self.path_in_trunk_toml(Path::new(dockerfile));
+            set(
+                &mut table,
+                "build.dockerfile",
+                toml::Value::String(dockerfile),
+            );
+        }
+        if let Some(integration_test) = &self.integration_test {
+            let integration_test = self.path_in_trunk_toml(integration_test);
+            set(
+                &mut table,
+                "build.integration_test",
+                toml::Value::String(integration_test),
+            );
+        }
+        if let Some(registry_auth) = &self.registry_auth {
+            let auth_file = self.path_in_trunk_toml(registry_auth.path());
+            set(
+                &mut table,
+                "publish.auth_file",
+                toml::Value::String(auth_file),
+            );
+        }
+
+        let settings_toml = toml::to_string(&table)?;
+        // e.g. without a Trunk.toml, nothing sets the license
+        if let Err(err) = toml::from_str::<TrunkToml>(&settings_toml) {
+            warn!("The settings are not a complete Trunk.toml, fill in what is missing before using it: {err}");
+        }
+
+        Ok(settings_toml)
+    }
+
+    /// `path`, relative to the current directory, as it would be written in `<path>/Trunk.toml`
+    fn path_in_trunk_toml(&self, path: &Path) -> String {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let path = canonical(path);
+        path.strip_prefix(canonical(Path::new(&self.path)))
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// A memory limit in the largest unit that [`parse_memory`] reads back exactly, e.g. `2g`
+fn format_memory(bytes: u64) -> String {
+    [('g', 1 << 30), ('m', 1 << 20), ('k', 1 << 10)]
+        .into_iter()
+        .find(|(_, unit)| bytes.is_multiple_of(*unit))
+        .map(|(suffix, unit)| format!("{}{suffix}", bytes / unit))
+        .unwrap_or_else(|| bytes.to_string())
+}
+
+/// Settings that say where a build reads its context from or writes to, whether it may
+/// overwrite, or how the archive is tested, rather than what it builds, which
+/// `--print-context-checksum` leaves out
+const LOCATION_SETTINGS: [&str; 17] = [
+    "path",
+    "source_tarball",
+    "context_from_stdin",
+    "temp_dir",
+    "output_path",
+    "output_layout",
+    "require_explicit_output",
+    "compare",
+    "report",
+    "resume",
+    "fail_if_exists",
+    "label_file",
+    "registry_auth_file",
+    "sign_key",
+    "encrypt_key",
+    "integration_test",
+    "no_integration_test",
+];
+
+/// A setting, its value as JSON, its flag and its Trunk.toml key
+type ExplainedSetting = (
+    &'static str,
+    String,
+    Option<&'static str>,
+    Option<&'static str>,
+);
+
+/// Converts a setting's value to TOML, leaving out nulls, which TOML can't represent
+fn json_to_toml(value: serde_json::Value) -> Option<toml::Value> {
+    use serde_json::Value;
+
+    match value {
+        Value::Null => None,
+        Value::Bool(value) => Some(toml::Value::Boolean(value)),
+        Value::Number(number) => number
+            .as_i64()
+            .map(toml::Value::Integer)
+            .or_else(|| number.as_f64().map(toml::Value::Float)),
+        Value::String(value) => Some(toml::Value::String(value)),
+        Value::Array(values) => Some(toml::Value::Array(
+            values.into_iter().filter_map(json_to_toml).collect(),
+        )),
+        Value::Object(map) => Some(toml::Value::Table(
+            map.into_iter()
+                .filter_map(|(key, value)| Some((key, json_to_toml(value)?)))
+                .collect(),
+        )),
+    }
+}
+
+impl BuildCommand {
+    fn settings(&self) -> Result<BuildSettings, anyhow::Error> {
+        self.settings_from(None)
+    }
+
+    /// The settings of a build of the sources in `checkout`, a ref of `--versions`, instead of
+    /// `--path`. The archive is still written under `--path`, unless told otherwise
+    fn settings_from(&self, checkout: Option<&Path>) -> Result<BuildSettings, anyhow::Error> {
+        // path cannot be set from Trunk.toml, since --path can also
+        // be used to specify the path to the directory that includes a
+        // Trunk.toml file.
+        let mut sources = Sources::default();
+        let source_tarball = sources.track(
+            "source_tarball",
+            self.source_tarball
+                .clone()
+                .map(|source_tarball| Resolved::new(source_tarball, Source::Cli)),
+        );
+        let temp_dir = sources.track(
+            "temp_dir",
+            match &self.temp_dir {
+                Some(temp_dir) => Some(Resolved::new(temp_dir.clone(), Source::Cli)),
+                None => resolve_env("TMPDIR"),
+            },
+        );
+        let context_globs = |flag: &str, patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| parse_context_glob(flag, pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let context_exclude = sources
+            .track(
+                "context_exclude",
+                Some(resolve_flag(
+                    context_globs("--context-exclude", &self.context_exclude)?,
+                    Vec::new(),
+                )),
+            )
+            .expect("context_exclude always resolves");
+        let context_include = sources
+            .track(
+                "context_include",
+                Some(resolve_flag(
+                    context_globs("--context-include", &self.context_include)?,
+                    Vec::new(),
+                )),
+            )
+            .expect("context_include always resolves");
+        let context_from_stdin = sources
+            .track(
+                "context_from_stdin",
+                Some(resolve_flag(self.context_from_stdin, false)),
+            )
+            .expect("context_from_stdin always resolves");
+        let extracted = match &source_tarball {
+            Some(source_tarball) => {
+                Some(extract_source_tarball(source_tarball, temp_dir.as_deref())?)
+            }
+            None if context_from_stdin => Some(extract_stdin_context(temp_dir.as_deref())?),
+            None => None,
+        };
+        let (build_path, source_dir) = match extracted {
+            Some((source_dir, root)) => {
+                let root = root.to_string_lossy().into_owned();
+                sources.track("path", Some(Resolved::new(root.clone(), Source::Cli)));
+                (root, Some(source_dir))
+            }
+            None => {
+                let build_path = match checkout {
+                    Some(checkout) => {
+                        Resolved::new(checkout.to_string_lossy().into_owned(), Source::Cli)
+                    }
+                    None => resolve_flag(self.path.clone(), ".".to_string()),
+                };
+                let build_path = sources
+                    .track("path", Some(build_path))
+                    .expect("path always resolves");
+                (build_path, None)
+            }
+        };
+        let trunkfile_name = config::find_trunk_file(Path::new(&build_path));
+        let trunkfile_path = resolve_trunk_file(&trunkfile_name)?;
+        // Paths in Trunk.toml are relative to the directory containing the resolved file
+        let trunkfile_dir = trunkfile_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let mut trunk_toml = match File::open(&trunkfile_path) {
+            Ok(file) => Some(config::parse_trunk_file(&trunkfile_name, file)?),
+            Err(_e) => {
+                warn_or_fail(
+                    &self.fail_on_warn,
+                    WarningCategory::MissingTrunkToml,
+                    "Trunk.toml not found",
+                )?;
+
+                None
+            }
+        };
+        // The profile is merged in first, so that flags still take precedence over it
+        if let Some(profile_name) = &self.profile_name {
+            let Some(toml) = trunk_toml.take() else {
+                return Err(anyhow!(
+                    "--profile-name {profile_name} was given, but there is no Trunk.toml to read it from"
+                ));
+            };
+            let (toml, profile_keys) = toml.apply_profile(profile_name)?;
+            sources.set_profile_keys(profile_name, profile_keys);
+            trunk_toml = Some(toml);
+        }
+        // The overrides are merged in after the profile, and again after the platform's
+        // overrides below, so that they take precedence over both
+        if !self.config_override.is_empty() {
+            let Some(toml) = trunk_toml.take() else {
+                return Err(anyhow!(
+                    "--config-override was given, but there is no Trunk.toml to override"
+                ));
+            };
+            let (toml, override_keys) = toml.apply_overrides(&self.config_override)?;
+            sources.set_override_keys(override_keys);
+            trunk_toml = Some(toml);
+        }
+
+        let platform = sources.track(
+            "platform",
+            resolve_cli_env_or_trunk(
+                &self.platform,
+                "TRUNK_PLATFORM",
+                |toml| &toml.build.platform,
+                &trunk_toml,
+            ),
+        );
+
+        // Values in [build.platforms."<platform>"] take precedence over those in [build]
+        // for the platform being built, so they are merged in before anything else is resolved
+        let original_trunk_toml = trunk_toml.as_ref().map(Table::try_from).transpose()?;
+        let mut default_install_command_key = "build.default_install_command".to_string();
+        if let Some(toml) = trunk_toml.as_mut() {
+            if let Some(base_image) = &toml.build.base_image {
+                validate_base_image(base_image, "build.base_image")?;
+            }
+            for (platform, overrides) in toml.build.platforms.iter().flatten() {
+                validate_platform(platform)?;
+                if let Some(base_image) = &overrides.base_image {
+                    validate_base_image(
+                        base_image,
+                        &format!("build.platforms.\"{platform}\".base_image"),
+                    )?;
+                }
+            }
+
+            if let Some(platform) = &platform {
+                for (field, toml_key) in toml.build.apply_platform_overrides(platform) {
+                    match field {
+                        "dockerfile" => sources.set_toml_key("dockerfile_path", toml_key),
+                        "default_install_command" => default_install_command_key = toml_key,
+                        field => sources.set_toml_key(field, toml_key),
+                    }
+                }
+                if !self.config_override.is_empty() {
+                    let (toml, _) = trunk_toml
+                        .take()
+                        .expect("the platform's overrides were just applied")
+                        .apply_overrides(&self.config_override)?;
+                    trunk_toml = Some(toml);
+                }
+            }
+        }
+
+        // If output_path is not specified, default to .trunk directory in
+        // the directory specified by --path. Sources extracted from a tarball are
+        // removed after the build, so their output goes to .trunk in the current directory.
+        let require_explicit_output = sources
+            .track(
+                "require_explicit_output",
+                resolve_trunk_flag(
+                    self.no_default_output,
+                    |toml| &toml.build.require_explicit_output,
+                    &trunk_toml,
+                ),
+            )
+            .expect("require_explicit_output always resolves");
+        let output_path = match &self.output_path {
+            Some(output_path) => Resolved::new(output_path.clone(), Source::Cli),
+            None if require_explicit_output
+                && resolve_env::<String>("TRUNK_OUTPUT_PATH").is_none() =>
+            {
+                let reason = match sources.get("require_explicit_output") {
+                    Some(Source::TrunkToml) => "require_explicit_output is set in Trunk.toml",
+                    _ => "--no-default-output was given",
+                };
+                return Err(anyhow!(
+                    "No output path was given, and {reason}. Pass --output-path or set \
+                     TRUNK_OUTPUT_PATH to say where the archive should be written"
+                ));
+            }
+            None => resolve_env("TRUNK_OUTPUT_PATH").unwrap_or_else(|| {
+                let output_dir = if source_dir.is_some() {
+                    Path::new(".")
+                } else if checkout.is_some() {
+                    Path::new(&self.path)
+                } else {
+                    Path::new(&build_path)
+                };
+                let output_path = output_dir.join(".trunk");
+                let output_path = output_path
+                    .to_str()
+                    .expect("Failed trying to specify a subdirectory .trunk of the --path argument")
+                    .to_string();
+                Resolved::new(output_path, Source::Default)
+            }),
+        };
+        let output_path = sources
+            .track("output_path", Some(output_path))
+            .expect("output_path always resolves");
+        let output_layout = sources
+            .track(
+                "output_layout",
+                Some(match self.output_layout {
+                    Some(output_layout) => Resolved::new(output_layout, Source::Cli),
+                    None => Resolved::new(OutputLayout::default(), Source::Default),
+                }),
+            )
+            .expect("output_layout always resolves");
+        output_layout.validate(platform.as_deref())?;
+
+        let name = sources.track(
+            "name",
+            resolve_cli_env_or_trunk(
+                &self.name,
+                "TRUNK_NAME",
+                |toml| &toml.extension.name,
+                &trunk_toml,
+            ),
+        );
+
+        let loadable_libraries = sources.track(
+            "loadable_libraries",
+            resolve_cli_or_trunk_opt(
+                &None,
+                |toml| &toml.extension.loadable_libraries,
+                &trunk_toml,
+            ),
+        );
+        let preload = sources.track(
+            "preload",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.preload, &trunk_toml),
+        );
+
+        let kind = sources
+            .track(
+                "kind",
+                resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.kind, &trunk_toml).or(Some(
+                    Resolved::new(ExtensionKind::default(), Source::Default),
+                )),
+            )
+            .expect("kind always resolves");
+
+        let control = sources.track(
+            "control",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.control, &trunk_toml),
+        );
+        validate_kind(kind, control.as_ref())?;
+
+        let extension_name = sources.track(
+            "extension_name",
+            resolve_cli_env_or_trunk_opt(
+                &self.extension_name,
+                "TRUNK_EXTENSION_NAME",
+                |toml| &toml.extension.extension_name,
+                &trunk_toml,
+            ),
+        );
+
+        // The name given to `CREATE EXTENSION`. pgrx builds without a name get theirs from
+        // Cargo.toml, and are checked when it is read
+        let allow_unusual_name = sources
+            .track(
+                "allow_unusual_name",
+                Some(resolve_flag(self.allow_unusual_name, false)),
+            )
+            .expect("allow_unusual_name always resolves");
+        if let Some(name) = extension_name.as_ref().or(name.as_ref()) {
+            validate_extension_name(name, allow_unusual_name)?;
+        }
+
+        let cargo_features = CargoFeatures {
+            features: sources
+                .track(
+                    "cargo_features",
+                    resolve_cli_or_trunk_opt(
+                        &self.cargo_features,
+                        |toml| &toml.build.pgrx.features,
+                        &trunk_toml,
+                    ),
+                )
+                .unwrap_or_default(),
+            no_default_features: sources
+                .track(
+                    "no_default_features",
+                    resolve_trunk_flag(
+                        self.no_default_features,
+                        |toml| &toml.build.pgrx.no_default_features,
+                        &trunk_toml,
+                    ),
+                )
+                .expect("no_default_features always resolves"),
+            all_features: sources
+                .track(
+                    "all_features",
+                    resolve_trunk_flag(
+                        self.all_features,
+                        |toml| &toml.build.pgrx.all_features,
+                        &trunk_toml,
+                    ),
+                )
+                .expect("all_features always resolves"),
+        };
+        cargo_features.validate()?;
+        let profile = sources.track(
+            "profile",
+            resolve_cli_or_trunk_opt(&self.profile, |toml| &toml.build.pgrx.profile, &trunk_toml),
+        );
+        let rust_toolchain = sources.track(
+            "rust_toolchain",
+            resolve_cli_or_trunk_opt(
+                &self.rust_toolchain,
+                |toml| &toml.build.pgrx.rust_toolchain,
+                &trunk_toml,
+            ),
+        );
+        if let Some(rust_toolchain) = &rust_toolchain {
+            validate_rust_toolchain(rust_toolchain)?;
+        }
+        let force_pgrx = sources
+            .track("force_pgrx", Some(resolve_flag(self.force_pgrx, false)))
+            .expect("force_pgrx always resolves");
+
+        let extension_dependencies = sources.track(
+            "extension_dependencies",
+            resolve_cli_env_or_trunk_opt(
+                &self.extension_dependencies,
+                "TRUNK_EXTENSION_DEPENDENCIES",
+                |toml| &toml.extension.extension_dependencies,
+                &trunk_toml,
+            ),
+        );
+
+        let version = sources.track(
+            "version",
+            resolve_cli_env_or_trunk(
+                &self.version,
+                "TRUNK_VERSION",
+                |toml| &toml.extension.version,
+                &trunk_toml,
+            ),
+        );
+
+        let configure_command = sources.track(
+            "configure_command",
+            resolve_cli_env_or_trunk_opt(
+                &self.configure_command,
+                "TRUNK_CONFIGURE_COMMAND",
+                |toml| &toml.build.configure_command,
+                &trunk_toml,
+            ),
+        );
+        let build_command = sources.track(
+            "build_command",
+            resolve_cli_env_or_trunk_opt(
+                &self.build_command,
+                "TRUNK_BUILD_COMMAND",
+                |toml| &toml.build.build_command,
+                &trunk_toml,
+            ),
+        );
+        let compiler_flags = CompilerFlags {
+            cflags: sources.track(
+                "cflags",
+                resolve_cli_or_trunk_opt(&self.cflags, |toml| &toml.build.cflags, &trunk_toml),
+            ),
+            cxxflags: sources.track(
+                "cxxflags",
+                resolve_cli_or_trunk_opt(&self.cxxflags, |toml| &toml.build.cxxflags, &trunk_toml),
+            ),
+        };
+
+        // `default_install_command` is only a fallback for when no install command was given
+        let install_command = resolve_cli_env_or_trunk_opt(
+            &self.install_command,
+            "TRUNK_INSTALL_COMMAND",
+            |toml| &toml.build.install_command,
+            &trunk_toml,
+        )
+        .or_else(|| {
+            let default_install_command = resolve_cli_or_trunk_opt(
+                &None,
+                |toml| &toml.build.default_install_command,
+                &trunk_toml,
+            );
+            if default_install_command.is_some() {
+                sources.set_toml_key("install_command", default_install_command_key);
+            }
+            default_install_command
+        });
+        let install_command = sources.track("install_command", install_command);
+        let before_install = sources.track(
+            "before_install",
+            resolve_cli_or_trunk_opt(
+                &self.before_install,
+                |toml| &toml.build.before_install,
+                &trunk_toml,
+            ),
+        );
+        let after_install = sources.track(
+            "after_install",
+            resolve_cli_or_trunk_opt(
+                &self.after_install,
+                |toml| &toml.build.after_install,
+                &trunk_toml,
+            ),
+        );
+        let install_user = sources.track(
+            "install_user",
+            resolve_cli_or_trunk_opt(&self.user, |toml| &toml.build.user, &trunk_toml),
+        );
+        if let Some(install_user) = &install_user {
+            validate_install_user(install_user)?;
+        }
+        let check_install_writes = sources
+            .track(
+                "check_install_writes",
+                Some(resolve_flag(self.check_install_writes, false)),
+            )
+            .expect("check_install_writes always resolves");
+        validate_writable_paths(&self.writable_path)?;
+        let writable_paths = sources
+            .track(
+                "writable_paths",
+                Some(resolve_flag(self.writable_path.clone(), Vec::new())),
+            )
+            .expect("writable_paths always resolve");
+        let entrypoint =
+            resolve_cli_or_trunk_opt(&self.entrypoint, |toml| &toml.build.entrypoint, &trunk_toml)
+                .map(|entrypoint| {
+                    parse_entrypoint(&entrypoint.value)
+                        .map(|argv| Resolved::new(argv, entrypoint.source))
+                })
+                .transpose()?;
+        let entrypoint = sources.track("entrypoint", entrypoint);
+
+        let extension_dir = sources.track(
+            "extension_dir",
+            resolve_cli_env_or_trunk_opt(
+                &self.extension_dir,
+                "TRUNK_EXTENSION_DIR",
+                |toml| &toml.build.extension_dir,
+                &trunk_toml,
+            ),
+        );
+
+        if let Some(extension_dir) = &extension_dir {
+            validate_extension_dir(Path::new(&build_path), extension_dir)?;
+        }
+        let cargo_manifest = sources.track(
+            "cargo_manifest",
+            self.cargo_manifest
+                .clone()
+                .map(|cargo_manifest| Resolved::new(cargo_manifest, Source::Cli)),
+        );
+        if let Some(cargo_manifest) = &cargo_manifest {
+            validate_cargo_manifest(cargo_manifest)?;
+        }
+
+        let install_layout = InstallLayout {
+            lib_dir: sources.track(
+                "lib_dir",
+                resolve_cli_env_or_trunk_opt(
+                    &self.lib_dir,
+                    "TRUNK_LIB_DIR",
+                    |toml| &toml.build.lib_dir,
+                    &trunk_toml,
+                ),
+            ),
+            sql_dir: sources.track(
+                "sql_dir",
+                resolve_cli_env_or_trunk_opt(
+                    &self.sql_dir,
+                    "TRUNK_SQL_DIR",
+                    |toml| &toml.build.sql_dir,
+                    &trunk_toml,
+                ),
+            ),
+            control_dir: sources.track(
+                "control_dir",
+                resolve_cli_env_or_trunk_opt(
+                    &self.control_dir,
+                    "TRUNK_CONTROL_DIR",
+                    |toml| &toml.build.control_dir,
+                    &trunk_toml,
+                ),
+            ),
+            prefixes: sources
+                .track(
+                    "install_prefixes",
+                    resolve_cli_or_trunk_opt(&self.prefix, |toml| &toml.build.prefix, &trunk_toml),
+                )
+                .unwrap_or_default(),
+        };
+        validate_install_prefixes(&install_layout.prefixes)?;
+
+        let base_image = sources.track(
+            "base_image",
+            resolve_cli_env_or_trunk_opt(
+                &self.base_image,
+                "TRUNK_BASE_IMAGE",
+                |toml| &toml.build.base_image,
+                &trunk_toml,
+            ),
+        );
+        // Values from Trunk.toml were checked before the platform overrides were merged in
+        if let Some(base_image) = &base_image {
+            match sources.get("base_image") {
+                Some(Source::Cli) => validate_base_image(base_image, "--base-image")?,
+                Some(Source::Env(variable)) => validate_base_image(base_image, variable)?,
+                _ => {}
+            }
+        }
+
+        sources.track(
+            "include",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.build.include, &trunk_toml),
+        );
+        let cpus = sources.track(
+            "cpus",
+            resolve_cli_or_trunk_opt(&self.cpus, |toml| &toml.build.cpus, &trunk_toml),
+        );
+        if let Some(cpus) = cpus {
+            validate_cpus(cpus)?;
+        }
+
+        let memory = sources
+            .track(
+                "memory",
+                resolve_cli_or_trunk_opt(&self.memory, |toml| &toml.build.memory, &trunk_toml),
+            )
+            .map(|memory| parse_memory(&memory))
+            .transpose()?;
+        let step_timeout = sources
+            .track(
+                "step_timeout",
+                resolve_cli_or_trunk_opt(
+                    &self.step_timeout,
+                    |toml| &toml.build.step_timeout,
+                    &trunk_toml,
+                ),
+            )
+            .map(|timeout| parse_step_timeout(&timeout))
+            .transpose()?;
+        let fail_on_warn = sources
+            .track(
+                "fail_on_warn",
+                Some(resolve_flag(self.fail_on_warn.clone(), vec![])),
+            )
+            .expect("fail_on_warn always resolves");
+
+        let glob_patterns_to_include = trunk_toml
+            .as_ref()
+            .map(|toml| toml.build.build_glob_patterns())
+            .transpose()?
+            .unwrap_or(Vec::new());
+
+        warn!(
+            "will include files matching {}",
+            glob_patterns_to_include.display()
+        );
+
+        let included_files = sources
+            .track(
+                "include_files",
+                resolve_cli_or_trunk_opt(&None, |toml| &toml.build.include_files, &trunk_toml),
+            )
+            .unwrap_or_default();
+        for included_file in &included_files {
+            validate_included_file(Path::new(&build_path), included_file)?;
+        }
+        let capture_globs = sources
+            .track(
+                "capture_globs",
+                resolve_cli_or_trunk_opt(&None, |toml| &toml.build.capture_globs, &trunk_toml),
+            )
+            .unwrap_or_default()
+            .iter()
+            .map(|pattern| parse_capture_glob(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        validate_artifact_suffix(&self.artifact_suffix)?;
+        let artifact_suffix = sources
+            .track(
+                "artifact_suffix",
+                Some(resolve_flag(
+                    self.artifact_suffix.clone(),
+                    ".tar.gz".to_string(),
+                )),
+            )
+            .expect("artifact_suffix always resolves");
+        let artifact_mode = sources
+            .track(
+                "artifact_mode",
+                Some(resolve_flag(
+                    parse_artifact_mode(&self.artifact_mode)?,
+                    DEFAULT_ARTIFACT_MODE,
+                )),
+            )
+            .expect("artifact_mode always resolves");
+        validate_format_version(self.format_version)?;
+        let format_version = sources
+            .track(
+                "format_version",
+                Some(resolve_flag(self.format_version, MANIFEST_VERSION)),
+            )
+            .expect("format_version always resolves");
+        validate_signing(self.sign, self.sign_key.as_deref())?;
+        let sign = sources.track(
+            "sign",
+            self.sign.map(|sign| Resolved::new(sign, Source::Cli)),
+        );
+        let sign_key = sources.track(
+            "sign_key",
+            self.sign_key
+                .clone()
+                .map(|sign_key| Resolved::new(sign_key, Source::Cli)),
+        );
+        validate_encryption(
+            self.encrypt,
+            self.encrypt_key.as_deref(),
+            output_layout,
+            self.resume,
+        )?;
+        let encrypt = sources
+            .track("encrypt", Some(resolve_flag(self.encrypt, false)))
+            .expect("encrypt always resolves");
+        let encrypt_key = sources.track(
+            "encrypt_key",
+            self.encrypt_key
+                .clone()
+                .map(|encrypt_key| Resolved::new(encrypt_key, Source::Cli)),
+        );
+        if let Some(previous_archive) = &self.compare {
+            validate_compare(previous_archive)?;
+        }
+        let compare = sources.track(
+            "compare",
+            self.compare
+                .clone()
+                .map(|compare| Resolved::new(compare, Source::Cli)),
+        );
+        let report = sources.track(
+            "report",
+            self.report
+                .clone()
+                .map(|report| Resolved::new(report, Source::Cli)),
+        );
+        let resume = sources
+            .track("resume", Some(resolve_flag(self.resume, false)))
+            .expect("resume always resolves");
+        let fail_if_exists = sources
+            .track(
+                "fail_if_exists",
+                Some(resolve_flag(self.fail_if_exists, false)),
+            )
+            .expect("fail_if_exists always resolves");
+
+        let configurations = sources.track(
+            "configurations",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.configurations, &trunk_toml),
+        );
+
+        let system_dependencies = sources.track(
+            "system_dependencies",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.dependencies, &trunk_toml),
+        );
+
+        // Dockerfile is handled slightly differently in Trunk.toml as the CLI.
+        // On CLI, the argument is --dockerfile_path, and it means the path relative
+        // to the current working directory where the command line argument is executed.
+        // In Trunk.toml, the field is called "dockerfile", and it means the file relative
+        // to the directory of the Trunk.toml file, after following symlinks.
+        // TRUNK_DOCKERFILE has the same meaning as --dockerfile.
+        let dockerfile_path = match &self.dockerfile_path {
+            Some(dockerfile_path) => Some(Resolved::new(dockerfile_path.clone(), Source::Cli)),
+            None => resolve_env("TRUNK_DOCKERFILE").or_else(|| {
+                trunk_toml
+                    .as_ref()
+                    .and_then(|toml| toml.build.dockerfile.as_ref())
+                    .map(|dockerfile| {
+                        let dockerfile_path =
+                            trunkfile_dir.join(dockerfile).to_string_lossy().into();
+                        Resolved::new(dockerfile_path, Source::TrunkToml)
+                    })
+            }),
+        };
+        let dockerfile_path = sources.track("dockerfile_path", dockerfile_path);
+        let builder = sources.track(
+            "builder",
+            resolve_cli_or_trunk_opt(&self.builder, |toml| &toml.build.builder, &trunk_toml),
+        );
+        if let Some(builder) = &builder {
+            validate_builder(builder, dockerfile_path.is_some())?;
+        }
+        let target = sources.track(
+            "target",
+            self.target
+                .clone()
+                .map(|target| Resolved::new(target, Source::Cli)),
+        );
+        if target.is_some() && dockerfile_path.is_none() {
+            return Err(target_requires_dockerfile());
+        }
+
+        let buildkit = match (self.buildkit, self.no_buildkit) {
+            (true, _) => Resolved::new(true, Source::Cli),
+            (_, true) => Resolved::new(false, Source::Cli),
+            _ => Resolved::new(false, Source::Default),
+        };
+        let buildkit = sources
+            .track("buildkit", Some(buildkit))
+            .expect("buildkit always resolves");
+
+        let pull = match self.pull {
+            Some(PullPolicy::Always) if self.offline => {
+                return Err(anyhow!("--pull=always cannot be used with --offline"));
+            }
+            Some(pull) => Resolved::new(pull, Source::Cli),
+            // Offline builds must not reach out to a registry for base images
+            None if self.offline => Resolved::new(PullPolicy::Never, Source::Default),
+            None => Resolved::new(PullPolicy::default(), Source::Default),
+        };
+        let pull = sources
+            .track("pull", Some(pull))
+            .expect("pull always resolves");
+
+        let offline = sources
+            .track("offline", Some(resolve_flag(self.offline, false)))
+            .expect("offline always resolves");
+        let skip_platform_check = sources
+            .track(
+                "skip_platform_check",
+                Some(resolve_flag(self.skip_platform_check, false)),
+            )
+            .expect("skip_platform_check always resolves");
+        let allow_arch_mismatch = sources
+            .track(
+                "allow_arch_mismatch",
+                Some(resolve_flag(self.allow_arch_mismatch, false)),
+            )
+            .expect("allow_arch_mismatch always resolves");
+        let no_version_check = sources
+            .track(
+                "no_version_check",
+                Some(resolve_flag(self.no_version_check, false)),
+            )
+            .expect("no_version_check always resolves");
+        let no_install = sources
+            .track("no_install", Some(resolve_flag(self.no_install, false)))
+            .expect("no_install always resolves");
+        let shell_in = sources
+            .track("shell_in", Some(resolve_flag(self.shell_in, false)))
+            .expect("shell_in always resolves");
+        let no_install = match (no_install, shell_in) {
+            (_, true) => Some(NoInstall::Shell),
+            (true, false) => Some(NoInstall::PrintCommand),
+            (false, false) => None,
+        };
+
+        // Like dockerfile, the auth file in Trunk.toml is relative to the Trunk.toml file
+        let registry_auth_file = match &self.registry_auth_file {
+            Some(registry_auth_file) => {
+                Some(Resolved::new(registry_auth_file.clone(), Source::Cli))
+            }
+            None => resolve_env("TRUNK_REGISTRY_AUTH_FILE").or_else(|| {
+                trunk_toml
+                    .as_ref()
+                    .and_then(|toml| toml.publish.as_ref())
+                    .and_then(|publish| publish.auth_file.as_ref())
+                    .map(|auth_file| {
+                        Resolved::new(trunkfile_dir.join(auth_file), Source::TrunkToml)
+                    })
+            }),
+        };
+        let registry_auth = sources
+            .track("registry_auth_file", registry_auth_file)
+            .map(|registry_auth_file| RegistryAuth::load(&registry_auth_file))
+            .transpose()?;
+        let allow_missing_control = sources
+            .track(
+                "allow_missing_control",
+                Some(resolve_flag(self.allow_missing_control, false)),
+            )
+            .expect("allow_missing_control always resolves");
+        let file_digests = sources
+            .track("file_digests", Some(resolve_flag(self.file_digests, false)))
+            .expect("file_digests always resolves");
+        let strip = sources
+            .track("strip", Some(resolve_flag(self.strip, false)))
+            .expect("strip always resolves");
+        let keep_debug = sources
+            .track("keep_debug", Some(resolve_flag(self.keep_debug, false)))
+            .expect("keep_debug always resolves");
+        let changelog_from_git = sources
+            .track(
+                "changelog_from_git",
+                Some(resolve_flag(self.changelog_from_git, false)),
+            )
+            .expect("changelog_from_git always resolves");
+        let label_file = sources.track(
+            "label_file",
+            self.label_file
+                .clone()
+                .map(|label_file| Resolved::new(label_file, Source::Cli)),
+        );
+        let labels = {
+            // Trunk.toml, then the label file, then --label, each overriding the one before
+            let mut labels = trunk_toml
+                .as_ref()
+                .and_then(|toml| toml.build.labels.clone())
+                .unwrap_or_default();
+            let mut source = if labels.is_empty() {
+                Source::Default
+            } else {
+                Source::TrunkToml
+            };
+            if let Some(label_file) = &label_file {
+                labels.extend(read_label_file(label_file)?);
+                source = Source::CliFlag("--label-file");
+            }
+            if !self.label.is_empty() {
+                labels.extend(self.label.iter().cloned());
+                source = Source::Cli;
+            }
+            for key in labels.keys() {
+                validate_label_key(key).map_err(|err| anyhow!("Invalid label: {err}"))?;
+            }
+            sources
+                .track("labels", Some(Resolved::new(labels, source)))
+                .expect("labels always resolve")
+        };
+        let categories = sources
+            .track(
+                "categories",
+                resolve_cli_or_trunk(
+                    &self.categories,
+                    |toml| &toml.extension.categories,
+                    &trunk_toml,
+                ),
+            )
+            .unwrap_or_default();
+        let manifest_transform = sources.track(
+            "manifest_transform",
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.hooks.manifest_transform, &trunk_toml),
+        );
+        let no_hooks = sources
+            .track("no_hooks", Some(resolve_flag(self.no_hooks, false)))
+            .expect("no_hooks always resolves");
+        let should_test = sources
+            .track("should_test", Some(resolve_flag(self.test, false)))
+            .expect("should_test always resolves");
+        let no_integration_test = sources
+            .track(
+                "no_integration_test",
+                Some(resolve_flag(self.no_integration_test, false)),
+            )
+            .expect("no_integration_test always resolves");
+        let integration_test = match &self.integration_test {
+            Some(sql_file) => Some(Resolved::new(sql_file.clone(), Source::Cli)),
+            None => trunk_toml
+                .as_ref()
+                .and_then(|toml| toml.build.integration_test.as_ref())
+                .map(|sql_file| Resolved::new(trunkfile_dir.join(sql_file), Source::TrunkToml)),
+        }
+        .filter(|_| !no_integration_test);
+        let integration_test = sources.track("integration_test", integration_test);
+        if let Some(sql_file) = &integration_test {
+            if !sql_file.is_file() {
+                return Err(anyhow!(
+                    "The integration test {} does not exist or is not a file",
+                    sql_file.display()
+                ));
+            }
+        }
+        let pg_version = sources
+            .track("pg_version", Some(resolve_flag(self.pg_version, 15)))
+            .expect("pg_version always resolves");
+        validate_pg_version(pg_version)?;
+        let supported_pg_versions = SupportedPgVersions {
+            min: sources.track(
+                "min_pg_version",
+                resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.min_pg_version, &trunk_toml),
+            ),
+            max: sources.track(
+                "max_pg_version",
+                resolve_cli_or_trunk_opt(&None, |toml| &toml.extension.max_pg_version, &trunk_toml),
+            ),
+        };
+        supported_pg_versions.validate()?;
+
+        let build_settings = BuildSettings {
+            path: build_path,
+            output_path,
+            output_layout,
+            require_explicit_output,
+            version,
+            name,
+            extension_name,
+            extension_dependencies,
+            system_dependencies,
+            glob_patterns_to_include,
+            capture_globs,
+            platform,
+            dockerfile_path,
+            builder,
+            target,
+            configure_command,
+            build_command,
+            compiler_flags,
+            install_command,
+            before_install,
+            after_install,
+            install_user,
+            check_install_writes,
+            writable_paths,
+            entrypoint,
+            extension_dir,
+            cargo_manifest,
+            install_layout,
+            included_files,
+            artifact_suffix,
+            artifact_mode,
+            format_version,
+            sign,
+            sign_key,
+            encrypt,
+            encrypt_key,
+            compare,
+            report,
+            resume,
+            fail_if_exists,
+            allow_missing_control,
+            file_digests,
+            strip,
+            keep_debug,
+            changelog_from_git,
+            labels,
+            categories,
+            label_file,
+            manifest_transform,
+            no_hooks,
+            allow_unusual_name,
+            cargo_features,
+            profile,
+            rust_toolchain,
+            force_pgrx,
+            buildkit,
+            pull,
+            offline,
+            no_install,
+            skip_platform_check,
+            allow_arch_mismatch,
+            no_version_check,
+            registry_auth,
+            base_image,
+            cpus,
+            memory,
+            step_timeout,
+            fail_on_warn,
+            should_test,
+            integration_test,
+            no_integration_test,
+            configurations,
+            loadable_libraries,
+            preload,
+            kind,
+            control,
+            pg_version,
+            supported_pg_versions,
+            sources,
+            trunk_toml: original_trunk_toml,
+            source_tarball,
+            context_from_stdin,
+            temp_dir,
+            context_exclude,
+            context_include,
+            encrypted_output_path: None,
+            source_dir,
+        };
+        build_settings.check_config_overrides()?;
+
+        Ok(build_settings)
+    }
+}
+
+/// Follows the trunk file at `trunkfile_path`, as found by [`config::find_trunk_file`], through
+/// any symlinks. Relative symlink targets are resolved against the directory of the symlink, so
+/// the result stays relative if `trunkfile_path` is.
+fn resolve_trunk_file(trunkfile_path: &Path) -> Result<PathBuf, anyhow::Error> {
+    const MAX_SYMLINKS: usize = 40;
+
+    let mut path = trunkfile_path.to_path_buf();
+    for _ in 0..MAX_SYMLINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                path = path.parent().unwrap_or(Path::new("")).join(target);
+            }
+            Err(_) if path != trunkfile_path => {
+                anyhow::bail!(
+                    "{} is a symlink to {}, which does not exist",
+                    trunkfile_path.display(),
+                    path.display()
+                );
+            }
+            _ => return Ok(path),
+        }
+    }
+
+    anyhow::bail!(
+        "Too many levels of symlinks when resolving {}",
+        trunkfile_path.display()
+    )
+}
+
+/// Free space on the filesystem of `dir`, as reported by `df`
+fn available_space(dir: &Path) -> Option<u64> {
+    let output = std::process::Command::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Reads the available space from the output of `df -Pk`, whose second line is
+/// `<filesystem> <1024-blocks> <used> <available> <capacity> <mounted on>`
+fn parse_df_available(df: &str) -> Option<u64> {
+    let kib: u64 = df.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+
+    Some(kib * 1024)
+}
+
+/// Why `dir` can't hold `needed` bytes of extracted sources, if it can't
+fn check_temp_dir(dir: &Path, needed: u64) -> Result<(), String> {
+    if !dir.is_dir() {
+        return Err("is not a directory".to_string());
+    }
+    if let Err(err) = tempfile::Builder::new().prefix(".trunk-").tempfile_in(dir) {
+        return Err(format!("is not writable: {err}"));
+    }
+    match available_space(dir) {
+        Some(available) if available < needed => Err(format!(
+            "has {} free, but the sources need {}",
+            format_size(available),
+            format_size(needed)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The directory to extract `needed` bytes of sources into: `requested` if it can hold them, or
+/// else the system's temporary directory
+fn temp_dir_for(requested: Option<&Path>, needed: u64) -> PathBuf {
+    let system_temp_dir = std::env::temp_dir();
+    let Some(requested) = requested else {
+        return system_temp_dir;
+    };
+
+    match check_temp_dir(requested, needed) {
+        Ok(()) => requested.to_path_buf(),
+        Err(problem) => {
+            // std::env::temp_dir() is TMPDIR itself when that's what failed the check
+            let fallback = if system_temp_dir == requested && cfg!(unix) {
+                PathBuf::from("/tmp")
+            } else {
+                system_temp_dir
+            };
+            warn!(
+                "Temporary directory {} {problem}, using {} instead",
+                requested.display(),
+                fallback.display()
+            );
+            fallback
+        }
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Extracts a source tarball, gzipped or not, into a temporary directory. Returns the directory
+/// along with the extension's root within it: the archive's single top-level directory, if it
+/// has one, or else the top-level directory that holds a Trunk.toml or Cargo.toml.
+fn extract_source_tarball(
+    source_tarball: &Path,
+    temp_dir: Option<&Path>,
+) -> Result<(TempDir, PathBuf), anyhow::Error> {
+    let open = || -> Result<Box<dyn Read>, anyhow::Error> {
+        let mut file = File::open(source_tarball).with_context(|| {
+            format!("Failed to open source tarball {}", source_tarball.display())
+        })?;
+        let mut magic = [0; 2];
+        let is_gzipped = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+        file.rewind()?;
+        Ok(if is_gzipped {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        })
+    };
+
+    // Reading the archive twice is cheap next to a build, and tells how much space it needs
+    let mut needed = 0;
+    for entry in tar::Archive::new(open()?).entries()? {
+        needed += entry?.header().size()?;
+    }
+    let temp_dir = temp_dir_for(temp_dir, needed);
+
+    let label = format!("source tarball {}", source_tarball.display());
+    extract_sources(open()?, &label, &temp_dir, true)
+}
+
+/// Extracts the build context streamed on stdin as a tar, gzipped or not, for
+/// `--context-from-stdin`. Unlike a source tarball, it may leave out Trunk.toml and Cargo.toml
+/// when the name and version are given as flags.
+fn extract_stdin_context(temp_dir: Option<&Path>) -> Result<(TempDir, PathBuf), anyhow::Error> {
+    let mut stdin = io::BufReader::new(io::stdin().lock());
+    let is_gzipped = stdin.fill_buf()?.starts_with(&GZIP_MAGIC);
+    let archive: Box<dyn Read> = if is_gzipped {
+        Box::new(flate2::read::GzDecoder::new(stdin))
+    } else {
+        Box::new(stdin)
+    };
+    // A stream can only be read once, so its size isn't known before extracting it
+    let temp_dir = temp_dir_for(temp_dir, 0);
+
+    extract_sources(archive, "build context from stdin", &temp_dir, false)
+}
+
+/// Unpacks `archive` into a new directory in `temp_dir`, and finds the extension's root in it
+fn extract_sources(
+    archive: Box<dyn Read>,
+    label: &str,
+    temp_dir: &Path,
+    require_manifest: bool,
+) -> Result<(TempDir, PathBuf), anyhow::Error> {
+    let has_manifest = |dir: &Path| {
+        std::iter::once("Trunk.toml")
+            .chain(config::TRUNK_YAML_FILE_NAMES)
+            .any(|name| fs::symlink_metadata(dir.join(name)).is_ok())
+            || dir.join("Cargo.toml").is_file()
+    };
+    let source_dir = tempfile::Builder::new()
+        .prefix("trunk-source-")
+        .tempdir_in(temp_dir)?;
+    tar::Archive::new(archive)
+        .unpack(source_dir.path())
+        .with_context(|| format!("Failed to extract the {label}"))?;
+    tee_println!("Extracted the {label} to {}", source_dir.path().display());
+
+    let mut top_level = Vec::new();
+    for entry in fs::read_dir(source_dir.path())? {
+        let entry = entry?;
+        // Written by `git archive`, not part of the sources
+        if entry.file_name() != "pax_global_header" {
+            top_level.push(entry.path());
+        }
+    }
+    top_level.sort();
+    let dirs: Vec<&PathBuf> = top_level.iter().filter(|path| path.is_dir()).collect();
+
+    let root = if let ([dir], 1) = (dirs.as_slice(), top_level.len()) {
+        dir.to_path_buf()
+    } else if has_manifest(source_dir.path()) {
+        source_dir.path().to_path_buf()
+    } else {
+        let candidates: Vec<&&PathBuf> = dirs.iter().filter(|dir| has_manifest(dir)).collect();
+        match candidates.as_slice() {
+            [dir] => dir.to_path_buf(),
+            [] => source_dir.path().to_path_buf(),
+            _ => anyhow::bail!(
+                "The {label} contains several extensions ({}), extract it and build one with --path",
+                candidates
+                    .iter()
+                    .filter_map(|dir| dir.file_name())
+                    .map(|name| name.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    };
+    if require_manifest && !has_manifest(&root) {
+        anyhow::bail!("No Trunk.toml or Cargo.toml found in the {label}");
+    }
+
+    Ok((source_dir, root))
+}
+
+/// Postgres truncates identifiers longer than this many bytes
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// Checks that `name` can be passed to `CREATE EXTENSION` without quoting. Unless
+/// `allow_unusual_name` is set, a name that can't is an error rather than a warning.
+fn validate_extension_name(name: &str, allow_unusual_name: bool) -> Result<(), anyhow::Error> {
+    if is_unquoted_identifier(name) {
+        return Ok(());
+    }
+
+    let message = format!(
+        "Extension name '{name}' is not a legal unquoted Postgres identifier, so `CREATE EXTENSION {name}` would fail. \
+         Extension names must start with a lowercase letter or an underscore, contain only lowercase letters, \
+         digits, underscores and dollar signs, and be at most {MAX_IDENTIFIER_LENGTH} bytes long. \
+         Consider naming it '{}'",
+        suggest_extension_name(name)
+    );
+    if allow_unusual_name {
+        warn!("{message}");
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{message}, or pass --allow-unusual-name to build it anyway"
+        ))
+    }
+}
+
+fn is_unquoted_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    name.len() <= MAX_IDENTIFIER_LENGTH
+        && chars
+            .next()
+            .is_some_and(|ch| ch.is_ascii_lowercase() || ch == '_')
+        && chars.all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || ch == '_' || ch == '$')
+}
+
+/// The closest name to `name` that is a legal unquoted identifier
+fn suggest_extension_name(name: &str) -> String {
+    let mut suggestion: String = name
+        .chars()
+        .map(|ch| match ch.to_ascii_lowercase() {
+            ch @ ('a'..='z' | '0'..='9' | '_' | '$') => ch,
+            _ => '_',
+        })
+        .collect();
+    if !suggestion.starts_with(|ch: char| ch.is_ascii_lowercase() || ch == '_') {
+        suggestion.insert(0, '_');
+    }
+    // Every character is ASCII, so this can't split one
+    suggestion.truncate(MAX_IDENTIFIER_LENGTH);
+
+    suggestion
+}
+
+/// `path` made absolute for logs, with symlinks resolved if it exists. Paths that don't exist
+/// yet, like an output directory the build creates, are made absolute without resolving them
+fn resolved_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn validate_artifact_suffix(artifact_suffix: &str) -> Result<(), anyhow::Error> {
+    if artifact_suffix.is_empty() || artifact_suffix.contains(['/', '\\']) {
+        return Err(anyhow!(
+            "--artifact-suffix must be non-empty and must not contain path separators. Got: {artifact_suffix}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The archive to compare with must exist before building, not just once the build is done
+fn validate_compare(previous_archive: &Path) -> Result<(), anyhow::Error> {
+    if !previous_archive.is_file() {
+        return Err(anyhow!(
+            "--compare {} does not exist",
+            previous_archive.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Mode of the archive when `--artifact-mode` is not given, readable by everyone
+const DEFAULT_ARTIFACT_MODE: u32 = 0o644;
+
+/// Parses an octal file mode such as `0644`, `644` or `0o644`
+fn parse_artifact_mode(artifact_mode: &str) -> Result<u32, anyhow::Error> {
+    let digits = artifact_mode.strip_prefix("0o").unwrap_or(artifact_mode);
+    let mode = (!digits.is_empty() && digits.len() <= 4)
+        .then(|| u32::from_str_radix(digits, 8).ok())
+        .flatten()
+        .ok_or_else(|| {
+            anyhow!("--artifact-mode must be an octal file mode such as 0644. Got: {artifact_mode}")
+        })?;
+    validate_artifact_mode(mode)?;
+
+    Ok(mode)
+}
+
+/// The Postgres versions Trunk has builder images and sources for
+fn validate_pg_version(pg_version: u8) -> Result<(), anyhow::Error> {
+    if !matches!(pg_version, 14..=16) {
+        return Err(anyhow!(
+            "--pg-version must be 14, 15 or 16. Got: {pg_version}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Only manifest versions that `trunk install` can read are written
+fn validate_format_version(format_version: i32) -> Result<(), anyhow::Error> {
+    if !(OLDEST_MANIFEST_VERSION..=MANIFEST_VERSION).contains(&format_version) {
+        return Err(anyhow!(
+            "--format-version must be a manifest version from {OLDEST_MANIFEST_VERSION} to {MANIFEST_VERSION}. Got: {format_version}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Archives are plain files, so the setuid, setgid and sticky bits make no sense on them
+fn validate_artifact_mode(artifact_mode: u32) -> Result<(), anyhow::Error> {
+    if artifact_mode > 0o777 {
+        return Err(anyhow!(
+            "--artifact-mode may only set permission bits, up to 0777. Got: {artifact_mode:04o}"
+        ));
+    }
+    if artifact_mode & 0o400 == 0 {
+        warn!("--artifact-mode {artifact_mode:04o} makes the archive unreadable to its owner");
+    }
+
+    Ok(())
+}
+
+/// Loadable modules have no control file to check `[extension.control]` against
+fn validate_kind(
+    kind: ExtensionKind,
+    control: Option<&ControlFields>,
+) -> Result<(), anyhow::Error> {
+    if kind == ExtensionKind::Module && control.is_some() {
+        return Err(anyhow!(
+            "[extension.control] can't be set with kind = \"module\" in Trunk.toml, as loadable modules have no control file"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Creates the output directory, if it doesn't exist yet, so that everyone can list it
+/// regardless of the umask. An existing directory's permissions are left alone.
+fn create_output_dir(output_path: &Path) -> Result<(), anyhow::Error> {
+    if output_path.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(output_path)
+        .with_context(|| format!("Failed to create {}", output_path.display()))?;
+    fs::set_permissions(output_path, fs::Permissions::from_mode(0o755))
+        .with_context(|| format!("Failed to set the mode of {}", output_path.display()))?;
+
+    Ok(())
+}
+
+/// `builder` must be bundled with trunk, and can't be combined with a custom Dockerfile
+fn validate_builder(builder: &str, has_dockerfile: bool) -> Result<(), anyhow::Error> {
+    bundled_builder(builder)?;
+    if has_dockerfile {
+        return Err(anyhow!(
+            "--builder {builder} selects a bundled Dockerfile, so it cannot be combined with --dockerfile"
+        ));
+    }
+
+    Ok(())
+}
+
+fn list_builders() -> String {
+    let width = BUNDLED_BUILDERS
+        .iter()
+        .map(|builder| builder.name.len())
+        .max()
+        .unwrap_or_default();
+
+    let mut list = String::new();
+    for builder in BUNDLED_BUILDERS {
+        let default = if builder.name == DEFAULT_BUILDER {
+            " (default)"
+        } else {
+            ""
+        };
+        list.push_str(&format!(
+            "{:<width$}  {}{default}\n",
+            builder.name, builder.description
+        ));
+    }
+
+    list
+}
+
+fn target_requires_dockerfile() -> anyhow::Error {
+    anyhow!(
+        "--target requires a custom --dockerfile. The bundled Dockerfiles have a single stage, \
+         so there is no stage to target"
+    )
+}
+
+/// The Dockerfile cut off after the `target` stage, which must be one of its stages
+fn target_dockerfile(dockerfile: &str, target: &str) -> Result<String, anyhow::Error> {
+    dockerfile_up_to_stage(dockerfile, target).ok_or_else(|| {
+        anyhow!(
+            "--target {target} is not a stage of the Dockerfile. Its named stages are: {}",
+            dockerfile_stages(dockerfile).display()
+        )
+    })
+}
+
+fn validate_cpus(cpus: f64) -> Result<(), anyhow::Error> {
+    if !(cpus.is_finite() && cpus > 0.0) {
+        return Err(anyhow!(
+            "--cpus must be a positive number of CPUs. Got: {cpus}"
+        ));
+    }
+
+    Ok(())
+}
+
+/// The extension directory must be a subdirectory of the build context, since it
+/// is copied into the image along with the rest of the context.
+fn validate_extension_dir(build_path: &Path, extension_dir: &str) -> Result<(), anyhow::Error> {
+    let relative = Path::new(extension_dir);
+    if relative.is_absolute()
+        || relative
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+    {
+        return Err(anyhow!(
+            "--extension-dir must be a path relative to --path, without '..' components. Got: {extension_dir}"
+        ));
+    }
+
+    let full_path = build_path.join(relative);
+    if !full_path.is_dir() {
+        return Err(anyhow!(
+            "--extension-dir {} does not exist or is not a directory",
+            full_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// `capture_globs` are matched against paths relative to `pg_config --prefix` in the container
+fn parse_capture_glob(pattern: &str) -> Result<glob::Pattern, anyhow::Error> {
+    let relative = Path::new(pattern);
+    if relative.is_absolute()
+        || relative
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+    {
+        return Err(anyhow!(
+            "capture_globs entries must be globs relative to pg_config --prefix, without '..' components. Got: {pattern}"
+        ));
+    }
+
+    glob::Pattern::new(pattern)
+        .with_context(|| format!("Invalid glob in capture_globs in Trunk.toml: {pattern}"))
+}
+
+/// Files listed in `include_files` are read from the build context on the host.
+fn validate_included_file(build_path: &Path, included_file: &str) -> Result<(), anyhow::Error> {
+    let relative = Path::new(included_file);
+    if relative.is_absolute()
+        || relative
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+    {
+        return Err(anyhow!(
+            "include_files entries must be paths relative to --path, without '..' components. Got: {included_file}"
+        ));
+    }
+
+    let full_path = build_path.join(relative);
+    if !full_path.is_file() {
+        return Err(anyhow!(
+            "File {} listed in include_files does not exist or is not a file",
+            full_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// `--cargo-manifest` decides how the extension is built, so it must be a Cargo.toml that can be read
+fn validate_cargo_manifest(cargo_manifest: &Path) -> Result<(), anyhow::Error> {
+    if cargo_manifest.file_name() != Some(OsStr::new("Cargo.toml")) {
+        return Err(anyhow!(
+            "--cargo-manifest must point at a file named Cargo.toml. Got: {}",
+            cargo_manifest.display()
+        ));
+    }
+    let contents = fs::read_to_string(cargo_manifest).with_context(|| {
+        format!(
+            "Could not read --cargo-manifest {}",
+            cargo_manifest.display()
+        )
+    })?;
+    toml::from_str::<Table>(&contents).map_err(|err| {
+        anyhow!(
+            "--cargo-manifest {} is not valid TOML: {err}",
+            cargo_manifest.display()
+        )
+    })?;
+
+    Ok(())
+}
+
+/// pgrx builds compile the crate in `--extension-dir` inside the build context. With
+/// `--cargo-manifest`, that must be the directory of the manifest, which is used when
+/// `--extension-dir` isn't set
+fn cargo_manifest_extension_dir(
+    path: &Path,
+    extension_dir: Option<&str>,
+    package_dir: &Path,
+) -> Result<Option<String>, anyhow::Error> {
+    let package_dir = fs::canonicalize(package_dir)?;
+    let relative = package_dir
+        .strip_prefix(fs::canonicalize(path)?)
+        .map_err(|_| {
+            anyhow!(
+                "pgrx builds compile the crate inside the build context, but --cargo-manifest is in {}, outside of --path",
+                package_dir.display()
+            )
+        })?;
+
+    match extension_dir {
+        Some(extension_dir) if fs::canonicalize(path.join(extension_dir))? != package_dir => {
+            Err(anyhow!(
+                "pgrx builds compile the crate in --extension-dir {extension_dir}, but --cargo-manifest is in {}",
+                package_dir.display()
+            ))
+        }
+        Some(extension_dir) => Ok(Some(extension_dir.to_string())),
+        None if relative.as_os_str().is_empty() => Ok(None),
+        None => Ok(Some(relative.to_string_lossy().into_owned())),
+    }
+}
+
+/// A mismatch between a pgrx extension's Cargo.toml and a value given on the command-line is an error.
+/// The same mismatch coming from Trunk.toml is only warned about, since Cargo.toml takes precedence.
+fn check_matches_cargo_toml(
+    field: &str,
+    provided: &str,
+    cargo_value: &str,
+    source: Option<Source>,
+) -> Result<(), anyhow::Error> {
+    if provided == cargo_value {
+        return Ok(());
+    }
+
+    match source {
+        Some(Source::TrunkToml) => {
+            warn!(
+                "Trunk.toml {field} '{provided}' does not match Cargo.toml {field} '{cargo_value}'. \
+                 pgrx extensions are built with the {field} in Cargo.toml, ignoring the value in Trunk.toml"
+            );
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "User-provided {field} must match {field} in Cargo.toml\n \
+             User-provided {field}: {provided}\n \
+             Cargo.toml {field}: {cargo_value}\n"
+        )),
+    }
+}
+
+/// Fills in the name and version of a generic extension from `[package]` in its Cargo.toml,
+/// where neither the command-line, the environment nor Trunk.toml set them. A Cargo.toml
+/// that can't be read is only warned about, as generic builds don't otherwise need it.
+fn inherit_cargo_package(build_settings: &mut BuildSettings, extension_path: &Path) {
+    let package = match CargoPackage::read(extension_path) {
+        Ok(package) => package,
+        Err(err) => {
+            warn!("Could not read the name and version from Cargo.toml: {err}");
+            return;
+        }
+    };
+
+    for (field, setting, cargo_value) in [
+        ("name", &mut build_settings.name, package.name),
+        ("version", &mut build_settings.version, package.version),
+    ] {
+        if setting.is_none() {
+            tee_println!("Using {field} {cargo_value} from Cargo.toml");
+            *setting = build_settings
+                .sources
+                .track(field, Some(Resolved::new(cargo_value, Source::CargoToml)));
+        }
+    }
+}
+
+fn get_dockerfile(path: Option<String>, builder: Option<&str>) -> Result<String, anyhow::Error> {
+    if let Some(dockerfile_path) = path {
+        info!("Using Dockerfile at {}", &dockerfile_path);
+        Ok(fs::read_to_string(dockerfile_path.as_str())?)
+    } else {
+        let builder = bundled_builder(builder.unwrap_or(DEFAULT_BUILDER))?;
+        if builder.name != DEFAULT_BUILDER {
+            tee_println!("Using the bundled {} builder", builder.name);
+        }
+        Ok(builder.dockerfile.to_string())
+    }
+}
+
+#[async_trait]
+impl SubCommand for BuildCommand {
+    async fn execute(&self, task: Task) -> Result<(), anyhow::Error> {
+        if self.list_builders {
+            print!("{}", list_builders());
+            return Ok(());
+        }
+        // Set before the settings are resolved, to tag their warnings. What --explain and the
+        // other --print-* flags print is meant to be parsed, so it isn't tagged
+        build_log::set_prefix(&self.log_prefix);
+        if self.trace_docker {
+            runtime_trace::enable();
+        }
+        if !self.versions.is_empty() || self.versions_file.is_some() {
+            return self.build_versions(&task).await;
+        }
+        let build_settings = self.settings()?;
+        if self.explain {
+            print!("{}", build_settings.explain());
+            return Ok(());
+        }
+        if self.print_settings_toml {
+            print!("{}", build_settings.settings_toml()?);
+            return Ok(());
+        }
+        if self.print_install_command {
+            print!("{}", install_commands(&build_settings)?);
+            return Ok(());
+        }
+        if self.print_context_checksum {
+            println!("{}", build_settings.context_checksum()?);
+            return Ok(());
+        }
+        if let Some(log_file) = &self.log_file {
+            let header = format!(
+                "trunk build {}\nResolved settings:\n{}\n",
+                env!("CARGO_PKG_VERSION"),
+                build_settings.explain()
+            );
+            build_log::init(log_file, &header)?;
+        }
+
+        if build_settings.no_install.is_some() {
+            build_extension(build_settings, task).await?;
+            return Ok(());
+        }
+        if self.with_deps {
+            self.build_dependencies(&build_settings, &task).await?;
+        }
+        build(build_settings, task).await?;
+
+        Ok(())
+    }
+}
+
+/// Gives `build_settings` the version in `git_ref`, a ref of `--versions` such as `v1.2.0`,
+/// unless a version was set or Cargo.toml gives it, once the extension is built
+fn use_ref_version(build_settings: &mut BuildSettings, git_ref: &str) {
+    let has_cargo_toml = Path::new(&build_settings.path).join("Cargo.toml").is_file();
+    if let (None, false, Some(version)) = (
+        &build_settings.version,
+        has_cargo_toml,
+        version_from_ref(git_ref),
+    ) {
+        tee_println!("Using version {version} from the ref {git_ref}");
+        build_settings.version = build_settings.sources.track(
+            "version",
+            Some(Resolved::new(
+                version.to_string(),
+                Source::CliFlag("--versions"),
+            )),
+        );
+    }
+}
+
+impl BuildCommand {
+    /// Builds each ref of `--versions` from its sources in the repository at `--path`, then
+    /// reports which of them built. Without `--keep-going`, stops at the first that fails
+    async fn build_versions(&self, task: &Task) -> Result<(), anyhow::Error> {
+        let refs = read_version_refs(&self.versions, self.versions_file.as_deref())?;
+        if let Some(log_file) = &self.log_file {
+            let header = format!(
+                "trunk build {}\nBuilding the refs {}\n",
+                env!("CARGO_PKG_VERSION"),
+                refs.join(", ")
+            );
+            build_log::init(log_file, &header)?;
+        }
+        let temp_dir = temp_dir_for(self.temp_dir.as_deref(), 0);
+
+        let mut results: VersionResults = Vec::new();
+        for git_ref in refs {
+            tee_println!("Building {git_ref}");
+            let built = async {
+                // Removed once the ref is built
+                let checkout = checkout_ref(Path::new(&self.path), &git_ref, &temp_dir)?;
+                let mut build_settings = self.settings_from(Some(checkout.path()))?;
+                use_ref_version(&mut build_settings, &git_ref);
+                build(build_settings, task.clone()).await
+            }
+            .await;
+            match built {
+                Ok(output) => results.push((git_ref, Ok(output.artifact_path))),
+                Err(err) if self.keep_going => {
+                    warn!("Failed to build {git_ref}: {err:#}");
+                    results.push((git_ref, Err(format!("{err:#}"))));
+                }
+                Err(err) => {
+                    if !results.is_empty() {
+                        tee_print!("{}", summary(&results));
+                    }
+                    anyhow::bail!("Failed to build {git_ref}: {err:#}");
+                }
+            }
+        }
+
+        tee_print!("{}", summary(&results));
+        let failed: Vec<&str> = results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(git_ref, _)| git_ref.as_str())
+            .collect();
+        if !failed.is_empty() {
+            anyhow::bail!(
+                "{} of {} versions failed to build: {}",
+                failed.len(),
+                results.len(),
+                failed.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Builds the extensions in the repository that the one in `build_settings` requires, for
+    /// `--with-deps`, with the same Postgres version, platform and output path
+    async fn build_dependencies(
+        &self,
+        build_settings: &BuildSettings,
+        task: &Task,
+    ) -> Result<(), anyhow::Error> {
+        let path = Path::new(&build_settings.path);
+        let extension_dir = path.join(build_settings.extension_dir.as_deref().unwrap_or_default());
+        let requires = extension_requires(
+            &extension_dir,
+            build_settings
+                .extension_dependencies
+                .as_deref()
+                .unwrap_or_default(),
+        )?;
+        let target = build_settings
+            .extension_name
+            .as_ref()
+            .or(build_settings.name.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        let root = repository_root(path);
+        let extensions = discover_extensions(&root)?;
+        let (order, missing) = build_order(&target, &requires, &extensions)?;
+        for name in missing {
+            tee_println!(
+                "{name} is not in {}, so it isn't built; install it separately",
+                root.display()
+            );
+        }
+
+        for dependency in order {
+            tee_println!(
+                "Building dependency {} from {}",
+                dependency.name,
+                dependency.dir.display()
+            );
+            // The dependency's Trunk.toml is read for the same Postgres version and platform
+            let mut args = vec![
+                "build".to_string(),
+                "--path".to_string(),
+                dependency.dir.to_string_lossy().into_owned(),
+                "--output-path".to_string(),
+                build_settings.output_path.clone(),
+                "--pg-version".to_string(),
+                build_settings.pg_version.to_string(),
+            ];
+            if let Some(platform) = &build_settings.platform {
+                args.extend(["--platform".to_string(), platform.clone()]);
+            }
+            let matches = BuildCommand::augment_args(clap::Command::new("build"))
+                .try_get_matches_from(args)?;
+            let command = BuildCommand::from_arg_matches(&matches)?;
+            let settings = dependency_settings(build_settings, command.settings()?);
+            build(settings, task.clone())
+                .await
+                .with_context(|| format!("Failed to build the dependency {}", dependency.name))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings of a dependency built before the extension of `parent`, from `own`, the ones
+/// resolved from the dependency's directory and Trunk.toml. What the dependency is, and how it's
+/// built and installed, are its own; where its archive is written and how, and how the build
+/// runs, are the parent's, so that what `trunk build` was given applies to the dependencies too
+fn dependency_settings(parent: &BuildSettings, own: BuildSettings) -> BuildSettings {
+    BuildSettings {
+        path: own.path,
+        source_tarball: own.source_tarball,
+        context_from_stdin: own.context_from_stdin,
+        context_exclude: own.context_exclude,
+        context_include: own.context_include,
+        version: own.version,
+        name: own.name,
+        extension_name: own.extension_name,
+        extension_dependencies: own.extension_dependencies,
+        configurations: own.configurations,
+        system_dependencies: own.system_dependencies,
+        glob_patterns_to_include: own.glob_patterns_to_include,
+        capture_globs: own.capture_globs,
+        dockerfile_path: own.dockerfile_path,
+        builder: own.builder,
+        target: own.target,
+        configure_command: own.configure_command,
+        build_command: own.build_command,
+        compiler_flags: own.compiler_flags,
+        install_command: own.install_command,
+        before_install: own.before_install,
+        after_install: own.after_install,
+        install_user: own.install_user,
+        entrypoint: own.entrypoint,
+        extension_dir: own.extension_dir,
+        cargo_manifest: own.cargo_manifest,
+        install_layout: own.install_layout,
+        included_files: own.included_files,
+        allow_missing_control: own.allow_missing_control,
+        changelog_from_git: own.changelog_from_git,
+        categories: own.categories,
+        manifest_transform: own.manifest_transform,
+        cargo_features: own.cargo_features,
+        profile: own.profile,
+        rust_toolchain: own.rust_toolchain,
+        force_pgrx: own.force_pgrx,
+        no_version_check: own.no_version_check,
+        base_image: own.base_image,
+        integration_test: own.integration_test.filter(|_| !parent.no_integration_test),
+        loadable_libraries: own.loadable_libraries,
+        preload: own.preload,
+        kind: own.kind,
+        control: own.control,
+        supported_pg_versions: own.supported_pg_versions,
+        sources: own.sources,
+        trunk_toml: own.trunk_toml,
+        encrypted_output_path: None,
+        source_dir: own.source_dir,
+        // Only the extension `trunk build` was asked for is compared, reported on, or stopped
+        // before installing
+        compare: None,
+        report: None,
+        no_install: None,
+        temp_dir: parent.temp_dir.clone(),
+        output_path: parent.output_path.clone(),
+        output_layout: parent.output_layout,
+        require_explicit_output: parent.require_explicit_output,
+        platform: parent.platform.clone(),
+        check_install_writes: parent.check_install_writes,
+        writable_paths: parent.writable_paths.clone(),
+        artifact_suffix: parent.artifact_suffix.clone(),
+        artifact_mode: parent.artifact_mode,
+        format_version: parent.format_version,
+        sign: parent.sign,
+        sign_key: parent.sign_key.clone(),
+        encrypt: parent.encrypt,
+        encrypt_key: parent.encrypt_key.clone(),
+        resume: parent.resume,
+        fail_if_exists: parent.fail_if_exists,
+        file_digests: parent.file_digests,
+        strip: parent.strip,
+        keep_debug: parent.keep_debug,
+        labels: parent.labels.clone(),
+        label_file: parent.label_file.clone(),
+        no_hooks: parent.no_hooks,
+        allow_unusual_name: parent.allow_unusual_name,
+        buildkit: parent.buildkit,
+        pull: parent.pull,
+        offline: parent.offline,
+        skip_platform_check: parent.skip_platform_check,
+        allow_arch_mismatch: parent.allow_arch_mismatch,
+        registry_auth: parent.registry_auth.clone(),
+        cpus: parent.cpus,
+        memory: parent.memory,
+        step_timeout: parent.step_timeout,
+        fail_on_warn: parent.fail_on_warn.clone(),
+        should_test: parent.should_test,
+        no_integration_test: parent.no_integration_test,
+        pg_version: parent.pg_version,
+    }
+}
+
+/// Builds and packages the extension described by `build_settings`, like `trunk build`
+pub async fn build(
+    mut build_settings: BuildSettings,
+    task: Task,
+) -> Result<BuildOutput, anyhow::Error> {
+    let started = Instant::now();
+    let artifact_mode = build_settings.artifact_mode;
+    let previous_archive = build_settings.compare.clone();
+    let report_path = build_settings.report.clone();
+    let source_path = PathBuf::from(&build_settings.path);
+    let output_layout = build_settings.output_layout;
+    let output_path = PathBuf::from(&build_settings.output_path);
+    let platform = build_settings.platform.clone();
+    // Check for the signing tool first, rather than failing once the build is done
+    let signing = match build_settings.sign {
+        Some(tool) => {
+            find_signing_tool(tool)?;
+            Some((tool, build_settings.sign_key.clone()))
+        }
+        None => None,
+    };
+    // An archive to encrypt is written to a private directory, removed along with anything
+    // left in it when the build ends, so that no unencrypted archive is left in the output
+    // directory, even if the build fails
+    let encryption = match (build_settings.encrypt, &build_settings.encrypt_key) {
+        (true, Some(key)) => {
+            find_age("--encrypt")?;
+            create_output_dir(&output_path)?;
+            let staging = tempfile::Builder::new()
+                .prefix(".trunk-staging-")
+                .tempdir_in(&output_path)
+                .with_context(|| {
+                    format!(
+                        "Failed to create a staging directory in {}",
+                        output_path.display()
+                    )
+                })?;
+            build_settings.output_path = staging.path().to_string_lossy().into_owned();
+            build_settings.encrypted_output_path = Some(output_path.clone());
+            Some((key.clone(), staging))
+        }
+        _ => None,
+    };
+    let mut output = build_extension(build_settings, task)
+        .await?
+        .context("--no-install builds don't produce an archive")?;
+    // Compared before it's encrypted, as the comparison reads what's in the archive. The
+    // archive is already written, so a failed comparison doesn't fail the build
+    let comparison = previous_archive.map(|previous_archive| {
+        let comparison = compare_archives(&previous_archive, &output.artifact_path);
+        (previous_archive, comparison)
+    });
+    if let Some((key, staging)) = &encryption {
+        encrypt_output(&mut output, key, staging.path(), &output_path)?;
+    }
+    // Signatures are of the archive that ships, so of the encrypted one with --encrypt
+    if let Some((tool, key)) = signing {
+        let signing_started = Instant::now();
+        output.signatures = sign_artifact(tool, key.as_deref(), &output.artifact_path)?;
+        output.timings.record_since("signing", signing_started);
+    }
+    for artifact in std::iter::once(&output.artifact_path)
+        .chain(&output.signatures)
+        .chain(&output.debug_symbols_path)
+        .chain(&output.encryption_sidecar)
+    {
+        fs::set_permissions(artifact, fs::Permissions::from_mode(artifact_mode))
+            .with_context(|| format!("Failed to set the mode of {}", artifact.display()))?;
+    }
+    let index_path = output_layout.write_index(&output_path, &output, platform.as_deref())?;
+
+    let artifact = output
+        .artifact_path
+        .file_name()
+        .unwrap_or(output.artifact_path.as_os_str())
+        .to_string_lossy();
+    let elapsed = started.elapsed();
+    tee_print!("{}", output.timings.summary(&artifact, elapsed));
+    if let Some(container_runtime) = &output.manifest.container_runtime {
+        tee_println!("Container runtime: {container_runtime}");
+    }
+    if let Some(base_image) = &output.manifest.base_image {
+        tee_println!("Base image: {base_image}");
+    }
+    if let Some(sidecar) = &output.encryption_sidecar {
+        tee_println!("Encrypted archive: {}", output.artifact_path.display());
+        tee_println!("Encryption sidecar: {}", sidecar.display());
+    }
+    if let Some(debug_symbols_path) = &output.debug_symbols_path {
+        tee_println!("Debug symbols: {}", debug_symbols_path.display());
+    }
+    for signature in &output.signatures {
+        tee_println!("Signature: {}", signature.display());
+    }
+    if let Some(index_path) = &index_path {
+        tee_println!("Registry index: {}", index_path.display());
+    }
+    match comparison {
+        Some((previous_archive, Ok(comparison))) => {
+            tee_print!("{}", comparison.summary(&previous_archive))
+        }
+        Some((previous_archive, Err(err))) => warn!(
+            "Could not compare the archive with {}: {err:#}",
+            previous_archive.display()
+        ),
+        None => {}
+    }
+    if let Some(report_path) = report_path {
+        // The commit is only provenance, so sources outside a git repository are reported without
+        let git = read_git_revision(&source_path)
+            .map_err(|err| info!("Not recording a git commit in the report: {err:#}"))
+            .ok();
+        BuildReport::new(&output, git, elapsed)?.write(&report_path)?;
+        tee_println!("Build report: {}", report_path.display());
+    }
+
+    Ok(output)
+}
+
+/// Encrypts the archive of `output` and its debug symbols, written to `staging`, to the age
+/// recipients in `key`, and moves the encrypted files to where they'd have been written in
+/// `output_path`. The unencrypted files are removed
+fn encrypt_output(
+    output: &mut BuildOutput,
+    key: &Path,
+    staging: &Path,
+    output_path: &Path,
+) -> anyhow::Result<()> {
+    let unstaged = |path: &Path| -> anyhow::Result<PathBuf> {
+        let unstaged = output_path.join(path.strip_prefix(staging)?);
+        if let Some(parent) = unstaged.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        Ok(unstaged)
+    };
+    let move_out = |from: &Path, to: &Path| {
+        fs::rename(from, to).with_context(|| format!("Failed to move {}", to.display()))
+    };
+
+    let (encrypted, sidecar) = encrypt_artifact(&output.artifact_path, key)?;
+    let debug_symbols = output
+        .debug_symbols_path
+        .as_deref()
+        .map(|debug_symbols| encrypt_file(debug_symbols, key))
+        .transpose()?;
+    // Moved once everything is encrypted, so a failure leaves nothing in the output directory
+    let (artifact_path, sidecar_path) = (unstaged(&encrypted)?, unstaged(&sidecar)?);
+    let debug_symbols_path = debug_symbols.as_deref().map(unstaged).transpose()?;
+    move_out(&encrypted, &artifact_path)?;
+    move_out(&sidecar, &sidecar_path)?;
+    if let (Some(from), Some(to)) = (&debug_symbols, &debug_symbols_path) {
+        move_out(from, to)?;
+    }
+    output.artifact_path = artifact_path;
+    output.encryption_sidecar = Some(sidecar_path);
+    output.debug_symbols_path = debug_symbols_path;
+
+    Ok(())
+}
+
+/// The Cargo.toml that decides whether the extension is built with pgrx, from `--cargo-manifest`
+/// or else in the extension's directory, along with the directory of its package
+fn cargo_toml_location(build_settings: &BuildSettings) -> (PathBuf, PathBuf) {
+    let cargo_toml_path = match &build_settings.cargo_manifest {
+        Some(cargo_manifest) => cargo_manifest.clone(),
+        None => {
+            let path = Path::new(&build_settings.path);
+            match &build_settings.extension_dir {
+                Some(extension_dir) => path.join(extension_dir),
+                None => path.to_path_buf(),
+            }
+            .join("Cargo.toml")
+        }
+    };
+    let package_dir = match cargo_toml_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    (cargo_toml_path, package_dir)
+}
+
+/// Whether the extension is built with pgrx: with `--force-pgrx`, or if its Cargo.toml depends
+/// on pgrx
+fn is_pgrx_build(build_settings: &BuildSettings, cargo_toml_path: &Path) -> anyhow::Result<bool> {
+    if !cargo_toml_path.exists() {
+        if build_settings.force_pgrx {
+            return Err(anyhow!(
+                "--force-pgrx requires a Cargo.toml, but there is none at {}",
+                cargo_toml_path.display()
+            ));
+        }
+        return Ok(false);
+    }
+    if build_settings.force_pgrx {
+        return Ok(true);
+    }
+    let cargo_toml = read_cargo_toml(cargo_toml_path)?;
+
+    Ok(depends_on_pgrx(&cargo_toml))
+}
+
+/// The argv of a generic build's install step: the install command, `make install` unless set,
+/// passed to the entrypoint or else to `/bin/sh -c`
+fn generic_install_argv(build_settings: &BuildSettings) -> Vec<String> {
+    match (&build_settings.entrypoint, &build_settings.install_command) {
+        (_, Some(install_command)) => generic_command_argv(build_settings, install_command),
+        (Some(_), None) => generic_command_argv(build_settings, "make install"),
+        (None, None) => vec!["make".to_string(), "install".to_string()],
+    }
+}
+
+/// The argv that runs `command` in the build container like the install command, passed to the
+/// entrypoint or else to `/bin/sh -c`
+fn generic_command_argv(build_settings: &BuildSettings, command: &str) -> Vec<String> {
+    let command = process_install_command(command, build_settings.pg_version).into_owned();
+    let mut argv = match &build_settings.entrypoint {
+        Some(entrypoint) => entrypoint.clone(),
+        None => vec!["/bin/sh".to_string(), "-c".to_string()],
+    };
+    argv.push(command);
+    argv
+}
+
+/// The argv of the `before_install` and `after_install` commands of a generic build
+fn generic_install_hooks(build_settings: &BuildSettings) -> InstallHooks {
+    let argv = |command: &Option<String>| {
+        command
+            .as_deref()
+            .map(|command| generic_command_argv(build_settings, command))
+    };
+
+    InstallHooks {
+        before: argv(&build_settings.before_install),
+        after: argv(&build_settings.after_install),
+    }
+}
+
+/// `argv` as a command line to paste into a shell
+fn shell_join(argv: &[String]) -> String {
+    shlex::try_join(argv.iter().map(String::as_str)).unwrap_or_else(|_| argv.join(" "))
+}
+
+/// The commands the install step of the build runs, one per line, for `--print-install-command`.
+/// Generic builds run the install command from `--extension-dir` in the builder container. pgrx
+/// builds run `cargo pgrx package` while building the builder image, then copy its output into
+/// place in the container.
+fn install_commands(build_settings: &BuildSettings) -> anyhow::Result<String> {
+    let (cargo_toml_path, package_dir) = cargo_toml_location(build_settings);
+    if !is_pgrx_build(build_settings, &cargo_toml_path)? {
+        let hooks = generic_install_hooks(build_settings);
+        let commands: Vec<String> = hooks
+            .before
+            .into_iter()
+            .chain([generic_install_argv(build_settings)])
+            .chain(hooks.after)
+            .map(|argv| format!("{}\n", shell_join(&argv)))
+            .collect();
+        return Ok(commands.concat());
+    }
+
+    let package = CargoPackage::read(&package_dir)?;
+    let profile = build_settings.profile.unwrap_or_default();
+    // The flags are quoted for the shell that runs `cargo pgrx package` in the Dockerfile
+    let package_command = ["cargo", "pgrx", "package"]
+        .into_iter()
+        .map(ToString::to_string)
+        .chain(cargo_pgrx_flags(&build_settings.cargo_features, profile))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let copy_command =
+        copy_packaged_files_command(&package.name, build_settings.pg_version, profile);
+
+    Ok(format!(
+        "{package_command}\n{}\n",
+        shell_join(&copy_command)
+    ))
+}
+
+/// The directory the build of `name` and `version` writes its archive to, under the output path
+/// as arranged by `--output-layout`
+fn artifact_dir(build_settings: &BuildSettings, name: &str, version: &str) -> PathBuf {
+    build_settings.output_layout.artifact_dir(
+        Path::new(&build_settings.output_path),
+        name,
+        version,
+        build_settings.platform.as_deref(),
+    )
+}
+
+/// Where the build of `name` and `version` writes its archive
+fn artifact_path(build_settings: &BuildSettings, name: &str, version: &str) -> PathBuf {
+    artifact_dir(build_settings, name, version).join(format!(
+        "{name}-{version}-pg{}{}",
+        build_settings.pg_version, build_settings.artifact_suffix
+    ))
+}
+
+/// With `--fail-if-exists`, fails if the archive of `name` and `version` is already in the output
+/// directory, before anything is built
+fn check_artifact_absent(
+    build_settings: &BuildSettings,
+    name: &str,
+    version: &str,
+) -> anyhow::Result<()> {
+    if !build_settings.fail_if_exists {
+        return Ok(());
+    }
+    let mut artifact_path = artifact_path(build_settings, name, version);
+    // An encrypted archive is built in a staging directory, and only its encrypted copy is moved
+    // to the output directory
+    if let Some(output_path) = &build_settings.encrypted_output_path {
+        let staged = artifact_path.strip_prefix(&build_settings.output_path)?;
+        artifact_path = encrypted_path(&output_path.join(staged));
+    }
+    if fs::symlink_metadata(&artifact_path).is_ok() {
+        return Err(anyhow!(
+            "{} already exists, and --fail-if-exists forbids overwriting it. \
+             Bump the version, or remove the archive to rebuild it",
+            artifact_path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// With `--resume`, the archive of `name` and `version` that an earlier build left in the output
+/// directory, unless it is missing or no longer matches its manifest
+fn resumable_artifact(
+    build_settings: &BuildSettings,
+    name: &str,
+    version: &str,
+) -> Option<BuildOutput> {
+    if !build_settings.resume || build_settings.no_install.is_some() {
+        return None;
+    }
+    let artifact_path = artifact_path(build_settings, name, version);
+    if !artifact_path.exists() {
+        info!(
+            "No earlier archive at {}, building it",
+            artifact_path.display()
+        );
+        return None;
+    }
+
+    match read_verified_archive(&artifact_path) {
+        Ok(manifest) if manifest.pg_version == build_settings.pg_version => {
+            tee_println!(
+                "Skipping the build, {} from an earlier build is intact (--resume)",
+                artifact_path.display()
+            );
+            Some(BuildOutput {
+                artifact_path,
+                manifest,
+                timings: BuildTimings::default(),
+                signatures: Vec::new(),
+                debug_symbols_path: None,
+                encryption_sidecar: None,
+            })
+        }
+        Ok(manifest) => {
+            warn!(
+                "{} was built for PostgreSQL {}, rebuilding it",
+                artifact_path.display(),
+                manifest.pg_version
+            );
+            None
+        }
+        Err(err) => {
+            warn!("Rebuilding {}: {err:#}", artifact_path.display());
+            None
+        }
+    }
+}
+
+/// Builds the extension, or only its builder image with `--no-install`, in which case there
+/// is no output
+async fn build_extension(
+    mut build_settings: BuildSettings,
+    task: Task,
+) -> Result<Option<BuildOutput>, anyhow::Error> {
+    if !build_settings
+        .supported_pg_versions
+        .contains(build_settings.pg_version)
+    {
+        let message = format!(
+            "Building for PostgreSQL {}, but the extension only supports PostgreSQL {}. \
+             Installing the archive will fail",
+            build_settings.pg_version, build_settings.supported_pg_versions
+        );
+        warn_or_fail(
+            &build_settings.fail_on_warn,
+            WarningCategory::UnsupportedPgVersion,
+            &message,
+        )?;
+    }
+    for category in &build_settings.categories {
+        if !VALID_CATEGORY_SLUGS.contains(&category.as_str()) {
+            let message = format!(
+                "'{category}' is not a category the registry knows, expected one of: {}",
+                VALID_CATEGORY_SLUGS.join(", ")
+            );
+            warn_or_fail(
+                &build_settings.fail_on_warn,
+                WarningCategory::UnknownCategory,
+                &message,
+            )?;
+        }
+    }
+    if build_settings.artifact_suffix != ".tar.gz" {
+        tee_println!("Using artifact suffix {}", build_settings.artifact_suffix);
+    }
+    let image_build_options = build_settings.image_build_options();
+    info!(
+        "Building from path {}",
+        resolved_path(Path::new(&build_settings.path)).display()
+    );
+    info!(
+        "Writing the archive to {}",
+        resolved_path(Path::new(&build_settings.output_path)).display()
+    );
+    if build_settings.no_install.is_none() {
+        create_output_dir(Path::new(&build_settings.output_path))?;
+    }
+    let path = PathBuf::from(&build_settings.path);
+
+    // Only adds provenance to the manifest, so a missing repository or git doesn't fail the build
+    let changelog = if build_settings.changelog_from_git && build_settings.no_install.is_none() {
+        match read_git_changelog(&path) {
+            Ok(changelog) => {
+                match &changelog.since_tag {
+                    Some(tag) => info!(
+                        "Recording {} commits since {tag} in the changelog",
+                        changelog.commits.len()
+                    ),
+                    None => info!(
+                        "No git tags found, recording the latest {} commits in the changelog",
+                        changelog.commits.len()
+                    ),
+                }
+                Some(changelog)
+            }
+            Err(err) => {
+                warn!("Not recording a changelog: {err:#}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let labels = (!build_settings.labels.is_empty()).then(|| build_settings.labels.clone());
+    let categories =
+        (!build_settings.categories.is_empty()).then(|| build_settings.categories.clone());
+    let manifest_transform = match &build_settings.manifest_transform {
+        Some(command) if build_settings.no_hooks => {
+            info!("Not running the manifest_transform hook `{command}`, as --no-hooks was given");
+            None
+        }
+        manifest_transform => manifest_transform.clone(),
+    };
+
+    let (cargo_toml_path, package_dir) = cargo_toml_location(&build_settings);
+    let is_pgrx = is_pgrx_build(&build_settings, &cargo_toml_path)?;
+
+    if cargo_toml_path.exists() {
+        if is_pgrx {
+            if build_settings.force_pgrx {
+                info!("Building a pgrx extension, as requested by --force-pgrx");
+            } else {
+                info!("Detected that we are building a pgrx extension");
+            }
+            let package = CargoPackage::read(&package_dir)?;
+            if build_settings.cargo_manifest.is_some() {
+                build_settings.extension_dir = cargo_manifest_extension_dir(
+                    &path,
+                    build_settings.extension_dir.as_deref(),
+                    &package_dir,
+                )?;
+            }
+            let layout = &build_settings.install_layout;
+            if layout.lib_dir.is_some() || layout.sql_dir.is_some() || layout.control_dir.is_some()
+            {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "lib_dir, sql_dir and control_dir only apply to generic builds, ignoring them",
+                )?;
+            }
+            if !layout.prefixes.is_empty() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "prefix only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.configure_command.is_some() || build_settings.build_command.is_some()
+            {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "configure_command and build_command only apply to generic builds, \
+                     ignoring them",
+                )?;
+            }
+            if build_settings.compiler_flags != CompilerFlags::default() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "cflags and cxxflags only apply to generic builds, ignoring them",
+                )?;
+            }
+            if build_settings.target.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "target only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.builder.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "builder only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.install_user.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "user only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.before_install.is_some() || build_settings.after_install.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "before_install and after_install only apply to generic builds, ignoring them",
+                )?;
+            }
+            if build_settings.check_install_writes {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "--check-install-writes only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.entrypoint.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "entrypoint only applies to generic builds, ignoring it",
+                )?;
+            }
+            if build_settings.integration_test.is_some() {
+                warn_or_fail(
+                    &build_settings.fail_on_warn,
+                    WarningCategory::IgnoredSetting,
+                    "integration_test only applies to generic builds, ignoring it",
+                )?;
+            }
+            // pgrx builds always take name and version from Cargo.toml, so
+            // check that whatever the user provided agrees with it
+            for (field, provided, cargo_value) in [
+                ("name", &build_settings.name, &package.name),
+                ("version", &build_settings.version, &package.version),
+            ] {
+                if let Some(provided) = provided {
+                    check_matches_cargo_toml(
+                        field,
+                        provided,
+                        cargo_value,
+                        build_settings.sources.get(field),
+                    )?;
+                }
+            }
+            if build_settings.extension_name.is_none() {
+                validate_extension_name(&package.name, build_settings.allow_unusual_name)?;
+            }
+            check_artifact_absent(&build_settings, &package.name, &package.version)?;
+            if let Some(output) =
+                resumable_artifact(&build_settings, &package.name, &package.version)
+            {
+                return Ok(Some(output));
+            }
+
+            let strip = build_settings.strip();
+            let artifact_dir = artifact_dir(&build_settings, &package.name, &package.version);
+            let output = build_pgrx(
+                build_settings.dockerfile_path.clone(),
+                build_settings.platform.clone(),
+                &path,
+                build_settings.extension_dir.as_deref(),
+                &artifact_dir.to_string_lossy(),
+                build_settings.extension_name,
+                build_settings.extension_dependencies,
+                package,
+                build_settings.force_pgrx,
+                build_settings.system_dependencies,
+                build_settings.glob_patterns_to_include,
+                build_settings.capture_globs,
+                build_settings.configurations,
+                build_settings.loadable_libraries,
+                build_settings.preload,
+                build_settings.kind,
+                build_settings.control,
+                build_settings.pg_version,
+                build_settings.supported_pg_versions,
+                build_settings.included_files,
+                &build_settings.artifact_suffix,
+                build_settings.allow_missing_control,
+                build_settings.file_digests,
+                strip,
+                changelog,
+                labels.clone(),
+                categories.clone(),
+                manifest_transform.clone(),
+                build_settings.format_version,
+                &build_settings.fail_on_warn,
+                build_settings.allow_arch_mismatch,
+                build_settings.cargo_features,
+                build_settings.profile.unwrap_or_default(),
+                build_settings.rust_toolchain,
+                build_settings.no_install,
+                image_build_options.clone(),
+                task,
+            )
+            .await?;
+            let Some(output) = output else {
+                return Ok(None);
+            };
+            if build_settings.profile == Some(CargoProfile::Debug) {
+                tee_println!(
+                    "WARNING: {} is an unoptimized debug build, not meant for production",
+                    output.artifact_path.display()
+                );
+            }
+            return Ok(Some(output));
+        }
+
+        if build_settings.name.is_none() || build_settings.version.is_none() {
+            inherit_cargo_package(&mut build_settings, &package_dir);
+        }
+    }
+
+    // Check if version or name are missing
+    if build_settings.version.is_none() || build_settings.name.is_none() {
+        return Err(anyhow!(
+            "--version and --name are required unless building a PGRX extension, \
+             or set under [package] in the extension's Cargo.toml"
+        ));
+    }
+
+    check_artifact_absent(
+        &build_settings,
+        build_settings.name.as_deref().unwrap_or_default(),
+        build_settings.version.as_deref().unwrap_or_default(),
+    )?;
+    if let Some(output) = resumable_artifact(
+        &build_settings,
+        build_settings.name.as_deref().unwrap_or_default(),
+        build_settings.version.as_deref().unwrap_or_default(),
+    ) {
+        return Ok(Some(output));
+    }
+
+    if build_settings.cargo_features != CargoFeatures::default() {
+        warn_or_fail(
+            &build_settings.fail_on_warn,
+            WarningCategory::IgnoredSetting,
+            "Cargo features only apply to pgrx builds, ignoring them",
+        )?;
+    }
+    if build_settings.rust_toolchain.is_some() {
+        warn_or_fail(
+            &build_settings.fail_on_warn,
+            WarningCategory::IgnoredSetting,
+            "rust_toolchain only applies to pgrx builds, ignoring it",
+        )?;
+    }
+    if let Some(profile) = build_settings.profile {
+        return Err(anyhow!(
+            "--profile {} only applies to pgrx extensions. Generic builds are compiled by their \
+             build or install command, so set optimization flags there, e.g. --build-command \"make CFLAGS=-O0\"",
+            profile.as_str()
+        ));
+    }
+
+    let mut dockerfile: String = get_dockerfile(
+        build_settings.dockerfile_path.clone(),
+        build_settings.builder.as_deref(),
+    )?;
+    if let Some(target) = &build_settings.target {
+        tee_println!("Building up to the Dockerfile stage {target}");
+        dockerfile = target_dockerfile(&dockerfile, target)?;
+    }
+    if build_settings.dockerfile_path.is_some() {
+        for warning in check_dockerfile(&dockerfile, BuilderKind::Generic) {
+            warn_or_fail(
+                &build_settings.fail_on_warn,
+                WarningCategory::Dockerfile,
+                &warning,
+            )?;
+        }
+    }
+    let configure_command = build_settings
+        .configure_command
+        .as_ref()
+        .map(|command| process_install_command(command, build_settings.pg_version));
+    let build_command = build_settings
+        .build_command
+        .as_ref()
+        .map(|command| process_install_command(command, build_settings.pg_version));
+    let dockerfile = staged_dockerfile(
+        &dockerfile,
+        build_settings.dockerfile_path.is_none(),
+        configure_command.as_deref(),
+        build_command.as_deref(),
+    );
+
+    if build_settings.install_command.is_none() {
+        warn_or_fail(
+            &build_settings.fail_on_warn,
+            WarningCategory::MissingInstallCommand,
+            "Install command is not specified, guessing the command is 'make install'",
+        )?;
+    }
+    if let Some(entrypoint) = &build_settings.entrypoint {
+        info!(
+            "Running the install command with entrypoint {}",
+            shell_join(entrypoint)
+        );
+    }
+    let install_argv = generic_install_argv(&build_settings);
+    let install_command_split: Vec<&str> = install_argv.iter().map(String::as_str).collect();
+    info!(
+        "Using install command {}",
+        install_command_split.clone().join(" ")
+    );
+
+    let dockerfile = dockerfile.as_str();
+    let strip = build_settings.strip();
+    let artifact_dir = artifact_dir(
+        &build_settings,
+        build_settings.name.as_deref().unwrap_or_default(),
+        build_settings.version.as_deref().unwrap_or_default(),
+    );
+    let output = build_generic(
+        dockerfile,
+        build_settings.platform.clone(),
+        install_command_split,
+        generic_install_hooks(&build_settings),
+        &path,
+        build_settings.extension_dir.as_deref(),
+        &artifact_dir.to_string_lossy(),
+        build_settings.name.clone().unwrap().as_str(),
+        build_settings.extension_name,
+        build_settings.extension_dependencies,
+        build_settings.system_dependencies,
+        build_settings.version.clone().unwrap().as_str(),
+        build_settings.glob_patterns_to_include,
+        build_settings.capture_globs,
+        task,
+        build_settings.should_test,
+        build_settings.integration_test.as_deref(),
+        build_settings.configurations,
+        build_settings.loadable_libraries,
+        build_settings.preload,
+        build_settings.kind,
+        build_settings.control,
+        build_settings.pg_version,
+        build_settings.supported_pg_versions,
+        build_settings.included_files,
+        &build_settings.artifact_suffix,
+        build_settings.allow_missing_control,
+        build_settings.file_digests,
+        strip,
+        changelog,
+        labels,
+        categories,
+        manifest_transform,
+        build_settings.compiler_flags,
+        build_settings.format_version,
+        &build_settings.fail_on_warn,
+        build_settings.allow_arch_mismatch,
+        !build_settings.no_version_check,
+        build_settings.install_layout,
+        build_settings.install_user.as_deref(),
+        build_settings
+            .check_install_writes
+            .then(|| build_settings.writable_paths.clone()),
+        build_settings.no_install,
+        image_build_options,
+    )
+    .await?;
+
+    Ok(output)
+}
+
+fn process_install_command(install_command: &str, pg_version: u8) -> Cow<'_, str> {
     if pg_version == 15 {
         Cow::Borrowed(install_command)
     } else {
@@ -320,3 +4839,324 @@ fn process_install_command(install_command: &str, pg_version: u8) -> Cow<'_, str
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inherits_name_and_version_from_cargo_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"my_ext\"\nversion = \"0.2.0\"\n",
+        )
+        .unwrap();
+
+        let mut settings = BuildSettings::builder(dir.path().to_string_lossy())
+            .version("0.3.0")
+            .build()
+            .unwrap();
+        inherit_cargo_package(&mut settings, dir.path());
+        assert_eq!(settings.name.as_deref(), Some("my_ext"));
+        assert_eq!(settings.sources.get("name"), Some(Source::CargoToml));
+        // Values set elsewhere take precedence over Cargo.toml
+        assert_eq!(settings.version.as_deref(), Some("0.3.0"));
+        assert_ne!(settings.sources.get("version"), Some(Source::CargoToml));
+    }
+
+    #[test]
+    fn checks_cargo_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("crates/ext");
+        fs::create_dir_all(&crate_dir).unwrap();
+        fs::write(crate_dir.join("Cargo.toml"), "[package]\nname = \"ext\"\n").unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package\n").unwrap();
+
+        assert!(validate_cargo_manifest(&crate_dir.join("Cargo.toml")).is_ok());
+        let err = validate_cargo_manifest(&dir.path().join("Cargo.toml")).unwrap_err();
+        assert!(err.to_string().contains("is not valid TOML"), "{err}");
+        assert!(err.to_string().contains("line 1, column 9"), "{err}");
+        assert!(validate_cargo_manifest(&dir.path().join("missing/Cargo.toml")).is_err());
+        assert!(validate_cargo_manifest(&crate_dir).is_err());
+
+        // pgrx builds run in the manifest's directory, which must be in the build context
+        assert_eq!(
+            cargo_manifest_extension_dir(dir.path(), None, &crate_dir).unwrap(),
+            Some("crates/ext".to_string())
+        );
+        assert_eq!(
+            cargo_manifest_extension_dir(dir.path(), None, dir.path()).unwrap(),
+            None
+        );
+        assert!(cargo_manifest_extension_dir(dir.path(), Some("crates/ext"), &crate_dir).is_ok());
+        assert!(cargo_manifest_extension_dir(dir.path(), Some("crates"), &crate_dir).is_err());
+        assert!(cargo_manifest_extension_dir(&crate_dir, None, dir.path()).is_err());
+    }
+
+    #[test]
+    fn dependencies_build_with_the_parents_settings() {
+        let parent_dir = tempfile::tempdir().unwrap();
+        let dependency_dir = tempfile::tempdir().unwrap();
+        let parent = BuildSettings::builder(parent_dir.path().to_string_lossy())
+            .name("parent")
+            .output_path("dist")
+            .output_layout(OutputLayout::Nested)
+            .platform("linux/arm64")
+            .memory(2 << 30)
+            .strip(false)
+            .label("ci.commit", "3f2a9c1")
+            .report(parent_dir.path().join("report.json"))
+            .pg_version(16)
+            .build()
+            .unwrap();
+        let own = BuildSettings::builder(dependency_dir.path().to_string_lossy())
+            .name("dependency")
+            .install_command("make install PREFIX=/usr")
+            .build()
+            .unwrap();
+
+        let settings = dependency_settings(&parent, own);
+        assert_eq!(settings.path, dependency_dir.path().to_string_lossy());
+        assert_eq!(settings.name.as_deref(), Some("dependency"));
+        assert_eq!(
+            settings.install_command.as_deref(),
+            Some("make install PREFIX=/usr")
+        );
+        assert_eq!(settings.output_path, "dist");
+        assert_eq!(settings.output_layout, OutputLayout::Nested);
+        assert_eq!(settings.platform.as_deref(), Some("linux/arm64"));
+        assert_eq!(settings.memory, Some(2 << 30));
+        assert!(!settings.strip);
+        assert_eq!(settings.labels["ci.commit"], "3f2a9c1");
+        assert_eq!(settings.pg_version, 16);
+        assert!(settings.report.is_none());
+    }
+
+    #[test]
+    fn checks_extension_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("ext/sql")).unwrap();
+
+        assert!(validate_extension_dir(dir.path(), "ext").is_ok());
+        assert!(validate_extension_dir(dir.path(), "ext/sql").is_ok());
+        for extension_dir in ["/ext", "../ext", "ext/../../ext"] {
+            let err = validate_extension_dir(dir.path(), extension_dir).unwrap_err();
+            assert!(
+                err.to_string().contains("without '..' components"),
+                "{extension_dir}: {err}"
+            );
+        }
+        let err = validate_extension_dir(dir.path(), "missing").unwrap_err();
+        assert!(err.to_string().contains("does not exist"), "{err}");
+    }
+
+    #[test]
+    fn takes_versions_from_refs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = BuildSettings::builder(dir.path().to_string_lossy())
+            .build()
+            .unwrap();
+        use_ref_version(&mut settings, "v1.2.0");
+        assert_eq!(settings.version.as_deref(), Some("1.2.0"));
+        // --version wasn't given, so it isn't named as the source
+        assert_eq!(
+            settings.sources.get("version"),
+            Some(Source::CliFlag("--versions"))
+        );
+
+        let mut settings = BuildSettings::builder(dir.path().to_string_lossy())
+            .version("2.0.0")
+            .build()
+            .unwrap();
+        use_ref_version(&mut settings, "v1.2.0");
+        assert_eq!(settings.version.as_deref(), Some("2.0.0"));
+    }
+
+    #[test]
+    fn resolves_paths_for_logs() {
+        let current_dir = fs::canonicalize(".").unwrap();
+        assert_eq!(resolved_path(Path::new(".")), current_dir);
+
+        let output_path = resolved_path(Path::new("missing/.trunk"));
+        assert!(output_path.is_absolute());
+        assert!(output_path.ends_with("missing/.trunk"));
+    }
+
+    #[test]
+    fn parses_artifact_modes() {
+        for (mode, expected) in [("0644", 0o644), ("640", 0o640), ("0o600", 0o600), ("0", 0)] {
+            assert_eq!(parse_artifact_mode(mode).unwrap(), expected, "{mode}");
+        }
+        for mode in ["", "0o", "rw-r--r--", "0648", "4755", "07777", "-644"] {
+            assert!(parse_artifact_mode(mode).is_err(), "{mode}");
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("shared/.trunk");
+        create_output_dir(&output_path).unwrap();
+        let mode = fs::metadata(&output_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn failed_encryption_leaves_no_unencrypted_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join(".trunk");
+        create_output_dir(&output_path).unwrap();
+        let staging = tempfile::tempdir_in(&output_path).unwrap();
+        let artifact_path = staging.path().join("ext-1.0.0-pg15.tar.gz");
+        fs::write(&artifact_path, "archive").unwrap();
+        let mut output = BuildOutput {
+            artifact_path,
+            manifest: Manifest::default(),
+            timings: BuildTimings::default(),
+            signatures: Vec::new(),
+            debug_symbols_path: None,
+            encryption_sidecar: None,
+        };
+
+        // The recipients file is missing, so age fails, or isn't installed at all
+        let recipients = dir.path().join("recipients.txt");
+        assert!(encrypt_output(&mut output, &recipients, staging.path(), &output_path).is_err());
+        drop(staging);
+        assert_eq!(fs::read_dir(&output_path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn fails_if_the_encrypted_archive_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join(".trunk");
+        let staging = output_path.join(".trunk-staging-test");
+        fs::create_dir_all(&staging).unwrap();
+        let mut settings = BuildSettings::builder(dir.path().to_string_lossy())
+            .output_path(staging.to_string_lossy())
+            .fail_if_exists(true)
+            .build()
+            .unwrap();
+        settings.encrypted_output_path = Some(output_path.clone());
+
+        assert!(check_artifact_absent(&settings, "ext", "1.0.0").is_ok());
+        // Checked before building, against what the encrypted archive will be moved to
+        fs::write(output_path.join("ext-1.0.0-pg15.tar.gz.age"), "archive").unwrap();
+        let err = check_artifact_absent(&settings, "ext", "1.0.0").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("ext-1.0.0-pg15.tar.gz.age already exists"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn checks_extension_names() {
+        for name in ["pg_cron", "_private", "pgmq", "http$2", "postgis_3"] {
+            assert!(is_unquoted_identifier(name), "{name}");
+        }
+        for name in ["", "my-ext", "MyExt", "3d", "pg cron", &"a".repeat(64)] {
+            assert!(!is_unquoted_identifier(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn cuts_dockerfile_at_target() {
+        let dockerfile = "FROM quay.io/coredb/c-builder:pg15 AS build\n\
+                          RUN make \\\n\
+                          # FROM inside a continued RUN\n\
+                          \x20   all\n\
+                          FROM --platform=linux/amd64 build AS trunk\n\
+                          RUN ls\n\
+                          FROM scratch AS export\n";
+
+        let build = target_dockerfile(dockerfile, "build").unwrap();
+        assert!(build.ends_with("    all\n"), "{build}");
+        let trunk = target_dockerfile(dockerfile, "trunk").unwrap();
+        assert!(trunk.ends_with("RUN ls\n"), "{trunk}");
+        assert_eq!(target_dockerfile(dockerfile, "export").unwrap(), dockerfile);
+        let err = target_dockerfile(dockerfile, "capture").unwrap_err();
+        assert!(err.to_string().contains("[build, trunk, export]"), "{err}");
+    }
+
+    /// Writes a gzipped tarball of `files`, given as paths and contents
+    fn source_tarball(files: &[(&str, &str)]) -> tempfile::NamedTempFile {
+        let tarball = tempfile::NamedTempFile::new().unwrap();
+        let encoder =
+            flate2::write::GzEncoder::new(tarball.reopen().unwrap(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        tarball
+    }
+
+    #[test]
+    fn picks_temp_dirs_with_room_for_the_sources() {
+        let df = "Filesystem     1024-blocks    Used Available Capacity Mounted on\n\
+                  tmpfs               65536   61440      4096      94% /tmp\n";
+        assert_eq!(parse_df_available(df), Some(4096 * 1024));
+        assert_eq!(parse_df_available("df: /missing: No such file\n"), None);
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        assert_eq!(temp_dir_for(Some(temp_dir.path()), 1), temp_dir.path());
+        assert!(check_temp_dir(temp_dir.path(), u64::MAX)
+            .unwrap_err()
+            .contains("but the sources need"));
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert_ne!(temp_dir_for(Some(&file), 1), file);
+
+        let tarball = source_tarball(&[("ext/Trunk.toml", "")]);
+        let (source_dir, _) =
+            extract_source_tarball(tarball.path(), Some(temp_dir.path())).unwrap();
+        assert!(source_dir.path().starts_with(temp_dir.path()));
+    }
+
+    #[test]
+    fn extracts_source_tarballs() {
+        let single_dir = source_tarball(&[("pg_ext-1.0/Trunk.toml", ""), ("pg_ext-1.0/ext.c", "")]);
+        let (source_dir, root) = extract_source_tarball(single_dir.path(), None).unwrap();
+        assert_eq!(root, source_dir.path().join("pg_ext-1.0"));
+
+        let flat = source_tarball(&[("Cargo.toml", ""), ("src/lib.rs", "")]);
+        let (source_dir, root) = extract_source_tarball(flat.path(), None).unwrap();
+        assert_eq!(root, source_dir.path());
+
+        let with_docs = source_tarball(&[("ext/Trunk.toml", ""), ("docs/README.md", "")]);
+        let (source_dir, root) = extract_source_tarball(with_docs.path(), None).unwrap();
+        assert_eq!(root, source_dir.path().join("ext"));
+
+        let several = source_tarball(&[("a/Trunk.toml", ""), ("b/Trunk.toml", "")]);
+        let err = extract_source_tarball(several.path(), None).unwrap_err();
+        assert!(
+            err.to_string().contains("several extensions (a, b)"),
+            "{err}"
+        );
+
+        let no_manifest = source_tarball(&[("ext/Makefile", "")]);
+        let err = extract_source_tarball(no_manifest.path(), None).unwrap_err();
+        assert!(
+            err.to_string().contains("No Trunk.toml or Cargo.toml"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn suggests_legal_extension_names() {
+        assert_eq!(suggest_extension_name("my-ext"), "my_ext");
+        assert_eq!(suggest_extension_name("MyExt"), "myext");
+        assert_eq!(suggest_extension_name("3d"), "_3d");
+        assert_eq!(suggest_extension_name(&"a".repeat(64)).len(), 63);
+        for name in ["my-ext", "MyExt", "3d", "pg cron", "é", ""] {
+            assert!(
+                is_unquoted_identifier(&suggest_extension_name(name)),
+                "{name}"
+            );
+        }
+    }
+}
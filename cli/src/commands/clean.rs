@@ -0,0 +1,375 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use bollard::image::{ListImagesOptions, RemoveImageOptions};
+use bollard::Docker;
+use clap::{ArgGroup, Args};
+use tokio_task_manager::Task;
+
+use super::containers::{GENERIC_BUILDER_IMAGE_PREFIX, PGRX_BUILDER_IMAGE_PREFIX};
+use super::output_layout::REGISTRY_INDEX_DIR;
+use super::SubCommand;
+
+#[derive(Args)]
+#[command(group(
+    ArgGroup::new("state")
+        .required(true)
+        .multiple(true)
+        .args(["artifacts", "images", "all"]),
+))]
+pub struct CleanCommand {
+    /// The file path of the extension whose build state should be removed
+    #[arg(short = 'p', long = "path", default_value = ".")]
+    path: String,
+    /// The output directory used when building. Defaults to the .trunk directory under --path
+    #[arg(short = 'o', long = "output-path")]
+    output_path: Option<String>,
+    /// Remove the artifacts in the output directory, then the directory once it's empty
+    #[arg(long = "artifacts")]
+    artifacts: bool,
+    /// Also remove the files trunk doesn't write from an output directory given with
+    /// --output-path, instead of refusing to clean it
+    #[arg(long = "force")]
+    force: bool,
+    /// Remove the builder images created by `trunk build`
+    #[arg(long = "images")]
+    images: bool,
+    /// Remove all of trunk's build state
+    #[arg(long = "all")]
+    all: bool,
+}
+
+#[async_trait]
+impl SubCommand for CleanCommand {
+    async fn execute(&self, _task: Task) -> Result<(), anyhow::Error> {
+        let mut freed = 0;
+
+        if self.artifacts || self.all {
+            freed += match &self.output_path {
+                Some(output_path) => clean_artifacts(Path::new(output_path), false, self.force)?,
+                None => clean_artifacts(&Path::new(&self.path).join(".trunk"), true, self.force)?,
+            };
+        }
+
+        if self.images || self.all {
+            freed += clean_images().await?;
+        }
+
+        println!("Freed {}", format_size(freed));
+
+        Ok(())
+    }
+}
+
+/// The files `trunk clean --artifacts` removes from an output directory, found before anything
+/// is removed
+#[derive(Debug, Default)]
+struct OutputFiles {
+    /// The files trunk wrote, or with `--force` every file
+    files: Vec<PathBuf>,
+    /// The directories, each after those in it, which are removed once they're empty
+    dirs: Vec<PathBuf>,
+    /// The files trunk doesn't write, which are only removed with `--force`
+    foreign: Vec<PathBuf>,
+    size: u64,
+}
+
+/// Suffixes trunk adds after the archive's: signatures, and encryption with its sidecar
+const ARTIFACT_SIDECAR_SUFFIXES: [&str; 5] = [".age.json", ".age", ".sig", ".pem", ".minisig"];
+
+/// Whether `name` is the file name of an archive `trunk build` writes, such as
+/// `pg_cron-1.6.2-pg15.tar.gz`, its `-debug` companion, a signature or its encrypted copy
+fn is_artifact_name(name: &str) -> bool {
+    let mut name = name;
+    while let Some(stripped) = ARTIFACT_SIDECAR_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+    {
+        name = stripped;
+    }
+    // <name>-<version>-pg<pg_version>[-debug]<artifact_suffix>
+    name.match_indices("-pg").any(|(at, _)| {
+        let rest = &name[at + 3..];
+        let digits = rest.chars().take_while(char::is_ascii_digit).count();
+        let rest = &rest[digits..];
+        let suffix = rest.strip_prefix("-debug").unwrap_or(rest);
+        at > 0 && digits > 0 && suffix.len() > 1 && suffix.starts_with('.')
+    })
+}
+
+/// Whether the file at `path` is a `--report` of `trunk build`
+fn is_build_report(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+        && fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice::<serde_json::Value>(&contents).ok())
+            .is_some_and(|report| report.get("trunk_version").is_some())
+}
+
+impl OutputFiles {
+    /// The files under `dir`. `owned` is set for directories only trunk writes to: the default
+    /// output directory, its staging directories and the registry index
+    fn find(
+        &mut self,
+        dir: &Path,
+        relative: &Path,
+        owned: bool,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            let relative = relative.join(entry.file_name());
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Symlinks are removed, not followed
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                let owned = owned
+                    || name.starts_with(".trunk-staging-")
+                    || Path::new(REGISTRY_INDEX_DIR).starts_with(&relative)
+                    || relative.starts_with(REGISTRY_INDEX_DIR);
+                self.find(&path, &relative, owned, force)?;
+                self.dirs.push(path);
+            } else if owned || force || is_artifact_name(&name) || is_build_report(&path) {
+                self.size += metadata.len();
+                self.files.push(path);
+            } else {
+                self.foreign.push(path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes what trunk wrote to the output directory, file by file, then the directories left
+/// empty, returning the number of bytes freed. `default` is set when the output directory is
+/// the default `.trunk` under `--path`, which only trunk writes to. Another output directory
+/// with files trunk doesn't write is refused, unless `force` is set
+fn clean_artifacts(output_path: &Path, default: bool, force: bool) -> Result<u64, anyhow::Error> {
+    if !output_path.exists() {
+        println!("No artifacts to remove at {}", output_path.display());
+        return Ok(0);
+    }
+
+    let output_path = output_path
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", output_path.display()))?;
+    check_not_dangerous(&output_path)?;
+
+    if !output_path.is_dir() {
+        bail!("{} is not a directory", output_path.display());
+    }
+
+    let mut found = OutputFiles::default();
+    found.find(&output_path, Path::new(""), default, force)?;
+    if let Some(foreign) = found.foreign.first() {
+        bail!(
+            "Refusing to clean {}: {} is not a file trunk writes, so this may not be a trunk output directory. \
+             Pass --force to remove everything in it",
+            output_path.display(),
+            foreign.display()
+        );
+    }
+
+    for file in &found.files {
+        fs::remove_file(file).with_context(|| format!("Failed to remove {}", file.display()))?;
+    }
+    for dir in found.dirs.iter().chain([&output_path]) {
+        fs::remove_dir(dir).with_context(|| format!("Failed to remove {}", dir.display()))?;
+    }
+    println!(
+        "Removed artifacts at {} ({})",
+        output_path.display(),
+        format_size(found.size)
+    );
+
+    Ok(found.size)
+}
+
+/// Refuses to remove the filesystem root, the home directory, or the current directory
+/// or any of its parents, which are never trunk's build state, even with `--force`.
+fn check_not_dangerous(path: &Path) -> Result<(), anyhow::Error> {
+    let is_root = path.parent().is_none();
+    let is_home = std::env::var_os("HOME")
+        .and_then(|home| Path::new(&home).canonicalize().ok())
+        .is_some_and(|home| home == path);
+    let contains_cwd = std::env::current_dir()
+        .and_then(|cwd| cwd.canonicalize())
+        .is_ok_and(|cwd| cwd.starts_with(path));
+
+    if is_root || is_home || contains_cwd {
+        bail!(
+            "Refusing to remove {}: it does not look like a trunk output directory",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the builder images tagged by `trunk build`, returning the number of bytes freed
+async fn clean_images() -> Result<u64, anyhow::Error> {
+    let docker = Docker::connect_with_local_defaults()?;
+    let images = docker
+        .list_images(Some(ListImagesOptions::<String> {
+            all: false,
+            ..Default::default()
+        }))
+        .await?;
+
+    let mut freed = 0;
+    for image in images {
+        let Some(tag) = image.repo_tags.iter().find(|tag| is_builder_image(tag)) else {
+            continue;
+        };
+
+        docker
+            .remove_image(
+                &image.id,
+                Some(RemoveImageOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+                None,
+            )
+            .await
+            .with_context(|| format!("Failed to remove image {tag}"))?;
+
+        // Layers shared with other images are only freed once they are no longer used,
+        // so this may overestimate the space reclaimed
+        let size = u64::try_from(image.size).unwrap_or_default();
+        println!("Removed image {tag} ({})", format_size(size));
+        freed += size;
+    }
+
+    Ok(freed)
+}
+
+fn is_builder_image(tag: &str) -> bool {
+    [GENERIC_BUILDER_IMAGE_PREFIX, PGRX_BUILDER_IMAGE_PREFIX]
+        .iter()
+        .any(|prefix| {
+            tag.strip_prefix(prefix)
+                .and_then(|suffix| suffix.split(':').next())
+                .is_some_and(|suffix| {
+                    !suffix.is_empty() && suffix.chars().all(|ch| ch.is_ascii_digit())
+                })
+        })
+}
+
+pub(crate) fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next_unit in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{size:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn matches_only_builder_images() {
+        assert!(is_builder_image("make_builder_123456:latest"));
+        assert!(is_builder_image("pgrx_builder_42:latest"));
+        assert!(!is_builder_image("make_builder_:latest"));
+        assert!(!is_builder_image("pgrx_builder_custom:latest"));
+        assert!(!is_builder_image("postgres:15"));
+    }
+
+    #[test]
+    fn refuses_dangerous_paths() {
+        assert!(check_not_dangerous(Path::new("/")).is_err());
+
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert!(check_not_dangerous(&cwd).is_err());
+        assert!(check_not_dangerous(cwd.parent().unwrap()).is_err());
+    }
+
+    #[test]
+    fn recognizes_artifacts() {
+        for name in [
+            "pg_cron-1.6.2-pg15.tar.gz",
+            "pg_cron-1.6.2-pg15-debug.tar.gz",
+            "pg_cron-1.6.2-pg15.tar.gz.minisig",
+            "pg_cron-1.6.2-pg15.tar.gz.age",
+            "pg_cron-1.6.2-pg15.tar.gz.age.json",
+            "pg_cron-1.6.2-pg15.tar.gz.age.sig",
+            "my-ext-1.0.0-pg16.tgz",
+        ] {
+            assert!(is_artifact_name(name), "{name}");
+        }
+        for name in [
+            "libc.so.6",
+            "README.md",
+            "pg_cron-1.6.2-pg15",
+            "-pg15.tar.gz",
+            "pg_cron-1.6.2-pgx.tar.gz",
+        ] {
+            assert!(!is_artifact_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn cleans_only_trunk_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("out");
+        let nested = output_path.join("pg_cron/1.6.2/linux-amd64");
+        let index = output_path.join(REGISTRY_INDEX_DIR);
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(&index).unwrap();
+        fs::write(nested.join("pg_cron-1.6.2-pg15.tar.gz"), [0; 1024]).unwrap();
+        fs::write(index.join("pg_cron"), "[]").unwrap();
+        fs::write(
+            output_path.join("report.json"),
+            r#"{"trunk_version": "0.1.0"}"#,
+        )
+        .unwrap();
+        fs::write(output_path.join("notes.txt"), "mine").unwrap();
+
+        let err = clean_artifacts(&output_path, false, false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("notes.txt is not a file trunk writes"),
+            "{err}"
+        );
+        assert!(nested.join("pg_cron-1.6.2-pg15.tar.gz").exists());
+
+        fs::remove_file(output_path.join("notes.txt")).unwrap();
+        assert!(clean_artifacts(&output_path, false, false).unwrap() > 1024);
+        assert!(!output_path.exists());
+
+        fs::create_dir_all(&output_path).unwrap();
+        fs::write(output_path.join("notes.txt"), "mine").unwrap();
+        assert!(clean_artifacts(&output_path, false, true).is_ok());
+        assert!(!output_path.exists());
+    }
+}
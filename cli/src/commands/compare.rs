@@ -0,0 +1,297 @@
+//! Compares a freshly built archive with an earlier one, for `trunk build --compare`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::commands::clean::format_size;
+use crate::commands::verify_archive::read_archive;
+use crate::manifest::Manifest;
+
+/// What changed between two archives of an extension
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ArchiveComparison {
+    /// Manifest fields that differ, with their old and new values
+    pub manifest_changes: Vec<(String, String, String)>,
+    /// Files only in the new archive, with their size
+    pub added: Vec<(PathBuf, u64)>,
+    /// Files only in the old archive, with their size
+    pub removed: Vec<(PathBuf, u64)>,
+    /// Files whose contents changed, with their old and new size
+    pub changed: Vec<(PathBuf, u64, u64)>,
+    pub unchanged: usize,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+/// Compares the archive at `new` with the one at `old`
+pub fn compare_archives(old: &Path, new: &Path) -> anyhow::Result<ArchiveComparison> {
+    let (old_manifest, old_files) = read_archive(old)?;
+    let (new_manifest, new_files) = read_archive(new)?;
+
+    let mut comparison = ArchiveComparison {
+        manifest_changes: manifest_changes(&old_manifest, &new_manifest),
+        old_size: fs::metadata(old)?.len(),
+        new_size: fs::metadata(new)?.len(),
+        ..Default::default()
+    };
+    for (path, new_file) in &new_files {
+        match old_files.get(path) {
+            None => comparison.added.push((path.clone(), new_file.size)),
+            Some(old_file) if old_file.digest != new_file.digest => {
+                comparison
+                    .changed
+                    .push((path.clone(), old_file.size, new_file.size))
+            }
+            Some(_) => comparison.unchanged += 1,
+        }
+    }
+    comparison.removed = old_files
+        .iter()
+        .filter(|(path, _)| !new_files.contains_key(*path))
+        .map(|(path, file)| (path.clone(), file.size))
+        .collect();
+
+    Ok(comparison)
+}
+
+fn manifest_changes(old: &Manifest, new: &Manifest) -> Vec<(String, String, String)> {
+    fn json<T: Serialize>(value: &T) -> String {
+        serde_json::to_string(value).unwrap_or_default()
+    }
+
+    let mut changes: Vec<(String, String, String)> = [
+        (
+            "version",
+            json(&old.extension_version),
+            json(&new.extension_version),
+        ),
+        ("pg_version", json(&old.pg_version), json(&new.pg_version)),
+        (
+            "manifest_version",
+            json(&old.manifest_version),
+            json(&new.manifest_version),
+        ),
+        (
+            "architecture",
+            json(&old.architecture),
+            json(&new.architecture),
+        ),
+        (
+            "extension_dependencies",
+            json(&old.extension_dependencies),
+            json(&new.extension_dependencies),
+        ),
+        (
+            "dependencies",
+            json(&old.dependencies.as_ref().map(sorted)),
+            json(&new.dependencies.as_ref().map(sorted)),
+        ),
+        (
+            "upgrade_paths",
+            json(&old.upgrade_paths),
+            json(&new.upgrade_paths),
+        ),
+        ("labels", json(&old.labels), json(&new.labels)),
+        ("categories", json(&old.categories), json(&new.categories)),
+        (
+            "elf_architecture",
+            json(&old.elf_architecture),
+            json(&new.elf_architecture),
+        ),
+        ("cflags", json(&old.cflags), json(&new.cflags)),
+        ("cxxflags", json(&old.cxxflags), json(&new.cxxflags)),
+        (
+            "container_runtime",
+            json(&old.container_runtime),
+            json(&new.container_runtime),
+        ),
+        ("base_image", json(&old.base_image), json(&new.base_image)),
+        ("stripped", json(&old.stripped), json(&new.stripped)),
+    ]
+    .into_iter()
+    .filter(|(_, old, new)| old != new)
+    .map(|(field, old, new)| (field.to_string(), old, new))
+    .collect();
+
+    // A dependency bump most often shows up as a new compiler or pg_config
+    let no_toolchain = BTreeMap::new();
+    let old_toolchain = old.toolchain.as_ref().unwrap_or(&no_toolchain);
+    let new_toolchain = new.toolchain.as_ref().unwrap_or(&no_toolchain);
+    let tools: BTreeSet<&String> = old_toolchain.keys().chain(new_toolchain.keys()).collect();
+    for tool in tools {
+        let (old, new) = (old_toolchain.get(tool), new_toolchain.get(tool));
+        if old != new {
+            changes.push((format!("toolchain {tool}"), json(&old), json(&new)));
+        }
+    }
+
+    changes
+}
+
+fn sorted<V: Clone>(map: &HashMap<String, V>) -> BTreeMap<String, V> {
+    map.iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// The difference between two sizes, e.g. `+512 B` or `-1.5 KiB`
+fn size_delta(old: u64, new: u64) -> String {
+    if new >= old {
+        format!("+{}", format_size(new - old))
+    } else {
+        format!("-{}", format_size(old - new))
+    }
+}
+
+impl ArchiveComparison {
+    fn any_changed(&self, extension: &str) -> bool {
+        let has_extension = |path: &PathBuf| path.extension().is_some_and(|ext| ext == extension);
+
+        self.added.iter().any(|(path, _)| has_extension(path))
+            || self.removed.iter().any(|(path, _)| has_extension(path))
+            || self.changed.iter().any(|(path, _, _)| has_extension(path))
+    }
+
+    /// The summary printed after the build
+    pub fn summary(&self, old: &Path) -> String {
+        let mut summary = format!("Compared with {}:\n", old.display());
+        for (field, old, new) in &self.manifest_changes {
+            let _ = writeln!(summary, "  {field}: {old} -> {new}");
+        }
+        for (path, size) in &self.added {
+            let _ = writeln!(summary, "  + {} ({})", path.display(), format_size(*size));
+        }
+        for (path, size) in &self.removed {
+            let _ = writeln!(summary, "  - {} ({})", path.display(), format_size(*size));
+        }
+        for (path, old_size, new_size) in &self.changed {
+            let _ = if old_size == new_size {
+                writeln!(
+                    summary,
+                    "  ~ {}: {}, same size but different contents",
+                    path.display(),
+                    format_size(*new_size)
+                )
+            } else {
+                writeln!(
+                    summary,
+                    "  ~ {}: {} -> {} ({})",
+                    path.display(),
+                    format_size(*old_size),
+                    format_size(*new_size),
+                    size_delta(*old_size, *new_size)
+                )
+            };
+        }
+        let _ = writeln!(summary, "  {} files unchanged", self.unchanged);
+
+        let changed = |extension| {
+            if self.any_changed(extension) {
+                "changed"
+            } else {
+                "unchanged"
+            }
+        };
+        let _ = writeln!(summary, "  Shared library: {}", changed("so"));
+        let _ = writeln!(summary, "  SQL scripts: {}", changed("sql"));
+        let _ = writeln!(
+            summary,
+            "  Archive: {} -> {} ({})",
+            format_size(self.old_size),
+            format_size(self.new_size),
+            size_delta(self.old_size, self.new_size)
+        );
+
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn write_archive(path: &Path, manifest: &Manifest, files: &[(&str, &str)]) {
+        let encoder =
+            flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::fast());
+        let mut archive = tar::Builder::new(encoder);
+        let manifest = serde_json::to_string(manifest).unwrap();
+        for (path, contents) in files.iter().copied().chain([("manifest.json", &*manifest)]) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn compares_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("ext-1.0.0-pg15.tar.gz");
+        let new = dir.path().join("ext-1.1.0-pg15.tar.gz");
+
+        let mut manifest = Manifest {
+            extension_version: "1.0.0".to_string(),
+            toolchain: Some(BTreeMap::from([(
+                "gcc".to_string(),
+                "gcc 12.2.0".to_string(),
+            )])),
+            ..Default::default()
+        };
+        write_archive(
+            &old,
+            &manifest,
+            &[
+                ("ext.control", "default_version = '1.0.0'\n"),
+                ("extension/ext--1.0.0.sql", "CREATE FUNCTION f();"),
+                ("ext.so", "elf"),
+            ],
+        );
+        manifest.extension_version = "1.1.0".to_string();
+        write_archive(
+            &new,
+            &manifest,
+            &[
+                ("ext.control", "default_version = '1.0.0'\n"),
+                ("extension/ext--1.0.0--1.1.0.sql", "CREATE FUNCTION g();"),
+                ("ext.so", "elf, but bigger"),
+            ],
+        );
+
+        let comparison = compare_archives(&old, &new).unwrap();
+        assert_eq!(
+            comparison.manifest_changes,
+            [(
+                "version".to_string(),
+                "\"1.0.0\"".to_string(),
+                "\"1.1.0\"".to_string()
+            )]
+        );
+        assert_eq!(
+            comparison.added,
+            [(PathBuf::from("extension/ext--1.0.0--1.1.0.sql"), 20)]
+        );
+        assert_eq!(
+            comparison.removed,
+            [(PathBuf::from("extension/ext--1.0.0.sql"), 20)]
+        );
+        assert_eq!(comparison.changed, [(PathBuf::from("ext.so"), 3, 15)]);
+        assert_eq!(comparison.unchanged, 1);
+
+        let summary = comparison.summary(&old);
+        assert!(
+            summary.contains("  ~ ext.so: 3 B -> 15 B (+12 B)\n"),
+            "{summary}"
+        );
+        assert!(summary.contains("  Shared library: changed\n"), "{summary}");
+        assert!(summary.contains("  SQL scripts: changed\n"), "{summary}");
+    }
+}
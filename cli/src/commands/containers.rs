@@ -1,28 +1,48 @@
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
 use bollard::container::{
     CreateContainerOptions, DownloadFromContainerOptions, StartContainerOptions,
 };
 use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
 use bollard::service::ExecInspectResponse;
 use bollard::Docker;
+use elf::endian::AnyEndian;
+use elf::ElfBytes;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::process::Command;
+use std::sync::OnceLock;
 
 use bollard::container::Config;
-use bollard::image::BuildImageOptions;
-use bollard::models::{BuildInfo, HostConfig};
-use std::fs::File;
+use bollard::image::{BuildImageOptions, BuilderVersion};
+use bollard::moby::buildkit::v1::StatusResponse;
+use bollard::models::{BuildInfo, BuildInfoAux, HostConfig, SystemInfo};
+use std::fs::{self, File};
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
-
-use crate::commands::generic_build::GenericBuildError;
-use crate::config::{ExtensionConfiguration, LoadableLibrary};
+use std::time::{Duration, Instant};
+
+use crate::build_log::{tee_eprintln, tee_print, tee_println};
+use crate::changelog::Changelog;
+use crate::commands::build::BuildOutput;
+use crate::commands::context_filter::ContextFilter;
+use crate::commands::generic_build::{CompilerFlags, GenericBuildError};
+use crate::commands::registry_auth::RegistryAuth;
+use crate::commands::runtime_trace;
+use crate::config::{
+    add_preloaded_libraries, ControlFields, ExtensionConfiguration, LoadableLibrary, Preload,
+};
 use crate::control_file::ControlFile;
-use crate::manifest::Manifest;
+use crate::hooks::transform_manifest;
+use crate::manifest::{CaptureCategory, ExtensionKind, Manifest, SupportedPgVersions};
+use crate::sql_scripts::SqlScripts;
 use crate::sync_utils::{ByteStreamSyncReceiver, ByteStreamSyncSender};
+use crate::timings::{BuildTimings, TimedWriter};
 use crate::trunk_toml::SystemDependencies;
+use crate::warnings::{check_warning, FatalWarningError, WarningCategory};
 use futures_util::stream::StreamExt;
 use hyper::Body;
+use log::info;
 use rand::Rng;
 use tar::{Archive, Builder, EntryType, Header};
 use tee_readwrite::TeeReader;
@@ -56,6 +76,7 @@ impl Drop for ReclaimableContainer {
         let handle = tokio::runtime::Handle::current();
         let mut task = self.task.clone();
         handle.spawn(async move {
+            trace_call(&docker, &["stop", &id]).await;
             docker
                 .stop_container(id.clone().as_str(), None)
                 .await
@@ -64,6 +85,230 @@ impl Drop for ReclaimableContainer {
         });
     }
 }
+
+/// Removes a partially written artifact when dropped, unless it was marked as complete.
+/// This covers both build errors and cancellation (Ctrl-C), where the packaging future is dropped.
+struct PartialArtifact {
+    path: PathBuf,
+    complete: bool,
+}
+
+impl PartialArtifact {
+    fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            complete: false,
+        }
+    }
+
+    fn complete(mut self) {
+        self.complete = true;
+    }
+}
+
+impl Drop for PartialArtifact {
+    fn drop(&mut self) {
+        if !self.complete && std::fs::remove_file(&self.path).is_ok() {
+            tee_eprintln!("Removed incomplete artifact {}", self.path.display());
+        }
+    }
+}
+
+/// Docker ran out of disk space while building the image or running a command in the container
+#[derive(thiserror::Error, Debug)]
+#[error("Docker ran out of disk space: {message}\nFree up space with `docker system prune`, and check how much storage is available to the Docker daemon with `docker system df` and `docker info`")]
+pub struct OutOfDiskSpaceError {
+    pub message: String,
+}
+
+impl OutOfDiskSpaceError {
+    /// Looks for an out-of-space failure in output from Docker or from a command run in a container
+    pub fn detect(output: &str) -> Option<Self> {
+        output
+            .lines()
+            .find(|line| {
+                let line = line.to_lowercase();
+                line.contains("no space left on device") || line.contains("enospc")
+            })
+            .map(|line| OutOfDiskSpaceError {
+                message: line.trim().to_string(),
+            })
+    }
+}
+
+/// A build step was killed, most likely by the kernel's out-of-memory killer
+#[derive(thiserror::Error, Debug)]
+#[error("{message}\nThe build was killed, most likely because it ran out of memory. {hint}")]
+pub struct OutOfMemoryError {
+    pub message: String,
+    hint: &'static str,
+}
+
+impl OutOfMemoryError {
+    /// Processes killed by the out-of-memory killer exit with 128 + SIGKILL
+    pub const EXIT_CODE: i64 = 137;
+
+    pub fn new(message: impl Into<String>, memory_limit: Option<u64>) -> Self {
+        let hint = match memory_limit {
+            Some(_) => "Try raising --memory",
+            None => "Try giving Docker more memory, or lower the build's parallelism",
+        };
+
+        Self {
+            message: message.into(),
+            hint,
+        }
+    }
+
+    /// Looks for a killed build step in the output of an image build
+    pub fn detect(output: &str, memory_limit: Option<u64>) -> Option<Self> {
+        const KILLED: [&str; 3] = ["non-zero code: 137", "exit code: 137", "out of memory"];
+
+        output
+            .lines()
+            .find(|line| {
+                let line = line.to_lowercase();
+                KILLED.iter().any(|killed| line.contains(killed))
+            })
+            .map(|line| Self::new(line.trim(), memory_limit))
+    }
+}
+
+/// The install command succeeded, but installed nothing that could be packaged
+#[derive(thiserror::Error, Debug)]
+#[error("The install command succeeded, but produced no extension files in {sharedir} or {pkglibdir}. Check that it installs under the prefix of pg_config, or point --lib-dir, --sql-dir, --control-dir or --prefix at where it installs the files. Pass --allow-empty to package the extension anyway")]
+pub struct NoExtensionFilesError {
+    pub sharedir: String,
+    pub pkglibdir: String,
+}
+
+impl NoExtensionFilesError {
+    /// Exit code of `trunk build`, so that scripts can tell an empty build apart from other failures
+    pub const EXIT_CODE: u8 = 3;
+
+    /// Whether `err` was caused by a build that produced no extension files
+    pub fn is_cause_of(err: &anyhow::Error) -> bool {
+        err.chain().any(|cause| cause.is::<Self>())
+    }
+}
+
+/// `kind = "module"` is set in Trunk.toml, but the install command installed no shared library
+#[derive(thiserror::Error, Debug)]
+#[error("kind = \"module\" is set in Trunk.toml, but the install command installed no shared library into {pkglibdir}. Check that it installs the module's .so file under the prefix of pg_config, or point --lib-dir at where it installs it")]
+pub struct NoModuleLibraryError {
+    pub pkglibdir: String,
+}
+
+/// rustup could not install the toolchain requested with `--rust-toolchain` in the pgrx builder
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to install the Rust toolchain {toolchain} in the builder image. Check that it is a channel rustup knows, such as stable, 1.79.0 or nightly-2024-06-01, and that the build has network access")]
+pub struct RustToolchainInstallError {
+    pub toolchain: String,
+}
+
+impl RustToolchainInstallError {
+    /// Looks for the message the bundled pgrx Dockerfile prints when the install fails
+    pub fn detect(output: &str) -> Option<Self> {
+        output.lines().find_map(|line| {
+            let toolchain = line
+                .trim()
+                .strip_prefix("Failed to install the Rust toolchain ")?;
+            Some(Self {
+                toolchain: toolchain.trim().to_string(),
+            })
+        })
+    }
+}
+
+/// Parses a memory size as accepted by Docker, e.g. `512m` or `2g`, into bytes
+pub fn parse_memory(memory: &str) -> Result<u64, anyhow::Error> {
+    let lowercase = memory.trim().to_lowercase();
+    let digits = lowercase.trim_end_matches('b');
+    let (number, multiplier) = match digits.chars().last() {
+        Some('k') => (&digits[..digits.len() - 1], 1 << 10),
+        Some('m') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('g') => (&digits[..digits.len() - 1], 1 << 30),
+        _ => (digits, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|number| *number > 0)
+        .and_then(|number| number.checked_mul(multiplier))
+        .with_context(|| {
+            format!("Invalid memory limit '{memory}', expected a size such as 512m or 2g")
+        })
+}
+
+/// Parses a step timeout such as `90s`, `10m` or `1h 30m`
+pub fn parse_step_timeout(timeout: &str) -> Result<Duration, anyhow::Error> {
+    humantime::parse_duration(timeout.trim())
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .with_context(|| {
+            format!("Invalid step timeout '{timeout}', expected a duration such as 90s or 10m")
+        })
+}
+
+/// A build step ran for longer than `--step-timeout`
+#[derive(thiserror::Error, Debug)]
+#[error("The build step '{step}' did not finish within --step-timeout ({}), it most likely hung", humantime::format_duration(*.timeout))]
+pub struct StepTimeoutError {
+    pub step: String,
+    pub timeout: Duration,
+}
+
+/// A shared library in the archive was built for another architecture than `--platform`
+#[derive(thiserror::Error, Debug)]
+#[error("{} was built for {found}, but --platform requested {requested}\nThis usually means the build cross-compiled for the wrong target, pass --allow-arch-mismatch to package it anyway", .library.display())]
+pub struct ArchMismatchError {
+    pub library: PathBuf,
+    pub found: String,
+    pub requested: String,
+}
+
+/// The extension's control file has another `default_version` than the version being packaged
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("The control file {extension}.control has default_version '{default_version}', but the extension is being packaged as version '{version}'\nSet --version or version in Trunk.toml to match it, or pass --no-version-check to package it anyway")]
+pub struct VersionMismatchError {
+    pub extension: String,
+    pub default_version: String,
+    pub version: String,
+}
+
+/// A build step failed to reach the network while building with `--offline`
+#[derive(thiserror::Error, Debug)]
+#[error("build requires network but --offline was set: {message}")]
+pub struct OfflineNetworkError {
+    pub message: String,
+}
+
+impl OfflineNetworkError {
+    /// Looks for a failed network access in output from Docker or from a command run in a container
+    pub fn detect(output: &str) -> Option<Self> {
+        const NETWORK_FAILURES: [&str; 5] = [
+            "temporary failure in name resolution",
+            "could not resolve host",
+            "network is unreachable",
+            "failed to lookup address information",
+            "name or service not known",
+        ];
+
+        output
+            .lines()
+            .find(|line| {
+                let line = line.to_lowercase();
+                NETWORK_FAILURES
+                    .iter()
+                    .any(|failure| line.contains(failure))
+            })
+            .map(|line| OfflineNetworkError {
+                message: line.trim().to_string(),
+            })
+    }
+}
+
 pub async fn exec_in_container(
     docker: &Docker,
     container_id: &str,
@@ -83,12 +328,44 @@ pub async fn exec_in_container_with_exit_code(
     dir: Option<&str>,
     env: Option<Vec<&str>>,
 ) -> Result<(String, Option<i64>), anyhow::Error> {
-    println!("Executing in container: {:?}", command.join(" "));
+    exec_in_container_as(docker, container_id, command, dir, env, None).await
+}
+
+/// Like [`exec_in_container_with_exit_code`], running the command as `user` instead of root
+pub async fn exec_in_container_as(
+    docker: &Docker,
+    container_id: &str,
+    command: Vec<&str>,
+    dir: Option<&str>,
+    env: Option<Vec<&str>>,
+    user: Option<&str>,
+) -> Result<(String, Option<i64>), anyhow::Error> {
+    match user {
+        Some(user) => tee_println!("Executing in container as {user}: {:?}", command.join(" ")),
+        None => tee_println!("Executing in container: {:?}", command.join(" ")),
+    }
+
+    if runtime_trace::enabled() {
+        let mut args = vec!["exec".to_string()];
+        if let Some(user) = user {
+            args.extend(["--user".to_string(), user.to_string()]);
+        }
+        if let Some(dir) = dir {
+            args.extend(["--workdir".to_string(), dir.to_string()]);
+        }
+        for assignment in env.iter().flatten() {
+            args.extend(["--env".to_string(), runtime_trace::assignment(assignment)]);
+        }
+        args.push(container_id.to_string());
+        args.extend(command.iter().map(|arg| arg.to_string()));
+        trace_call(docker, &args).await;
+    }
 
     let config = CreateExecOptions {
         cmd: Some(command),
         env,
         working_dir: dir,
+        user,
         attach_stdout: Some(true),
         attach_stderr: Some(true),
         ..Default::default()
@@ -106,38 +383,51 @@ pub async fn exec_in_container_with_exit_code(
             let mut output = output
                 .map(|result| match result {
                     Ok(log_output) => {
-                        println!("{log_output}");
+                        tee_println!("{log_output}");
                         total_output.push_str(log_output.to_string().as_str());
                     }
-                    Err(error) => eprintln!("Error while reading log output: {error}"),
+                    Err(error) => tee_eprintln!("Error while reading log output: {error}"),
                 })
                 .fuse();
             // Run the output stream to completion.
             while output.next().await.is_some() {}
         }
         StartExecResults::Detached => {
-            println!("Exec started in detached mode");
+            tee_println!("Exec started in detached mode");
         }
     }
 
     let ExecInspectResponse { exit_code, .. } = docker.inspect_exec(&exec.id).await?;
 
+    if !matches!(exit_code, Some(0)) {
+        if let Some(err) = OutOfDiskSpaceError::detect(&total_output) {
+            return Err(err.into());
+        }
+    }
+
     Ok((total_output, exit_code))
 }
 
+/// Starts a container named `name` of `image`, which sleeps so that commands can be run in it.
+/// It is stopped, and removed, when dropped
 pub async fn run_temporary_container(
     docker: Docker,
     platform: Option<String>,
+    name: &str,
     image: &str,
+    image_build_options: &ImageBuildOptions,
     _task: Task,
 ) -> Result<ReclaimableContainer, anyhow::Error> {
     let options = Some(CreateContainerOptions {
-        name: image.to_string(),
+        name: name.to_string(),
         platform,
     });
 
     let host_config = HostConfig {
         auto_remove: Some(true),
+        network_mode: image_build_options.offline.then(|| "none".to_string()),
+        nano_cpus: image_build_options.cpus.map(|cpus| (cpus * 1e9) as i64),
+        memory: image_build_options.memory.map(|memory| memory as i64),
         ..Default::default()
     };
 
@@ -150,6 +440,28 @@ pub async fn run_temporary_container(
         ..Default::default()
     };
 
+    if runtime_trace::enabled() {
+        let mut args = vec!["run", "--detach", "--rm", "--name", name];
+        if let Some(platform) = options
+            .as_ref()
+            .and_then(|options| options.platform.as_deref())
+        {
+            args.extend(["--platform", platform]);
+        }
+        if image_build_options.offline {
+            args.extend(["--network", "none"]);
+        }
+        let cpus = image_build_options.cpus.map(|cpus| cpus.to_string());
+        if let Some(cpus) = &cpus {
+            args.extend(["--cpus", cpus]);
+        }
+        let memory = image_build_options.memory.map(|memory| memory.to_string());
+        if let Some(memory) = &memory {
+            args.extend(["--memory", memory]);
+        }
+        args.extend(["--user", "root", "--entrypoint", "sleep", image, "300"]);
+        trace_call(&docker, &args).await;
+    }
     let container = docker.create_container(options, config).await?;
     docker
         .start_container(&container.id, None::<StartContainerOptions<String>>)
@@ -163,6 +475,95 @@ pub async fn run_temporary_container(
     ))
 }
 
+/// What a `--no-install` build does once the builder image is built
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoInstall {
+    /// Print how to start a shell in a container of the image
+    PrintCommand,
+    /// Open an interactive shell in a container of the image, with `--shell-in`
+    Shell,
+}
+
+/// The arguments of `docker run` that start a container of the builder image like the build's
+/// own containers, followed by the command to run in it
+fn debug_container_args<'a>(
+    image_name: &'a str,
+    platform: Option<&'a str>,
+    offline: bool,
+    command: &[&'a str],
+) -> Vec<&'a str> {
+    let mut args = vec!["run", "--rm", "-it", "--user", "root"];
+    if let Some(platform) = platform {
+        args.extend(["--platform", platform]);
+    }
+    if offline {
+        args.extend(["--network", "none"]);
+    }
+    args.push(image_name);
+    args.extend(command);
+
+    args
+}
+
+/// Ends a `--no-install` build, which keeps the builder image for debugging instead of
+/// installing and packaging the extension
+pub async fn stop_before_install(
+    docker: &Docker,
+    image_name: &str,
+    platform: Option<&str>,
+    image_build_options: &ImageBuildOptions,
+    no_install: NoInstall,
+) -> anyhow::Result<()> {
+    let cli = runtime_cli(docker).await;
+    let offline = image_build_options.offline;
+    tee_println!(
+        "Built the image {image_name} without running the install command. No archive was written"
+    );
+
+    match no_install {
+        NoInstall::PrintCommand => {
+            let args = debug_container_args(image_name, platform, offline, &["sh"]);
+            tee_println!("Start a shell in it with: {cli} {}", args.join(" "));
+        }
+        NoInstall::Shell => {
+            let program = which::which(cli)
+                .map_err(|_| anyhow!("--shell-in runs the {cli} CLI, but it is not on the PATH"))?;
+            let args: Vec<String> = debug_container_args(
+                image_name,
+                platform,
+                offline,
+                &[
+                    "sh",
+                    "-c",
+                    "command -v bash >/dev/null && exec bash || exec sh",
+                ],
+            )
+            .into_iter()
+            .map(ToOwned::to_owned)
+            .collect();
+            tee_println!("Opening a shell in {image_name}, exit it to finish");
+            runtime_trace::trace(cli, &args);
+            // The shell's exit status is that of the last command typed into it, not a failure
+            task::spawn_blocking(move || Command::new(program).args(args).status()).await??;
+        }
+    }
+    tee_println!("Remove the image once done with: {cli} rmi {image_name}");
+
+    Ok(())
+}
+
+/// Resolve a path relative to the container's working directory into an absolute path
+pub async fn container_path(
+    docker: &Docker,
+    container_id: &str,
+    relative: &str,
+) -> anyhow::Result<String> {
+    let workdir = exec_in_container(docker, container_id, vec!["pwd"], None, None).await?;
+    let workdir = workdir.trim().trim_end_matches('/');
+
+    Ok(format!("{workdir}/{relative}"))
+}
+
 pub struct ExtensionFiles {
     /// Files stored in `pg_config --sharedir`
     sharedir: Vec<String>,
@@ -170,6 +571,14 @@ pub struct ExtensionFiles {
     pkglibdir: Vec<String>,
     /// The parsed contents of the extension's control file, if it exists
     control_file: Option<ControlFile>,
+    /// The `default_version` of each control file, keyed by extension name
+    default_versions: BTreeMap<String, String>,
+    /// `pg_config --prefix`, which `capture_globs` are relative to
+    prefix: String,
+    /// Files only packaged because they matched `capture_globs`, by their path in the container
+    captured: BTreeMap<String, CaptureCategory>,
+    /// The `capture_globs` that matched no installed file
+    unmatched_capture_globs: Vec<String>,
 }
 
 /// Read the contents of a file in the given container
@@ -187,8 +596,12 @@ pub async fn find_installed_extension_files(
     docker: &Docker,
     container_id: &str,
     inclusion_patterns: &[glob::Pattern],
+    capture_globs: &[glob::Pattern],
 ) -> Result<ExtensionFiles, anyhow::Error> {
     let mut control_file = None;
+    let mut default_versions = BTreeMap::new();
+    let mut captured = BTreeMap::new();
+    let mut matched_capture_globs = vec![false; capture_globs.len()];
     let sharedir = exec_in_container(
         docker,
         container_id,
@@ -209,11 +622,22 @@ pub async fn find_installed_extension_files(
     .await?;
     let pkglibdir = pkglibdir.trim();
 
+    let prefix = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--prefix"],
+        None,
+        None,
+    )
+    .await?;
+    let prefix = prefix.trim();
+
     // collect changes from container filesystem
+    // Docker reports no changes at all as `None`
     let changes = docker
         .container_changes(container_id)
         .await?
-        .expect("Expected to find changed files");
+        .unwrap_or_default();
 
     let mut pkglibdir_list = vec![];
     let mut sharedir_list = vec![];
@@ -226,13 +650,39 @@ pub async fn find_installed_extension_files(
         let is_extra = inclusion_patterns
             .iter()
             .any(|pattern| pattern.matches(&file_added));
-
-        if file_added.ends_with(".so")
+        let is_packaged = file_added.ends_with(".so")
             || file_added.ends_with(".bc")
             || file_added.ends_with(".sql")
             || file_added.ends_with(".control")
-            || is_extra
-        {
+            || is_extra;
+        let capture_glob = matching_capture_glob(capture_globs, prefix, &file_added);
+        if let Some(index) = capture_glob {
+            matched_capture_globs[index] = true;
+        }
+        // Files of sharedir and pkglibdir that are packaged anyway aren't recorded as captured
+        let category = capture_glob.and_then(|_| {
+            if file_added.starts_with(pkglibdir) {
+                (!is_packaged).then_some(CaptureCategory::Pkglibdir)
+            } else if file_added.starts_with(sharedir) {
+                (!is_packaged).then_some(CaptureCategory::Sharedir)
+            } else {
+                Some(CaptureCategory::Prefix)
+            }
+        });
+
+        if category == Some(CaptureCategory::Prefix) {
+            // Only /usr is copied out of the container
+            if file_added.starts_with("/usr/") {
+                captured.insert(file_added.clone(), CaptureCategory::Prefix);
+            } else {
+                tee_println!(
+                    "WARNING: file {file_added} matches capture_globs, but is not under /usr, skipping it"
+                );
+            }
+        } else if is_packaged || category.is_some() {
+            if let Some(category) = category {
+                captured.insert(file_added.clone(), category);
+            }
             if file_added.starts_with(pkglibdir) {
                 let file_in_pkglibdir = &file_added;
                 let file_in_pkglibdir = file_in_pkglibdir.strip_prefix(pkglibdir);
@@ -246,7 +696,7 @@ pub async fn find_installed_extension_files(
                 let file_in_sharedir = file_in_sharedir.trim_start_matches('/');
                 sharedir_list.push(file_in_sharedir.to_owned());
             } else {
-                println!(
+                tee_println!(
                     "WARNING: file {} is not in pkglibdir or sharedir",
                     file_added
                 );
@@ -257,27 +707,117 @@ pub async fn find_installed_extension_files(
         if file_added.ends_with(".control") {
             let contents = read_from_container(docker, container_id, &file_added).await?;
             let parsed = ControlFile::parse(&contents);
+            let extension = Path::new(&file_added).file_stem();
+            if let (Some(extension), Some(default_version)) = (extension, &parsed.default_version) {
+                default_versions.insert(
+                    extension.to_string_lossy().into_owned(),
+                    default_version.clone(),
+                );
+            }
 
             control_file = Some(parsed);
         }
     }
 
-    println!("Sharedir files:");
+    tee_println!("Sharedir files:");
     for sharedir_file in &sharedir_list {
-        println!("\t{sharedir_file}");
+        tee_println!("\t{sharedir_file}");
     }
-    println!("Pkglibdir files:");
+    tee_println!("Pkglibdir files:");
     for pkglibdir_file in &pkglibdir_list {
-        println!("\t{pkglibdir_file}");
+        tee_println!("\t{pkglibdir_file}");
     }
+    if !captured.is_empty() {
+        tee_println!("Captured files:");
+        for (captured_file, category) in &captured {
+            tee_println!("\t{captured_file} ({})", category.as_str());
+        }
+    }
+    let unmatched_capture_globs = capture_globs
+        .iter()
+        .zip(matched_capture_globs)
+        .filter(|(_, matched)| !matched)
+        .map(|(pattern, _)| pattern.as_str().to_string())
+        .collect();
 
     Ok(ExtensionFiles {
         sharedir: sharedir_list,
         pkglibdir: pkglibdir_list,
         control_file,
+        default_versions,
+        prefix: prefix.to_string(),
+        captured,
+        unmatched_capture_globs,
     })
 }
 
+/// The index of the first of `capture_globs` that matches `path`, relative to `prefix`. `*`
+/// doesn't match `/`, so that a glob only matches deeper directories through `**`
+fn matching_capture_glob(
+    capture_globs: &[glob::Pattern],
+    prefix: &str,
+    path: &str,
+) -> Option<usize> {
+    let relative = Path::new(path).strip_prefix(prefix).ok()?;
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    capture_globs
+        .iter()
+        .position(|pattern| pattern.matches_path_with(relative, options))
+}
+
+/// Tools whose versions are recorded for generic builds, by name and version command
+pub const GENERIC_TOOLCHAIN: &[(&str, &str)] = &[
+    ("gcc", "gcc --version"),
+    ("clang", "clang --version"),
+    ("make", "make --version"),
+    ("pg_config", "pg_config --version"),
+];
+
+/// Tools whose versions are recorded for pgrx builds, by name and version command
+pub const PGRX_TOOLCHAIN: &[(&str, &str)] = &[
+    ("rustc", "rustc --version"),
+    ("cargo", "cargo --version"),
+    ("cargo-pgrx", "cargo pgrx --version"),
+    ("gcc", "gcc --version"),
+    ("pg_config", "pg_config --version"),
+];
+
+/// The first line each of `tools` prints when asked for its version in the container.
+/// Tools that are missing or fail are left out rather than failing the build.
+pub async fn toolchain_versions(
+    docker: &Docker,
+    container_id: &str,
+    tools: &[(&str, &str)],
+) -> BTreeMap<String, String> {
+    let script: String = tools
+        .iter()
+        .map(|(name, command)| {
+            format!("v=$({command} 2>/dev/null | head -n 1); [ -n \"$v\" ] && echo \"{name}=$v\"; ")
+        })
+        .collect();
+
+    match exec_in_container(docker, container_id, vec!["sh", "-c", &script], None, None).await {
+        Ok(output) => parse_toolchain_versions(&output),
+        Err(err) => {
+            tee_println!("WARNING: Could not determine the toolchain versions: {err}");
+            BTreeMap::new()
+        }
+    }
+}
+
+fn parse_toolchain_versions(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, version)| (name.trim().to_string(), version.trim().to_string()))
+        .filter(|(name, version)| !name.is_empty() && !version.is_empty())
+        .collect()
+}
+
 pub async fn find_license_files(
     docker: &Docker,
     container_id: &str,
@@ -302,19 +842,659 @@ pub async fn find_license_files(
         }
     }
 
-    println!("License files:");
+    tee_println!("License files:");
     for license_file in licensedir_list.clone() {
-        println!("\t{license_file}");
+        tee_println!("\t{license_file}");
     }
-    println!();
+    tee_println!();
 
     Ok(licensedir_list)
 }
 
+/// Builder images are tagged with one of these prefixes followed by a random number
+pub const GENERIC_BUILDER_IMAGE_PREFIX: &str = "make_builder_";
+pub const PGRX_BUILDER_IMAGE_PREFIX: &str = "pgrx_builder_";
+
+/// When base images are pulled while building the builder image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PullPolicy {
+    /// Always pull base images, picking up updates to their tags
+    Always,
+    /// Pull base images only if they are not present locally
+    #[default]
+    Missing,
+    /// Never pull base images, failing if one is not present locally
+    Never,
+}
+
+/// Options controlling how the builder image is built
+#[derive(Clone, Debug, Default)]
+pub struct ImageBuildOptions {
+    /// Build with BuildKit rather than the classic builder
+    pub buildkit: bool,
+    pub pull: PullPolicy,
+    /// Run the Dockerfile's build steps without network access
+    pub offline: bool,
+    /// Image to build on instead of the builder's default, passed as the `BASE_IMAGE` build argument
+    pub base_image: Option<String>,
+    /// Number of CPUs the build can use, e.g. `1.5`
+    pub cpus: Option<f64>,
+    /// Memory limit of the build, in bytes
+    pub memory: Option<u64>,
+    /// Credentials for pulling base images, instead of those of `docker login`
+    pub registry_auth: Option<RegistryAuth>,
+    /// Build without checking that the base images provide the requested platform
+    pub skip_platform_check: bool,
+    /// How long a single build step may run before the build is given up as hung
+    pub step_timeout: Option<Duration>,
+    /// The files of the build context that are sent to the container runtime
+    pub context_filter: ContextFilter,
+}
+
+/// The arguments of the runtime's CLI that build the image `options` describe from the
+/// directory `context`. The Dockerfile is sent along with the context, so it's read from stdin
+fn build_args_line(options: &BuildImageOptions<&str>, context: &Path) -> Vec<String> {
+    let mut args = vec!["build".to_string(), "--file".to_string(), "-".to_string()];
+    let mut flag = |flag: &str, value: String| args.extend([flag.to_string(), value]);
+    flag("--tag", options.t.to_string());
+    if !options.platform.is_empty() {
+        flag("--platform", options.platform.to_string());
+    }
+    if !options.networkmode.is_empty() {
+        flag("--network", options.networkmode.to_string());
+    }
+    if let Some(cpu_period) = options.cpuperiod {
+        flag("--cpu-period", cpu_period.to_string());
+    }
+    if let Some(cpu_quota) = options.cpuquota {
+        flag("--cpu-quota", cpu_quota.to_string());
+    }
+    if let Some(memory) = options.memory {
+        flag("--memory", memory.to_string());
+    }
+    let build_args: BTreeMap<_, _> = options.buildargs.iter().collect();
+    for (name, value) in build_args {
+        flag("--build-arg", runtime_trace::variable(name, value));
+    }
+    if options.pull {
+        args.push("--pull".to_string());
+    }
+    if options.rm {
+        args.push("--rm".to_string());
+    }
+    args.push(context.display().to_string());
+    if options.version == BuilderVersion::BuilderBuildKit {
+        args.splice(0..0, ["buildx".to_string()]);
+    }
+
+    args
+}
+
+/// Explains a failure to pull a base image, which is most often an image that only exists locally
+fn base_image_pull_error(message: &str, pull: PullPolicy) -> Option<anyhow::Error> {
+    const PULL_FAILURES: [&str; 3] = [
+        "pull access denied",
+        "repository does not exist",
+        "manifest unknown",
+    ];
+
+    let lowercase = message.to_lowercase();
+    if !PULL_FAILURES
+        .iter()
+        .any(|failure| lowercase.contains(failure))
+    {
+        return None;
+    }
+
+    let hint = match pull {
+        PullPolicy::Always => {
+            "Images that only exist locally can't be used with --pull=always, use --pull=missing or --pull=never"
+        }
+        PullPolicy::Missing | PullPolicy::Never => {
+            "The image is not present locally either, check its name and tag with `docker images`"
+        }
+    };
+
+    Some(anyhow::anyhow!(
+        "Failed to pull a base image: {}\n{hint}",
+        message.trim()
+    ))
+}
+
+/// Substitutes `$VAR` and `${VAR}` references with values from `args`.
+/// Unknown variables are replaced with an empty string, as Docker does.
+fn substitute_build_args(value: &str, args: &HashMap<&str, String>) -> String {
+    let mut substituted = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '$' {
+            substituted.push(ch);
+            continue;
+        }
+
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut variable = String::new();
+        while let Some(ch) = chars.next_if(|ch| ch.is_ascii_alphanumeric() || *ch == '_') {
+            variable.push(ch);
+        }
+        if braced {
+            chars.next_if_eq(&'}');
+        }
+
+        if let Some(value) = args.get(variable.as_str()) {
+            substituted.push_str(value);
+        }
+    }
+
+    substituted
+}
+
+/// The image the first stage of a Dockerfile is built from, with build arguments substituted.
+/// The bundled Dockerfiles take it from the `BASE_IMAGE` argument
+pub fn base_image(dockerfile: &str, build_args: &HashMap<&str, &str>) -> Option<String> {
+    base_images(dockerfile, build_args).into_iter().next()
+}
+
+/// The external images a Dockerfile's stages are built from, with build arguments substituted.
+/// References to earlier stages and `scratch` are not included.
+fn base_images(dockerfile: &str, build_args: &HashMap<&str, &str>) -> Vec<String> {
+    let mut args: HashMap<&str, String> = HashMap::new();
+    let mut stages = Vec::new();
+    let mut images = Vec::new();
+    let mut seen_from = false;
+
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        let Some(instruction) = words.next() else {
+            continue;
+        };
+
+        if instruction.eq_ignore_ascii_case("ARG") && !seen_from {
+            // Only ARGs declared before the first FROM can be used in FROM lines
+            for declaration in words {
+                let (name, default) = declaration.split_once('=').unwrap_or((declaration, ""));
+                let value = build_args
+                    .get(name)
+                    .map(|value| value.to_string())
+                    .unwrap_or_else(|| substitute_build_args(default.trim_matches('"'), &args));
+                args.insert(name, value);
+            }
+        } else if instruction.eq_ignore_ascii_case("FROM") {
+            seen_from = true;
+            let mut words = words.skip_while(|word| word.starts_with("--"));
+            let Some(image) = words.next() else {
+                continue;
+            };
+            let image = substitute_build_args(image, &args);
+
+            if !stages.contains(&image) && image != "scratch" && !images.contains(&image) {
+                images.push(image);
+            }
+
+            if let (Some(keyword), Some(stage)) = (words.next(), words.next()) {
+                if keyword.eq_ignore_ascii_case("AS") {
+                    stages.push(stage.to_string());
+                }
+            }
+        }
+    }
+
+    images
+}
+
+/// What trunk expects of the image built from a Dockerfile
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuilderKind {
+    /// Trunk runs the install command in a container of the image
+    Generic,
+    /// The image must contain the output of `cargo pgrx package`
+    Pgrx,
+}
+
+/// The instructions of a Dockerfile, as the uppercased instruction and its arguments,
+/// with line continuations joined and comments skipped.
+fn dockerfile_instructions(dockerfile: &str) -> Vec<(String, String)> {
+    let mut instructions = Vec::new();
+    let mut current = String::new();
+
+    for line in dockerfile.lines() {
+        let line = line.trim();
+        // Comments are skipped even in the middle of a continued instruction
+        if line.starts_with('#') || (current.is_empty() && line.is_empty()) {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                current.push_str(continued);
+                current.push(' ');
+            }
+            None => {
+                current.push_str(line);
+                let (instruction, arguments) = current
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((&current, ""));
+                instructions.push((
+                    instruction.to_ascii_uppercase(),
+                    arguments.trim().to_string(),
+                ));
+                current.clear();
+            }
+        }
+    }
+
+    instructions
+}
+
+/// The stage name declared by the arguments of a FROM instruction, `<image> AS <name>`
+fn stage_name(from_arguments: &str) -> Option<&str> {
+    let mut words = from_arguments
+        .split_whitespace()
+        .skip_while(|word| word.starts_with("--"))
+        .skip(1);
+    match (words.next(), words.next()) {
+        (Some(keyword), Some(stage)) if keyword.eq_ignore_ascii_case("AS") => Some(stage),
+        _ => None,
+    }
+}
+
+/// The names of a Dockerfile's stages, in order. Stages without a name are not included.
+pub fn dockerfile_stages(dockerfile: &str) -> Vec<String> {
+    dockerfile_instructions(dockerfile)
+        .iter()
+        .filter(|(instruction, _)| instruction == "FROM")
+        .filter_map(|(_, arguments)| stage_name(arguments))
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// The Dockerfile cut off after the stage named `target`, so that the target is its last stage
+/// and is what gets built, as with `docker build --target`. None if there is no such stage.
+pub fn dockerfile_up_to_stage(dockerfile: &str, target: &str) -> Option<String> {
+    let mut kept = String::new();
+    let mut continued = false;
+    let mut in_target = false;
+
+    for line in dockerfile.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let is_comment = trimmed.starts_with('#');
+        if !continued && !is_comment {
+            let mut words = trimmed.splitn(2, char::is_whitespace);
+            if words
+                .next()
+                .is_some_and(|instruction| instruction.eq_ignore_ascii_case("FROM"))
+            {
+                if in_target {
+                    return Some(kept);
+                }
+                in_target = stage_name(words.next().unwrap_or_default()) == Some(target);
+            }
+        }
+        // Comments don't end a continued instruction
+        if !is_comment {
+            continued = trimmed.ends_with('\\');
+        }
+        kept.push_str(line);
+    }
+
+    in_target.then_some(kept)
+}
+
+/// Looks for common mistakes in a custom Dockerfile that would otherwise only show up after
+/// a long build. These are heuristics rather than a full parse, so each finding is only a warning.
+pub fn check_dockerfile(dockerfile: &str, kind: BuilderKind) -> Vec<String> {
+    let instructions = dockerfile_instructions(dockerfile);
+    let Some(final_stage) = instructions
+        .iter()
+        .rposition(|(instruction, _)| instruction == "FROM")
+    else {
+        return vec!["The Dockerfile has no FROM instruction".to_string()];
+    };
+
+    let mut warnings = Vec::new();
+    let image = instructions[final_stage]
+        .1
+        .split_whitespace()
+        .find(|word| !word.starts_with("--"))
+        .unwrap_or_default();
+    if image == "scratch" || image.contains("distroless") {
+        warnings.push(format!(
+            "The final stage of the Dockerfile is built from {image}, which lacks the shell and tools \
+             trunk needs to run commands in the builder container"
+        ));
+    }
+
+    let mut runs = instructions
+        .iter()
+        .filter(|(instruction, _)| instruction == "RUN")
+        .map(|(_, arguments)| arguments.as_str());
+    match kind {
+        BuilderKind::Generic => {
+            if let Some(run) = runs.find(|run| runs_make_install(run)) {
+                warnings.push(format!(
+                    "The Dockerfile installs the extension while building the image (RUN {run}). \
+                     Trunk only packages the files installed by its install command, which runs after \
+                     the image is built, so this step belongs in --install-command"
+                ));
+            }
+        }
+        BuilderKind::Pgrx => {
+            if !runs.any(|run| run.contains("pgrx package")) {
+                warnings.push(
+                    "The Dockerfile does not run `cargo pgrx package`, whose output trunk packages"
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    warnings
+}
+
+/// Whether any of the shell commands in `run` is `make ... install`
+fn runs_make_install(run: &str) -> bool {
+    run.split(['&', ';', '|']).any(|command| {
+        let mut words = command
+            .split_whitespace()
+            .skip_while(|word| *word == "sudo");
+        words.next() == Some("make") && words.any(|word| word == "install")
+    })
+}
+
+/// Fails if any image the Dockerfile is built from has not been pulled already.
+async fn ensure_base_images_present(
+    docker: &Docker,
+    dockerfile: &str,
+    build_args: &HashMap<&str, &str>,
+) -> anyhow::Result<()> {
+    for image in base_images(dockerfile, build_args) {
+        trace_call(docker, &["image", "inspect", &image]).await;
+        match docker.inspect_image(&image).await {
+            Ok(_) => {}
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {
+                bail!("Image {image} not present locally and pull=never");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// An image platform such as `linux/arm64/v8`, as `--platform` and manifest lists name it
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ImagePlatform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl ImagePlatform {
+    fn parse(platform: &str) -> Option<Self> {
+        let mut parts = platform.split('/');
+        let os = parts.next().filter(|os| !os.is_empty())?;
+        let architecture = parts
+            .next()
+            .filter(|architecture| !architecture.is_empty())?;
+        let variant = parts.next().map(ToOwned::to_owned);
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            os: os.to_string(),
+            architecture: architecture.to_string(),
+            variant,
+        })
+    }
+
+    /// Reads the `platform` object of a manifest list entry
+    fn from_json(platform: &serde_json::Value) -> Option<Self> {
+        Some(Self {
+            os: platform.get("os")?.as_str()?.to_string(),
+            architecture: platform.get("architecture")?.as_str()?.to_string(),
+            variant: platform
+                .get("variant")
+                .and_then(serde_json::Value::as_str)
+                .map(ToOwned::to_owned),
+        })
+    }
+
+    /// Whether an image for this platform can be used for `requested`. A request without a
+    /// variant, such as `linux/arm64`, accepts any variant.
+    fn satisfies(&self, requested: &ImagePlatform) -> bool {
+        self.os == requested.os
+            && self.architecture == requested.architecture
+            && requested
+                .variant
+                .as_ref()
+                .is_none_or(|variant| self.variant.as_ref() == Some(variant))
+    }
+}
+
+impl fmt::Display for ImagePlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The platforms a `manifest inspect` document provides: each entry of a manifest list, or the
+/// single image that `docker manifest inspect --verbose` describes. `None` if it doesn't say.
+fn manifest_platforms(manifest: &serde_json::Value) -> Option<Vec<ImagePlatform>> {
+    let descriptor_platform = |entry: &serde_json::Value| {
+        ImagePlatform::from_json(entry.get("Descriptor")?.get("platform")?)
+    };
+
+    let platforms: Vec<ImagePlatform> = match manifest {
+        serde_json::Value::Array(entries) => {
+            entries.iter().filter_map(descriptor_platform).collect()
+        }
+        manifest => match manifest.get("manifests") {
+            Some(manifests) => manifests
+                .as_array()?
+                .iter()
+                .filter_map(|entry| ImagePlatform::from_json(entry.get("platform")?))
+                .collect(),
+            None => descriptor_platform(manifest).into_iter().collect(),
+        },
+    };
+    // BuildKit lists its attestations as images of the platform unknown/unknown
+    let platforms: Vec<ImagePlatform> = platforms
+        .into_iter()
+        .filter(|platform| platform.os != "unknown")
+        .collect();
+
+    (!platforms.is_empty()).then_some(platforms)
+}
+
+/// Asks the registry which platforms `image` provides, through the runtime's CLI
+async fn inspect_manifest_platforms(
+    podman: bool,
+    image: &str,
+) -> anyhow::Result<Vec<ImagePlatform>> {
+    let cli = if podman { "podman" } else { "docker" };
+    let program = which::which(cli).map_err(|_| anyhow!("{cli} is not on the PATH"))?;
+    let mut args = vec!["manifest", "inspect"];
+    // Without --verbose, Docker doesn't report the platform of an image that isn't a list
+    if !podman {
+        args.push("--verbose");
+    }
+    args.push(image);
+    runtime_trace::trace(cli, &args);
+    let mut command = Command::new(program);
+    command.args(args);
+
+    let output = task::spawn_blocking(move || command.output()).await??;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    let manifest: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+
+    manifest_platforms(&manifest).ok_or_else(|| anyhow!("its manifest doesn't name a platform"))
+}
+
+/// Fails if an image the Dockerfile is built from has no variant for `platform`, before a long
+/// build gets to it. Images whose platforms can't be looked up, such as private images the
+/// runtime's CLI isn't logged in to, are not checked.
+async fn check_base_image_platforms(
+    docker: &Docker,
+    dockerfile: &str,
+    build_args: &HashMap<&str, &str>,
+    platform: &str,
+    offline: bool,
+) -> anyhow::Result<()> {
+    let Some(requested) = ImagePlatform::parse(platform) else {
+        return Ok(());
+    };
+    let podman = is_podman(docker).await;
+
+    for image in base_images(dockerfile, build_args) {
+        trace_call(docker, &["image", "inspect", &image]).await;
+        if let Ok(inspect) = docker.inspect_image(&image).await {
+            let local = ImagePlatform {
+                os: inspect.os.unwrap_or_default(),
+                architecture: inspect.architecture.unwrap_or_default(),
+                variant: inspect.variant,
+            };
+            if local.satisfies(&requested) {
+                continue;
+            }
+        }
+        if offline {
+            tee_println!(
+                "Not checking that base image {image} provides {platform}, building offline"
+            );
+            continue;
+        }
+
+        let platforms = match inspect_manifest_platforms(podman, &image).await {
+            Ok(platforms) => platforms,
+            Err(err) => {
+                tee_println!("Could not check that base image {image} provides {platform}: {err}");
+                continue;
+            }
+        };
+        if !platforms
+            .iter()
+            .any(|provided| provided.satisfies(&requested))
+        {
+            let provided: Vec<String> = platforms.iter().map(ToString::to_string).collect();
+            bail!(
+                "Base image {image} has no {platform} variant, it provides {}\n\
+                 Build for one of those platforms, use another base image, or skip this check \
+                 with --skip-platform-check",
+                provided.join(", ")
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if the connected container runtime is Podman, through its Docker-compatible API
+async fn is_podman(docker: &Docker) -> bool {
+    daemon_version(docker).await.is_some_and(is_podman_version)
+}
+
+/// The CLI of the connected container runtime, `podman` or `docker`
+async fn runtime_cli(docker: &Docker) -> &'static str {
+    if is_podman(docker).await {
+        "podman"
+    } else {
+        "docker"
+    }
+}
+
+/// Prints the command line of the runtime's CLI that does what the API call about to be made
+/// does, with `--trace-docker`
+async fn trace_call<S: AsRef<str>>(docker: &Docker, args: &[S]) {
+    if runtime_trace::enabled() {
+        runtime_trace::trace(runtime_cli(docker).await, args);
+    }
+}
+
+/// The version the daemon reports, asked for once per invocation. None if it couldn't be asked
+async fn daemon_version(docker: &Docker) -> Option<&'static bollard::system::Version> {
+    static DAEMON_VERSION: OnceLock<Option<bollard::system::Version>> = OnceLock::new();
+    if DAEMON_VERSION.get().is_none() {
+        let version = docker.version().await.ok();
+        let _ = DAEMON_VERSION.set(version);
+    }
+
+    DAEMON_VERSION.get().and_then(Option::as_ref)
+}
+
+/// The name and version of the container runtime reporting `version`, e.g. `Docker 24.0.7`
+pub fn runtime_description(version: &bollard::system::Version) -> String {
+    let runtime = if is_podman_version(version) {
+        "Podman"
+    } else {
+        "Docker"
+    };
+    let version = version
+        .version
+        .as_deref()
+        .filter(|version| !version.trim().is_empty())
+        .unwrap_or("unknown");
+
+    format!("{runtime} {version}")
+}
+
+/// The name and version of the connected container runtime, or `unknown`
+async fn container_runtime(docker: &Docker) -> String {
+    daemon_version(docker)
+        .await
+        .map(runtime_description)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether the daemon reporting `version` is Podman's Docker-compatible service
+pub fn is_podman_version(version: &bollard::system::Version) -> bool {
+    version.components.as_ref().is_some_and(|components| {
+        components
+            .iter()
+            .any(|component| component.name.contains("Podman"))
+    })
+}
+
+/// Whether the daemon reporting `info` runs without root, as rootless Docker or rootless Podman.
+/// Builds need nothing special for it, as files go in and out of containers through the API
+/// rather than bind mounts, so SELinux labels and user namespaces don't get in the way.
+pub fn is_rootless(info: &SystemInfo) -> bool {
+    info.security_options.as_ref().is_some_and(|options| {
+        options
+            .iter()
+            .any(|option| option.split(',').any(|field| field == "name=rootless"))
+    })
+}
+
+/// Print the progress reported by BuildKit: completed steps, their logs and errors
+fn print_buildkit_status(status: &StatusResponse) {
+    for vertex in &status.vertexes {
+        if !vertex.error.is_empty() {
+            tee_eprintln!("ERROR: {} (detail: {})", vertex.name, vertex.error);
+        } else if vertex.completed.is_some() {
+            let cached = if vertex.cached { " CACHED" } else { "" };
+            tee_println!("{}{cached}", vertex.name);
+        }
+    }
+    for log in &status.logs {
+        tee_print!("{}", String::from_utf8_lossy(&log.msg));
+    }
+}
+
 // Build an image
 // The Dockerfile and build directory can be in different directories.
 // The caller provides an image name prefix, and this function returns
 // the complete image name.
+#[allow(clippy::too_many_arguments)]
 pub async fn build_image(
     platform: Option<String>,
     docker: Docker,
@@ -322,7 +1502,10 @@ pub async fn build_image(
     dockerfile_path: &str,
     build_directory: &Path,
     build_args: HashMap<&str, &str>,
+    image_build_options: &ImageBuildOptions,
+    timings: &mut BuildTimings,
 ) -> Result<String, anyhow::Error> {
+    let started = Instant::now();
     let dockerfile = dockerfile_path.to_owned();
 
     let random_suffix = {
@@ -337,12 +1520,18 @@ pub async fn build_image(
     // Making build_directory owned so we can send it to the tarring task below without having to worry
     // about the lifetime of the reference.
     let build_directory = build_directory.to_owned();
+    let context_directory = build_directory.clone();
+    let context_filter = image_build_options.context_filter.clone();
 
     // The docker API receives the build environment as a tar ball.
-    task::spawn_blocking(move || {
+    let context_handle = task::spawn_blocking(move || {
+        let started = Instant::now();
         let f = || {
             let mut tar = tar::Builder::new(stream);
-            tar.append_dir_all(".", build_directory)?;
+            let files = context_filter
+                .append_context(&mut tar, &build_directory)
+                .map_err(|err| std::io::Error::other(format!("{err:#}")))?;
+            info!("Sending {files} files of the build context");
 
             let mut header = Header::new_gnu();
             header.set_size(dockerfile.len() as u64);
@@ -354,8 +1543,27 @@ pub async fn build_image(
             Ok(()) => (),
             Err(err) => sender.try_send(Err(err)).map(|_| ()).unwrap_or_default(),
         }
+        started.elapsed()
     });
 
+    if image_build_options.pull == PullPolicy::Never {
+        ensure_base_images_present(&docker, dockerfile_path, &build_args).await?;
+    }
+    if let Some(platform) = platform.as_deref() {
+        if !image_build_options.skip_platform_check {
+            check_base_image_platforms(
+                &docker,
+                dockerfile_path,
+                &build_args,
+                platform,
+                image_build_options.offline,
+            )
+            .await?;
+        }
+    }
+
+    // Kept to tell which registry refused a pull
+    let pulled_images = base_images(dockerfile_path, &build_args);
     let build_args = build_args.clone();
     let image_name = image_name.to_owned();
 
@@ -363,51 +1571,280 @@ pub async fn build_image(
         dockerfile: "Dockerfile",
         t: &image_name.clone(),
         rm: true,
-        pull: true,
+        pull: image_build_options.pull == PullPolicy::Always,
         buildargs: build_args,
         ..Default::default()
     };
 
-    if platform.is_some() {
-        let platform_value = platform.as_ref().unwrap();
+    if let Some(platform_value) = platform.as_ref() {
         options.platform = platform_value.as_str();
     }
 
+    if image_build_options.offline {
+        tee_println!("Building image {image_name} without network access");
+        options.networkmode = "none";
+    }
+
+    // Docker limits CPU time per scheduling period, 100ms by default
+    const CPU_PERIOD: u64 = 100_000;
+    if let Some(cpus) = image_build_options.cpus {
+        options.cpuperiod = Some(CPU_PERIOD);
+        options.cpuquota = Some((cpus * CPU_PERIOD as f64) as u64);
+    }
+    options.memory = image_build_options.memory;
+
+    let mut buildkit = image_build_options.buildkit;
+    if buildkit && is_podman(&docker).await {
+        tee_println!(
+            "Note: Podman does not support BuildKit through its Docker-compatible API, --buildkit has no effect"
+        );
+        buildkit = false;
+    }
+
+    if buildkit {
+        tee_println!("Building image {image_name} with BuildKit");
+        options.version = BuilderVersion::BuilderBuildKit;
+        options.session = Some(image_name.clone());
+    } else {
+        tee_println!("Building image {image_name} with the classic builder");
+    }
+
+    if let Some(registry_auth) = &image_build_options.registry_auth {
+        tee_println!(
+            "Pulling base images with the credentials in {}",
+            registry_auth.path().display()
+        );
+    }
+    let credentials = image_build_options
+        .registry_auth
+        .as_ref()
+        .map(RegistryAuth::credentials);
+    trace_call(&docker, &build_args_line(&options, &context_directory)).await;
     let mut image_build_stream = docker.build_image(
         options,
-        None,
+        credentials,
         Some(Body::wrap_stream(ReceiverStream::new(receiver))),
     );
 
-    while let Some(next) = image_build_stream.next().await {
+    // Output of the build steps, used to explain why a step failed
+    let mut build_output = String::new();
+    // The classic builder announces each step with "Step 3/9 : RUN make"
+    let mut current_step: Option<(String, Instant)> = None;
+    let steps_started = Instant::now();
+
+    loop {
+        let next = match image_build_options.step_timeout {
+            Some(timeout) => {
+                // BuildKit doesn't announce its steps, so its whole build counts as one step
+                let (step, step_started) = current_step
+                    .as_ref()
+                    .map_or(("image build", steps_started), |(step, step_started)| {
+                        (step.as_str(), *step_started)
+                    });
+                let remaining = timeout.saturating_sub(step_started.elapsed());
+                tokio::time::timeout(remaining, image_build_stream.next())
+                    .await
+                    .map_err(|_| StepTimeoutError {
+                        step: step.to_string(),
+                        timeout,
+                    })?
+            }
+            None => image_build_stream.next().await,
+        };
+        let Some(next) = next else {
+            break;
+        };
         match next {
             Ok(BuildInfo {
                 stream: Some(s), ..
             }) => {
-                print!("{s}");
+                tee_print!("{s}");
+                if let Some((_, step)) = s.split_once(" : ").filter(|_| s.starts_with("Step ")) {
+                    if let Some((step, step_started)) = current_step.take() {
+                        timings.record_step(step, step_started.elapsed());
+                    }
+                    current_step = Some((step.trim().to_string(), Instant::now()));
+                }
+                build_output.push_str(&s);
+            }
+            Ok(BuildInfo {
+                aux: Some(BuildInfoAux::BuildKit(status)),
+                ..
+            }) => {
+                print_buildkit_status(&status);
             }
             Ok(BuildInfo {
                 error: Some(err),
                 error_detail,
                 ..
             }) => {
-                eprintln!(
-                    "ERROR: {} (detail: {})",
-                    err,
-                    error_detail.unwrap_or_default().message.unwrap_or_default()
-                );
+                let detail = error_detail.unwrap_or_default().message.unwrap_or_default();
+                let message = format!("{build_output}\n{err}\n{detail}");
+                if let Some(err) = OutOfDiskSpaceError::detect(&message) {
+                    return Err(err.into());
+                }
+                if let Some(err) = OutOfMemoryError::detect(&message, image_build_options.memory) {
+                    return Err(err.into());
+                }
+                if let Some(err) = RustToolchainInstallError::detect(&message) {
+                    return Err(err.into());
+                }
+                if image_build_options.offline {
+                    if let Some(err) = OfflineNetworkError::detect(&message) {
+                        return Err(err.into());
+                    }
+                }
+                if let Some(err) = image_build_options
+                    .registry_auth
+                    .as_ref()
+                    .and_then(|auth| auth.classify_failure(&message, &pulled_images))
+                {
+                    return Err(err.into());
+                }
+                if let Some(err) = base_image_pull_error(&message, image_build_options.pull) {
+                    return Err(err);
+                }
+                tee_eprintln!("ERROR: {} (detail: {})", err, detail);
             }
             Ok(_) => {}
             Err(err) => {
+                if let Some(err) = OutOfDiskSpaceError::detect(&err.to_string()) {
+                    return Err(err.into());
+                }
                 dbg!(&err);
                 return Err(err)?;
             }
         }
     }
 
+    if let Some((step, step_started)) = current_step {
+        timings.record_step(step, step_started.elapsed());
+    }
+    if let Ok(context_elapsed) = context_handle.await {
+        timings.record("build context", context_elapsed);
+    }
+    timings.record_since("image build", started);
+
     Ok(image_name)
 }
 
+/// Directory of the archive that holds the files listed in `include_files`
+pub const INCLUDED_FILES_DIR: &str = "included";
+
+/// Directory of the archive that holds the files `capture_globs` matched outside sharedir and
+/// pkglibdir, by their path relative to `pg_config --prefix`
+pub const CAPTURED_FILES_DIR: &str = "captured";
+
+/// What `--strip` does with the debug symbols of the shared libraries it strips
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strip {
+    /// Drop them
+    Discard,
+    /// Package them in a companion `-debug` archive, with `--keep-debug`
+    KeepDebug,
+}
+
+/// Where `--keep-debug` collects the debug symbols in the container
+const DEBUG_SYMBOLS_DIR: &str = "/tmp/trunk-debug";
+
+/// Strips the debug symbols from the shared libraries the build installed in `pkglibdir`, in the
+/// container, so that the archive is made of the stripped files. To keep them, each library's
+/// symbols are copied to `DEBUG_SYMBOLS_DIR` first and the library is linked to its copy, so that
+/// debuggers find them once installed next to it. Returns how many libraries were stripped
+async fn strip_libraries(
+    docker: &Docker,
+    container_id: &str,
+    pkglibdir: &str,
+    pkglibdir_files: &[String],
+    strip: Strip,
+) -> Result<usize, anyhow::Error> {
+    let libraries: Vec<&String> = pkglibdir_files
+        .iter()
+        .filter(|file| file.ends_with(".so"))
+        .collect();
+    for library in &libraries {
+        let path = format!("{pkglibdir}/{library}");
+        let debug_path = format!("{DEBUG_SYMBOLS_DIR}/{library}.debug");
+        let debug_dir = Path::new(&debug_path)
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let debuglink = format!("--add-gnu-debuglink={debug_path}");
+        let commands = match strip {
+            Strip::Discard => vec![vec!["strip", "--strip-debug", path.as_str()]],
+            Strip::KeepDebug => vec![
+                vec!["mkdir", "-p", debug_dir.as_str()],
+                vec!["objcopy", "--only-keep-debug", &path, &debug_path],
+                vec!["strip", "--strip-debug", &path],
+                vec!["objcopy", &debuglink, &path],
+            ],
+        };
+        for command in commands {
+            let program = command.join(" ");
+            let (output, code) =
+                exec_in_container_with_exit_code(docker, container_id, command, None, None).await?;
+            if code != Some(0) {
+                anyhow::bail!(
+                    "Failed to strip {path}: `{program}` exited with {}: {}\n\
+                     --strip needs binutils (strip and objcopy) in the builder image",
+                    code.map_or("an unknown status".to_string(), |code| format!(
+                        "code {code}"
+                    )),
+                    output.trim()
+                );
+            }
+        }
+    }
+
+    Ok(libraries.len())
+}
+
+/// Writes the debug symbols `--keep-debug` collected in the container to a gzipped tarball at
+/// `path`, by the paths of their libraries in pkglibdir
+async fn package_debug_symbols(
+    docker: &Docker,
+    container_id: &str,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let mut tarball = Vec::new();
+    trace_call(
+        docker,
+        &["cp", &format!("{container_id}:{DEBUG_SYMBOLS_DIR}"), "-"],
+    )
+    .await;
+    let mut stream = docker.download_from_container(
+        container_id,
+        Some(DownloadFromContainerOptions {
+            path: DEBUG_SYMBOLS_DIR,
+        }),
+    );
+    while let Some(chunk) = stream.next().await {
+        tarball.extend_from_slice(&chunk?);
+    }
+
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut debug_archive = Builder::new(flate2::write::GzEncoder::new(
+        file,
+        flate2::Compression::default(),
+    ));
+    let mut archive = Archive::new(Cursor::new(tarball));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        // The download is rooted at the directory's name
+        let entry_path = entry.path()?.into_owned();
+        let relative: PathBuf = entry_path.components().skip(1).collect();
+        let mut header = entry.header().clone();
+        debug_archive.append_data(&mut header, &relative, &mut entry)?;
+    }
+    debug_archive.into_inner()?.try_finish()?;
+
+    Ok(())
+}
+
 // Scan sharedir and package lib dir from a Trunk builder container for files from a provided list.
 // Package these files into a Trunk package.
 #[allow(clippy::too_many_arguments)]
@@ -421,12 +1858,43 @@ pub async fn package_installed_extension_files(
     extension_version: &str,
     extension_dependencies: Option<Vec<String>>,
     inclusion_patterns: Vec<glob::Pattern>,
+    capture_globs: Vec<glob::Pattern>,
     configurations: Option<Vec<ExtensionConfiguration>>,
     loadable_libraries: Option<Vec<LoadableLibrary>>,
+    preload: Option<Preload>,
+    kind: ExtensionKind,
+    control: Option<ControlFields>,
     pg_version: u8,
-) -> Result<(), anyhow::Error> {
+    supported_pg_versions: SupportedPgVersions,
+    context: &Path,
+    included_files: Vec<String>,
+    artifact_suffix: &str,
+    allow_missing_control: bool,
+    build_profile: Option<&str>,
+    toolchain: BTreeMap<String, String>,
+    base_image: Option<String>,
+    keep_ownership: bool,
+    file_digests: bool,
+    strip: Option<Strip>,
+    install_prefixes: BTreeMap<PathBuf, String>,
+    changelog: Option<Changelog>,
+    labels: Option<BTreeMap<String, String>>,
+    categories: Option<Vec<String>>,
+    manifest_transform: Option<String>,
+    compiler_flags: CompilerFlags,
+    manifest_version: i32,
+    fail_on_warn: &[WarningCategory],
+    platform: Option<&str>,
+    allow_arch_mismatch: bool,
+    version_check: bool,
+    mut timings: BuildTimings,
+) -> Result<BuildOutput, anyhow::Error> {
+    let started = Instant::now();
+    let container_runtime = container_runtime(&docker).await;
     let name = name.to_owned();
+    let context = context.to_owned();
     let extension_version = extension_version.to_owned();
+    let build_profile = build_profile.map(ToOwned::to_owned);
 
     let target_arch =
         exec_in_container(&docker, container_id, vec!["uname", "-m"], None, None).await?;
@@ -453,9 +1921,144 @@ pub async fn package_installed_extension_files(
     let pkglibdir = pkglibdir.trim();
 
     let extension_files =
-        find_installed_extension_files(&docker, container_id, &inclusion_patterns).await?;
+        find_installed_extension_files(&docker, container_id, &inclusion_patterns, &capture_globs)
+            .await?;
+    // Loadable modules are nothing but their shared libraries
+    let module = kind == ExtensionKind::Module;
+    if module {
+        if !extension_files
+            .pkglibdir
+            .iter()
+            .any(|file| file.ends_with(".so"))
+        {
+            return Err(NoModuleLibraryError {
+                pkglibdir: pkglibdir.to_string(),
+            }
+            .into());
+        }
+    // Extensions made of only a control file and SQL scripts are fine, but a build that installed
+    // nothing at all most likely has a broken install command
+    } else if extension_files.sharedir.is_empty() && extension_files.pkglibdir.is_empty() {
+        if !allow_missing_control {
+            return Err(NoExtensionFilesError {
+                sharedir: sharedir.to_string(),
+                pkglibdir: pkglibdir.to_string(),
+            }
+            .into());
+        }
+        tee_println!(
+            "WARNING: The install command did not install any extension files, packaging anyway"
+        );
+    }
+    for pattern in &extension_files.unmatched_capture_globs {
+        let message = format!("capture_globs pattern '{pattern}' matched no installed file");
+        check_warning(
+            fail_on_warn,
+            WarningCategory::UnmatchedCaptureGlob,
+            &message,
+        )?;
+        tee_println!("WARNING: {message}");
+    }
+    // pgrx extensions are compiled by rustc, which emits no bitcode for Postgres to inline
+    if build_profile.is_none() && lacks_bitcode(&extension_files.pkglibdir) {
+        let configure = exec_in_container(
+            &docker,
+            container_id,
+            vec!["pg_config", "--configure"],
+            None,
+            None,
+        )
+        .await
+        .unwrap_or_default();
+        if is_jit_enabled(&configure) {
+            let message = "Postgres in the builder image supports JIT, but the build installed a shared library without bitcode (.bc) files, so JIT won't inline the extension's functions. \
+                 PGXS only emits bitcode when clang is installed and Postgres was built with --with-llvm";
+            check_warning(fail_on_warn, WarningCategory::MissingBitcode, message)?;
+            tee_println!("WARNING: {message}");
+        }
+    }
+    // The fields in Trunk.toml are only recorded, so a mismatch shouldn't fail the build
+    if let Some(expected) = &control {
+        match &extension_files.control_file {
+            Some(control_file) => {
+                for mismatch in control_file.mismatches(expected) {
+                    check_warning(fail_on_warn, WarningCategory::ControlMismatch, &mismatch)?;
+                    tee_println!("WARNING: {mismatch}");
+                }
+            }
+            None => {
+                let message = "[extension.control] is set in Trunk.toml, but the build installed no control file to check it against";
+                check_warning(fail_on_warn, WarningCategory::ControlMismatch, message)?;
+                tee_println!("WARNING: {message}");
+            }
+        }
+    }
+    if version_check && !module {
+        if let Some(err) = control_version_mismatch(
+            &extension_files.default_versions,
+            extension_name.as_deref().unwrap_or(&name),
+            &extension_version,
+        ) {
+            return Err(err.into());
+        }
+    }
+    let sql_scripts = SqlScripts::from_files(extension_files.sharedir.iter().map(String::as_str));
+    // Loadable modules have no control file, so no install script to look for
+    if !module {
+        for (extension, default_version) in &extension_files.default_versions {
+            if !sql_scripts.can_install(extension, default_version) {
+                let message = format!(
+                    "The control file of {extension} has default_version '{default_version}', but the build installed no {extension}--{default_version}.sql script, nor update scripts that lead to it from another install script"
+                );
+                check_warning(
+                    fail_on_warn,
+                    WarningCategory::MissingInstallScript,
+                    &message,
+                )?;
+                tee_println!("WARNING: {message}");
+            }
+        }
+    }
+    // shared_preload_libraries names libraries without their .so
+    let installed_libraries: Vec<String> = extension_files
+        .pkglibdir
+        .iter()
+        .filter(|file| !file.contains('/'))
+        .filter_map(|file| file.strip_suffix(".so"))
+        .map(str::to_string)
+        .collect();
+    let preloaded = preload
+        .as_ref()
+        .map(|preload| preload.libraries(&installed_libraries))
+        .unwrap_or_default();
+    for name in preloaded
+        .iter()
+        .filter(|name| !installed_libraries.contains(name))
+    {
+        let message = format!(
+            "preload in Trunk.toml names {name}, but the build installed no {name}.so into pkglibdir"
+        );
+        check_warning(fail_on_warn, WarningCategory::PreloadMismatch, &message)?;
+        tee_println!("WARNING: {message}");
+    }
+    let loadable_libraries = add_preloaded_libraries(loadable_libraries, &preloaded);
+    let upgrade_paths = Some(sql_scripts.upgrade_paths).filter(|paths| !paths.is_empty());
     let license_files = find_license_files(&docker, container_id).await?;
 
+    let stripped = match strip {
+        Some(strip) => Some(
+            strip_libraries(
+                &docker,
+                container_id,
+                pkglibdir,
+                &extension_files.pkglibdir,
+                strip,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
     let sharedir_list = extension_files.sharedir;
     let pkglibdir_list = extension_files.pkglibdir;
     let licensedir_list = license_files;
@@ -464,10 +2067,20 @@ pub async fn package_installed_extension_files(
     let pkglibdir = pkglibdir.to_owned();
     let licensedir = "/usr/licenses".to_owned();
 
+    let debug_symbols_path =
+        (strip == Some(Strip::KeepDebug) && stripped.is_some_and(|count| count > 0)).then(|| {
+            PathBuf::from(format!(
+                "{package_path}/{name}-{extension_version}-pg{pg_version}-debug{artifact_suffix}"
+            ))
+        });
     // In this function, we open and work with .tar only, then we finalize the package with a .gz in a separate call
-    let package_path = format!("{package_path}/{name}-{extension_version}-pg{pg_version}.tar.gz");
-    println!("Creating package at: {package_path}");
+    let package_path =
+        format!("{package_path}/{name}-{extension_version}-pg{pg_version}{artifact_suffix}");
+    tee_println!("Creating package at: {package_path}");
     let file = File::create(&package_path)?;
+    timings.record_since("capture", started);
+    let started = Instant::now();
+    let partial_artifact = PartialArtifact::new(&package_path);
 
     // Stream used to pass information from docker to tar
     let receiver = ByteStreamSyncReceiver::new();
@@ -477,6 +2090,7 @@ pub async fn package_installed_extension_files(
     // Is there some way to copy from both sharedir and pkglibdir,
     // then combine the streams instead of scanning the whole /usr directory?
     // Looping over everything in that directory makes this way slower.
+    trace_call(&docker, &["cp", &format!("{container_id}:/usr"), "-"]).await;
     let options_usrdir = Some(DownloadFromContainerOptions { path: "/usr" });
     let file_stream = docker.download_from_container(container_id, options_usrdir);
 
@@ -486,7 +2100,7 @@ pub async fn package_installed_extension_files(
     if let Some(control) = sharedir_list.iter().find(|path| path.contains(".control")) {
         // If extension_name parameter is none, check for control file and fetch extension_name
         if extension_name.is_none() {
-            println!("Fetching extension_name from control file: {control}");
+            tee_println!("Fetching extension_name from control file: {control}");
             let path = Path::new(control);
             let file_stem = path
                 .file_stem()
@@ -494,17 +2108,19 @@ pub async fn package_installed_extension_files(
                 .to_string_lossy()
                 .to_string();
 
-            println!("Using extension_name: {}", file_stem);
+            tee_println!("Using extension_name: {}", file_stem);
             extension_name = Some(file_stem);
         }
     }
 
     // If extension_name is still none, we can assume no control file was found
     if extension_name.is_none() {
-        println!(
-            "No control file found. Falling back to extension name '{}'",
-            &name
-        );
+        if !module {
+            tee_println!(
+                "No control file found. Falling back to extension name '{}'",
+                &name
+            );
+        }
         extension_name = Some(name.clone())
     }
 
@@ -512,17 +2128,19 @@ pub async fn package_installed_extension_files(
     let tar_handle = task::spawn_blocking(move || {
         // Send ownership of the control file to the closure
         let control_file = extension_files.control_file;
+        let captured = extension_files.captured;
+        let prefix = extension_files.prefix;
         let mut archive = Archive::new(receiver);
-        let mut new_archive = Builder::new(flate2::write::GzEncoder::new(
+        let mut new_archive = Builder::new(TimedWriter::new(flate2::write::GzEncoder::new(
             file,
             flate2::Compression::default(),
-        ));
+        )));
         let mut manifest = Manifest {
             name,
             extension_name,
             extension_version,
             extension_dependencies,
-            manifest_version: 2,
+            manifest_version,
             architecture: target_arch,
             sys: "linux".to_string(),
             files: None,
@@ -530,9 +2148,33 @@ pub async fn package_installed_extension_files(
             configurations,
             loadable_libraries,
             pg_version,
+            included_files: None,
+            has_shared_library: None,
+            build_profile,
+            toolchain: (!toolchain.is_empty()).then_some(toolchain),
+            min_pg_version: supported_pg_versions.min,
+            max_pg_version: supported_pg_versions.max,
+            file_digests: None,
+            install_prefixes: None,
+            control,
+            changelog,
+            upgrade_paths,
+            labels,
+            categories,
+            elf_architecture: None,
+            cflags: compiler_flags.cflags,
+            cxxflags: compiler_flags.cxxflags,
+            container_runtime: Some(container_runtime),
+            base_image,
+            stripped: stripped.map(|stripped| stripped > 0),
+            captured_files: None,
+            kind: module.then_some(ExtensionKind::Module),
+            modules: None,
         };
+        let mut library_architectures = Vec::new();
+        let mut preload_symbols = Vec::new();
         // If the docker copy command starts to stream data
-        println!("Create Trunk bundle:");
+        tee_println!("Create Trunk bundle:");
         let entries = archive
             .entries()
             .expect("Expected to find some files in the /usr directory");
@@ -550,12 +2192,17 @@ pub async fn package_installed_extension_files(
             let pkglibdir_match = pkglibdir_list.contains(&trimmed);
             let sharedir_match = sharedir_list.contains(&trimmed);
             let licensedir_match = licensedir_list.contains(&trimmed);
+            let captured_as = captured.get(&full_path).copied();
             // Check if we found a file to package
-            if !(sharedir_match || pkglibdir_match || licensedir_match) {
+            if !(sharedir_match
+                || pkglibdir_match
+                || licensedir_match
+                || captured_as == Some(CaptureCategory::Prefix))
+            {
                 continue;
             }
             if path.to_str() == Some("manifest.json") {
-                println!("Found manifest.json, merging additions with existing manifest");
+                tee_println!("Found manifest.json, merging additions with existing manifest");
                 manifest.merge(serde_json::from_reader(entry)?);
             } else {
                 let root_path = Path::new("/");
@@ -564,14 +2211,24 @@ pub async fn package_installed_extension_files(
                 let prepared_path;
 
                 // trim pkglibdir, sharedir or licensedir from start of path
-                if path.to_string_lossy().contains(&pkglibdir) {
+                if captured_as == Some(CaptureCategory::Prefix) {
+                    prepared_path = Path::new(CAPTURED_FILES_DIR)
+                        .join(path.strip_prefix(&prefix)?)
+                        .into();
+                } else if path.to_string_lossy().contains(&pkglibdir) {
                     prepared_path = path.strip_prefix(format!("{}/", &pkglibdir))?.into();
                 } else if path.to_string_lossy().contains(&sharedir) {
-                    prepared_path = prepare_sharedir_file(&sharedir, control_file.as_ref(), &path)?;
+                    let in_sharedir =
+                        prepare_sharedir_file(&sharedir, control_file.as_ref(), &path)?;
+                    prepared_path = if manifest_version < 2 {
+                        v1_archive_path(in_sharedir)
+                    } else {
+                        in_sharedir
+                    };
                 } else if path.to_string_lossy().contains(&licensedir) {
                     prepared_path = path.strip_prefix("/usr/")?.into();
                 } else {
-                    println!(
+                    tee_println!(
                         "WARNING: Skipping file because it's not in sharedir, pkglibdir or licensedir {:?}",
                         &path
                     );
@@ -582,6 +2239,12 @@ pub async fn package_installed_extension_files(
                     let mut header = Header::new_gnu();
                     header.set_mode(entry.header().mode()?);
                     header.set_mtime(entry.header().mtime()?);
+                    // Files are owned by root unless installed as another user, so that the
+                    // archive doesn't carry over uids of the container
+                    if keep_ownership {
+                        header.set_uid(entry.header().uid()?);
+                        header.set_gid(entry.header().gid()?);
+                    }
                     header.set_size(entry.size());
                     header.set_cksum();
                     let entry_type = entry.header().entry_type();
@@ -591,37 +2254,309 @@ pub async fn package_installed_extension_files(
 
                     new_archive.append_data(&mut header, &prepared_path, &mut tee)?;
 
-                    let (_entry, _buf) = tee.into_inner();
+                    let (_entry, buf) = tee.into_inner();
 
                     if entry_type == EntryType::file() {
-                        let _ = manifest.add_file(&prepared_path);
-                        println!("\t{}", prepared_path.to_string_lossy());
+                        if let Some(architecture) =
+                            pkglibdir_match.then(|| elf_architecture(buf)).flatten()
+                        {
+                            manifest
+                                .elf_architecture
+                                .get_or_insert_with(|| architecture.to_string());
+                            library_architectures.push((prepared_path.to_path_buf(), architecture));
+                        }
+                        if let Some(symbol) = pkglibdir_match.then(|| preload_symbol(buf)).flatten()
+                        {
+                            preload_symbols.push((prepared_path.to_path_buf(), symbol));
+                        }
+                        if let Some(category) = captured_as {
+                            manifest
+                                .captured_files
+                                .get_or_insert_with(BTreeMap::new)
+                                .insert(prepared_path.to_path_buf(), category);
+                        }
+                        if captured_as != Some(CaptureCategory::Prefix) {
+                            let _ = manifest.add_file(&prepared_path);
+                        }
+                        if file_digests {
+                            manifest.add_digest(&prepared_path, buf);
+                        }
+                        if let Some(prefix) = install_prefixes.get(&path) {
+                            manifest
+                                .install_prefixes
+                                .get_or_insert_with(BTreeMap::new)
+                                .insert(prepared_path.to_path_buf(), prefix.clone());
+                        }
+                        tee_println!("\t{}", prepared_path.to_string_lossy());
                     }
                 }
             }
         }
 
-        let manifest = serde_json::to_string_pretty(&manifest).unwrap_or_default();
+        for included_file in included_files {
+            let archive_path = Path::new(INCLUDED_FILES_DIR).join(&included_file);
+            new_archive.append_path_with_name(context.join(&included_file), &archive_path)?;
+            if file_digests {
+                manifest.add_digest(&archive_path, &fs::read(context.join(&included_file))?);
+            }
+            tee_println!("\t{}", archive_path.to_string_lossy());
+            manifest
+                .included_files
+                .get_or_insert_with(Vec::new)
+                .push(archive_path);
+        }
+
+        manifest.has_shared_library = Some(manifest.contains_shared_library());
+        if module {
+            manifest.modules = Some(manifest.shared_libraries());
+        }
+        let mut manifest_json = serde_json::to_string_pretty(&manifest).unwrap_or_default();
+        if let Some(command) = manifest_transform {
+            tee_println!("Running the manifest_transform hook `{command}`");
+            (manifest_json, manifest) = transform_manifest(&command, &context, &manifest_json)?;
+        }
         let mut header = Header::new_gnu();
-        header.set_size(manifest.as_bytes().len() as u64);
+        header.set_size(manifest_json.len() as u64);
         header.set_cksum();
         header.set_mode(0o644);
-        new_archive.append_data(&mut header, "manifest.json", Cursor::new(manifest))?;
-        println!("\tmanifest.json");
-        Ok::<_, GenericBuildError>(())
+        new_archive.append_data(&mut header, "manifest.json", Cursor::new(manifest_json))?;
+        tee_println!("\tmanifest.json");
+        let compressed = new_archive.into_inner()?;
+        let compression_started = Instant::now();
+        let writing = compressed.elapsed;
+        compressed.into_inner().try_finish()?;
+        let compression = writing + compression_started.elapsed();
+        Ok::<_, GenericBuildError>((
+            manifest,
+            library_architectures,
+            preload_symbols,
+            compression,
+        ))
     });
 
     // Wait until completion of streaming, but ignore its error as it would only error out
     // if tar_handle errors out.
     let _ = receiver_sender.stream_to_end(file_stream).await;
     // Handle the error
-    tar_handle.await??;
+    let (manifest, library_architectures, preload_symbols, compression) = tar_handle.await??;
+    // Fails before the archive is marked complete, so that it is removed
+    if let Some(requested) = platform.and_then(ImagePlatform::parse) {
+        check_library_architectures(
+            &library_architectures,
+            &requested.architecture,
+            allow_arch_mismatch,
+        )?;
+    }
+    check_preload(&preload_symbols, preload.as_ref(), &preloaded, fail_on_warn)?;
+    let partial_debug_symbols = match &debug_symbols_path {
+        Some(path) => {
+            tee_println!("Packaging debug symbols to {}", path.display());
+            let partial = PartialArtifact::new(path);
+            package_debug_symbols(&docker, container_id, path).await?;
+            Some(partial)
+        }
+        None => None,
+    };
+    partial_artifact.complete();
+    if let Some(partial_debug_symbols) = partial_debug_symbols {
+        partial_debug_symbols.complete();
+    }
+    // Compression happens while files are copied out of the container
+    timings.record("capture", started.elapsed().saturating_sub(compression));
+    timings.record("compression", compression);
+
+    tee_println!("Packaged to {package_path}");
+
+    Ok(BuildOutput {
+        artifact_path: PathBuf::from(package_path),
+        manifest,
+        timings,
+        signatures: Vec::new(),
+        debug_symbols_path,
+        encryption_sidecar: None,
+    })
+}
+
+/// The architecture an ELF file was built for, named the way `--platform` names it, e.g. `arm64`.
+/// `None` if `contents` isn't an ELF file, or is one for a machine Docker has no platform for.
+fn elf_architecture(contents: &[u8]) -> Option<&'static str> {
+    let header = ElfBytes::<AnyEndian>::minimal_parse(contents).ok()?.ehdr;
+    let architecture = match header.e_machine {
+        elf::abi::EM_X86_64 => "amd64",
+        elf::abi::EM_AARCH64 => "arm64",
+        elf::abi::EM_ARM => "arm",
+        elf::abi::EM_386 => "386",
+        elf::abi::EM_PPC64 if matches!(header.endianness, AnyEndian::Little) => "ppc64le",
+        elf::abi::EM_PPC64 => "ppc64",
+        elf::abi::EM_S390 => "s390x",
+        elf::abi::EM_RISCV => "riscv64",
+        _ => return None,
+    };
+
+    Some(architecture)
+}
+
+/// Symbols of Postgres that a library only uses when it's loaded through
+/// `shared_preload_libraries`, to reserve shared memory or start a background worker at startup
+const PRELOAD_SYMBOLS: [&str; 5] = [
+    "process_shared_preload_libraries_in_progress",
+    "shmem_request_hook",
+    "RequestAddinShmemSpace",
+    "RequestNamedLWLockTranche",
+    "RegisterBackgroundWorker",
+];
+
+/// The first of [`PRELOAD_SYMBOLS`] that the ELF file `contents` refers to, if it is an ELF file
+fn preload_symbol(contents: &[u8]) -> Option<&'static str> {
+    let file = ElfBytes::<AnyEndian>::minimal_parse(contents).ok()?;
+    let (symbols, strings) = file.dynamic_symbol_table().ok()??;
+
+    symbols
+        .iter()
+        .filter(|symbol| symbol.is_undefined())
+        .find_map(|symbol| {
+            let name = strings.get(symbol.st_name as usize).ok()?;
+            PRELOAD_SYMBOLS.into_iter().find(|known| *known == name)
+        })
+}
+
+/// Checks the libraries that refer to one of [`PRELOAD_SYMBOLS`] against `preloaded`, the
+/// libraries `preload` in Trunk.toml names. Without `preload`, they are only pointed out
+fn check_preload(
+    preload_symbols: &[(PathBuf, &str)],
+    preload: Option<&Preload>,
+    preloaded: &[String],
+    fail_on_warn: &[WarningCategory],
+) -> Result<(), FatalWarningError> {
+    for (path, symbol) in preload_symbols {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        if preloaded.iter().any(|preloaded| *preloaded == name) {
+            continue;
+        }
+        let uses = format!(
+            "{} refers to {symbol}, which only works in libraries loaded through shared_preload_libraries",
+            path.display()
+        );
+        if preload.is_some() {
+            let message = format!("{uses}, but preload in Trunk.toml doesn't include {name}");
+            check_warning(fail_on_warn, WarningCategory::PreloadMismatch, &message)?;
+            tee_println!("WARNING: {message}");
+        } else {
+            tee_println!(
+                "{uses}. Set preload in [extension] of Trunk.toml to record that in the manifest"
+            );
+        }
+    }
+
+    Ok(())
+}
 
-    println!("Packaged to {package_path}");
+/// Compares `version` with the `default_version` of the control file of `extension`, or of the
+/// only control file the build installed. Builds without such a control file aren't checked
+fn control_version_mismatch(
+    default_versions: &BTreeMap<String, String>,
+    extension: &str,
+    version: &str,
+) -> Option<VersionMismatchError> {
+    let (extension, default_version) = default_versions.get_key_value(extension).or_else(|| {
+        let mut control_files = default_versions.iter();
+        control_files
+            .next()
+            .filter(|_| control_files.next().is_none())
+    })?;
+
+    (!same_version(default_version, version)).then(|| VersionMismatchError {
+        extension: extension.clone(),
+        default_version: default_version.clone(),
+        version: version.to_string(),
+    })
+}
+
+/// Whether two versions are the same, counting missing numeric components as zero: control files
+/// often say `1.5` for what is released as `1.5.0`
+fn same_version(left: &str, right: &str) -> bool {
+    fn components(version: &str) -> Option<Vec<u64>> {
+        let mut components = version
+            .split('.')
+            .map(|component| component.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        while components.last() == Some(&0) {
+            components.pop();
+        }
+        Some(components)
+    }
+
+    left == right || components(left).is_some_and(|left| Some(left) == components(right))
+}
+
+/// Fails if a shared library wasn't built for the `requested` architecture of `--platform`, or
+/// only warns with `--allow-arch-mismatch`
+fn check_library_architectures(
+    libraries: &[(PathBuf, &str)],
+    requested: &str,
+    allow_arch_mismatch: bool,
+) -> Result<(), ArchMismatchError> {
+    // Docker accepts the kernel's names for the two most common architectures
+    let requested = match requested {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        requested => requested,
+    };
+    for (library, found) in libraries {
+        if *found == requested {
+            continue;
+        }
+        let err = ArchMismatchError {
+            library: library.clone(),
+            found: found.to_string(),
+            requested: requested.to_string(),
+        };
+        if !allow_arch_mismatch {
+            return Err(err);
+        }
+        tee_println!("WARNING: {err}");
+    }
 
     Ok(())
 }
 
+/// Whether the files installed into pkglibdir include a shared library, but not the bitcode
+/// under `bitcode/` that JIT inlines
+fn lacks_bitcode(pkglibdir_files: &[String]) -> bool {
+    let installed = |suffix| pkglibdir_files.iter().any(|file| file.ends_with(suffix));
+
+    installed(".so") && !installed(".bc")
+}
+
+/// Whether Postgres was built with LLVM, and so can JIT compile queries, going by the output of
+/// `pg_config --configure`
+fn is_jit_enabled(configure: &str) -> bool {
+    configure
+        .split_whitespace()
+        .any(|option| option.trim_matches('\'') == "--with-llvm")
+}
+
+/// Where a file from sharedir goes in a version 1 archive, which has control files and SQL
+/// scripts at its root, to be installed into the extension's directory
+fn v1_archive_path(prepared_path: Cow<'_, Path>) -> Cow<'_, Path> {
+    let is_extension_file = prepared_path
+        .extension()
+        .is_some_and(|extension| extension == "control" || extension == "sql");
+    if !is_extension_file {
+        return prepared_path;
+    }
+
+    // The first component is `extension`, or the control file's `directory`
+    let mut components = prepared_path.components();
+    components.next();
+    let at_root = components.as_path();
+    if at_root.as_os_str().is_empty() {
+        prepared_path
+    } else {
+        Cow::Owned(at_root.to_path_buf())
+    }
+}
+
 /// Assumes `file_to_package.starts_with(sharedir)`.
 fn prepare_sharedir_file<'p>(
     sharedir: &str,
@@ -650,16 +2585,25 @@ fn prepare_sharedir_file<'p>(
     }
 }
 
-/// Attempt to locate the path of the Makefile within this container
+/// Attempt to locate the path of the Makefile within this container.
+/// If `search_dir` is given, only Makefiles within that directory are considered.
 pub async fn locate_makefile(
     docker: &Docker,
     container_id: &str,
     extension_name: &str,
+    search_dir: Option<&str>,
 ) -> anyhow::Result<Option<PathBuf>> {
     let stdout = exec_in_container(
         docker,
         container_id,
-        vec!["find", ".", "-type", "f", "-iname", "Makefile"],
+        vec![
+            "find",
+            search_dir.unwrap_or("."),
+            "-type",
+            "f",
+            "-iname",
+            "Makefile",
+        ],
         None,
         None,
     )
@@ -735,10 +2679,443 @@ pub async fn start_postgres(docker: &Docker, container_id: &str) -> anyhow::Resu
     ).await?;
 
     if status_code == Some(0) {
-        println!("Postgres is up!");
+        tee_println!("Postgres is up!");
     } else {
         bail!("Failed to start Postgres!");
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toolchain_versions() {
+        let output = "rustc=rustc 1.75.0 (82e1608df 2023-12-21)\n\
+                      cargo-pgrx=cargo-pgrx 0.11.2\n\
+                      pg_config=PostgreSQL 15.3\n\
+                      sh: 1: clang: not found\n";
+        let versions = parse_toolchain_versions(output);
+
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions["rustc"], "rustc 1.75.0 (82e1608df 2023-12-21)");
+        assert_eq!(versions["cargo-pgrx"], "cargo-pgrx 0.11.2");
+        assert!(!versions.contains_key("clang"));
+    }
+
+    #[test]
+    fn matches_capture_globs_under_the_prefix() {
+        let capture_globs = [
+            glob::Pattern::new("share/doc/ext/*.example").unwrap(),
+            glob::Pattern::new("etc/**/*.conf").unwrap(),
+        ];
+        let matching = |path| matching_capture_glob(&capture_globs, "/usr", path);
+
+        assert_eq!(matching("/usr/share/doc/ext/ext.conf.example"), Some(0));
+        assert_eq!(matching("/usr/share/doc/ext/samples/ext.example"), None);
+        assert_eq!(matching("/usr/etc/ext/ext.conf"), Some(1));
+        assert_eq!(matching("/usr/etc/ext/samples/ext.conf"), Some(1));
+        assert_eq!(matching("/opt/ext/share/doc/ext/ext.example"), None);
+    }
+
+    #[test]
+    fn checks_the_control_file_version() {
+        let default_versions = BTreeMap::from([
+            ("ext".to_string(), "1.2.0".to_string()),
+            ("ext_extra".to_string(), "0.1.0".to_string()),
+        ]);
+        assert_eq!(
+            control_version_mismatch(&default_versions, "ext", "1.2.0"),
+            None
+        );
+        assert_eq!(
+            control_version_mismatch(&default_versions, "ext", "1.2"),
+            None
+        );
+        assert_eq!(
+            control_version_mismatch(&default_versions, "ext", "1.3.0"),
+            Some(VersionMismatchError {
+                extension: "ext".to_string(),
+                default_version: "1.2.0".to_string(),
+                version: "1.3.0".to_string(),
+            })
+        );
+        // Without a control file named after the extension, there's no telling which one to check
+        assert_eq!(
+            control_version_mismatch(&default_versions, "other", "1.3.0"),
+            None
+        );
+
+        let default_versions = BTreeMap::from([("pg_ext".to_string(), "2.0".to_string())]);
+        let err = control_version_mismatch(&default_versions, "ext", "2.0.1").unwrap();
+        assert_eq!(err.extension, "pg_ext");
+        assert_eq!(
+            control_version_mismatch(&default_versions, "ext", "2.0.0"),
+            None
+        );
+        assert!(control_version_mismatch(&default_versions, "ext", "2.0-beta").is_some());
+        assert_eq!(
+            control_version_mismatch(&BTreeMap::new(), "ext", "2.0.0"),
+            None
+        );
+    }
+
+    #[test]
+    fn detects_out_of_disk_space() {
+        let output = "Step 5/9 : RUN make\n\
+                      cp: error writing '/usr/lib/postgresql/15/lib/ext.so': No space left on device\n\
+                      make: *** [install] Error 1";
+        let err = OutOfDiskSpaceError::detect(output).unwrap();
+        assert_eq!(
+            err.message,
+            "cp: error writing '/usr/lib/postgresql/15/lib/ext.so': No space left on device"
+        );
+
+        assert!(OutOfDiskSpaceError::detect("npm ERR! code ENOSPC").is_some());
+        assert!(OutOfDiskSpaceError::detect("make: *** [install] Error 1").is_none());
+    }
+
+    #[test]
+    fn detects_offline_network_failures() {
+        let output = "    Updating crates.io index\n\
+                      warning: spurious network error: Could not resolve host: index.crates.io";
+        let err = OfflineNetworkError::detect(output).unwrap();
+        assert_eq!(
+            err.message,
+            "warning: spurious network error: Could not resolve host: index.crates.io"
+        );
+
+        assert!(OfflineNetworkError::detect(
+            "Temporary failure resolving 'deb.debian.org'\n\
+             W: Failed to fetch http://deb.debian.org/: Temporary failure in name resolution"
+        )
+        .is_some());
+        assert!(OfflineNetworkError::detect("make: *** [install] Error 1").is_none());
+    }
+
+    #[test]
+    fn parses_memory_limits() {
+        assert_eq!(parse_memory("1024").unwrap(), 1024);
+        assert_eq!(parse_memory("512k").unwrap(), 512 * 1024);
+        assert_eq!(parse_memory("512m").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_memory("2g").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_memory("2GB").unwrap(), 2 * 1024 * 1024 * 1024);
+
+        assert!(parse_memory("").is_err());
+        assert!(parse_memory("0g").is_err());
+        assert!(parse_memory("2t").is_err());
+        assert!(parse_memory("lots").is_err());
+    }
+
+    #[test]
+    fn parses_step_timeouts() {
+        assert_eq!(parse_step_timeout("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_step_timeout("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(
+            parse_step_timeout("1h 30m").unwrap(),
+            Duration::from_secs(90 * 60)
+        );
+
+        assert!(parse_step_timeout("").is_err());
+        assert!(parse_step_timeout("0s").is_err());
+        assert!(parse_step_timeout("10").is_err());
+
+        let err = StepTimeoutError {
+            step: "RUN make".to_string(),
+            timeout: Duration::from_secs(600),
+        };
+        assert_eq!(
+            err.to_string(),
+            "The build step 'RUN make' did not finish within --step-timeout (10m), it most likely hung"
+        );
+    }
+
+    #[test]
+    fn detects_killed_build_steps() {
+        let output = "The command '/bin/sh -c cargo pgrx package' returned a non-zero code: 137";
+        let err = OutOfMemoryError::detect(output, Some(2 << 30)).unwrap();
+        assert!(err.to_string().contains("--memory"));
+
+        let output = "process \"/bin/sh -c make\" did not complete successfully: exit code: 137";
+        assert!(OutOfMemoryError::detect(output, None).is_some());
+
+        let output = "The command '/bin/sh -c make' returned a non-zero code: 2";
+        assert!(OutOfMemoryError::detect(output, None).is_none());
+    }
+
+    #[test]
+    fn lays_out_version_1_archives() {
+        let v1 = |path: &str| v1_archive_path(Cow::Borrowed(Path::new(path))).into_owned();
+        assert_eq!(v1("extension/pgmq.control"), Path::new("pgmq.control"));
+        assert_eq!(
+            v1("pljava/pljava--1.6.5.sql"),
+            Path::new("pljava--1.6.5.sql")
+        );
+        // Other files keep their place under sharedir, as in version 2
+        assert_eq!(
+            v1("extension/unit_units.data"),
+            Path::new("extension/unit_units.data")
+        );
+        assert_eq!(v1("pgmq.control"), Path::new("pgmq.control"));
+    }
+
+    #[test]
+    fn detects_missing_jit_bitcode() {
+        let configure = "'--build=x86_64-linux-gnu' '--prefix=/usr' '--with-llvm' 'LLVM_CONFIG=/usr/bin/llvm-config-15'";
+        assert!(is_jit_enabled(configure));
+        assert!(!is_jit_enabled("'--prefix=/usr' '--with-openssl'"));
+
+        let with_bitcode = [
+            "ext.so".to_string(),
+            "bitcode/ext.index.bc".to_string(),
+            "bitcode/ext/ext.bc".to_string(),
+        ];
+        assert!(!lacks_bitcode(&with_bitcode));
+        assert!(lacks_bitcode(&with_bitcode[..1]));
+        // SQL-only extensions have nothing to compile
+        assert!(!lacks_bitcode(&[]));
+    }
+
+    /// The 64 byte header of a little-endian ELF64 shared library for `machine`
+    fn elf_header(machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 64];
+        header[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        header[16..18].copy_from_slice(&elf::abi::ET_DYN.to_le_bytes());
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        header[52..54].copy_from_slice(&64u16.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn checks_shared_library_architectures() {
+        assert_eq!(
+            elf_architecture(&elf_header(elf::abi::EM_X86_64)),
+            Some("amd64")
+        );
+        assert_eq!(
+            elf_architecture(&elf_header(elf::abi::EM_AARCH64)),
+            Some("arm64")
+        );
+        assert_eq!(
+            elf_architecture(&elf_header(elf::abi::EM_PPC64)),
+            Some("ppc64le")
+        );
+        assert_eq!(elf_architecture(b"CREATE FUNCTION f();"), None);
+
+        let libraries = [(PathBuf::from("ext.so"), "amd64")];
+        assert!(check_library_architectures(&libraries, "amd64", false).is_ok());
+        assert!(check_library_architectures(&libraries, "x86_64", false).is_ok());
+        let err = check_library_architectures(&libraries, "arm64", false).unwrap_err();
+        assert_eq!(err.found, "amd64");
+        assert!(err
+            .to_string()
+            .starts_with("ext.so was built for amd64, but --platform requested arm64"));
+        assert!(check_library_architectures(&libraries, "arm64", true).is_ok());
+    }
+
+    #[test]
+    fn checks_libraries_that_need_preloading() {
+        assert_eq!(preload_symbol(&elf_header(elf::abi::EM_X86_64)), None);
+        assert_eq!(preload_symbol(b"CREATE FUNCTION f();"), None);
+
+        let symbols = [(PathBuf::from("lib/pg_cron.so"), "RegisterBackgroundWorker")];
+        let fail_on_warn = [WarningCategory::PreloadMismatch];
+        let preloaded = ["pg_cron".to_string()];
+        let preload = Preload::All(true);
+        assert!(check_preload(&symbols, Some(&preload), &preloaded, &fail_on_warn).is_ok());
+        // Without preload, the library is only pointed out
+        assert!(check_preload(&symbols, None, &[], &fail_on_warn).is_ok());
+        let err =
+            check_preload(&symbols, Some(&Preload::All(false)), &[], &fail_on_warn).unwrap_err();
+        assert_eq!(err.category, WarningCategory::PreloadMismatch);
+        assert!(err
+            .message
+            .ends_with("but preload in Trunk.toml doesn't include pg_cron"));
+    }
+
+    #[test]
+    fn detects_failed_rust_toolchain_installs() {
+        let output = "Step 5/12 : RUN if [ -n \"${RUST_TOOLCHAIN}\" ]; then ...\n\
+                      error: invalid toolchain name: 'nightly-2099'\n\
+                      Failed to install the Rust toolchain nightly-2099\n";
+        let err = RustToolchainInstallError::detect(output).unwrap();
+        assert_eq!(err.toolchain, "nightly-2099");
+        assert!(RustToolchainInstallError::detect("error: could not compile").is_none());
+    }
+
+    #[test]
+    fn base_images_substitutes_build_args() {
+        let dockerfile = include_str!("./builders/Dockerfile.pgrx");
+        let build_args = HashMap::from([("PG_VERSION", "16"), ("PGRX_VERSION", "0.11.4")]);
+
+        assert_eq!(
+            base_images(dockerfile, &build_args),
+            vec!["quay.io/coredb/pgrx-builder:pg16-pgrx0.11.4"]
+        );
+    }
+
+    #[test]
+    fn default_dockerfiles_pass_checks() {
+        let pgrx = include_str!("./builders/Dockerfile.pgrx");
+
+        for builder in crate::commands::generic_build::BUNDLED_BUILDERS {
+            assert!(
+                check_dockerfile(builder.dockerfile, BuilderKind::Generic).is_empty(),
+                "{}",
+                builder.name
+            );
+        }
+        assert!(check_dockerfile(pgrx, BuilderKind::Pgrx).is_empty());
+    }
+
+    #[test]
+    fn checks_find_common_dockerfile_mistakes() {
+        assert_eq!(check_dockerfile("RUN make", BuilderKind::Generic).len(), 1);
+
+        let installs_in_image = "FROM builder\nRUN cd ext && \\\n    make && make install\n";
+        let warnings = check_dockerfile(installs_in_image, BuilderKind::Generic);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("--install-command"), "{warnings:?}");
+
+        let from_scratch =
+            "FROM builder AS build\nRUN make\nFROM scratch\nCOPY --from=build /out /\n";
+        let warnings = check_dockerfile(from_scratch, BuilderKind::Generic);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("scratch"), "{warnings:?}");
+
+        let no_package = "FROM pgrx-builder\n# RUN cargo pgrx package\nRUN cargo build\n";
+        assert_eq!(check_dockerfile(no_package, BuilderKind::Pgrx).len(), 1);
+    }
+
+    #[test]
+    fn base_images_uses_arg_defaults() {
+        let dockerfile = include_str!("./builders/Dockerfile.generic");
+
+        assert_eq!(
+            base_images(dockerfile, &HashMap::new()),
+            vec!["quay.io/coredb/c-builder:pg15"]
+        );
+    }
+
+    #[test]
+    fn base_images_uses_base_image_arg() {
+        let dockerfile = include_str!("./builders/Dockerfile.generic");
+        let build_args = HashMap::from([("BASE_IMAGE", "my-local-builder")]);
+
+        assert_eq!(
+            base_images(dockerfile, &build_args),
+            vec!["my-local-builder"]
+        );
+    }
+
+    #[test]
+    fn explains_base_image_pull_failures() {
+        let message = "pull access denied for my-local-builder, repository does not exist or may require 'docker login'";
+
+        let err = base_image_pull_error(message, PullPolicy::Always).unwrap();
+        assert!(err.to_string().contains("--pull=missing"));
+
+        let err = base_image_pull_error(message, PullPolicy::Missing).unwrap();
+        assert!(err.to_string().contains("not present locally"));
+
+        assert!(base_image_pull_error("make: *** Error 1", PullPolicy::Always).is_none());
+    }
+
+    #[test]
+    fn starts_debug_containers_like_the_build() {
+        assert_eq!(
+            debug_container_args("builder_1", Some("linux/arm64"), true, &["sh"]).join(" "),
+            "run --rm -it --user root --platform linux/arm64 --network none builder_1 sh"
+        );
+        assert_eq!(
+            debug_container_args("builder_1", None, false, &["sh"]).join(" "),
+            "run --rm -it --user root builder_1 sh"
+        );
+    }
+
+    #[test]
+    fn traces_image_builds_as_cli_command_lines() {
+        let options = BuildImageOptions {
+            t: "builder_1",
+            rm: true,
+            platform: "linux/arm64",
+            networkmode: "none",
+            memory: Some(1024),
+            buildargs: HashMap::from([("PG_VERSION", "15"), ("GITHUB_TOKEN", "ghp_x")]),
+            ..Default::default()
+        };
+        assert_eq!(
+            build_args_line(&options, Path::new("ext")).join(" "),
+            "build --file - --tag builder_1 --platform linux/arm64 --network none --memory 1024 \
+             --build-arg GITHUB_TOKEN=<redacted> --build-arg PG_VERSION=15 --rm ext"
+        );
+
+        let options = BuildImageOptions {
+            t: "builder_1",
+            version: BuilderVersion::BuilderBuildKit,
+            ..Default::default()
+        };
+        assert_eq!(
+            build_args_line(&options, Path::new("ext")).join(" "),
+            "buildx build --file - --tag builder_1 ext"
+        );
+    }
+
+    #[test]
+    fn matches_image_platforms() {
+        let arm64 = ImagePlatform::parse("linux/arm64").unwrap();
+        let arm64_v8 = ImagePlatform::parse("linux/arm64/v8").unwrap();
+        assert_eq!(arm64_v8.to_string(), "linux/arm64/v8");
+        assert!(arm64_v8.satisfies(&arm64));
+        assert!(!arm64.satisfies(&arm64_v8));
+        assert!(!ImagePlatform::parse("linux/amd64")
+            .unwrap()
+            .satisfies(&arm64));
+        assert!(ImagePlatform::parse("linux").is_none());
+    }
+
+    #[test]
+    fn reads_manifest_platforms() {
+        let index = serde_json::json!({
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {"platform": {"architecture": "amd64", "os": "linux"}},
+                {"platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}},
+                {"platform": {"architecture": "unknown", "os": "unknown"}},
+            ],
+        });
+        let platforms: Vec<String> = manifest_platforms(&index)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(platforms, ["linux/amd64", "linux/arm64/v8"]);
+
+        let verbose_image = serde_json::json!({
+            "Ref": "docker.io/library/postgres:15",
+            "Descriptor": {"platform": {"architecture": "amd64", "os": "linux"}},
+        });
+        assert_eq!(
+            manifest_platforms(&verbose_image).unwrap(),
+            [ImagePlatform::parse("linux/amd64").unwrap()]
+        );
+        let verbose_list = serde_json::json!([verbose_image]);
+        assert_eq!(manifest_platforms(&verbose_list).unwrap().len(), 1);
+
+        let image = serde_json::json!({"schemaVersion": 2, "config": {}, "layers": []});
+        assert!(manifest_platforms(&image).is_none());
+    }
+
+    #[test]
+    fn base_images_skips_stages_and_scratch() {
+        let dockerfile = "FROM --platform=linux/amd64 rust:1.70 AS builder\n\
+                          FROM builder\n\
+                          FROM scratch\n\
+                          COPY --from=builder /app /app\n";
+
+        assert_eq!(base_images(dockerfile, &HashMap::new()), vec!["rust:1.70"]);
+    }
+}
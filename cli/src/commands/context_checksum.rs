@@ -0,0 +1,140 @@
+//! A digest of what a build is made from, for `trunk build --print-context-checksum`.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::commands::context_filter::ContextFilter;
+
+/// Hashes every file under `context` that `filter` includes, the way it is sent to the container
+/// runtime, along with
+/// `settings`, as `(setting, value)`, and the contents of a custom Dockerfile. The bundled
+/// Dockerfiles are covered by trunk's version, which is hashed too. Only paths relative to
+/// `context`, file modes and contents are hashed, not timestamps, so identical inputs give the
+/// same digest
+pub fn context_checksum(
+    context: &Path,
+    filter: &ContextFilter,
+    settings: &[(&str, String)],
+    dockerfile: Option<&str>,
+) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("trunk {}\n", env!("CARGO_PKG_VERSION")));
+    for (setting, value) in settings {
+        hasher.update(format!("setting {setting} = {value}\n"));
+    }
+    if let Some(dockerfile) = dockerfile {
+        hasher.update(format!("dockerfile {}\n", dockerfile.len()));
+        hasher.update(dockerfile);
+    }
+    hash_dir(&mut hasher, context, filter, Path::new(""))?;
+
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn hash_dir(
+    hasher: &mut Sha256,
+    root: &Path,
+    filter: &ContextFilter,
+    relative: &Path,
+) -> anyhow::Result<()> {
+    let dir = root.join(relative);
+    let mut entries = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        if !metadata.is_dir() && !filter.includes(&path) {
+            continue;
+        }
+        if metadata.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            hasher.update(format!(
+                "symlink {} -> {}\n",
+                path.display(),
+                target.display()
+            ));
+        } else if metadata.is_dir() {
+            hasher.update(format!("dir {} {mode:o}\n", path.display()));
+            hash_dir(hasher, root, filter, &path)?;
+        } else {
+            let contents = fs::read(entry.path())
+                .with_context(|| format!("Failed to read {}", entry.path().display()))?;
+            hasher.update(format!(
+                "file {} {mode:o} {}\n",
+                path.display(),
+                contents.len()
+            ));
+            hasher.update(contents);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_the_context() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sql")).unwrap();
+        fs::write(dir.path().join("Makefile"), "install:\n").unwrap();
+        fs::write(dir.path().join("sql").join("ext--1.0.sql"), "SELECT 1;").unwrap();
+        let settings = [("pg_version", "15".to_string())];
+        let checksum =
+            || context_checksum(dir.path(), &ContextFilter::default(), &settings, None).unwrap();
+
+        let first = checksum();
+        assert_eq!(first.len(), 64);
+        // Rewriting a file with the same contents only changes its timestamps
+        fs::write(dir.path().join("Makefile"), "install:\n").unwrap();
+        assert_eq!(checksum(), first);
+
+        fs::write(dir.path().join("sql").join("ext--1.0.sql"), "SELECT 2;").unwrap();
+        assert_ne!(checksum(), first);
+        let second = checksum();
+        assert_ne!(
+            context_checksum(
+                dir.path(),
+                &ContextFilter::default(),
+                &[("pg_version", "16".to_string())],
+                None
+            )
+            .unwrap(),
+            second
+        );
+        assert_ne!(
+            context_checksum(
+                dir.path(),
+                &ContextFilter::default(),
+                &settings,
+                Some("FROM ubuntu")
+            )
+            .unwrap(),
+            second
+        );
+
+        // Excluded files aren't sent, so they don't change the checksum. Directories always are
+        let filter = ContextFilter {
+            exclude: vec![glob::Pattern::new("fixtures").unwrap()],
+            include: Vec::new(),
+        };
+        fs::create_dir(dir.path().join("fixtures")).unwrap();
+        let filtered = context_checksum(dir.path(), &filter, &settings, None).unwrap();
+        fs::write(dir.path().join("fixtures").join("large.sql"), "SELECT 3;").unwrap();
+        assert_eq!(
+            context_checksum(dir.path(), &filter, &settings, None).unwrap(),
+            filtered
+        );
+        assert_ne!(checksum(), second);
+    }
+}
@@ -0,0 +1,145 @@
+//! The files of the build context that are sent to the container runtime, refined with
+//! `trunk build --context-exclude` and `--context-include`.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path};
+
+use anyhow::{anyhow, Context};
+
+/// Globs matched against paths relative to the build context. A file is left out if it matches
+/// an exclude glob and no include glob. A glob matches a path if it matches the path itself or
+/// one of its parent directories, so `fixtures` excludes everything under `fixtures/`
+#[derive(Clone, Debug, Default)]
+pub struct ContextFilter {
+    pub exclude: Vec<glob::Pattern>,
+    pub include: Vec<glob::Pattern>,
+}
+
+impl ContextFilter {
+    /// Whether the file at `relative`, a path relative to the build context, is sent
+    pub fn includes(&self, relative: &Path) -> bool {
+        let matches = |patterns: &[glob::Pattern]| {
+            let options = glob::MatchOptions {
+                require_literal_separator: true,
+                ..Default::default()
+            };
+            relative.ancestors().any(|path| {
+                !path.as_os_str().is_empty()
+                    && patterns
+                        .iter()
+                        .any(|pattern| pattern.matches_path_with(path, options))
+            })
+        };
+
+        !matches(&self.exclude) || matches(&self.include)
+    }
+
+    /// Appends the directories under `context` and the files this filter includes to `tar`,
+    /// in a stable order. Returns the number of files appended
+    pub fn append_context<W: Write>(
+        &self,
+        tar: &mut tar::Builder<W>,
+        context: &Path,
+    ) -> anyhow::Result<usize> {
+        tar.append_dir(".", context)?;
+        self.append_dir(tar, context, Path::new(""))
+    }
+
+    fn append_dir<W: Write>(
+        &self,
+        tar: &mut tar::Builder<W>,
+        context: &Path,
+        relative: &Path,
+    ) -> anyhow::Result<usize> {
+        let dir = context.join(relative);
+        let mut entries = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut files = 0;
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            // Symlinks are followed, as when the whole context is sent
+            if entry.path().is_dir() {
+                tar.append_dir(&path, entry.path())?;
+                files += self.append_dir(tar, context, &path)?;
+            } else if self.includes(&path) {
+                tar.append_path_with_name(entry.path(), &path)?;
+                files += 1;
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+/// `--context-exclude` and `--context-include` globs are matched against paths relative to the
+/// build context
+pub fn parse_context_glob(flag: &str, pattern: &str) -> anyhow::Result<glob::Pattern> {
+    let relative = Path::new(pattern);
+    if relative.is_absolute()
+        || relative
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+    {
+        return Err(anyhow!(
+            "{flag} must be a glob relative to the build context, without '..' components. Got: {pattern}"
+        ));
+    }
+
+    glob::Pattern::new(pattern).with_context(|| format!("Invalid glob in {flag}: {pattern}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_the_context() {
+        let glob = |pattern| parse_context_glob("--context-exclude", pattern).unwrap();
+        let filter = ContextFilter {
+            exclude: vec![glob("fixtures"), glob("**/*.csv")],
+            include: vec![glob("fixtures/small.sql")],
+        };
+        assert!(filter.includes(Path::new("Makefile")));
+        assert!(!filter.includes(Path::new("fixtures/large.sql")));
+        assert!(filter.includes(Path::new("fixtures/small.sql")));
+        assert!(!filter.includes(Path::new("data/nested/rows.csv")));
+        assert!(filter.includes(Path::new("src/fixtures.c")));
+        assert!(ContextFilter::default().includes(Path::new("fixtures/large.sql")));
+
+        for invalid in ["/etc", "../sibling"] {
+            let err = parse_context_glob("--context-exclude", invalid).unwrap_err();
+            assert!(
+                err.to_string().contains("relative to the build context"),
+                "{err}"
+            );
+        }
+        assert!(parse_context_glob("--context-include", "[fixtures").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("fixtures")).unwrap();
+        for file in ["Makefile", "fixtures/large.sql", "fixtures/small.sql"] {
+            fs::write(dir.path().join(file), file).unwrap();
+        }
+        let mut tar = tar::Builder::new(Vec::new());
+        assert_eq!(filter.append_context(&mut tar, dir.path()).unwrap(), 2);
+        let tar = tar.into_inner().unwrap();
+        let mut paths: Vec<String> = tar::Archive::new(tar.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        paths.sort();
+        assert_eq!(paths, [".", "Makefile", "fixtures", "fixtures/small.sql"]);
+    }
+}
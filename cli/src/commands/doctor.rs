@@ -0,0 +1,508 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::bail;
+use async_trait::async_trait;
+use bollard::models::SystemInfo;
+use bollard::system::Version;
+use bollard::Docker;
+use clap::Args;
+use tokio_task_manager::Task;
+
+use super::containers::{is_podman_version, is_rootless, runtime_description};
+use super::SubCommand;
+
+/// The oldest Docker API that builds images with BuildKit, from Docker 18.09
+const BUILDKIT_MIN_API_VERSION: (u32, u32) = (1, 39);
+
+const BINFMT_MISC_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+#[derive(Args)]
+pub struct DoctorCommand {
+    /// The file path of the extension to check the environment for
+    #[arg(short = 'p', long = "path", default_value = ".")]
+    path: String,
+    /// The output directory to check for write access. Defaults to the .trunk directory under --path
+    #[arg(short = 'o', long = "output-path")]
+    output_path: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Pass,
+    /// Builds work, but some features don't
+    Warn,
+    /// Builds can't work until this is fixed
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// How to fix a warning or a failure
+    hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl SubCommand for DoctorCommand {
+    async fn execute(&self, _task: Task) -> Result<(), anyhow::Error> {
+        let output_path = match &self.output_path {
+            Some(output_path) => PathBuf::from(output_path),
+            None => Path::new(&self.path).join(".trunk"),
+        };
+
+        let mut checks = Vec::new();
+        let docker = Docker::connect_with_local_defaults().map_err(|err| err.to_string());
+        let version = match &docker {
+            Ok(docker) => docker.version().await.map_err(|err| err.to_string()),
+            Err(err) => Err(err.clone()),
+        };
+        match (&docker, &version) {
+            (Ok(docker), Ok(version)) => {
+                checks.push(check_runtime(version));
+                checks.push(check_buildkit(version));
+                checks.push(check_emulation(version, Path::new(BINFMT_MISC_DIR)));
+                match docker.info().await {
+                    Ok(info) => checks.push(check_rootless(&info)),
+                    Err(err) => checks.push(Check::warn(
+                        "rootless",
+                        format!("not checked, the container runtime didn't describe itself: {err}"),
+                        "check that the container runtime is healthy",
+                    )),
+                }
+            }
+            (_, Err(err)) | (Err(err), _) => {
+                checks.push(runtime_unreachable(err));
+                for name in ["BuildKit", "multi-arch", "rootless"] {
+                    checks.push(Check::warn(
+                        name,
+                        "not checked, the container runtime is unreachable",
+                        "fix the container runtime first",
+                    ));
+                }
+            }
+        }
+        checks.push(check_output_path(&output_path));
+        checks.push(check_git());
+
+        print!("{}", report(&checks));
+
+        let failed = checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count();
+        if failed > 0 {
+            bail!(
+                "{failed} required check(s) failed, trunk build will not work until they are fixed"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn report(checks: &[Check]) -> String {
+    let mut report = String::new();
+    for check in checks {
+        report.push_str(&format!(
+            "[{}] {}: {}\n",
+            check.status, check.name, check.detail
+        ));
+        if let Some(hint) = &check.hint {
+            report.push_str(&format!("       hint: {hint}\n"));
+        }
+    }
+
+    report
+}
+
+fn check_runtime(version: &Version) -> Check {
+    Check::pass(
+        "container runtime",
+        format!(
+            "{} (API {}) on {}/{}",
+            runtime_description(version),
+            version.api_version.as_deref().unwrap_or("unknown"),
+            version.os.as_deref().unwrap_or("unknown"),
+            version.arch.as_deref().unwrap_or("unknown"),
+        ),
+    )
+}
+
+fn runtime_unreachable(err: &str) -> Check {
+    let hint = if err.to_lowercase().contains("permission denied") {
+        "your user can't access the Docker socket. Add it to the docker group \
+         (sudo usermod -aG docker $USER) and log in again"
+    } else {
+        "install Docker or Podman and start it. If the daemon isn't on the default socket, \
+         point DOCKER_HOST at it"
+    };
+
+    Check::fail(
+        "container runtime",
+        format!("can't reach the container runtime: {err}"),
+        hint,
+    )
+}
+
+/// Parses an API version such as `1.43`
+fn parse_api_version(api_version: &str) -> Option<(u32, u32)> {
+    let (major, minor) = api_version.split_once('.')?;
+
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+fn check_buildkit(version: &Version) -> Check {
+    if is_podman_version(version) {
+        return Check::warn(
+            "BuildKit",
+            "Podman does not support BuildKit through its Docker-compatible API",
+            "builds use the classic builder, --buildkit has no effect",
+        );
+    }
+
+    match version.api_version.as_deref().and_then(parse_api_version) {
+        Some(api_version) if api_version >= BUILDKIT_MIN_API_VERSION => {
+            Check::pass("BuildKit", "available, enable it with --buildkit")
+        }
+        _ => Check::warn(
+            "BuildKit",
+            format!(
+                "Docker API {} is too old for BuildKit",
+                version.api_version.as_deref().unwrap_or("unknown")
+            ),
+            "upgrade to Docker 18.09 or later to use --buildkit",
+        ),
+    }
+}
+
+/// Rootless runtimes build like any other, but need the cgroup controllers delegated to the user
+/// to apply `--cpus` and `--memory`
+fn check_rootless(info: &SystemInfo) -> Check {
+    if !is_rootless(info) {
+        return Check::pass("rootless", "no, the container runtime runs as root");
+    }
+
+    let unsupported: Vec<&str> = [
+        ("--cpus", info.cpu_cfs_quota),
+        ("--memory", info.memory_limit),
+    ]
+    .into_iter()
+    .filter(|(_, supported)| *supported == Some(false))
+    .map(|(flag, _)| flag)
+    .collect();
+    if unsupported.is_empty() {
+        Check::pass("rootless", "yes, builds need no extra setup")
+    } else {
+        Check::warn(
+            "rootless",
+            format!(
+                "yes, but the container runtime can't apply {}",
+                unsupported.join(" or ")
+            ),
+            "use cgroup v2 and delegate the cpu and memory controllers to your user, \
+             see https://rootlesscontaine.rs/getting-started/common/cgroup2/",
+        )
+    }
+}
+
+/// The architectures that `binfmt_misc_dir` has an enabled QEMU handler for, e.g. `aarch64`
+fn emulated_architectures(binfmt_misc_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(binfmt_misc_dir) else {
+        return Vec::new();
+    };
+
+    let mut architectures: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let architecture = name.strip_prefix("qemu-")?.to_string();
+            let handler = fs::read_to_string(entry.path()).ok()?;
+            (handler.lines().next() == Some("enabled")).then_some(architecture)
+        })
+        .collect();
+    architectures.sort();
+
+    architectures
+}
+
+/// Whether the daemon can run images of the platforms trunk builds for other than its own,
+/// `linux/amd64` and `linux/arm64`
+fn check_emulation(version: &Version, binfmt_misc_dir: &Path) -> Check {
+    let (other_platform, qemu_architecture) = match version.arch.as_deref() {
+        Some("amd64") => ("linux/arm64", "aarch64"),
+        Some("arm64") => ("linux/amd64", "x86_64"),
+        arch => {
+            return Check::warn(
+                "multi-arch",
+                format!(
+                    "trunk builds for linux/amd64 and linux/arm64, the daemon runs on {}",
+                    arch.unwrap_or("an unknown architecture")
+                ),
+                "builds for either platform need QEMU emulation",
+            )
+        }
+    };
+
+    // Docker Desktop runs the daemon in a VM that comes with emulation set up
+    if !cfg!(target_os = "linux") {
+        return Check::pass(
+            "multi-arch",
+            format!(
+                "not checked, {other_platform} builds rely on the emulation of the runtime's VM"
+            ),
+        );
+    }
+
+    if emulated_architectures(binfmt_misc_dir)
+        .iter()
+        .any(|architecture| architecture == qemu_architecture)
+    {
+        Check::pass("multi-arch", format!("QEMU can emulate {other_platform}"))
+    } else {
+        Check::warn(
+            "multi-arch",
+            format!(
+                "no QEMU handler for {qemu_architecture}, builds for {other_platform} will fail"
+            ),
+            "register QEMU with docker run --privileged --rm tonistiigi/binfmt --install all",
+        )
+    }
+}
+
+/// Whether `output_path` can be written to, or created in its closest existing parent
+fn check_output_path(output_path: &Path) -> Check {
+    const NAME: &str = "output path";
+
+    // The last ancestor of a relative path is empty, meaning the current directory
+    let existing = output_path
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+
+    if !existing.is_dir() {
+        return Check::fail(
+            NAME,
+            format!(
+                "{} can't be created, {} is not a directory",
+                output_path.display(),
+                existing.display()
+            ),
+            "pass a different directory with --output-path",
+        );
+    }
+
+    match tempfile::Builder::new()
+        .prefix(".trunk-doctor-")
+        .tempfile_in(existing)
+    {
+        Ok(_) if existing == output_path => {
+            Check::pass(NAME, format!("{} is writable", output_path.display()))
+        }
+        Ok(_) => Check::pass(
+            NAME,
+            format!(
+                "{} can be created in {}",
+                output_path.display(),
+                existing.display()
+            ),
+        ),
+        Err(err) => Check::fail(
+            NAME,
+            format!("{} is not writable: {err}", existing.display()),
+            "fix the directory's permissions or pass a different directory with --output-path",
+        ),
+    }
+}
+
+fn check_git() -> Check {
+    let version = which::which("git").ok().and_then(|git| {
+        let output = Command::new(git).arg("--version").output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+
+    match version {
+        Some(version) => Check::pass("git", version),
+        None => Check::warn(
+            "git",
+            "git is not installed",
+            "builds don't need it, but install git to check out extension sources",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docker_version(api_version: &str, arch: &str) -> Version {
+        Version {
+            version: Some("24.0.7".to_string()),
+            api_version: Some(api_version.to_string()),
+            arch: Some(arch.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn checks_buildkit_api_version() {
+        assert_eq!(
+            check_buildkit(&docker_version("1.43", "amd64")).status,
+            Status::Pass
+        );
+        assert_eq!(
+            check_buildkit(&docker_version("1.38", "amd64")).status,
+            Status::Warn
+        );
+        assert_eq!(parse_api_version("1.9"), Some((1, 9)));
+        assert!(parse_api_version("latest").is_none());
+    }
+
+    #[test]
+    fn describes_the_runtime() {
+        let docker = docker_version("1.43", "amd64");
+        assert_eq!(
+            check_runtime(&docker).detail,
+            "Docker 24.0.7 (API 1.43) on unknown/amd64"
+        );
+
+        let podman = Version {
+            version: None,
+            components: Some(vec![bollard::system::VersionComponents {
+                name: "Podman Engine".to_string(),
+                version: "4.9.3".to_string(),
+                details: None,
+            }]),
+            ..docker
+        };
+        assert_eq!(runtime_description(&podman), "Podman unknown");
+    }
+
+    #[test]
+    fn checks_rootless_resource_limits() {
+        let rootful = SystemInfo {
+            security_options: Some(vec!["name=seccomp,profile=default".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(check_rootless(&rootful).status, Status::Pass);
+        assert!(!is_rootless(&rootful));
+
+        let mut rootless = SystemInfo {
+            security_options: Some(vec![
+                "name=seccomp,profile=default".to_string(),
+                "name=rootless".to_string(),
+            ]),
+            cpu_cfs_quota: Some(true),
+            memory_limit: Some(true),
+            ..Default::default()
+        };
+        assert!(is_rootless(&rootless));
+        assert_eq!(check_rootless(&rootless).status, Status::Pass);
+
+        rootless.memory_limit = Some(false);
+        let check = check_rootless(&rootless);
+        assert_eq!(check.status, Status::Warn);
+        assert!(
+            check.detail.ends_with("can't apply --memory"),
+            "{}",
+            check.detail
+        );
+    }
+
+    #[test]
+    fn finds_enabled_qemu_handlers() {
+        let binfmt_misc = tempfile::tempdir().unwrap();
+        fs::write(
+            binfmt_misc.path().join("qemu-aarch64"),
+            "enabled\ninterpreter /usr/bin/qemu-aarch64\n",
+        )
+        .unwrap();
+        fs::write(binfmt_misc.path().join("qemu-riscv64"), "disabled\n").unwrap();
+        fs::write(binfmt_misc.path().join("status"), "enabled\n").unwrap();
+
+        assert_eq!(emulated_architectures(binfmt_misc.path()), ["aarch64"]);
+        if cfg!(target_os = "linux") {
+            let amd64 = docker_version("1.43", "amd64");
+            let arm64 = docker_version("1.43", "arm64");
+            assert_eq!(
+                check_emulation(&amd64, binfmt_misc.path()).status,
+                Status::Pass
+            );
+            assert_eq!(
+                check_emulation(&arm64, binfmt_misc.path()).status,
+                Status::Warn
+            );
+        }
+    }
+
+    #[test]
+    fn checks_output_path_is_writable() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let check = check_output_path(&tmp_dir.path().join(".trunk"));
+        assert_eq!(check.status, Status::Pass);
+        assert!(check.detail.contains("can be created"), "{}", check.detail);
+        assert!(fs::read_dir(tmp_dir.path()).unwrap().next().is_none());
+
+        let file = tmp_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let check = check_output_path(&file.join(".trunk"));
+        assert_eq!(check.status, Status::Fail);
+        assert!(
+            check.detail.contains("is not a directory"),
+            "{}",
+            check.detail
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Encryption of build artifacts at rest with age, for `trunk build --encrypt`, and their
+//! decryption with `--decrypt-key`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::commands::output_layout::OutputLayout;
+
+const AGE: &str = "age";
+/// The only scheme trunk encrypts with, recorded in the sidecar
+pub const AGE_SCHEME: &str = "age";
+
+/// What the sidecar next to an encrypted archive records. It isn't encrypted, so that the
+/// scheme is known before the archive is decrypted. It records nothing derived from the
+/// contents of the archive: age authenticates the ciphertext, so a tampered archive fails to
+/// decrypt
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptionSidecar {
+    pub scheme: String,
+    /// The file name of the archive before it was encrypted
+    pub archive: String,
+}
+
+/// Checks the settings that don't depend on age being installed
+pub fn validate_encryption(
+    encrypt: bool,
+    key: Option<&Path>,
+    output_layout: OutputLayout,
+    resume: bool,
+) -> anyhow::Result<()> {
+    if encrypt && output_layout == OutputLayout::Registry {
+        bail!(
+            "--encrypt can't be used with --output-layout registry, as registries serve the \
+             unencrypted archive"
+        );
+    }
+    if encrypt && resume {
+        bail!("--encrypt can't be used with --resume, as an encrypted archive can't be checked without its key");
+    }
+    match (encrypt, key) {
+        (true, None) => bail!(
+            "--encrypt requires --encrypt-key, the file of age recipients to encrypt the \
+             archive to"
+        ),
+        (false, Some(_)) => bail!("--encrypt-key requires --encrypt"),
+        (true, Some(key)) if !key.is_file() => {
+            bail!("Encryption key {} does not exist", key.display())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Finds age on the PATH, so a build that can't be encrypted fails before it starts
+pub fn find_age(flag: &str) -> anyhow::Result<PathBuf> {
+    which::which(AGE)
+        .map_err(|_| anyhow!("{flag} requires {AGE} on the PATH, but it was not found"))
+}
+
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Where [`encrypt_file`] writes the encrypted copy of `file`
+pub fn encrypted_path(file: &Path) -> PathBuf {
+    with_extension(file, ".age")
+}
+
+/// The sidecar of the encrypted archive at `encrypted`
+pub fn sidecar_path(encrypted: &Path) -> PathBuf {
+    with_extension(encrypted, ".json")
+}
+
+/// Encrypts `file` to the age recipients in `recipients`, writing `<file>.age`, then removes
+/// `file`. Returns the encrypted file
+pub fn encrypt_file(file: &Path, recipients: &Path) -> anyhow::Result<PathBuf> {
+    let program = find_age("--encrypt")?;
+    let encrypted = encrypted_path(file);
+    info!("Encrypting {} with {AGE}", file.display());
+    let status = Command::new(&program)
+        .arg("--encrypt")
+        .arg("--recipients-file")
+        .arg(recipients)
+        .arg("--output")
+        .arg(&encrypted)
+        .arg(file)
+        .status()
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    if !status.success() {
+        // age may have written part of the output before failing
+        let _ = fs::remove_file(&encrypted);
+        bail!("{AGE} failed to encrypt {}: {status}", file.display());
+    }
+    fs::remove_file(file)
+        .with_context(|| format!("Failed to remove the unencrypted {}", file.display()))?;
+
+    Ok(encrypted)
+}
+
+/// Encrypts the archive at `artifact` like [`encrypt_file`], and writes the sidecar
+/// `<artifact>.age.json`. Returns the encrypted archive and the sidecar
+pub fn encrypt_artifact(artifact: &Path, recipients: &Path) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let encrypted = encrypt_file(artifact, recipients)?;
+    let sidecar = EncryptionSidecar {
+        scheme: AGE_SCHEME.to_string(),
+        archive: artifact
+            .file_name()
+            .unwrap_or(artifact.as_os_str())
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let sidecar_path = sidecar_path(&encrypted);
+    fs::write(&sidecar_path, serde_json::to_string_pretty(&sidecar)?)
+        .with_context(|| format!("Failed to write {}", sidecar_path.display()))?;
+
+    Ok((encrypted, sidecar_path))
+}
+
+/// Decrypts the archive at `encrypted` with the age identity in `identity`. age fails if the
+/// archive was tampered with. Returns the file name of the archive, from its sidecar if there is
+/// one, and its contents
+pub fn decrypt_artifact(encrypted: &Path, identity: &Path) -> anyhow::Result<(String, Vec<u8>)> {
+    let sidecar_path = sidecar_path(encrypted);
+    let sidecar = match fs::read(&sidecar_path) {
+        Ok(contents) => Some(
+            serde_json::from_slice::<EncryptionSidecar>(&contents)
+                .with_context(|| format!("Failed to parse {}", sidecar_path.display()))?,
+        ),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", sidecar_path.display()))
+        }
+    };
+    if let Some(sidecar) = &sidecar {
+        if sidecar.scheme != AGE_SCHEME {
+            bail!(
+                "{} was encrypted with {}, but trunk only decrypts {AGE_SCHEME}",
+                encrypted.display(),
+                sidecar.scheme
+            );
+        }
+    }
+
+    let program = find_age("--decrypt-key")?;
+    let output = Command::new(&program)
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(identity)
+        .arg(encrypted)
+        .output()
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    if !output.status.success() {
+        bail!(
+            "{AGE} failed to decrypt {}: {}",
+            encrypted.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let name = match sidecar {
+        Some(sidecar) => {
+            // Only the file name, so that the archive stays in the directory it's decrypted to
+            Path::new(&sidecar.archive)
+                .file_name()
+                .with_context(|| format!("{} names no archive", sidecar_path.display()))?
+                .to_string_lossy()
+                .into_owned()
+        }
+        None => encrypted
+            .file_stem()
+            .unwrap_or(encrypted.as_os_str())
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    Ok((name, output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_encryption_settings() {
+        let flat = OutputLayout::Flat;
+        assert!(validate_encryption(false, None, flat, false).is_ok());
+        let err = validate_encryption(true, None, flat, false).unwrap_err();
+        assert!(err.to_string().contains("requires --encrypt-key"), "{err}");
+        assert!(
+            validate_encryption(false, Some(Path::new("recipients.txt")), flat, false).is_err()
+        );
+        let missing = Path::new("/nonexistent/recipients.txt");
+        assert!(validate_encryption(true, Some(missing), flat, false).is_err());
+
+        let recipients = tempfile::NamedTempFile::new().unwrap();
+        assert!(validate_encryption(true, Some(recipients.path()), flat, false).is_ok());
+        assert!(validate_encryption(true, Some(recipients.path()), flat, true).is_err());
+        assert!(
+            validate_encryption(true, Some(recipients.path()), OutputLayout::Registry, false)
+                .is_err()
+        );
+        assert_eq!(
+            sidecar_path(Path::new("out/ext-1.0.0-pg15.tar.gz.age")),
+            Path::new("out/ext-1.0.0-pg15.tar.gz.age.json")
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_schemes() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = dir.path().join("ext-1.0.0-pg15.tar.gz.age");
+        fs::write(&encrypted, "encrypted").unwrap();
+        let sidecar = EncryptionSidecar {
+            scheme: "gpg".to_string(),
+            archive: "ext-1.0.0-pg15.tar.gz".to_string(),
+        };
+        fs::write(
+            sidecar_path(&encrypted),
+            serde_json::to_string(&sidecar).unwrap(),
+        )
+        .unwrap();
+
+        let err = decrypt_artifact(&encrypted, Path::new("identity.txt")).unwrap_err();
+        assert!(err.to_string().contains("was encrypted with gpg"), "{err}");
+    }
+}
@@ -1,6 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
 
 use std::fs;
 
@@ -12,14 +13,23 @@ use tokio::sync::mpsc;
 
 use tokio_task_manager::Task;
 
+use crate::build_log::tee_println;
+use crate::changelog::Changelog;
+use crate::commands::build::BuildOutput;
 use crate::commands::containers::{
-    build_image, exec_in_container, exec_in_container_with_exit_code, locate_makefile,
-    makefile_contains_target, package_installed_extension_files, run_temporary_container,
-    start_postgres,
+    base_image, build_image, container_path, exec_in_container, exec_in_container_as,
+    exec_in_container_with_exit_code, locate_makefile, makefile_contains_target,
+    package_installed_extension_files, run_temporary_container, start_postgres,
+    stop_before_install, toolchain_versions, ImageBuildOptions, NoInstall, OfflineNetworkError,
+    OutOfMemoryError, StepTimeoutError, Strip, GENERIC_BUILDER_IMAGE_PREFIX, GENERIC_TOOLCHAIN,
 };
+use crate::commands::integration_test::run_integration_test;
 use crate::commands::license::{copy_licenses, find_licenses};
-use crate::config::{ExtensionConfiguration, LoadableLibrary};
+use crate::config::{ControlFields, ExtensionConfiguration, LoadableLibrary, Preload};
+use crate::manifest::{ExtensionKind, SupportedPgVersions};
+use crate::timings::BuildTimings;
 use crate::trunk_toml::SystemDependencies;
+use crate::warnings::WarningCategory;
 use crate::{pg_release_for_version, pg_version_to_str};
 
 #[derive(Error, Debug)]
@@ -66,12 +76,621 @@ pub enum GenericBuildError {
 // docker diff 05a11b4b1bd5
 //
 // Any file that has changed, copy out of the container and into the trunk package
+/// Where the install command puts the extension's files, when that differs from `pg_config`'s
+/// `--pkglibdir` and `--sharedir`/extension. Relative paths are relative to `pg_config --prefix`.
+#[derive(Clone, Debug, Default)]
+pub struct InstallLayout {
+    /// Directory containing the installed shared libraries and bitcode
+    pub lib_dir: Option<String>,
+    /// Directory containing the installed SQL scripts
+    pub sql_dir: Option<String>,
+    /// Directory containing the installed control file
+    pub control_dir: Option<String>,
+    /// Other absolute prefixes the install command installs to, e.g. `/opt/ext` for
+    /// `make install prefix=/opt/ext`. Their files are captured as if installed under
+    /// `pg_config --prefix`.
+    pub prefixes: Vec<String>,
+}
+
+/// Checks that each of `prefixes` is an absolute path without `..`
+pub fn validate_install_prefixes(prefixes: &[String]) -> Result<(), anyhow::Error> {
+    for prefix in prefixes {
+        let path = Path::new(prefix);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            anyhow::bail!("--prefix must be an absolute path without '..'. Got: {prefix}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Where each extension file the install command wrote under one of `prefixes` goes under
+/// `pg_prefix`, with the prefix it came from. Files under nested prefixes belong to the
+/// innermost one.
+fn prefix_destinations(
+    changed_files: &[String],
+    prefixes: &[String],
+    pg_prefix: &Path,
+    inclusion_patterns: &[glob::Pattern],
+) -> Vec<(String, PathBuf, String)> {
+    const EXTENSION_FILES: [&str; 4] = [".so", ".bc", ".sql", ".control"];
+
+    changed_files
+        .iter()
+        .filter(|file| {
+            EXTENSION_FILES.iter().any(|suffix| file.ends_with(suffix))
+                || inclusion_patterns
+                    .iter()
+                    .any(|pattern| pattern.matches(file))
+        })
+        .filter_map(|file| {
+            let (prefix, relative) = prefixes
+                .iter()
+                .filter_map(|prefix| Some((prefix, Path::new(file).strip_prefix(prefix).ok()?)))
+                .min_by_key(|(_, relative)| relative.components().count())?;
+            let destination = pg_prefix.join(relative);
+            (destination != Path::new(file)).then(|| (file.clone(), destination, prefix.clone()))
+        })
+        .collect()
+}
+
+/// Copies the extension files installed under the `prefixes` of `layout` to the same place
+/// under `pg_config --prefix`, so that they are captured with the rest. A file that is already
+/// there with the same contents is skipped, one with different contents fails the build.
+/// Returns the prefix each copied file came from, keyed by where it was copied to.
+async fn capture_install_prefixes(
+    docker: &Docker,
+    container_id: &str,
+    layout: &InstallLayout,
+    inclusion_patterns: &[glob::Pattern],
+) -> Result<BTreeMap<PathBuf, String>, anyhow::Error> {
+    let mut captured = BTreeMap::new();
+    if layout.prefixes.is_empty() {
+        return Ok(captured);
+    }
+
+    let pg_prefix = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--prefix"],
+        None,
+        None,
+    )
+    .await?;
+    let changed_files: Vec<String> = docker
+        .container_changes(container_id)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|change| change.path)
+        .collect();
+
+    let destinations = prefix_destinations(
+        &changed_files,
+        &layout.prefixes,
+        Path::new(pg_prefix.trim()),
+        inclusion_patterns,
+    );
+    for (file, destination, prefix) in destinations {
+        let target = destination.to_string_lossy().into_owned();
+        let (_, exit_code) = exec_in_container_with_exit_code(
+            docker,
+            container_id,
+            vec![
+                "sh",
+                "-c",
+                r#"if [ -d "$1" ]; then exit 4; fi
+                   if [ -e "$2" ]; then cmp -s "$1" "$2" && exit 2 || exit 3; fi
+                   mkdir -p "$(dirname "$2")" && cp -p "$1" "$2""#,
+                "sh",
+                &file,
+                &target,
+            ],
+            None,
+            None,
+        )
+        .await?;
+        match exit_code {
+            Some(0) => {
+                tee_println!("Capturing {file} => {target}");
+                captured.insert(destination, prefix);
+            }
+            Some(2) => tee_println!("Skipping {file}, {target} is identical"),
+            // Directories named like extension files
+            Some(4) => {}
+            Some(3) => anyhow::bail!(
+                "{file}, installed under --prefix {prefix}, conflicts with {target}, which has \
+                 different contents"
+            ),
+            _ => anyhow::bail!("Failed to copy {file} to {target}"),
+        }
+    }
+
+    Ok(captured)
+}
+
+/// Copies the files found in the directories of `layout` to where Postgres expects them,
+/// so that they are captured like files installed in the default layout.
+async fn relocate_installed_files(
+    docker: &Docker,
+    container_id: &str,
+    layout: &InstallLayout,
+) -> Result<(), anyhow::Error> {
+    let prefix = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--prefix"],
+        None,
+        None,
+    )
+    .await?;
+    let pkglibdir = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--pkglibdir"],
+        None,
+        None,
+    )
+    .await?;
+    let sharedir = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--sharedir"],
+        None,
+        None,
+    )
+    .await?;
+    let prefix = Path::new(prefix.trim());
+    let extension_dir = Path::new(sharedir.trim()).join("extension");
+
+    let overrides = [
+        (
+            "lib_dir",
+            &layout.lib_dir,
+            &["*.so", "*.bc"][..],
+            Path::new(pkglibdir.trim()),
+        ),
+        (
+            "sql_dir",
+            &layout.sql_dir,
+            &["*.sql"][..],
+            extension_dir.as_path(),
+        ),
+        (
+            "control_dir",
+            &layout.control_dir,
+            &["*.control"][..],
+            extension_dir.as_path(),
+        ),
+    ];
+
+    for (setting, dir, patterns, destination) in overrides {
+        let Some(dir) = dir else {
+            continue;
+        };
+        let dir = prefix.join(dir).to_string_lossy().into_owned();
+
+        let mut find = vec!["find", dir.as_str(), "-type", "f", "("];
+        for (idx, pattern) in patterns.iter().enumerate() {
+            if idx > 0 {
+                find.push("-o");
+            }
+            find.extend(["-name", pattern]);
+        }
+        find.push(")");
+        let found = exec_in_container(docker, container_id, find, None, None).await?;
+
+        let files: Vec<&str> = found
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with(&dir))
+            .collect();
+        if files.is_empty() {
+            anyhow::bail!(
+                "No files matching {} found in {dir}, set through {setting}",
+                patterns.join(", ")
+            );
+        }
+
+        for file in files {
+            let relative = Path::new(file).strip_prefix(&dir)?;
+            let target = destination.join(relative).to_string_lossy().into_owned();
+            tee_println!("Relocating {file} => {target}");
+            exec_in_container(
+                docker,
+                container_id,
+                vec![
+                    "sh",
+                    "-c",
+                    r#"mkdir -p "$(dirname "$2")" && cp -p "$1" "$2""#,
+                    "sh",
+                    file,
+                    &target,
+                ],
+                None,
+                None,
+            )
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Scratch directories the install command may always write to with `--check-install-writes`
+const SCRATCH_DIRS: [&str; 2] = ["/tmp", "/var/tmp"];
+
+/// With `--check-install-writes`, the install command changed files outside the paths it may write
+#[derive(Error, Debug)]
+#[error(
+    "With --check-install-writes, the install command may only write under {}, but it changed:\n{}\n\
+     If these writes are expected, allow them with --writable-path",
+    .allowed.join(", "),
+    .paths.iter().map(|path| format!("  {path}")).collect::<Vec<_>>().join("\n")
+)]
+pub struct UnexpectedWritesError {
+    pub paths: Vec<String>,
+    pub allowed: Vec<String>,
+}
+
+/// Commands run in the build container around the install command, as argv
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstallHooks {
+    /// `before_install`, run right before the install command
+    pub before: Option<Vec<String>>,
+    /// `after_install`, run right after the install command
+    pub after: Option<Vec<String>>,
+}
+
+/// The `before_install` or `after_install` command exited with an error
+#[derive(Error, Debug)]
+#[error("The {hook} command failed with {}", .code.map_or("an unknown exit status".to_string(), |code| format!("exit code {code}")))]
+pub struct InstallHookError {
+    pub hook: &'static str,
+    pub code: Option<i64>,
+}
+
+/// Runs an install hook in the container the way the install command is run, from `dir`, with
+/// `env` and as `user`. Fails if it exits with an error or runs longer than `--step-timeout`
+#[allow(clippy::too_many_arguments)]
+async fn run_install_hook(
+    docker: &Docker,
+    container_id: &str,
+    hook: &'static str,
+    argv: &[String],
+    dir: Option<&str>,
+    env: Option<Vec<&str>>,
+    user: Option<&str>,
+    image_build_options: &ImageBuildOptions,
+) -> Result<(), anyhow::Error> {
+    tee_println!("Running the {hook} command");
+    let run = exec_in_container_as(
+        docker,
+        container_id,
+        argv.iter().map(String::as_str).collect(),
+        dir,
+        env,
+        user,
+    );
+    let (_output, code) = match image_build_options.step_timeout {
+        Some(timeout) => {
+            tokio::time::timeout(timeout, run)
+                .await
+                .map_err(|_| StepTimeoutError {
+                    step: format!("{hook} command"),
+                    timeout,
+                })??
+        }
+        None => run.await?,
+    };
+    if code == Some(OutOfMemoryError::EXIT_CODE) {
+        let message = format!("The {hook} command was killed");
+        return Err(OutOfMemoryError::new(message, image_build_options.memory).into());
+    }
+    if code != Some(0) {
+        return Err(InstallHookError { hook, code }.into());
+    }
+
+    Ok(())
+}
+
+/// Checks that each `--writable-path` is an absolute path without `..`
+pub fn validate_writable_paths(paths: &[String]) -> Result<(), anyhow::Error> {
+    for path in paths {
+        let parsed = Path::new(path);
+        if !parsed.is_absolute() || parsed.components().any(|c| c == Component::ParentDir) {
+            anyhow::bail!("--writable-path must be an absolute path without '..'. Got: {path}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The paths in `changed` that are neither in one of `allowed` nor a directory above one, which
+/// Docker reports as changed when something below it is
+fn unexpected_writes(changed: &[String], allowed: &[String]) -> Vec<String> {
+    let mut unexpected: Vec<String> = changed
+        .iter()
+        .filter(|path| {
+            let path = Path::new(path);
+            !allowed.iter().any(|allowed| {
+                let allowed = Path::new(allowed);
+                path.starts_with(allowed) || allowed.starts_with(path)
+            })
+        })
+        .cloned()
+        .collect();
+    unexpected.sort();
+
+    unexpected
+}
+
+/// The paths of the container's filesystem that differ from its image
+async fn changed_paths(docker: &Docker, container_id: &str) -> Result<Vec<String>, anyhow::Error> {
+    Ok(docker
+        .container_changes(container_id)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|change| change.path)
+        .collect())
+}
+
+/// The paths the install command may change with `--check-install-writes`: `pg_config --prefix`,
+/// the layout's directories and prefixes, the directory it runs from, the scratch directories
+/// and `writable_paths`
+async fn allowed_install_paths(
+    docker: &Docker,
+    container_id: &str,
+    layout: &InstallLayout,
+    install_dir: Option<&str>,
+    writable_paths: &[String],
+) -> Result<Vec<String>, anyhow::Error> {
+    let prefix = exec_in_container(
+        docker,
+        container_id,
+        vec!["pg_config", "--prefix"],
+        None,
+        None,
+    )
+    .await?;
+    let install_dir = match install_dir {
+        Some(install_dir) => install_dir.to_string(),
+        None => exec_in_container(docker, container_id, vec!["pwd"], None, None)
+            .await?
+            .trim()
+            .to_string(),
+    };
+    let layout_dirs = [&layout.lib_dir, &layout.sql_dir, &layout.control_dir]
+        .into_iter()
+        .flatten()
+        .filter(|dir| Path::new(dir).is_absolute());
+
+    Ok([prefix.trim().to_string(), install_dir]
+        .into_iter()
+        .chain(layout_dirs.cloned())
+        .chain(layout.prefixes.iter().cloned())
+        .chain(SCRATCH_DIRS.map(String::from))
+        .chain(writable_paths.iter().cloned())
+        .collect())
+}
+
+/// Checks that `user` is a `user[:group]` to run the install command as, each given by name
+/// or numeric id. Whether named users and groups exist is checked in the builder image.
+pub fn validate_install_user(user: &str) -> Result<(), anyhow::Error> {
+    let is_valid_part = |part: &str| {
+        !part.is_empty()
+            && !part.starts_with('-')
+            && part
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '.'))
+    };
+
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+    if !is_valid_part(name) || !group.is_none_or(is_valid_part) {
+        anyhow::bail!(
+            "--user must be a user[:group], by name or numeric id, e.g. 1000:1000. Got: {user}"
+        );
+    }
+
+    Ok(())
+}
+
+/// Splits `entrypoint` into the argv the install command is appended to, like a POSIX shell
+/// would, e.g. `/bin/bash -lc` or `env FOO=bar sh -c`
+pub fn parse_entrypoint(entrypoint: &str) -> Result<Vec<String>, anyhow::Error> {
+    match shlex::split(entrypoint) {
+        Some(argv) if !argv.is_empty() => Ok(argv),
+        Some(_) => anyhow::bail!(
+            "--entrypoint must name a program to run the install command with, e.g. \"/bin/bash -lc\""
+        ),
+        None => anyhow::bail!(
+            "--entrypoint is not a valid command line, check its quotes. Got: {entrypoint}"
+        ),
+    }
+}
+
+fn is_numeric_id(id: &str) -> bool {
+    id.chars().all(|ch| ch.is_ascii_digit())
+}
+
+/// Checks that the named parts of `user` exist in the container, then hands it Postgres'
+/// library and extension directories so that the install command can write to them as `user`.
+/// Only the directories change owner: files already in them are left alone, so they don't show
+/// up as changed and get packaged.
+async fn prepare_install_user(
+    docker: &Docker,
+    container_id: &str,
+    user: &str,
+) -> Result<(), anyhow::Error> {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, Some(group)),
+        None => (user, None),
+    };
+
+    if !is_numeric_id(name) {
+        let (_, exit_code) = exec_in_container_with_exit_code(
+            docker,
+            container_id,
+            vec!["id", "-u", name],
+            None,
+            None,
+        )
+        .await?;
+        if !matches!(exit_code, Some(0)) {
+            anyhow::bail!("User {name}, given by --user, does not exist in the builder image. Create it in the Dockerfile or pass a numeric uid");
+        }
+    }
+    if let Some(group) = group.filter(|group| !is_numeric_id(group)) {
+        let pattern = format!("^{group}:");
+        let (_, exit_code) = exec_in_container_with_exit_code(
+            docker,
+            container_id,
+            vec!["grep", "-q", &pattern, "/etc/group"],
+            None,
+            None,
+        )
+        .await?;
+        if !matches!(exit_code, Some(0)) {
+            anyhow::bail!("Group {group}, given by --user, does not exist in the builder image. Create it in the Dockerfile or pass a numeric gid");
+        }
+    }
+
+    exec_in_container(
+        docker,
+        container_id,
+        vec![
+            "sh",
+            "-c",
+            r#"for dir in "$(pg_config --pkglibdir)" "$(pg_config --pkglibdir)/bitcode" "$(pg_config --sharedir)/extension"; do
+                   if [ -d "$dir" ]; then chown "$1" "$dir"; fi
+               done"#,
+            "sh",
+            user,
+        ],
+        None,
+        None,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// A Dockerfile for generic builds bundled with trunk, selected with `--builder`
+#[derive(Debug)]
+pub struct BundledBuilder {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub dockerfile: &'static str,
+}
+
+/// The builder used unless `--builder` or `--dockerfile` is given
+pub const DEFAULT_BUILDER: &str = "generic";
+
+pub const BUNDLED_BUILDERS: &[BundledBuilder] = &[
+    BundledBuilder {
+        name: "generic",
+        description: "the quay.io/coredb/c-builder image, with the tools and libraries common extensions build with",
+        dockerfile: include_str!("./builders/Dockerfile.generic"),
+    },
+    BundledBuilder {
+        name: "debian",
+        description: "the official postgres Debian image, with only build-essential and the server headers",
+        dockerfile: include_str!("./builders/Dockerfile.debian"),
+    },
+];
+
+/// The bundled builder called `name`
+pub fn bundled_builder(name: &str) -> Result<&'static BundledBuilder, anyhow::Error> {
+    BUNDLED_BUILDERS
+        .iter()
+        .find(|builder| builder.name == name)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown builder '{name}'. The bundled builders are: {}",
+                BUNDLED_BUILDERS
+                    .iter()
+                    .map(|builder| builder.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// The step of the bundled Dockerfiles that compiles the extension
+const DEFAULT_BUILD_STEP: &str = "RUN make -C ${EXTENSION_DIR}";
+
+/// Appends `configure_command` and `build_command` to `dockerfile` as separate `RUN` steps, in
+/// that order, so that Docker caches each of them and changing the install command reuses both.
+/// In the bundled Dockerfiles, they replace the `make` step, and the build stage defaults to `make`.
+pub fn staged_dockerfile(
+    dockerfile: &str,
+    is_default_dockerfile: bool,
+    configure_command: Option<&str>,
+    mut build_command: Option<&str>,
+) -> String {
+    if configure_command.is_none() && build_command.is_none() {
+        return dockerfile.to_string();
+    }
+
+    let mut staged = dockerfile.trim_end();
+    if is_default_dockerfile {
+        if let Some(without_build_step) = staged.strip_suffix(DEFAULT_BUILD_STEP) {
+            staged = without_build_step.trim_end();
+            build_command = build_command.or(Some("make"));
+        }
+    }
+
+    // Redeclared so that the stages can use them with Dockerfiles that don't declare them
+    let mut staged = format!("{staged}\n\nARG EXTENSION_DIR=.\nARG CFLAGS\nARG CXXFLAGS\n");
+    for (stage, command) in [("configure", configure_command), ("build", build_command)] {
+        let Some(command) = command else {
+            continue;
+        };
+        let script = format!(
+            "echo \"Running the {stage} stage\"\n\
+             start=$(date +%s)\n\
+             cd \"$EXTENSION_DIR\" && (\n{command}\n)\n\
+             status=$?\n\
+             echo \"The {stage} stage finished in $(($(date +%s) - start))s\"\n\
+             exit $status"
+        );
+        let instruction = serde_json::to_string(&["/bin/sh", "-c", &script])
+            .expect("a list of strings always serializes");
+        staged.push_str(&format!("RUN {instruction}\n"));
+    }
+
+    staged
+}
+
+/// `[build] cflags` and `cxxflags`, passed to the compile and install steps of generic builds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompilerFlags {
+    pub cflags: Option<String>,
+    pub cxxflags: Option<String>,
+}
+
+impl CompilerFlags {
+    /// The flags that are set, as `(variable, value)`, e.g. `("CFLAGS", "-O2")`
+    pub fn variables(&self) -> Vec<(&'static str, &str)> {
+        [("CFLAGS", &self.cflags), ("CXXFLAGS", &self.cxxflags)]
+            .into_iter()
+            .filter_map(|(variable, value)| Some((variable, value.as_deref()?)))
+            .collect()
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub async fn build_generic(
     dockerfile: &str,
     platform: Option<String>,
     install_command: Vec<&str>,
+    install_hooks: InstallHooks,
     path: &Path,
+    extension_dir: Option<&str>,
     output_path: &str,
     name: &str,
     extension_name: Option<String>,
@@ -79,52 +698,230 @@ pub async fn build_generic(
     system_dependencies: Option<SystemDependencies>,
     extension_version: &str,
     inclusion_patterns: Vec<glob::Pattern>,
+    capture_globs: Vec<glob::Pattern>,
     _task: Task,
     should_test: bool,
+    integration_test: Option<&Path>,
     configurations: Option<Vec<ExtensionConfiguration>>,
     loadable_libraries: Option<Vec<LoadableLibrary>>,
+    preload: Option<Preload>,
+    kind: ExtensionKind,
+    control: Option<ControlFields>,
     pg_version: u8,
-) -> Result<(), GenericBuildError> {
-    println!("Building with name {}", &name);
-    println!("Building with version {}", &extension_version);
-    println!("Building for PostgreSQL {pg_version}");
+    supported_pg_versions: SupportedPgVersions,
+    included_files: Vec<String>,
+    artifact_suffix: &str,
+    allow_missing_control: bool,
+    file_digests: bool,
+    strip: Option<Strip>,
+    changelog: Option<Changelog>,
+    labels: Option<BTreeMap<String, String>>,
+    categories: Option<Vec<String>>,
+    manifest_transform: Option<String>,
+    compiler_flags: CompilerFlags,
+    manifest_version: i32,
+    fail_on_warn: &[WarningCategory],
+    allow_arch_mismatch: bool,
+    version_check: bool,
+    layout: InstallLayout,
+    install_user: Option<&str>,
+    writable_paths: Option<Vec<String>>,
+    no_install: Option<NoInstall>,
+    image_build_options: ImageBuildOptions,
+) -> Result<Option<BuildOutput>, GenericBuildError> {
+    tee_println!("Building with name {}", &name);
+    tee_println!("Building with version {}", &extension_version);
+    tee_println!("Building for PostgreSQL {pg_version}");
 
     let mut build_args = HashMap::new();
     build_args.insert("EXTENSION_NAME", name);
     build_args.insert("EXTENSION_VERSION", extension_version);
     build_args.insert("PG_VERSION", pg_version_to_str(pg_version));
     build_args.insert("PG_RELEASE", pg_release_for_version(pg_version));
-
-    let image_name_prefix = "make_builder_".to_string();
+    build_args.insert("EXTENSION_DIR", extension_dir.unwrap_or("."));
+    if let Some(base_image) = image_build_options.base_image.as_deref() {
+        tee_println!("Using base image {base_image}");
+        build_args.insert("BASE_IMAGE", base_image);
+    }
+    // Declared by the bundled Dockerfiles, custom ones need `ARG CFLAGS` to pick them up
+    for (variable, value) in compiler_flags.variables() {
+        tee_println!("Building with {variable}={value}");
+        build_args.insert(variable, value);
+    }
 
     let docker = Docker::connect_with_local_defaults()?;
+    let mut timings = BuildTimings::default();
 
+    let base_image = base_image(dockerfile, &build_args);
     let image_name = build_image(
         platform.clone(),
         docker.clone(),
-        &image_name_prefix,
+        GENERIC_BUILDER_IMAGE_PREFIX,
         dockerfile,
         path,
         build_args,
+        &image_build_options,
+        &mut timings,
     )
     .await?;
+    if let Some(no_install) = no_install {
+        stop_before_install(
+            &docker,
+            &image_name,
+            platform.as_deref(),
+            &image_build_options,
+            no_install,
+        )
+        .await?;
+        return Ok(None);
+    }
 
-    let temp_container =
-        run_temporary_container(docker.clone(), platform.clone(), image_name.as_str(), _task)
-            .await?;
+    let temp_container = run_temporary_container(
+        docker.clone(),
+        platform.clone(),
+        image_name.as_str(),
+        image_name.as_str(),
+        &image_build_options,
+        _task.clone(),
+    )
+    .await?;
 
     if should_test {
         let extension_name = extension_name.as_deref().unwrap_or(name);
         // Check if there are extensions to run
-        run_tests(&docker, &temp_container.id, extension_name).await?;
+        let started = Instant::now();
+        run_tests(&docker, &temp_container.id, extension_name, extension_dir).await?;
+        timings.record_since("tests", started);
+    }
+
+    let install_dir = match extension_dir {
+        Some(extension_dir) => {
+            let install_dir = container_path(&docker, &temp_container.id, extension_dir).await?;
+            tee_println!("Running install command from {install_dir}");
+            Some(install_dir)
+        }
+        None => None,
+    };
+
+    if let Some(user) = install_user {
+        prepare_install_user(&docker, &temp_container.id, user).await?;
     }
 
-    println!("Determining installation files...");
-    let _exec_output =
-        exec_in_container(&docker, &temp_container.id, install_command, None, None).await?;
+    // Changes made before the install command, like creating the install user, are allowed
+    let changed_before_install = match &writable_paths {
+        Some(_) => changed_paths(&docker, &temp_container.id).await?,
+        None => Vec::new(),
+    };
+
+    tee_println!("Determining installation files...");
+    let started = Instant::now();
+    let install_variables: Vec<String> = compiler_flags
+        .variables()
+        .into_iter()
+        .map(|(variable, value)| format!("{variable}={value}"))
+        .collect();
+    // The hooks run with the same environment as the install command
+    let install_env = || {
+        (!install_variables.is_empty())
+            .then(|| install_variables.iter().map(String::as_str).collect())
+    };
+    if let Some(before_install) = &install_hooks.before {
+        run_install_hook(
+            &docker,
+            &temp_container.id,
+            "before_install",
+            before_install,
+            install_dir.as_deref(),
+            install_env(),
+            install_user,
+            &image_build_options,
+        )
+        .await?;
+    }
+    let install = exec_in_container_as(
+        &docker,
+        &temp_container.id,
+        install_command,
+        install_dir.as_deref(),
+        install_env(),
+        install_user,
+    );
+    let (install_output, exit_code) = match image_build_options.step_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, install).await.map_err(|_| {
+            anyhow::Error::from(StepTimeoutError {
+                step: "install command".to_string(),
+                timeout,
+            })
+        })??,
+        None => install.await?,
+    };
+    if let Some(after_install) = &install_hooks.after {
+        if exit_code == Some(0) {
+            run_install_hook(
+                &docker,
+                &temp_container.id,
+                "after_install",
+                after_install,
+                install_dir.as_deref(),
+                install_env(),
+                install_user,
+                &image_build_options,
+            )
+            .await?;
+        } else {
+            tee_println!("Not running the after_install command, as the install command failed");
+        }
+    }
+    tee_println!(
+        "The install stage finished in {:.1}s",
+        started.elapsed().as_secs_f64()
+    );
+    timings.record_since("install", started);
+
+    if exit_code == Some(OutOfMemoryError::EXIT_CODE) {
+        let err =
+            OutOfMemoryError::new("The install command was killed", image_build_options.memory);
+        return Err(anyhow::Error::from(err).into());
+    }
+
+    if image_build_options.offline && !matches!(exit_code, Some(0)) {
+        if let Some(err) = OfflineNetworkError::detect(&install_output) {
+            return Err(anyhow::Error::from(err).into());
+        }
+    }
+
+    if let Some(writable_paths) = &writable_paths {
+        let allowed = allowed_install_paths(
+            &docker,
+            &temp_container.id,
+            &layout,
+            install_dir.as_deref(),
+            writable_paths,
+        )
+        .await?;
+        let changed: Vec<String> = changed_paths(&docker, &temp_container.id)
+            .await?
+            .into_iter()
+            .filter(|path| !changed_before_install.contains(path))
+            .collect();
+        let paths = unexpected_writes(&changed, &allowed);
+        if !paths.is_empty() {
+            let err = UnexpectedWritesError { paths, allowed };
+            return Err(anyhow::Error::from(err).into());
+        }
+        tee_println!(
+            "The install command only wrote under {}",
+            allowed.join(", ")
+        );
+    }
+
+    let started = Instant::now();
+    relocate_installed_files(&docker, &temp_container.id, &layout).await?;
+    let install_prefixes =
+        capture_install_prefixes(&docker, &temp_container.id, &layout, &inclusion_patterns).await?;
 
     // Search for license files to include
-    println!("Determining license files to include...");
+    tee_println!("Determining license files to include...");
     let license_vec = find_licenses(docker.clone(), &temp_container.id).await?;
 
     // Create directory /usr/licenses/
@@ -150,10 +947,15 @@ pub async fn build_generic(
     )
     .await?;
 
+    timings.record_since("capture", started);
+
+    tee_println!("Determining toolchain versions...");
+    let toolchain = toolchain_versions(&docker, &temp_container.id, GENERIC_TOOLCHAIN).await;
+
     // output_path is the locally output path
     fs::create_dir_all(output_path)?;
 
-    package_installed_extension_files(
+    let mut output = package_installed_extension_files(
         docker.clone(),
         &temp_container.id,
         output_path,
@@ -163,22 +965,66 @@ pub async fn build_generic(
         extension_version,
         extension_dependencies,
         inclusion_patterns,
+        capture_globs,
         configurations,
         loadable_libraries,
+        preload,
+        kind,
+        control,
         pg_version,
+        supported_pg_versions,
+        path,
+        included_files,
+        artifact_suffix,
+        allow_missing_control,
+        None,
+        toolchain,
+        base_image,
+        install_user.is_some(),
+        file_digests,
+        strip,
+        install_prefixes,
+        changelog,
+        labels,
+        categories,
+        manifest_transform,
+        compiler_flags,
+        manifest_version,
+        fail_on_warn,
+        platform.as_deref(),
+        allow_arch_mismatch,
+        version_check,
+        timings,
     )
     .await?;
 
-    Ok(())
+    if let Some(sql_file) = integration_test {
+        run_integration_test(
+            &docker,
+            platform,
+            &image_name,
+            &image_build_options,
+            _task,
+            &output.artifact_path,
+            sql_file,
+            &mut output.timings,
+        )
+        .await?;
+    }
+
+    Ok(Some(output))
 }
 
 async fn run_tests(
     docker: &Docker,
     container_id: &str,
     extension_name: &str,
+    extension_dir: Option<&str>,
 ) -> anyhow::Result<()> {
-    let Some(project_dir) = locate_makefile(docker, container_id, extension_name).await? else {
-        println!("Makefile not found!");
+    let Some(project_dir) =
+        locate_makefile(docker, container_id, extension_name, extension_dir).await?
+    else {
+        tee_println!("Makefile not found!");
         return Ok(());
     };
 
@@ -189,7 +1035,7 @@ async fn run_tests(
     };
 
     if has("check").await? {
-        println!("make check was found in the Makefile");
+        tee_println!("make check was found in the Makefile");
         start_postgres(docker, container_id).await?;
 
         let (_, exit_code) = exec_in_container_with_exit_code(
@@ -203,14 +1049,14 @@ async fn run_tests(
 
         anyhow::ensure!(matches!(exit_code, Some(0)), "Tests failed!");
 
-        println!("Tests passed successfully!");
+        tee_println!("Tests passed successfully!");
         return Ok(());
     }
 
     if has("installcheck").await? {
         start_postgres(docker, container_id).await?;
 
-        println!("make installcheck was found in the Makefile");
+        tee_println!("make installcheck was found in the Makefile");
         exec_in_container(
             docker,
             container_id,
@@ -230,10 +1076,198 @@ async fn run_tests(
 
         anyhow::ensure!(matches!(exit_code, Some(0)), "Tests failed!");
 
-        println!("Tests passed successfully!");
+        tee_println!("Tests passed successfully!");
         return Ok(());
     }
 
-    println!("Test target not found in Makefile.");
+    tee_println!("Test target not found in Makefile.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::containers::NoExtensionFilesError;
+
+    const DEFAULT_DOCKERFILE: &str = include_str!("./builders/Dockerfile.generic");
+
+    #[test]
+    fn detects_builds_without_extension_files() {
+        let empty = NoExtensionFilesError {
+            sharedir: "/usr/share/postgresql/15".to_string(),
+            pkglibdir: "/usr/lib/postgresql/15/lib".to_string(),
+        };
+        let err = anyhow::Error::from(GenericBuildError::from(anyhow::Error::from(empty)));
+        assert!(NoExtensionFilesError::is_cause_of(&err));
+        assert!(err
+            .chain()
+            .last()
+            .unwrap()
+            .to_string()
+            .contains("--allow-empty"));
+
+        let other = anyhow::Error::from(GenericBuildError::from(anyhow::anyhow!("docker died")));
+        assert!(!NoExtensionFilesError::is_cause_of(&other));
+    }
+
+    #[test]
+    fn selects_bundled_builders() {
+        assert_eq!(
+            bundled_builder(DEFAULT_BUILDER).unwrap().dockerfile,
+            DEFAULT_DOCKERFILE
+        );
+        assert!(bundled_builder("debian").is_ok());
+        let err = bundled_builder("alpine").unwrap_err();
+        assert!(err.to_string().ends_with("are: generic, debian"), "{err}");
+        for builder in BUNDLED_BUILDERS {
+            let staged = staged_dockerfile(builder.dockerfile, true, None, Some("make all"));
+            assert!(!staged.contains(DEFAULT_BUILD_STEP), "{}", builder.name);
+        }
+    }
+
+    #[test]
+    fn dockerfile_without_stages_is_unchanged() {
+        assert_eq!(
+            staged_dockerfile(DEFAULT_DOCKERFILE, true, None, None),
+            DEFAULT_DOCKERFILE
+        );
+    }
+
+    #[test]
+    fn stages_replace_the_default_build_step() {
+        let staged = staged_dockerfile(DEFAULT_DOCKERFILE, true, Some("./configure"), None);
+
+        assert!(!staged.contains(DEFAULT_BUILD_STEP));
+        let configure = staged.find("./configure").unwrap();
+        let build = staged.find("(\\nmake\\n)").unwrap();
+        assert!(configure < build);
+    }
+
+    #[test]
+    fn validates_install_users() {
+        for user in [
+            "1000",
+            "1000:1000",
+            "postgres",
+            "postgres:postgres",
+            "build-user:1000",
+        ] {
+            assert!(validate_install_user(user).is_ok(), "{user}");
+        }
+        for user in [
+            "",
+            ":",
+            "1000:",
+            ":1000",
+            "-u",
+            "a b",
+            "user:$(id)",
+            "a:b:c",
+        ] {
+            assert!(validate_install_user(user).is_err(), "{user}");
+        }
+    }
+
+    #[test]
+    fn maps_install_prefixes_onto_pg_config_prefix() {
+        let changed_files = [
+            "/opt/ext/lib/postgresql/15/lib/ext.so",
+            "/opt/ext/share/postgresql/15/extension/ext.control",
+            "/opt/ext/docs/sql/share/postgresql/15/extension/ext--1.0.sql",
+            "/opt/ext/share/doc/README",
+            "/usr/lib/postgresql/15/lib/other.so",
+        ]
+        .map(String::from);
+        let prefixes = [
+            "/opt/ext".to_string(),
+            "/opt/ext/docs/sql".to_string(),
+            "/usr".to_string(),
+        ];
+
+        let destinations = prefix_destinations(&changed_files, &prefixes, Path::new("/usr"), &[]);
+        assert_eq!(
+            destinations,
+            [
+                (
+                    changed_files[0].clone(),
+                    PathBuf::from("/usr/lib/postgresql/15/lib/ext.so"),
+                    "/opt/ext".to_string()
+                ),
+                (
+                    changed_files[1].clone(),
+                    PathBuf::from("/usr/share/postgresql/15/extension/ext.control"),
+                    "/opt/ext".to_string()
+                ),
+                (
+                    changed_files[2].clone(),
+                    PathBuf::from("/usr/share/postgresql/15/extension/ext--1.0.sql"),
+                    "/opt/ext/docs/sql".to_string()
+                ),
+            ]
+        );
+
+        assert!(validate_install_prefixes(&prefixes).is_ok());
+        assert!(validate_install_prefixes(&["opt/ext".to_string()]).is_err());
+        assert!(validate_install_prefixes(&["/opt/../etc".to_string()]).is_err());
+    }
+
+    #[test]
+    fn finds_unexpected_writes() {
+        let allowed = ["/usr".to_string(), "/app".to_string(), "/tmp".to_string()];
+        let changed = [
+            "/usr",
+            "/usr/share/postgresql/15/extension/ext.control",
+            "/app/ext.o",
+            "/root",
+            "/root/.cache/pip",
+            "/tmp/build.log",
+            "/",
+            "/etc/ld.so.cache",
+        ]
+        .map(String::from);
+        assert_eq!(
+            unexpected_writes(&changed, &allowed),
+            ["/etc/ld.so.cache", "/root", "/root/.cache/pip"]
+        );
+
+        let err = UnexpectedWritesError {
+            paths: vec!["/etc/ld.so.cache".to_string()],
+            allowed: allowed.to_vec(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "With --check-install-writes, the install command may only write under /usr, /app, /tmp, \
+             but it changed:\n  /etc/ld.so.cache\nIf these writes are expected, allow them with \
+             --writable-path"
+        );
+        assert!(validate_writable_paths(&["/opt/cache".to_string()]).is_ok());
+        assert!(validate_writable_paths(&["cache".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parses_entrypoints() {
+        assert_eq!(
+            parse_entrypoint("/bin/bash -lc").unwrap(),
+            ["/bin/bash", "-lc"]
+        );
+        assert_eq!(
+            parse_entrypoint("env 'PATH=/opt/pg bin' sh -c").unwrap(),
+            ["env", "PATH=/opt/pg bin", "sh", "-c"]
+        );
+        assert!(parse_entrypoint("").is_err());
+        assert!(parse_entrypoint("   ").is_err());
+        assert!(parse_entrypoint("bash -c 'unterminated").is_err());
+    }
+
+    #[test]
+    fn stages_are_appended_to_custom_dockerfiles() {
+        let dockerfile = "FROM builder\nRUN make -C ${EXTENSION_DIR}\n";
+        let staged = staged_dockerfile(dockerfile, false, None, Some("make all"));
+
+        assert!(staged.starts_with(dockerfile.trim_end()));
+        let steps: Vec<&str> = staged.lines().filter(|l| l.starts_with("RUN")).collect();
+        assert_eq!(steps.len(), 2);
+        assert!(steps[1].starts_with(r#"RUN ["/bin/sh","-c","#));
+        assert!(steps[1].contains("make all"));
+    }
+}
@@ -0,0 +1,137 @@
+//! Building several versions of an extension from the git refs of its repository, for
+//! `trunk build --versions`.
+
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context};
+use tempfile::TempDir;
+
+/// The refs given with `--versions`, or listed one per line in `--versions-file`, where blank
+/// lines and lines starting with `#` are skipped
+pub fn read_version_refs(
+    versions: &[String],
+    versions_file: Option<&Path>,
+) -> anyhow::Result<Vec<String>> {
+    let mut refs: Vec<String> = versions.to_vec();
+    if let Some(versions_file) = versions_file {
+        let contents = fs::read_to_string(versions_file)
+            .with_context(|| format!("Failed to read {}", versions_file.display()))?;
+        refs.extend(
+            contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_string),
+        );
+    }
+    if let Some(empty) = refs.iter().position(|git_ref| git_ref.trim().is_empty()) {
+        bail!("--versions has an empty ref at position {}", empty + 1);
+    }
+    if refs.is_empty() {
+        bail!("--versions-file lists no refs to build");
+    }
+
+    Ok(refs)
+}
+
+/// Exports the sources of `git_ref` in the repository at `path` into a new directory in
+/// `temp_dir` with `git archive`, leaving the working tree alone. As when building from `path`,
+/// only what's under `path` is exported
+pub fn checkout_ref(path: &Path, git_ref: &str, temp_dir: &Path) -> anyhow::Result<TempDir> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(["archive", "--format=tar", git_ref])
+        .output()
+        .context("Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "git archive {git_ref} failed in {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let dir = tempfile::Builder::new()
+        .prefix("trunk-ref-")
+        .tempdir_in(temp_dir)?;
+    tar::Archive::new(output.stdout.as_slice())
+        .unpack(dir.path())
+        .with_context(|| format!("Failed to extract the sources of {git_ref}"))?;
+
+    Ok(dir)
+}
+
+/// The version a ref such as `v1.2.0`, `release-1.2.0` or `refs/tags/1.2.0` names: what follows
+/// its first digit, if it has a dot. Refs that name no version, such as commit hashes, give none
+pub fn version_from_ref(git_ref: &str) -> Option<&str> {
+    let name = git_ref.rsplit('/').next()?;
+    let version = &name[name.find(|ch: char| ch.is_ascii_digit())?..];
+    let is_version = version.contains('.')
+        && version
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '-' | '+'));
+
+    is_version.then_some(version)
+}
+
+/// What became of each ref: the archive it was built into, or why it wasn't
+pub type VersionResults = Vec<(String, Result<PathBuf, String>)>;
+
+/// One line per ref, after a count of those that built
+pub fn summary(results: &VersionResults) -> String {
+    let built = results.iter().filter(|(_, result)| result.is_ok()).count();
+    let width = results
+        .iter()
+        .map(|(git_ref, _)| git_ref.len())
+        .max()
+        .unwrap_or_default();
+    let mut summary = format!("Built {built} of {} versions:\n", results.len());
+    for (git_ref, result) in results {
+        let _ = match result {
+            Ok(artifact_path) => {
+                writeln!(summary, "  {git_ref:width$}  {}", artifact_path.display())
+            }
+            Err(err) => writeln!(summary, "  {git_ref:width$}  FAILED: {err}"),
+        };
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_versions_from_refs() {
+        assert_eq!(version_from_ref("v1.2.0"), Some("1.2.0"));
+        assert_eq!(version_from_ref("release-1.2.0-rc1"), Some("1.2.0-rc1"));
+        assert_eq!(version_from_ref("refs/tags/1.5"), Some("1.5"));
+        assert_eq!(version_from_ref("3f2a9c1"), None);
+        assert_eq!(version_from_ref("main"), None);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "# backfill\nv1.1.0\n\n  v1.2.0\n").unwrap();
+        assert_eq!(
+            read_version_refs(&["v1.0.0".to_string()], Some(file.path())).unwrap(),
+            ["v1.0.0", "v1.1.0", "v1.2.0"]
+        );
+        assert!(read_version_refs(&["v1.0.0".to_string(), String::new()], None).is_err());
+
+        let results: VersionResults = vec![
+            (
+                "v1.0.0".to_string(),
+                Ok(PathBuf::from(".trunk/ext-1.0.0.tar.gz")),
+            ),
+            ("v1.10.0".to_string(), Err("make failed".to_string())),
+        ];
+        assert_eq!(
+            summary(&results),
+            "Built 1 of 2 versions:\n  v1.0.0   .trunk/ext-1.0.0.tar.gz\n  v1.10.0  FAILED: make failed\n"
+        );
+    }
+}
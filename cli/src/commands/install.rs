@@ -1,6 +1,7 @@
+use super::encryption::decrypt_artifact;
 use super::SubCommand;
 use crate::control_file::ControlFile;
-use crate::manifest::{Manifest, PackagedFile};
+use crate::manifest::{check_manifest_version, Manifest, PackagedFile};
 use crate::retry::get_retry;
 use crate::semver::compare_by_semver;
 use crate::v1::TrunkProjectView;
@@ -9,7 +10,7 @@ use async_recursion::async_recursion;
 use async_trait::async_trait;
 use clap::Args;
 use flate2::read::GzDecoder;
-use log::{error, info, warn};
+use log::{info, warn};
 use reqwest;
 use reqwest::Url;
 use sha2::{Digest, Sha256};
@@ -51,6 +52,10 @@ pub struct InstallCommand {
     pg_config: Option<PathBuf>,
     #[arg(long = "file", short = 'f')]
     file: Option<PathBuf>,
+    /// The age identity file to decrypt the archive given with --file, as written by
+    /// trunk build --encrypt
+    #[arg(long = "decrypt-key", value_name = "PATH", requires = "file")]
+    decrypt_key: Option<PathBuf>,
     #[arg(long = "version", short = 'v', default_value = "latest")]
     version: String,
     #[arg(
@@ -167,10 +172,23 @@ impl SubCommand for InstallCommand {
         println!("Using sharedir: {sharedir:?}");
         println!("Using Postgres version: {postgres_version}");
 
+        // An encrypted archive is installed from a decrypted copy, named as it was before
+        let decrypted_dir = tempfile::tempdir()?;
+        let file = match (&self.file, &self.decrypt_key) {
+            (Some(file), Some(key)) => {
+                let (name, contents) = decrypt_artifact(file, key)?;
+                let decrypted = decrypted_dir.path().join(name);
+                fs::write(&decrypted, contents)
+                    .with_context(|| format!("Failed to write {}", decrypted.display()))?;
+                Some(decrypted)
+            }
+            (file, _) => file.clone(),
+        };
+
         install(
             Name::TrunkProject(&self.name),
             &self.version,
-            &self.file,
+            &file,
             &self.registry,
             package_lib_dir,
             sharedir,
@@ -312,7 +330,7 @@ async fn fetch_archive_from_v1(
             Name::Extension(ext_name) => Some(ext_name.to_owned()),
         };
 
-        let url = Url::parse(&download.link).with_context(|| "Failed to parse URL")?;
+        let url = download_url(registry, &download.link)?;
         Ok((url, download.sha256, extension_name))
     } else {
         let body = response.text().await?;
@@ -321,6 +339,15 @@ async fn fetch_archive_from_v1(
     }
 }
 
+/// The URL of a download link. Links relative to the registry, like those of the index that
+/// `trunk build --output-layout registry` writes, are resolved against it
+fn download_url(registry: &str, link: &str) -> anyhow::Result<Url> {
+    let registry = Url::parse(&format!("{}/", registry.trim_end_matches('/')))
+        .with_context(|| format!("Failed to parse the registry URL {registry}"))?;
+
+    registry.join(link).with_context(|| "Failed to parse URL")
+}
+
 async fn fetch_archive_legacy(registry: &str, name: &str, version: &str) -> anyhow::Result<Url> {
     let endpoint = format!("{}/extensions/{}/{}/download", registry, name, version);
 
@@ -339,6 +366,7 @@ async fn fetch_archive_legacy(registry: &str, name: &str, version: &str) -> anyh
 }
 
 #[async_recursion]
+#[allow(clippy::too_many_arguments)]
 async fn install<'name: 'async_recursion>(
     name: Name<'name>,
     version: &str,
@@ -411,13 +439,13 @@ async fn install<'name: 'async_recursion>(
     info!("Downloading from: {url}");
 
     // Get the path segments as an iterator
-    let segments = url
+    let mut segments = url
         .path_segments()
         .ok_or(anyhow!("Cannot extract path segments"))?;
 
     // Get the last segment, which should be the file name
     let file_name = segments
-        .last()
+        .next_back()
         .ok_or(anyhow!("Cannot extract file name from URL"))?;
 
     // Write the bytes of the archive to a temporary directory
@@ -558,6 +586,19 @@ async fn install_file(
         }
     }
 
+    // Refuse before installing any dependencies
+    if let Some(manifest) = &manifest {
+        check_manifest_version(manifest.manifest_version)?;
+        let supported = manifest.supported_pg_versions();
+        if !supported.contains(postgres_version) {
+            bail!(
+                "{} {} supports PostgreSQL {supported}, it cannot be installed on PostgreSQL {postgres_version}",
+                manifest.name,
+                manifest.extension_version
+            );
+        }
+    }
+
     let maybe_manifest_deps = manifest
         .as_ref()
         .and_then(|manifest| manifest.extension_dependencies.as_ref());
@@ -737,10 +778,25 @@ fn print_post_installation_guide(manifest: &Manifest) {
         );
     }
 
+    // Loadable modules have no control file to create an extension from
+    if manifest.is_module() {
+        println!("\nLoad the module with:");
+        for module in manifest.modules.iter().flatten() {
+            println!("\tLOAD '{}';", load_path(module));
+        }
+        return;
+    }
+
     println!("\nEnable the extension with:");
     println!("\tCREATE EXTENSION IF NOT EXISTS {extension_name} CASCADE;");
 }
 
+/// What `LOAD` takes to load the shared library at `module` in pkglibdir, e.g. `$libdir/auto_explain`
+fn load_path(module: &Path) -> String {
+    let module = module.with_extension("");
+    format!("$libdir/{}", module.display())
+}
+
 fn assert_sha256_matches(contents: &[u8], maybe_hash: Option<String>) -> Result<(), anyhow::Error> {
     if let Some(expected_hash) = maybe_hash {
         let mut hasher = Sha256::new();
@@ -0,0 +1,286 @@
+//! Runs a SQL file against a Postgres with the built archive installed, for
+//! `trunk build --integration-test`.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+use bollard::container::UploadToContainerOptions;
+use bollard::Docker;
+use flate2::read::GzDecoder;
+use tar::{Archive, Builder, Header};
+use tokio_task_manager::Task;
+
+use crate::build_log::tee_println;
+use crate::commands::containers::{
+    exec_in_container, exec_in_container_as, run_temporary_container, ImageBuildOptions,
+};
+use crate::manifest::{Manifest, PackagedFile};
+use crate::timings::BuildTimings;
+
+/// Where the SQL file is copied to in the container
+const SQL_PATH: &str = "/tmp/trunk-integration-test.sql";
+/// The data directory of the test's Postgres, which also holds its Unix socket
+const DATA_DIR: &str = "/tmp/trunk-pgdata";
+const LOG_PATH: &str = "/tmp/trunk-postgres.log";
+
+/// The SQL file of `--integration-test` failed against the installed archive
+#[derive(thiserror::Error, Debug)]
+#[error("The integration test {} failed, psql exited with {}", .sql_file.display(), .code.map_or("an unknown status".to_string(), |code| code.to_string()))]
+pub struct IntegrationTestError {
+    pub sql_file: PathBuf,
+    pub code: Option<i64>,
+}
+
+/// The directories of the container's Postgres that `trunk install` installs into
+struct PostgresDirs {
+    sharedir: PathBuf,
+    pkglibdir: PathBuf,
+}
+
+/// Starts a fresh container of the builder image, which has the Postgres the extension was built
+/// against but none of the files of the install command. The archive is installed into it the
+/// way `trunk install` lays it out, then `sql_file` runs with psql, stopping at the first error
+#[allow(clippy::too_many_arguments)]
+pub async fn run_integration_test(
+    docker: &Docker,
+    platform: Option<String>,
+    image_name: &str,
+    image_build_options: &ImageBuildOptions,
+    task: Task,
+    archive: &Path,
+    sql_file: &Path,
+    timings: &mut BuildTimings,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let sql =
+        fs::read(sql_file).with_context(|| format!("Failed to read {}", sql_file.display()))?;
+    tee_println!(
+        "Running the integration test {} against {}",
+        sql_file.display(),
+        archive.display()
+    );
+    let container = run_temporary_container(
+        docker.clone(),
+        platform,
+        &format!("{image_name}-integration-test"),
+        image_name,
+        image_build_options,
+        task,
+    )
+    .await?;
+    let container_id = container.id.as_str();
+    let pg_config = |option| async move {
+        exec_in_container(docker, container_id, vec!["pg_config", option], None, None)
+            .await
+            .map(|output| output.trim().to_string())
+    };
+    let dirs = PostgresDirs {
+        sharedir: pg_config("--sharedir").await?.into(),
+        pkglibdir: pg_config("--pkglibdir").await?.into(),
+    };
+    let bindir = pg_config("--bindir").await?;
+
+    let files = install_tar(archive, &dirs, &sql)?;
+    docker
+        .upload_to_container(
+            container_id,
+            Some(UploadToContainerOptions {
+                path: "/",
+                ..Default::default()
+            }),
+            files.into(),
+        )
+        .await?;
+
+    let as_postgres = |command: Vec<String>| async move {
+        let command = command.iter().map(String::as_str).collect();
+        exec_in_container_as(docker, container_id, command, None, None, Some("postgres")).await
+    };
+    let (_, code) = as_postgres(vec![
+        format!("{bindir}/initdb"),
+        "-D".into(),
+        DATA_DIR.into(),
+    ])
+    .await?;
+    anyhow::ensure!(
+        code == Some(0),
+        "Failed to initialize Postgres for the integration test"
+    );
+    let (_, code) = as_postgres(vec![
+        format!("{bindir}/pg_ctl"),
+        "-D".into(),
+        DATA_DIR.into(),
+        "-o".into(),
+        format!("-k {DATA_DIR}"),
+        "-l".into(),
+        LOG_PATH.into(),
+        "-w".into(),
+        "start".into(),
+    ])
+    .await?;
+    if code != Some(0) {
+        let _ = exec_in_container(docker, container_id, vec!["cat", LOG_PATH], None, None).await;
+        anyhow::bail!("Failed to start Postgres for the integration test");
+    }
+
+    let (_, code) = as_postgres(vec![
+        format!("{bindir}/psql"),
+        "-X".into(),
+        "-h".into(),
+        DATA_DIR.into(),
+        "-d".into(),
+        "postgres".into(),
+        "-v".into(),
+        "ON_ERROR_STOP=1".into(),
+        "-f".into(),
+        SQL_PATH.into(),
+    ])
+    .await?;
+    timings.record_since("integration test", started);
+    if code != Some(0) {
+        tee_println!("Postgres log:");
+        let _ = exec_in_container(docker, container_id, vec!["cat", LOG_PATH], None, None).await;
+        return Err(IntegrationTestError {
+            sql_file: sql_file.to_path_buf(),
+            code,
+        }
+        .into());
+    }
+    tee_println!("Integration test {} passed", sql_file.display());
+
+    Ok(())
+}
+
+/// A tar of the files of `archive` at their absolute path in `dirs`, where `trunk install` puts
+/// them, along with `sql` at `SQL_PATH`
+fn install_tar(archive: &Path, dirs: &PostgresDirs, sql: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let open = || -> anyhow::Result<_> {
+        let file =
+            File::open(archive).with_context(|| format!("Failed to open {}", archive.display()))?;
+        Ok(Archive::new(GzDecoder::new(file)))
+    };
+    let mut manifest = None;
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == Path::new("manifest.json") {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            manifest = Some(serde_json::from_str::<Manifest>(&contents)?);
+        }
+    }
+    let manifest =
+        manifest.with_context(|| format!("{} has no manifest.json", archive.display()))?;
+    let files = manifest.files.unwrap_or_default();
+
+    let mut tar = Builder::new(Vec::new());
+    for entry in open()?.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let dir = match files.get(&path) {
+            None | Some(PackagedFile::LicenseFile {}) => continue,
+            // In manifest v1, control files and SQL scripts are in the root of the archive
+            Some(PackagedFile::ControlFile {} | PackagedFile::SqlFile {})
+                if manifest.manifest_version < 2 =>
+            {
+                dirs.sharedir.join("extension")
+            }
+            Some(
+                PackagedFile::ControlFile {} | PackagedFile::SqlFile {} | PackagedFile::Extra {},
+            ) => dirs.sharedir.clone(),
+            Some(PackagedFile::SharedObject {} | PackagedFile::Bitcode {}) => {
+                dirs.pkglibdir.clone()
+            }
+        };
+        let destination = dir.join(&path);
+        let mut header = entry.header().clone();
+        tar.append_data(
+            &mut header,
+            destination.strip_prefix("/").unwrap_or(&destination),
+            &mut entry,
+        )?;
+    }
+
+    let mut header = Header::new_gnu();
+    header.set_size(sql.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, SQL_PATH.trim_start_matches('/'), sql)?;
+
+    Ok(tar.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_the_archive_like_trunk_install() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("ext-1.0.0-pg15.tar.gz");
+        let mut manifest = Manifest {
+            manifest_version: 2,
+            ..Default::default()
+        };
+        let mut tar = Builder::new(flate2::write::GzEncoder::new(
+            File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        ));
+        for (path, contents) in [
+            ("extension/ext.control", "default_version = '1.0.0'"),
+            ("extension/ext--1.0.0.sql", "SELECT 1;"),
+            ("ext.so", "\x7fELF"),
+            ("licenses/ext/LICENSE", "MIT"),
+        ] {
+            manifest.add_file(path);
+            let mut header = Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        // Files that the manifest doesn't list aren't installed
+        let mut header = Header::new_gnu();
+        header.set_size(0);
+        header.set_cksum();
+        tar.append_data(&mut header, "included/README.md", &[][..])
+            .unwrap();
+        let manifest = serde_json::to_string(&manifest).unwrap();
+        let mut header = Header::new_gnu();
+        header.set_size(manifest.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "manifest.json", manifest.as_bytes())
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let dirs = PostgresDirs {
+            sharedir: "/usr/share/postgresql/15".into(),
+            pkglibdir: "/usr/lib/postgresql/15/lib".into(),
+        };
+        let installed = install_tar(&archive, &dirs, b"CREATE EXTENSION ext;").unwrap();
+        let mut paths = Vec::new();
+        for entry in Archive::new(installed.as_slice()).entries().unwrap() {
+            paths.push(
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "tmp/trunk-integration-test.sql",
+                "usr/lib/postgresql/15/lib/ext.so",
+                "usr/share/postgresql/15/extension/ext--1.0.0.sql",
+                "usr/share/postgresql/15/extension/ext.control",
+            ]
+        );
+    }
+}
@@ -3,13 +3,28 @@ use tokio_task_manager::Task;
 
 pub mod build;
 pub mod categories;
+pub mod clean;
+mod compare;
 mod containers;
+mod context_checksum;
+mod context_filter;
+pub mod doctor;
+mod encryption;
 mod generic_build;
+mod git_versions;
 pub mod install;
+mod integration_test;
 pub mod license;
+mod output_layout;
 mod pgrx;
 pub mod publish;
+mod registry_auth;
+mod report;
+mod runtime_trace;
+mod signing;
 pub mod verify;
+pub mod verify_archive;
+mod workspace;
 
 #[async_trait]
 pub trait SubCommand {
@@ -0,0 +1,273 @@
+//! How `trunk build --output-layout` arranges archives under the output path.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::commands::build::BuildOutput;
+use crate::v1::{Download, Extension, TrunkProjectView};
+
+/// Where the registry layout keeps its archives, under the output path
+pub const REGISTRY_DOWNLOADS_DIR: &str = "downloads";
+/// Where the registry layout keeps the index of each project, under the output path. It is the
+/// endpoint `trunk install` queries
+pub const REGISTRY_INDEX_DIR: &str = "api/v1/trunk-projects";
+
+/// How archives are arranged under the output path
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputLayout {
+    /// Every archive directly in the output path
+    #[default]
+    Flat,
+    /// Archives in <name>/<version>/<platform>/
+    Nested,
+    /// Archives and an index that `trunk install --registry` can read from a static file server
+    Registry,
+}
+
+impl OutputLayout {
+    /// The layouts other than `flat` put the platform in the path, so they need to know it
+    /// before anything is built
+    pub fn validate(self, platform: Option<&str>) -> anyhow::Result<()> {
+        if self != OutputLayout::Flat && platform.is_none() {
+            bail!(
+                "--output-layout {} puts the platform in the path of the archive, so it requires \
+                 --platform, or platform in Trunk.toml",
+                self.as_str()
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutputLayout::Flat => "flat",
+            OutputLayout::Nested => "nested",
+            OutputLayout::Registry => "registry",
+        }
+    }
+
+    /// The directory the archive of `name` and `version` is written to
+    pub fn artifact_dir(
+        self,
+        output_path: &Path,
+        name: &str,
+        version: &str,
+        platform: Option<&str>,
+    ) -> PathBuf {
+        let nested = || {
+            Path::new(name)
+                .join(version)
+                .join(platform_dir(platform.unwrap_or_default()))
+        };
+        match self {
+            OutputLayout::Flat => output_path.to_path_buf(),
+            OutputLayout::Nested => output_path.join(nested()),
+            OutputLayout::Registry => output_path.join(REGISTRY_DOWNLOADS_DIR).join(nested()),
+        }
+    }
+
+    /// Records the archive of `output` in the index of the layout, if it has one. Returns the
+    /// path of the index
+    pub fn write_index(
+        self,
+        output_path: &Path,
+        output: &BuildOutput,
+        platform: Option<&str>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        if self != OutputLayout::Registry {
+            return Ok(None);
+        }
+        let manifest = &output.manifest;
+        let index_path = output_path.join(REGISTRY_INDEX_DIR).join(&manifest.name);
+        let mut projects: Vec<TrunkProjectView> = match fs::read(&index_path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .with_context(|| format!("Failed to parse {}", index_path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", index_path.display()))
+            }
+        };
+
+        let archive = fs::read(&output.artifact_path)
+            .with_context(|| format!("Failed to read {}", output.artifact_path.display()))?;
+        let link = output
+            .artifact_path
+            .strip_prefix(output_path)
+            .with_context(|| {
+                format!(
+                    "{} is not under {}",
+                    output.artifact_path.display(),
+                    output_path.display()
+                )
+            })?
+            .to_string_lossy()
+            .into_owned();
+        let download = Download {
+            link,
+            pg_version: manifest.pg_version,
+            platform: platform.unwrap_or_default().to_string(),
+            sha256: hex::encode(Sha256::digest(&archive)),
+        };
+        let extension_name = manifest
+            .extension_name
+            .clone()
+            .unwrap_or_else(|| manifest.name.clone());
+
+        let project = match projects
+            .iter_mut()
+            .find(|project| project.version == manifest.extension_version)
+        {
+            Some(project) => project,
+            None => {
+                projects.push(TrunkProjectView {
+                    name: manifest.name.clone(),
+                    version: manifest.extension_version.clone(),
+                    postgres_versions: None,
+                    downloads: None,
+                    extensions: Vec::new(),
+                });
+                projects.last_mut().expect("a project was just pushed")
+            }
+        };
+        // A rebuild for the same Postgres version and platform replaces the earlier archive
+        let downloads = project.downloads.get_or_insert_with(Vec::new);
+        downloads.retain(|existing| {
+            existing.pg_version != download.pg_version || existing.platform != download.platform
+        });
+        downloads.push(download);
+        downloads.sort_by(|a, b| (a.pg_version, &a.platform).cmp(&(b.pg_version, &b.platform)));
+        let postgres_versions = project.postgres_versions.get_or_insert_with(Vec::new);
+        if !postgres_versions.contains(&manifest.pg_version) {
+            postgres_versions.push(manifest.pg_version);
+            postgres_versions.sort_unstable();
+        }
+        if !project
+            .extensions
+            .iter()
+            .any(|extension| extension.extension_name == extension_name)
+        {
+            project.extensions.push(Extension { extension_name });
+        }
+
+        let index_dir = index_path.parent().expect("the index is in a directory");
+        fs::create_dir_all(index_dir)
+            .with_context(|| format!("Failed to create {}", index_dir.display()))?;
+        fs::write(&index_path, serde_json::to_string_pretty(&projects)?)
+            .with_context(|| format!("Failed to write {}", index_path.display()))?;
+
+        Ok(Some(index_path))
+    }
+}
+
+/// The directory of a platform such as `linux/arm64/v8`, as one path component
+fn platform_dir(platform: &str) -> String {
+    platform.replace('/', "-")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::timings::BuildTimings;
+
+    #[test]
+    fn lays_out_archives() {
+        let output_path = Path::new(".trunk");
+        let dir = |layout: OutputLayout| {
+            layout.artifact_dir(output_path, "pg_ext", "1.2.0", Some("linux/arm64"))
+        };
+        assert_eq!(dir(OutputLayout::Flat), Path::new(".trunk"));
+        assert_eq!(
+            dir(OutputLayout::Nested),
+            Path::new(".trunk/pg_ext/1.2.0/linux-arm64")
+        );
+        assert_eq!(
+            dir(OutputLayout::Registry),
+            Path::new(".trunk/downloads/pg_ext/1.2.0/linux-arm64")
+        );
+
+        assert!(OutputLayout::Flat.validate(None).is_ok());
+        assert!(OutputLayout::Nested.validate(Some("linux/amd64")).is_ok());
+        let err = OutputLayout::Registry.validate(None).unwrap_err();
+        assert!(err.to_string().contains("requires --platform"), "{err}");
+    }
+
+    #[test]
+    fn merges_builds_into_the_registry_index() {
+        let output_path = tempfile::tempdir().unwrap();
+        let build = |pg_version: u8, platform: &str, contents: &str| {
+            let dir = OutputLayout::Registry.artifact_dir(
+                output_path.path(),
+                "pg_ext",
+                "1.2.0",
+                Some(platform),
+            );
+            fs::create_dir_all(&dir).unwrap();
+            let artifact_path = dir.join(format!("pg_ext-1.2.0-pg{pg_version}.tar.gz"));
+            fs::write(&artifact_path, contents).unwrap();
+            let output = BuildOutput {
+                artifact_path,
+                manifest: Manifest {
+                    name: "pg_ext".to_string(),
+                    extension_name: Some("ext".to_string()),
+                    extension_version: "1.2.0".to_string(),
+                    pg_version,
+                    ..Default::default()
+                },
+                timings: BuildTimings::default(),
+                signatures: Vec::new(),
+                debug_symbols_path: None,
+                encryption_sidecar: None,
+            };
+            OutputLayout::Registry
+                .write_index(output_path.path(), &output, Some(platform))
+                .unwrap()
+                .unwrap()
+        };
+        build(16, "linux/amd64", "first");
+        build(15, "linux/amd64", "other");
+        let index_path = build(16, "linux/amd64", "rebuilt");
+        assert_eq!(
+            index_path,
+            output_path.path().join("api/v1/trunk-projects/pg_ext")
+        );
+
+        let projects: Vec<TrunkProjectView> =
+            serde_json::from_slice(&fs::read(index_path).unwrap()).unwrap();
+        assert_eq!(projects.len(), 1);
+        assert_eq!(projects[0].version, "1.2.0");
+        assert_eq!(projects[0].postgres_versions, Some(vec![15, 16]));
+        assert_eq!(projects[0].extensions[0].extension_name, "ext");
+        let downloads = projects[0].downloads.as_ref().unwrap();
+        assert_eq!(downloads.len(), 2);
+        assert_eq!(
+            downloads[1].link,
+            "downloads/pg_ext/1.2.0/linux-amd64/pg_ext-1.2.0-pg16.tar.gz"
+        );
+        assert_eq!(downloads[1].sha256, hex::encode(Sha256::digest(b"rebuilt")));
+
+        assert_eq!(
+            OutputLayout::Nested
+                .write_index(
+                    output_path.path(),
+                    &BuildOutput {
+                        artifact_path: PathBuf::new(),
+                        manifest: Manifest::default(),
+                        timings: BuildTimings::default(),
+                        signatures: Vec::new(),
+                        debug_symbols_path: None,
+                        encryption_sidecar: None,
+                    },
+                    None
+                )
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -1,24 +1,37 @@
 use semver::{Version, VersionReq};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use std::path::{Path, StripPrefixError};
+use std::path::{Path, PathBuf, StripPrefixError};
 use std::string::FromUtf8Error;
+use std::time::Instant;
 use std::{fs, include_str};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use bollard::Docker;
+use log::warn;
 
+use crate::build_log::tee_println;
+use crate::changelog::Changelog;
 use crate::commands::containers::{
-    build_image, exec_in_container, package_installed_extension_files, run_temporary_container,
+    base_image, build_image, check_dockerfile, container_path, exec_in_container,
+    package_installed_extension_files, run_temporary_container, stop_before_install,
+    toolchain_versions, BuilderKind, ImageBuildOptions, NoInstall, Strip,
+    PGRX_BUILDER_IMAGE_PREFIX, PGRX_TOOLCHAIN,
 };
-use crate::config::{ExtensionConfiguration, LoadableLibrary};
+use crate::config::{ControlFields, ExtensionConfiguration, LoadableLibrary, Preload};
+use crate::manifest::{ExtensionKind, SupportedPgVersions};
+use crate::timings::BuildTimings;
 use crate::trunk_toml::SystemDependencies;
+use crate::warnings::{warn_or_fail, WarningCategory};
 use crate::{pg_release_for_version, pg_version_to_str};
 use tokio::sync::mpsc;
 
 use tokio::task::JoinError;
 
+use crate::commands::build::BuildOutput;
+use crate::commands::generic_build::CompilerFlags;
 use crate::commands::license::{copy_licenses, find_licenses};
 use tokio_task_manager::Task;
 use toml::Value;
@@ -57,12 +70,15 @@ pub enum PgrxBuildError {
     OtherError(#[from] anyhow::Error),
 }
 
+/// The pgrx versions there are builder images for, newest first
+const PGRX_VERSIONS: [&str; 22] = [
+    "0.12.5", "0.12.4", "0.12.3", "0.12.2", "0.12.1", "0.12.0", "0.11.4", "0.11.3", "0.11.2",
+    "0.11.1", "0.11.0", "0.10.2", "0.10.1", "0.10.0", "0.9.8", "0.9.7", "0.9.1", "0.9.0", "0.8.4",
+    "0.8.3", "0.8.0", "0.7.4",
+];
+
 fn semver_from_range(pgrx_range: &str) -> Result<String, PgrxBuildError> {
-    let versions = [
-        "0.12.5", "0.12.4", "0.12.3", "0.12.2", "0.12.1", "0.12.0", "0.11.4", "0.11.3", "0.11.2",
-        "0.11.1", "0.11.0", "0.10.2", "0.10.1", "0.10.0", "0.9.8", "0.9.7", "0.9.1", "0.9.0",
-        "0.8.4", "0.8.3", "0.8.0", "0.7.4",
-    ];
+    let versions = PGRX_VERSIONS;
 
     if versions.contains(&pgrx_range) {
         // If the input is already a specific version, return it as-is
@@ -92,9 +108,385 @@ fn semver_from_range(pgrx_range: &str) -> Result<String, PgrxBuildError> {
     Ok(pgrx_version)
 }
 
+/// What a pgrx build needs from the extension's Cargo.toml, with the values it inherits from
+/// its workspace (`version.workspace = true`) resolved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CargoPackage {
+    pub name: String,
+    pub version: String,
+    /// Used by generic builds and `trunk publish` that don't set a description otherwise
+    pub description: Option<String>,
+    /// The requirement on pgrx from `[dependencies]` or the workspace, or else the version
+    /// locked in Cargo.lock. `None` if none of them mention pgrx.
+    pub pgrx_requirement: Option<String>,
+}
+
+/// Whether the Cargo.toml has a direct dependency on pgrx, however it is specified
+pub fn depends_on_pgrx(cargo_toml: &toml::Table) -> bool {
+    cargo_toml
+        .get("dependencies")
+        .and_then(Value::as_table)
+        .is_some_and(|dependencies| dependencies.contains_key("pgrx"))
+}
+
+impl CargoPackage {
+    /// Reads the Cargo.toml in `package_dir`, consulting the workspace root for inherited values
+    pub fn read(package_dir: &Path) -> Result<Self, PgrxBuildError> {
+        let manifest_error = |message: String| PgrxBuildError::ManifestError(message);
+        let cargo_toml = read_cargo_toml(&package_dir.join("Cargo.toml"))?;
+        let package = cargo_toml
+            .get("package")
+            .and_then(Value::as_table)
+            .ok_or_else(|| {
+                manifest_error("Could not find package info in Cargo.toml".to_string())
+            })?;
+        let name = match package.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            // Cargo itself rejects this, but a clear error beats a missing name
+            Some(name) if is_inherited(name) => {
+                return Err(manifest_error(
+                    "Cargo.toml sets name.workspace = true, but a package's name can't be \
+                     inherited from the workspace"
+                        .to_string(),
+                ))
+            }
+            _ => {
+                return Err(manifest_error(
+                    "Could not find package name in Cargo.toml".to_string(),
+                ))
+            }
+        };
+
+        let workspace = find_workspace(package_dir, package)?;
+        let workspace_table = |section: &str| {
+            workspace.as_ref().and_then(|(_, workspace)| {
+                workspace
+                    .get("workspace")
+                    .and_then(|workspace| workspace.get(section))
+                    .and_then(Value::as_table)
+            })
+        };
+
+        let version = match package.get("version") {
+            Some(Value::String(version)) => version.clone(),
+            Some(version) if is_inherited(version) => {
+                let Some((workspace_dir, _)) = &workspace else {
+                    return Err(manifest_error(format!(
+                        "Cargo.toml inherits its version from the workspace, but no workspace \
+                         root was found in {} or its parent directories",
+                        package_dir.display()
+                    )));
+                };
+                workspace_table("package")
+                    .and_then(|package| package.get("version"))
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| {
+                        manifest_error(format!(
+                            "Cargo.toml inherits its version from the workspace, but {} has no \
+                             version in [workspace.package]",
+                            workspace_dir.join("Cargo.toml").display()
+                        ))
+                    })?
+                    .to_string()
+            }
+            _ => {
+                return Err(manifest_error(
+                    "Could not find package version in Cargo.toml".to_string(),
+                ))
+            }
+        };
+
+        let description = match package.get("description") {
+            Some(description) if is_inherited(description) => workspace_table("package")
+                .and_then(|package| package.get("description"))
+                .and_then(Value::as_str)
+                .map(ToOwned::to_owned),
+            description => description.and_then(Value::as_str).map(ToOwned::to_owned),
+        };
+
+        let pgrx_dependency = cargo_toml
+            .get("dependencies")
+            .and_then(|dependencies| dependencies.get("pgrx"));
+        let pgrx_dependency = match pgrx_dependency {
+            Some(dependency) if is_inherited(dependency) => {
+                workspace_table("dependencies").and_then(|dependencies| dependencies.get("pgrx"))
+            }
+            dependency => dependency,
+        };
+        let pgrx_requirement = pgrx_dependency
+            .and_then(|dependency| match dependency {
+                Value::String(requirement) => Some(requirement.as_str()),
+                dependency => dependency.get("version").and_then(Value::as_str),
+            })
+            .map(ToOwned::to_owned)
+            .or_else(|| {
+                let lock_dirs = [
+                    Some(package_dir),
+                    workspace.as_ref().map(|(dir, _)| dir.as_path()),
+                ];
+                lock_dirs
+                    .into_iter()
+                    .flatten()
+                    .find_map(locked_pgrx_version)
+                    .map(|version| format!("={version}"))
+            });
+
+        Ok(Self {
+            name,
+            version,
+            description,
+            pgrx_requirement,
+        })
+    }
+}
+
+/// Reads and parses the Cargo.toml at `path`. Errors name the file, and parse errors say where
+/// in it they are, as a Cargo.toml broken by a bad merge is easy to miss
+pub fn read_cargo_toml(path: &Path) -> Result<toml::Table, PgrxBuildError> {
+    let contents = fs::read_to_string(path).map_err(|err| {
+        PgrxBuildError::ManifestError(format!("Failed to read {}: {err}", path.display()))
+    })?;
+
+    toml::from_str(&contents).map_err(|err| {
+        PgrxBuildError::ManifestError(format!("{} is not valid TOML: {err}", path.display()))
+    })
+}
+
+/// Whether a Cargo.toml value is `{ workspace = true }`
+fn is_inherited(value: &Value) -> bool {
+    value.get("workspace").and_then(Value::as_bool) == Some(true)
+}
+
+/// The workspace root of the package in `package_dir` and its Cargo.toml: the directory given by
+/// `package.workspace`, or else the closest parent directory whose Cargo.toml has a `[workspace]`
+fn find_workspace(
+    package_dir: &Path,
+    package: &toml::Table,
+) -> Result<Option<(PathBuf, toml::Table)>, PgrxBuildError> {
+    let package_dir = fs::canonicalize(package_dir)?;
+
+    if let Some(workspace_dir) = package.get("workspace").and_then(Value::as_str) {
+        let workspace_dir = package_dir.join(workspace_dir);
+        let workspace = read_cargo_toml(&workspace_dir.join("Cargo.toml"))?;
+        return Ok(Some((workspace_dir, workspace)));
+    }
+
+    for dir in package_dir.ancestors() {
+        let cargo_toml_path = dir.join("Cargo.toml");
+        if !cargo_toml_path.is_file() {
+            continue;
+        }
+        let cargo_toml = read_cargo_toml(&cargo_toml_path)?;
+        if cargo_toml.contains_key("workspace") {
+            return Ok(Some((dir.to_path_buf(), cargo_toml)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The version of pgrx in the Cargo.lock of `dir`, if there is one
+fn locked_pgrx_version(dir: &Path) -> Option<String> {
+    let lockfile: toml::Table =
+        toml::from_str(&fs::read_to_string(dir.join("Cargo.lock")).ok()?).ok()?;
+
+    lockfile
+        .get("package")?
+        .as_array()?
+        .iter()
+        .find(|package| package.get("name").and_then(Value::as_str) == Some("pgrx"))?
+        .get("version")?
+        .as_str()
+        .map(ToOwned::to_owned)
+}
+
+/// The pgrx version to build with. Builds forced into pgrx mode don't need to declare pgrx in a
+/// way trunk can find, and fall back to the newest version trunk has a builder image for.
+fn resolve_pgrx_version(
+    pgrx_requirement: Option<&str>,
+    force_pgrx: bool,
+) -> Result<String, PgrxBuildError> {
+    match pgrx_requirement {
+        Some(pgrx_requirement) => {
+            tee_println!("Detected pgrx version range {pgrx_requirement}");
+            semver_from_range(pgrx_requirement)
+        }
+        None if force_pgrx => {
+            let newest = PGRX_VERSIONS[0];
+            warn!(
+                "Could not find the pgrx version in Cargo.toml or Cargo.lock, building with pgrx {newest}"
+            );
+            Ok(newest.to_string())
+        }
+        None => Err(PgrxBuildError::ManifestError(
+            "Could not find pgrx dependency info in Cargo.toml".to_string(),
+        )),
+    }
+}
+
+/// The Cargo profile `cargo pgrx package` builds with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CargoProfile {
+    /// Optimized, as pgrx packages by default
+    #[default]
+    Release,
+    /// Unoptimized, with debug symbols
+    Debug,
+}
+
+impl CargoProfile {
+    /// The name of the profile, which is also its directory under `target/`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CargoProfile::Release => "release",
+            CargoProfile::Debug => "debug",
+        }
+    }
+}
+
+/// The Cargo features `cargo pgrx package` builds with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CargoFeatures {
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+    pub all_features: bool,
+}
+
+impl CargoFeatures {
+    /// Rejects combinations Cargo would reject, and feature names that would be split or
+    /// expanded by the shell running `cargo pgrx package`
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.all_features && self.no_default_features {
+            anyhow::bail!("--all-features and --no-default-features cannot be used together");
+        }
+        for feature in &self.features {
+            let is_valid = !feature.is_empty()
+                && feature
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '-' | '/' | '+'));
+            if !is_valid {
+                anyhow::bail!(
+                    "Invalid Cargo feature '{feature}'. Features may only contain letters, digits, \
+                     '_', '-', '+' and '/'"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The flags passed to `cargo pgrx package`
+    pub fn flags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        if !self.features.is_empty() {
+            flags.push("--features".to_string());
+            flags.push(self.features.join(","));
+        }
+        if self.no_default_features {
+            flags.push("--no-default-features".to_string());
+        }
+        if self.all_features {
+            flags.push("--all-features".to_string());
+        }
+        flags
+    }
+}
+
+/// The flags passed to `cargo pgrx package`, for `cargo_features` and `profile`
+pub fn cargo_pgrx_flags(cargo_features: &CargoFeatures, profile: CargoProfile) -> Vec<String> {
+    let mut flags = cargo_features.flags();
+    if profile == CargoProfile::Debug {
+        flags.push("--debug".to_string());
+    }
+    flags
+}
+
+/// The command that copies the files `cargo pgrx package` wrote into place in the builder
+/// container, to be captured from there
+pub fn copy_packaged_files_command(
+    name: &str,
+    pg_version: u8,
+    profile: CargoProfile,
+) -> Vec<String> {
+    vec![
+        "cp".to_string(),
+        "--verbose".to_string(),
+        "-R".to_string(),
+        format!("target/{}/{name}-pg{pg_version}/usr", profile.as_str()),
+        "/".to_string(),
+    ]
+}
+
+/// Rejects toolchain names rustup wouldn't accept, as the name is passed through a shell
+pub fn validate_rust_toolchain(toolchain: &str) -> Result<(), anyhow::Error> {
+    let is_valid = !toolchain.is_empty()
+        && !toolchain.starts_with('-')
+        && toolchain
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '-' | '_'));
+    if !is_valid {
+        anyhow::bail!(
+            "Invalid Rust toolchain '{toolchain}'. Expected a channel such as stable, 1.79.0 or \
+             nightly-2024-06-01"
+        );
+    }
+
+    Ok(())
+}
+
+/// The toolchain channel pinned by the `rust-toolchain.toml`, or the legacy `rust-toolchain`
+/// file, in `dir`
+fn read_rust_toolchain_file(dir: &Path) -> Result<Option<(PathBuf, String)>, PgrxBuildError> {
+    let toml_path = dir.join("rust-toolchain.toml");
+    let legacy_path = dir.join("rust-toolchain");
+    let (path, contents) = match fs::read_to_string(&toml_path) {
+        Ok(contents) => (toml_path, contents),
+        Err(_) => match fs::read_to_string(&legacy_path) {
+            Ok(contents) => (legacy_path, contents),
+            Err(_) => return Ok(None),
+        },
+    };
+
+    // The legacy file may hold just the channel name
+    let trimmed = contents.trim();
+    if !trimmed.is_empty() && !trimmed.contains(['\n', '=', '[']) {
+        return Ok(Some((path, trimmed.to_string())));
+    }
+
+    let toolchain: toml::Table = toml::from_str(&contents).map_err(|err| {
+        PgrxBuildError::ManifestError(format!("{} is not valid TOML: {err}", path.display()))
+    })?;
+    let channel = toolchain
+        .get("toolchain")
+        .and_then(|toolchain| toolchain.get("channel"))
+        .and_then(Value::as_str)
+        .map(ToOwned::to_owned);
+
+    Ok(channel.map(|channel| (path, channel)))
+}
+
+/// The Rust toolchain to build with: the one requested, or else the one pinned in the sources
+fn resolve_rust_toolchain(
+    rust_toolchain: Option<String>,
+    extension_dir: &Path,
+) -> Result<Option<String>, PgrxBuildError> {
+    if let Some(toolchain) = rust_toolchain {
+        tee_println!("Using Rust toolchain {toolchain}");
+        return Ok(Some(toolchain));
+    }
+    let Some((path, channel)) = read_rust_toolchain_file(extension_dir)? else {
+        return Ok(None);
+    };
+    validate_rust_toolchain(&channel)?;
+    tee_println!("Using Rust toolchain {channel} from {}", path.display());
+
+    Ok(Some(channel))
+}
+
 fn get_dockerfile(path: Option<String>) -> Result<String, std::io::Error> {
     if let Some(dockerfile_path) = path {
-        println!("Using Dockerfile at {}", &dockerfile_path);
+        tee_println!("Using Dockerfile at {}", &dockerfile_path);
         Ok(fs::read_to_string(dockerfile_path.as_str())?)
     } else {
         Ok(include_str!("./builders/Dockerfile.pgrx").to_string())
@@ -105,65 +497,72 @@ pub async fn build_pgrx(
     dockerfile_path: Option<String>,
     platform: Option<String>,
     path: &Path,
+    extension_dir: Option<&str>,
     output_path: &str,
     extension_name: Option<String>,
     extension_dependencies: Option<Vec<String>>,
-    cargo_toml: toml::Table,
+    package: CargoPackage,
+    force_pgrx: bool,
     system_dependencies: Option<SystemDependencies>,
     inclusion_patterns: Vec<glob::Pattern>,
+    capture_globs: Vec<glob::Pattern>,
     configurations: Option<Vec<ExtensionConfiguration>>,
     loadable_libraries: Option<Vec<LoadableLibrary>>,
+    preload: Option<Preload>,
+    kind: ExtensionKind,
+    control: Option<ControlFields>,
     pg_version: u8,
+    supported_pg_versions: SupportedPgVersions,
+    included_files: Vec<String>,
+    artifact_suffix: &str,
+    allow_missing_control: bool,
+    file_digests: bool,
+    strip: Option<Strip>,
+    changelog: Option<Changelog>,
+    labels: Option<BTreeMap<String, String>>,
+    categories: Option<Vec<String>>,
+    manifest_transform: Option<String>,
+    manifest_version: i32,
+    fail_on_warn: &[WarningCategory],
+    allow_arch_mismatch: bool,
+    cargo_features: CargoFeatures,
+    profile: CargoProfile,
+    rust_toolchain: Option<String>,
+    no_install: Option<NoInstall>,
+    image_build_options: ImageBuildOptions,
     _task: Task,
-) -> Result<(), PgrxBuildError> {
-    let cargo_package_info = cargo_toml
-        .get("package")
-        .into_iter()
-        .filter_map(Value::as_table)
-        .next()
-        .ok_or(PgrxBuildError::ManifestError(
-            "Could not find package info in Cargo.toml".to_string(),
-        ))?;
-    let name = cargo_package_info
-        .get("name")
-        .into_iter()
-        .filter_map(Value::as_str)
-        .next()
-        .ok_or(PgrxBuildError::ManifestError(
-            "Could not find package name in Cargo.toml".to_string(),
-        ))?;
-    let extension_version = cargo_package_info
-        .get("version")
-        .into_iter()
-        .filter_map(Value::as_str)
-        .next()
-        .ok_or(PgrxBuildError::ManifestError(
-            "Could not find package version in Cargo.toml".to_string(),
-        ))?;
-    let pgrx_range = cargo_toml
-        .get("dependencies")
-        .into_iter()
-        .filter_map(Value::as_table)
-        .next()
-        .ok_or(PgrxBuildError::ManifestError(
-            "Could not find dependencies info in Cargo.toml".to_string(),
-        ))?
-        .get("pgrx")
-        .into_iter()
-        .filter_map(Value::as_str)
-        .next()
-        .ok_or(PgrxBuildError::ManifestError(
-            "Could not find pgrx dependency info in Cargo.toml".to_string(),
-        ))?;
+) -> Result<Option<BuildOutput>, PgrxBuildError> {
+    let name = package.name.as_str();
+    let extension_version = package.version.as_str();
+    let pgrx_version = resolve_pgrx_version(package.pgrx_requirement.as_deref(), force_pgrx)?;
+    tee_println!("Using pgrx version {pgrx_version}");
 
-    println!("Detected pgrx version range {}", &pgrx_range);
-
-    let pgrx_version = semver_from_range(pgrx_range)?;
-    println!("Using pgrx version {pgrx_version}");
-
-    println!("Building pgrx extension at path {}", &path.display());
+    tee_println!("Building pgrx extension at path {}", &path.display());
+    let rust_toolchain =
+        resolve_rust_toolchain(rust_toolchain, &path.join(extension_dir.unwrap_or(".")))?;
 
+    let is_custom_dockerfile = dockerfile_path.is_some();
     let dockerfile = get_dockerfile(dockerfile_path).unwrap();
+    let cargo_flags = cargo_pgrx_flags(&cargo_features, profile).join(" ");
+    if is_custom_dockerfile {
+        for warning in check_dockerfile(&dockerfile, BuilderKind::Pgrx) {
+            warn_or_fail(fail_on_warn, WarningCategory::Dockerfile, &warning)
+                .map_err(anyhow::Error::from)?;
+        }
+        if !cargo_flags.is_empty() && !dockerfile.contains("CARGO_PGRX_FLAGS") {
+            warn!(
+                "The Dockerfile does not use the CARGO_PGRX_FLAGS build argument, so the Cargo \
+                 feature flags ({cargo_flags}) have no effect"
+            );
+        }
+        if rust_toolchain.is_some() && !dockerfile.contains("RUST_TOOLCHAIN") {
+            warn!(
+                "The Dockerfile does not use the RUST_TOOLCHAIN build argument, so the Rust \
+                 toolchain is not selected"
+            );
+        }
+    }
+    tee_println!("Packaging with: cargo pgrx package {cargo_flags}");
 
     let mut build_args = HashMap::new();
     build_args.insert("EXTENSION_NAME", name);
@@ -171,43 +570,77 @@ pub async fn build_pgrx(
     build_args.insert("PGRX_VERSION", pgrx_version.as_str());
     build_args.insert("PG_VERSION", pg_version_to_str(pg_version));
     build_args.insert("PG_RELEASE", pg_release_for_version(pg_version));
-
-    let image_name_prefix = "pgrx_builder_".to_string();
+    build_args.insert("EXTENSION_DIR", extension_dir.unwrap_or("."));
+    build_args.insert("CARGO_PGRX_FLAGS", cargo_flags.as_str());
+    if let Some(toolchain) = rust_toolchain.as_deref() {
+        build_args.insert("RUST_TOOLCHAIN", toolchain);
+    }
+    if let Some(base_image) = image_build_options.base_image.as_deref() {
+        tee_println!("Using base image {base_image}");
+        build_args.insert("BASE_IMAGE", base_image);
+    }
 
     let docker = Docker::connect_with_local_defaults()?;
+    let mut timings = BuildTimings::default();
 
+    let base_image = base_image(&dockerfile, &build_args);
     let image_name = build_image(
         platform.clone(),
         docker.clone(),
-        &image_name_prefix,
+        PGRX_BUILDER_IMAGE_PREFIX,
         &dockerfile,
         path,
         build_args,
+        &image_build_options,
+        &mut timings,
     )
     .await?;
+    if let Some(no_install) = no_install {
+        stop_before_install(
+            &docker,
+            &image_name,
+            platform.as_deref(),
+            &image_build_options,
+            no_install,
+        )
+        .await?;
+        return Ok(None);
+    }
 
-    let temp_container =
-        run_temporary_container(docker.clone(), platform.clone(), image_name.as_str(), _task)
-            .await?;
+    let temp_container = run_temporary_container(
+        docker.clone(),
+        platform.clone(),
+        image_name.as_str(),
+        image_name.as_str(),
+        &image_build_options,
+        _task,
+    )
+    .await?;
+
+    let extension_dir = match extension_dir {
+        Some(extension_dir) => {
+            Some(container_path(&docker, &temp_container.id, extension_dir).await?)
+        }
+        None => None,
+    };
 
-    println!("Determining installation files...");
+    tee_println!("Determining installation files...");
+    let started = Instant::now();
+    let copy_command = copy_packaged_files_command(name, pg_version, profile);
     let _exec_output = exec_in_container(
         &docker,
         &temp_container.id,
-        vec![
-            "cp",
-            "--verbose",
-            "-R",
-            format!("target/release/{name}-pg{pg_version}/usr").as_str(),
-            "/",
-        ],
-        None,
+        copy_command.iter().map(String::as_str).collect(),
+        extension_dir.as_deref(),
         None,
     )
     .await?;
 
+    timings.record_since("install", started);
+
     // Search for license files to include
-    println!("Determining license files to include...");
+    let started = Instant::now();
+    tee_println!("Determining license files to include...");
     let license_vec = find_licenses(docker.clone(), &temp_container.id).await?;
 
     // Create directory /usr/licenses/
@@ -233,6 +666,14 @@ pub async fn build_pgrx(
     )
     .await?;
 
+    timings.record_since("capture", started);
+
+    tee_println!("Determining toolchain versions...");
+    let mut toolchain = toolchain_versions(&docker, &temp_container.id, PGRX_TOOLCHAIN).await;
+    if let Some(rust_toolchain) = rust_toolchain {
+        toolchain.insert("rust-toolchain".to_string(), rust_toolchain);
+    }
+
     // output_path is the locally output path
     fs::create_dir_all(output_path)?;
 
@@ -246,19 +687,230 @@ pub async fn build_pgrx(
         extension_version,
         extension_dependencies,
         inclusion_patterns,
+        capture_globs,
         configurations,
         loadable_libraries,
+        preload,
+        kind,
+        control,
         pg_version,
+        supported_pg_versions,
+        path,
+        included_files,
+        artifact_suffix,
+        allow_missing_control,
+        Some(profile.as_str()),
+        toolchain,
+        base_image,
+        false,
+        file_digests,
+        strip,
+        BTreeMap::new(),
+        changelog,
+        labels,
+        categories,
+        manifest_transform,
+        CompilerFlags::default(),
+        manifest_version,
+        fail_on_warn,
+        platform.as_deref(),
+        allow_arch_mismatch,
+        // The version of a pgrx extension and its control file's default_version both come from
+        // Cargo.toml
+        false,
+        timings,
     )
-    .await?;
-
-    Ok(())
+    .await
+    .map(Some)
+    .map_err(PgrxBuildError::from)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn cargo_feature_flags() {
+        let cargo_features = CargoFeatures {
+            features: vec!["pg_test".to_string(), "serde/std".to_string()],
+            no_default_features: true,
+            all_features: false,
+        };
+        assert!(cargo_features.validate().is_ok());
+        assert_eq!(
+            cargo_features.flags(),
+            ["--features", "pg_test,serde/std", "--no-default-features"]
+        );
+        assert!(CargoFeatures::default().flags().is_empty());
+
+        for invalid in [
+            CargoFeatures {
+                no_default_features: true,
+                all_features: true,
+                ..Default::default()
+            },
+            CargoFeatures {
+                features: vec!["a b".to_string()],
+                ..Default::default()
+            },
+            CargoFeatures {
+                features: vec!["$(id)".to_string()],
+                ..Default::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid:?}");
+        }
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_workspace_inherited_package_metadata() {
+        let workspace = tempfile::tempdir().unwrap();
+        write_files(
+            workspace.path(),
+            &[
+                (
+                    "Cargo.toml",
+                    "[workspace]\nmembers = [\"extension\"]\n\n\
+                     [workspace.package]\nversion = \"1.2.3\"\ndescription = \"My extension\"\n\n\
+                     [workspace.dependencies]\npgrx = { version = \"=0.11.2\" }\n",
+                ),
+                (
+                    "extension/Cargo.toml",
+                    "[package]\nname = \"my_ext\"\nversion.workspace = true\n\
+                     description.workspace = true\n\n\
+                     [dependencies]\npgrx = { workspace = true }\n",
+                ),
+            ],
+        );
+
+        let package = CargoPackage::read(&workspace.path().join("extension")).unwrap();
+        assert_eq!(
+            package,
+            CargoPackage {
+                name: "my_ext".to_string(),
+                version: "1.2.3".to_string(),
+                description: Some("My extension".to_string()),
+                pgrx_requirement: Some("=0.11.2".to_string()),
+            }
+        );
+
+        // A version set in the member takes precedence over [workspace.package]
+        write_files(
+            workspace.path(),
+            &[(
+                "direct/Cargo.toml",
+                "[package]\nname = \"other_ext\"\nversion = \"0.4.0\"\n",
+            )],
+        );
+        let package = CargoPackage::read(&workspace.path().join("direct")).unwrap();
+        assert_eq!(package.name, "other_ext");
+        assert_eq!(package.version, "0.4.0");
+    }
+
+    #[test]
+    fn explains_unresolvable_inherited_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        write_files(
+            dir.path(),
+            &[(
+                "ext/Cargo.toml",
+                "[package]\nname = \"my_ext\"\nversion.workspace = true\n",
+            )],
+        );
+        let err = CargoPackage::read(&dir.path().join("ext"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no workspace root was found in"), "{err}");
+
+        write_files(
+            dir.path(),
+            &[("Cargo.toml", "[workspace]\nmembers = [\"ext\"]\n")],
+        );
+        let err = CargoPackage::read(&dir.path().join("ext"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("has no version in [workspace.package]"),
+            "{err}"
+        );
+
+        write_files(
+            dir.path(),
+            &[(
+                "ext/Cargo.toml",
+                "[package]\nname.workspace = true\nversion = \"0.1.0\"\n",
+            )],
+        );
+        let err = CargoPackage::read(&dir.path().join("ext"))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("a package's name can't be inherited"), "{err}");
+    }
+
+    #[test]
+    fn reports_where_cargo_toml_is_malformed() {
+        let dir = tempfile::tempdir().unwrap();
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[package]\nname = \"my_ext\"\n<<<<<<< HEAD\nversion = \"0.1.0\"\n",
+            )],
+        );
+        let err = CargoPackage::read(dir.path()).unwrap_err().to_string();
+        let cargo_toml = dir.path().join("Cargo.toml");
+        assert!(
+            err.contains(&format!("{} is not valid TOML", cargo_toml.display())),
+            "{err}"
+        );
+        assert!(err.contains("line 3, column 1"), "{err}");
+
+        let missing = dir.path().join("missing/Cargo.toml");
+        let err = read_cargo_toml(&missing).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("Failed to read {}", missing.display())),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn reads_packages_without_a_dependencies_table() {
+        let dir = tempfile::tempdir().unwrap();
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.toml",
+                "[package]\nname = \"my_ext\"\nversion = \"0.1.0\"\n",
+            )],
+        );
+        let cargo_toml = read_cargo_toml(&dir.path().join("Cargo.toml")).unwrap();
+        assert!(!depends_on_pgrx(&cargo_toml));
+
+        let package = CargoPackage::read(dir.path()).unwrap();
+        assert_eq!(package.pgrx_requirement, None);
+        assert!(resolve_pgrx_version(None, false).is_err());
+        assert_eq!(resolve_pgrx_version(None, true).unwrap(), PGRX_VERSIONS[0]);
+
+        // The version in Cargo.lock is used when Cargo.toml doesn't say
+        write_files(
+            dir.path(),
+            &[(
+                "Cargo.lock",
+                "version = 3\n\n[[package]]\nname = \"pgrx\"\nversion = \"0.11.3\"\n",
+            )],
+        );
+        let package = CargoPackage::read(dir.path()).unwrap();
+        assert_eq!(package.pgrx_requirement.as_deref(), Some("=0.11.3"));
+    }
+
     #[test]
     fn test_semver_from_range_specific_version() {
         // Test that a specific version string is returned as-is
@@ -287,4 +939,38 @@ mod tests {
         let result = semver_from_range(">=0.10.0, <0.11.0");
         assert_eq!(result.unwrap(), "0.10.2");
     }
+
+    #[test]
+    fn resolves_rust_toolchains() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(resolve_rust_toolchain(None, dir.path()).unwrap(), None);
+
+        fs::write(dir.path().join("rust-toolchain"), "1.79.0\n").unwrap();
+        assert_eq!(
+            resolve_rust_toolchain(None, dir.path()).unwrap().as_deref(),
+            Some("1.79.0")
+        );
+
+        fs::write(
+            dir.path().join("rust-toolchain.toml"),
+            "[toolchain]\nchannel = \"nightly-2024-06-01\"\ncomponents = [\"rustfmt\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_rust_toolchain(None, dir.path()).unwrap().as_deref(),
+            Some("nightly-2024-06-01")
+        );
+        // The requested toolchain wins over the one pinned in the sources
+        assert_eq!(
+            resolve_rust_toolchain(Some("stable".to_string()), dir.path())
+                .unwrap()
+                .as_deref(),
+            Some("stable")
+        );
+
+        assert!(validate_rust_toolchain("1.79.0-x86_64-unknown-linux-gnu").is_ok());
+        assert!(validate_rust_toolchain("").is_err());
+        assert!(validate_rust_toolchain("--force").is_err());
+        assert!(validate_rust_toolchain("stable; rm -rf /").is_err());
+    }
 }
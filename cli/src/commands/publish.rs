@@ -1,5 +1,6 @@
 use super::SubCommand;
 use crate::commands::categories::VALID_CATEGORY_SLUGS;
+use crate::commands::pgrx::CargoPackage;
 use crate::commands::publish::PublishError::InvalidExtensionName;
 use crate::config::{self, ExtensionConfiguration, LoadableLibrary};
 use crate::manifest::Manifest;
@@ -59,6 +60,7 @@ pub enum PublishError {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct Category {
     pub name: String,
     pub description: String,
@@ -83,24 +85,46 @@ pub struct PublishSettings {
     loadable_libraries: Option<Vec<LoadableLibrary>>,
 }
 
+/// The `[package]` of the Cargo.toml in `dir`, if there is one. The name, version and
+/// description fall back to it when neither the command-line nor Trunk.toml set them.
+fn read_cargo_package(dir: &Path) -> Option<CargoPackage> {
+    if !dir.join("Cargo.toml").exists() {
+        return None;
+    }
+
+    CargoPackage::read(dir)
+        .map_err(|err| warn!("Could not read Cargo.toml: {err}"))
+        .ok()
+}
+
+fn from_cargo_toml(field: &str, value: Option<&String>) -> Option<String> {
+    let value = value?;
+    info!("Cargo.toml: using setting `package.{field}`: {value}");
+
+    Some(value.clone())
+}
+
 impl PublishCommand {
     fn settings(&self) -> Result<PublishSettings, anyhow::Error> {
         // The file path of the extension to publish
         let publish_path = ".";
-        let trunkfile_path = Path::new(&publish_path).join("Trunk.toml");
+        let trunkfile_path = config::find_trunk_file(Path::new(&publish_path));
 
-        let trunk_toml = match File::open(trunkfile_path) {
-            Ok(file) => Some(config::parse_trunk_toml(file)?),
+        let trunk_toml = match File::open(&trunkfile_path) {
+            Ok(file) => Some(config::parse_trunk_file(&trunkfile_path, file)?),
             Err(_e) => {
                 warn!("Trunk.toml not found");
                 None
             }
         };
 
-        let maybe_name = cli_or_trunk(&self.name, |toml| &toml.extension.name, &trunk_toml);
+        let cargo_package = read_cargo_package(Path::new(publish_path));
+
+        let maybe_name = cli_or_trunk(&self.name, |toml| &toml.extension.name, &trunk_toml)
+            .or_else(|| from_cargo_toml("name", cargo_package.as_ref().map(|p| &p.name)));
         let Some(name) = maybe_name else {
             return Err(anyhow!("Extension name must be provided when publishing. Please specify the extension name \
-            as the first argument, or under extension.name in Trunk.toml"));
+            as the first argument, under extension.name in Trunk.toml, or under package.name in Cargo.toml"));
         };
 
         let extension_name = cli_or_trunk_opt(
@@ -121,10 +145,11 @@ impl PublishCommand {
             .cloned();
 
         let maybe_version =
-            cli_or_trunk(&self.version, |toml| &toml.extension.version, &trunk_toml);
+            cli_or_trunk(&self.version, |toml| &toml.extension.version, &trunk_toml)
+                .or_else(|| from_cargo_toml("version", cargo_package.as_ref().map(|p| &p.version)));
         let Some(version) = maybe_version else {
             return Err(anyhow!("Extension version must be provided when publishing. Please specify the extension version \
-            with --version, or under extension.version in Trunk.toml"));
+            with --version, under extension.version in Trunk.toml, or under package.version in Cargo.toml"));
         };
 
         let file = self
@@ -146,7 +171,13 @@ impl PublishCommand {
             &self.description,
             |toml| &toml.extension.description,
             &trunk_toml,
-        );
+        )
+        .or_else(|| {
+            from_cargo_toml(
+                "description",
+                cargo_package.as_ref().and_then(|p| p.description.as_ref()),
+            )
+        });
         let documentation = cli_or_trunk_opt(
             &self.documentation,
             |toml| &toml.extension.documentation,
@@ -0,0 +1,253 @@
+//! Registry credentials read from a Docker-style `config.json`, for builds that can't rely on
+//! the credentials of `docker login`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// The key of Docker Hub in `config.json`
+const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+#[derive(Error, Debug)]
+pub enum RegistryAuthError {
+    #[error("Registry auth file {0} does not exist")]
+    Missing(PathBuf),
+
+    #[error("Registry auth file {path} is not a valid Docker config.json: {reason}")]
+    Invalid { path: PathBuf, reason: String },
+
+    #[error(
+        "Authentication to {registry} failed: no credentials for it in the registry auth file. \
+         Add an entry for {registry} under \"auths\""
+    )]
+    NoCredentials { registry: String },
+
+    #[error(
+        "Authentication to {registry} failed: the registry rejected the credentials in the registry auth file"
+    )]
+    BadCredentials { registry: String },
+}
+
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// Credentials for each registry, keyed by the registry's address
+#[derive(Clone, Default)]
+pub struct RegistryAuth {
+    path: PathBuf,
+    credentials: HashMap<String, DockerCredentials>,
+}
+
+impl fmt::Debug for RegistryAuth {
+    // Only the registries, so that credentials don't end up in logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegistryAuth")
+            .field("path", &self.path)
+            .field("registries", &self.credentials.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl RegistryAuth {
+    pub fn load(path: &Path) -> Result<Self, RegistryAuthError> {
+        if !path.is_file() {
+            return Err(RegistryAuthError::Missing(path.to_owned()));
+        }
+        let invalid = |reason: String| RegistryAuthError::Invalid {
+            path: path.to_owned(),
+            reason,
+        };
+
+        let contents = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+        let config: DockerConfig =
+            serde_json::from_str(&contents).map_err(|err| invalid(err.to_string()))?;
+
+        if config.auths.is_empty()
+            && (config.creds_store.is_some() || !config.cred_helpers.is_empty())
+        {
+            return Err(invalid(
+                "credential helpers (credsStore, credHelpers) are not supported, \
+                 the credentials must be listed under \"auths\""
+                    .to_string(),
+            ));
+        }
+
+        let mut credentials = HashMap::new();
+        for (registry, entry) in config.auths {
+            let (username, password) = match entry.auth {
+                Some(auth) => {
+                    let decoded = STANDARD
+                        .decode(auth.trim())
+                        .ok()
+                        .and_then(|decoded| String::from_utf8(decoded).ok())
+                        .ok_or_else(|| invalid(format!("the auth of {registry} is not base64")))?;
+                    let (username, password) = decoded.split_once(':').ok_or_else(|| {
+                        invalid(format!("the auth of {registry} is not username:password"))
+                    })?;
+                    (Some(username.to_string()), Some(password.to_string()))
+                }
+                None => (entry.username, entry.password),
+            };
+
+            credentials.insert(
+                registry.clone(),
+                DockerCredentials {
+                    username,
+                    password,
+                    identitytoken: entry.identitytoken,
+                    serveraddress: Some(registry),
+                    ..Default::default()
+                },
+            );
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            credentials,
+        })
+    }
+
+    /// The file the credentials were read from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The credentials to send with an image build, keyed by registry
+    pub fn credentials(&self) -> HashMap<String, DockerCredentials> {
+        self.credentials.clone()
+    }
+
+    fn has_credentials_for(&self, registry: &str) -> bool {
+        self.credentials.keys().any(|key| {
+            let key = key
+                .trim_start_matches("https://")
+                .trim_start_matches("http://");
+            key == registry || key.starts_with(&format!("{registry}/"))
+        }) || (registry == "docker.io" && self.credentials.contains_key(DOCKER_HUB))
+    }
+
+    /// Explains a failed pull of one of `images` as missing or rejected credentials,
+    /// if `message` is an authentication failure.
+    pub fn classify_failure(&self, message: &str, images: &[String]) -> Option<RegistryAuthError> {
+        const AUTH_FAILURES: [&str; 4] = [
+            "unauthorized",
+            "authentication required",
+            "incorrect username or password",
+            "pull access denied",
+        ];
+
+        let lowercase = message.to_lowercase();
+        if !AUTH_FAILURES
+            .iter()
+            .any(|failure| lowercase.contains(failure))
+        {
+            return None;
+        }
+
+        // The image named in the message, or the first one if the message doesn't say
+        let image = images
+            .iter()
+            .find(|image| message.contains(image.as_str()))
+            .or(images.first())?;
+        let registry = registry_of(image);
+
+        if self.has_credentials_for(&registry) {
+            Some(RegistryAuthError::BadCredentials { registry })
+        } else {
+            Some(RegistryAuthError::NoCredentials { registry })
+        }
+    }
+}
+
+/// The registry an image reference is pulled from, `docker.io` for unqualified references
+fn registry_of(image: &str) -> String {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host.to_string(),
+        _ => "docker.io".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(contents: &str) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn loads_docker_config() {
+        let auth = STANDARD.encode("robot:s3cret");
+        let file = write_config(&format!(
+            r#"{{"auths": {{"quay.io": {{"auth": "{auth}"}}}}}}"#
+        ));
+
+        let registry_auth = RegistryAuth::load(file.path()).unwrap();
+        let credentials = registry_auth.credentials();
+        assert_eq!(credentials["quay.io"].username.as_deref(), Some("robot"));
+        assert_eq!(credentials["quay.io"].password.as_deref(), Some("s3cret"));
+        assert!(!format!("{registry_auth:?}").contains("s3cret"));
+    }
+
+    #[test]
+    fn rejects_unusable_files() {
+        assert!(matches!(
+            RegistryAuth::load(Path::new("/nonexistent/config.json")),
+            Err(RegistryAuthError::Missing(_))
+        ));
+        for contents in ["not json", r#"{"credsStore": "desktop"}"#] {
+            let file = write_config(contents);
+            assert!(matches!(
+                RegistryAuth::load(file.path()),
+                Err(RegistryAuthError::Invalid { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn classifies_auth_failures() {
+        let file = write_config(r#"{"auths": {"quay.io": {"username": "u", "password": "p"}}}"#);
+        let registry_auth = RegistryAuth::load(file.path()).unwrap();
+        let images = vec![
+            "quay.io/coredb/c-builder:pg15".to_string(),
+            "postgres:15".to_string(),
+        ];
+
+        assert!(matches!(
+            registry_auth.classify_failure(
+                "unauthorized: access to quay.io/coredb/c-builder:pg15 denied",
+                &images
+            ),
+            Some(RegistryAuthError::BadCredentials { registry }) if registry == "quay.io"
+        ));
+        assert!(matches!(
+            registry_auth.classify_failure("pull access denied for postgres:15", &images),
+            Some(RegistryAuthError::NoCredentials { registry }) if registry == "docker.io"
+        ));
+        assert!(registry_auth
+            .classify_failure("no space left on device", &images)
+            .is_none());
+    }
+}
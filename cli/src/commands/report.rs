@@ -0,0 +1,205 @@
+//! The provenance report of a build, written with `trunk build --report`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::changelog::{Changelog, GitRevision};
+use crate::commands::build::BuildOutput;
+use crate::commands::verify_archive::read_archive;
+use crate::manifest::sha256_digest;
+
+/// Everything a build produced and was made from, in one JSON document. Large outputs, like
+/// the archive and its signatures, are referenced by path and digest rather than included
+#[derive(Serialize, Debug)]
+pub struct BuildReport<'a> {
+    pub trunk_version: &'static str,
+    pub name: &'a str,
+    pub extension_name: Option<&'a str>,
+    pub version: &'a str,
+    pub pg_version: u8,
+    pub architecture: &'a str,
+    pub artifact: ReportedFile,
+    pub signatures: Vec<ReportedFile>,
+    /// The companion archive of debug symbols, with `--keep-debug`
+    pub debug_symbols: Option<ReportedFile>,
+    /// The digest of every file in the archive, other than manifest.json, by its path there.
+    /// Empty for an encrypted archive, so that the report discloses nothing of its contents
+    pub files: BTreeMap<PathBuf, String>,
+    pub toolchain: Option<&'a BTreeMap<String, String>>,
+    pub container_runtime: Option<&'a str>,
+    pub base_image: Option<&'a str>,
+    pub cflags: Option<&'a str>,
+    pub cxxflags: Option<&'a str>,
+    pub build_profile: Option<&'a str>,
+    /// The commit the sources were built from, if they are in a git repository
+    pub git: Option<GitRevision>,
+    pub changelog: Option<&'a Changelog>,
+    pub labels: Option<&'a BTreeMap<String, String>>,
+    /// Seconds spent in each phase of the build, and in total
+    pub timings: BTreeMap<&'static str, f64>,
+}
+
+/// A file written by the build
+#[derive(Serialize, Debug)]
+pub struct ReportedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub digest: String,
+}
+
+impl ReportedFile {
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents =
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: contents.len() as u64,
+            digest: sha256_digest(&contents),
+        })
+    }
+}
+
+impl<'a> BuildReport<'a> {
+    /// The report of `output`, reading the digests of the archive, its files and its signatures
+    pub fn new(
+        output: &'a BuildOutput,
+        git: Option<GitRevision>,
+        total: Duration,
+    ) -> anyhow::Result<Self> {
+        let manifest = &output.manifest;
+        let archived_files = match output.encryption_sidecar {
+            Some(_) => BTreeMap::new(),
+            None => read_archive(&output.artifact_path)?.1,
+        };
+        let mut timings: BTreeMap<&'static str, f64> = output
+            .timings
+            .phases()
+            .iter()
+            .map(|(phase, duration)| (*phase, duration.as_secs_f64()))
+            .collect();
+        timings.insert("total", total.as_secs_f64());
+
+        Ok(Self {
+            trunk_version: env!("CARGO_PKG_VERSION"),
+            name: &manifest.name,
+            extension_name: manifest.extension_name.as_deref(),
+            version: &manifest.extension_version,
+            pg_version: manifest.pg_version,
+            architecture: &manifest.architecture,
+            artifact: ReportedFile::read(&output.artifact_path)?,
+            signatures: output
+                .signatures
+                .iter()
+                .map(|signature| ReportedFile::read(signature))
+                .collect::<anyhow::Result<_>>()?,
+            debug_symbols: output
+                .debug_symbols_path
+                .as_deref()
+                .map(ReportedFile::read)
+                .transpose()?,
+            files: archived_files
+                .into_iter()
+                .map(|(path, file)| (path, file.digest))
+                .collect(),
+            toolchain: manifest.toolchain.as_ref(),
+            container_runtime: manifest.container_runtime.as_deref(),
+            base_image: manifest.base_image.as_deref(),
+            cflags: manifest.cflags.as_deref(),
+            cxxflags: manifest.cxxflags.as_deref(),
+            build_profile: manifest.build_profile.as_deref(),
+            git,
+            changelog: manifest.changelog.as_ref(),
+            labels: manifest.labels.as_ref(),
+            timings,
+        })
+    }
+
+    /// Writes the report to `path`, as pretty-printed JSON
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, format!("{json}\n"))
+            .with_context(|| format!("Failed to write the build report to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::timings::BuildTimings;
+    use std::fs::File;
+
+    #[test]
+    fn reports_builds() {
+        let dir = tempfile::tempdir().unwrap();
+        let artifact_path = dir.path().join("ext-1.0.0-pg15.tar.gz");
+        let manifest = Manifest {
+            name: "ext".to_string(),
+            extension_version: "1.0.0".to_string(),
+            pg_version: 15,
+            container_runtime: Some("Docker 24.0.7".to_string()),
+            base_image: Some("postgres:15-bookworm".to_string()),
+            ..Default::default()
+        };
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&artifact_path).unwrap(),
+            flate2::Compression::fast(),
+        );
+        let mut archive = tar::Builder::new(encoder);
+        let manifest_json = serde_json::to_string(&manifest).unwrap();
+        for (path, contents) in [
+            ("ext.control", "comment = 'ext'\n"),
+            ("manifest.json", &*manifest_json),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+
+        let mut timings = BuildTimings::default();
+        timings.record("install", Duration::from_secs(2));
+        let output = BuildOutput {
+            artifact_path: artifact_path.clone(),
+            manifest,
+            timings,
+            signatures: Vec::new(),
+            debug_symbols_path: None,
+            encryption_sidecar: None,
+        };
+        let git = GitRevision {
+            commit: "3f2a9c1".to_string(),
+            dirty: false,
+        };
+        let report = BuildReport::new(&output, Some(git), Duration::from_secs(5)).unwrap();
+        let report_path = dir.path().join("report.json");
+        report.write(&report_path).unwrap();
+
+        let written: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&report_path).unwrap()).unwrap();
+        assert_eq!(written["version"], "1.0.0");
+        assert_eq!(
+            written["artifact"]["digest"],
+            sha256_digest(&fs::read(&artifact_path).unwrap())
+        );
+        assert_eq!(
+            written["files"]["ext.control"],
+            sha256_digest(b"comment = 'ext'\n")
+        );
+        assert_eq!(written["container_runtime"], "Docker 24.0.7");
+        assert_eq!(written["base_image"], "postgres:15-bookworm");
+        assert_eq!(written["git"]["commit"], "3f2a9c1");
+        assert_eq!(written["timings"]["install"], 2.0);
+        assert_eq!(written["timings"]["total"], 5.0);
+    }
+}
@@ -0,0 +1,122 @@
+//! `trunk build --trace-docker`: each call trunk makes to the container runtime, printed to
+//! stderr as the docker or podman command line that does the same, right before it's made.
+//! Trunk drives the runtime through its API rather than its CLI, so for most calls the line is
+//! the CLI equivalent of the request, with the same image, container, flags and arguments.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::build_log::tee_eprintln;
+
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// What the values of secret-looking environment variables and build arguments are replaced
+/// with
+pub const REDACTED: &str = "<redacted>";
+
+/// Words in the name of an environment variable or build argument that mark its value secret
+const SECRET_WORDS: [&str; 7] = [
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "KEY",
+    "AUTH",
+    "CREDENTIAL",
+];
+
+/// Traces the runtime calls made from now on
+pub fn enable() {
+    TRACE.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    TRACE.load(Ordering::Relaxed)
+}
+
+/// Whether the variable `name` looks like it holds a secret, e.g. `GITHUB_TOKEN` or `PGPASSWORD`
+fn is_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_WORDS.iter().any(|word| name.contains(word))
+}
+
+/// `NAME=VALUE`, with the value redacted if `NAME` looks secret
+pub fn variable(name: &str, value: &str) -> String {
+    if is_secret(name) {
+        format!("{name}={REDACTED}")
+    } else {
+        format!("{name}={value}")
+    }
+}
+
+/// `assignment`, a `NAME=VALUE` environment entry, with the value redacted if `NAME` looks
+/// secret
+pub fn assignment(assignment: &str) -> String {
+    match assignment.split_once('=') {
+        Some((name, value)) => variable(name, value),
+        None => assignment.to_string(),
+    }
+}
+
+/// `arg` quoted for a POSIX shell, if it needs to be
+fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg.chars().all(|ch| {
+            ch.is_ascii_alphanumeric()
+                || matches!(
+                    ch,
+                    '_' | '-' | '.' | '/' | ':' | '=' | '@' | '%' | '+' | ','
+                )
+        });
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The command line of `cli` run with `args`, quoted so that it can be pasted into a shell
+pub fn command_line<S: AsRef<str>>(cli: &str, args: &[S]) -> String {
+    let mut line = cli.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&quote(arg.as_ref()));
+    }
+
+    line
+}
+
+/// Prints the command line of `cli` run with `args`, if tracing is enabled. Secret values must
+/// already be redacted, with [`variable`] or [`assignment`]
+pub fn trace<S: AsRef<str>>(cli: &str, args: &[S]) {
+    if enabled() {
+        tee_eprintln!("+ {}", command_line(cli, args));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_and_redacts_command_lines() {
+        assert_eq!(variable("PG_VERSION", "15"), "PG_VERSION=15");
+        assert_eq!(variable("GITHUB_TOKEN", "ghp_x"), "GITHUB_TOKEN=<redacted>");
+        assert_eq!(assignment("PGPASSWORD=s3cret"), "PGPASSWORD=<redacted>");
+        assert_eq!(assignment("PGUSER=postgres"), "PGUSER=postgres");
+
+        let args = [
+            "exec".to_string(),
+            "--env".to_string(),
+            assignment("api_key=abc"),
+            "c0ffee".to_string(),
+            "sh".to_string(),
+            "-c".to_string(),
+            "make install && echo 'done'".to_string(),
+        ];
+        assert_eq!(
+            command_line("podman", &args),
+            r"podman exec --env 'api_key=<redacted>' c0ffee sh -c 'make install && echo '\''done'\'''"
+        );
+        assert_eq!(command_line("docker", &["run", ""]), "docker run ''");
+    }
+}
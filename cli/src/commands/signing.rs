@@ -0,0 +1,195 @@
+//! Detached signatures of build artifacts, made with cosign or minisign.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context};
+use log::info;
+use serde::Serialize;
+
+/// The tool `--sign` signs the archive with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningTool {
+    /// Sign with cosign, keyless through Sigstore unless --sign-key is given
+    Cosign,
+    /// Sign with minisign, using the secret key given with --sign-key
+    Minisign,
+}
+
+impl SigningTool {
+    /// The executable of the tool, looked up on the PATH
+    pub fn program(self) -> &'static str {
+        match self {
+            SigningTool::Cosign => "cosign",
+            SigningTool::Minisign => "minisign",
+        }
+    }
+}
+
+/// Checks the settings that don't depend on the tool being installed
+pub fn validate_signing(tool: Option<SigningTool>, key: Option<&Path>) -> anyhow::Result<()> {
+    match (tool, key) {
+        (None, Some(_)) => bail!("--sign-key requires --sign"),
+        (Some(SigningTool::Minisign), None) => bail!("--sign minisign requires --sign-key"),
+        (Some(_), Some(key)) if !key.is_file() => {
+            bail!("Signing key {} does not exist", key.display())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Finds the signing tool on the PATH, so a build that can't be signed fails before it starts
+pub fn find_signing_tool(tool: SigningTool) -> anyhow::Result<PathBuf> {
+    which::which(tool.program()).map_err(|_| {
+        anyhow!(
+            "--sign {} requires {} on the PATH, but it was not found",
+            tool.program(),
+            tool.program()
+        )
+    })
+}
+
+/// The command that signs `artifact`, and the files it writes next to it
+fn sign_command(
+    program: &Path,
+    tool: SigningTool,
+    key: Option<&Path>,
+    artifact: &Path,
+) -> (Command, Vec<PathBuf>) {
+    let with_extension = |extension: &str| {
+        let mut path = artifact.as_os_str().to_owned();
+        path.push(extension);
+        PathBuf::from(path)
+    };
+
+    let mut command = Command::new(program);
+    let signatures = match tool {
+        SigningTool::Cosign => {
+            let signature = with_extension(".sig");
+            command.args(["sign-blob", "--yes"]);
+            command.arg("--output-signature").arg(&signature);
+            match key {
+                Some(key) => {
+                    command.arg("--key").arg(key);
+                    vec![signature]
+                }
+                // Keyless signatures are only verifiable along with the certificate Fulcio issued
+                None => {
+                    let certificate = with_extension(".pem");
+                    command.arg("--output-certificate").arg(&certificate);
+                    vec![signature, certificate]
+                }
+            }
+        }
+        SigningTool::Minisign => {
+            let signature = with_extension(".minisig");
+            command.arg("-S");
+            if let Some(key) = key {
+                command.arg("-s").arg(key);
+            }
+            command.arg("-x").arg(&signature).arg("-m");
+            vec![signature]
+        }
+    };
+    command.arg(artifact);
+
+    (command, signatures)
+}
+
+/// Signs `artifact`, returning the detached signature and, for keyless cosign, its certificate.
+/// The tool inherits the terminal, as it may prompt for a key password or a Sigstore login.
+pub fn sign_artifact(
+    tool: SigningTool,
+    key: Option<&Path>,
+    artifact: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let program = find_signing_tool(tool)?;
+    let (mut command, signatures) = sign_command(&program, tool, key, artifact);
+    info!("Signing {} with {}", artifact.display(), tool.program());
+
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    if !status.success() {
+        bail!(
+            "{} failed to sign {}: {status}",
+            tool.program(),
+            artifact.display()
+        );
+    }
+    if let Some(missing) = signatures.iter().find(|signature| !signature.is_file()) {
+        bail!("{} did not write {}", tool.program(), missing.display());
+    }
+
+    Ok(signatures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &Command) -> Vec<String> {
+        command
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn builds_signing_commands() {
+        let artifact = Path::new("out/ext-1.0.0-pg15.tar.gz");
+
+        let (command, signatures) =
+            sign_command(Path::new("cosign"), SigningTool::Cosign, None, artifact);
+        assert_eq!(
+            args(&command),
+            [
+                "sign-blob",
+                "--yes",
+                "--output-signature",
+                "out/ext-1.0.0-pg15.tar.gz.sig",
+                "--output-certificate",
+                "out/ext-1.0.0-pg15.tar.gz.pem",
+                "out/ext-1.0.0-pg15.tar.gz",
+            ]
+        );
+        assert_eq!(signatures.len(), 2);
+
+        let (command, signatures) = sign_command(
+            Path::new("minisign"),
+            SigningTool::Minisign,
+            Some(Path::new("minisign.key")),
+            artifact,
+        );
+        assert_eq!(
+            args(&command),
+            [
+                "-S",
+                "-s",
+                "minisign.key",
+                "-x",
+                "out/ext-1.0.0-pg15.tar.gz.minisig",
+                "-m",
+                "out/ext-1.0.0-pg15.tar.gz",
+            ]
+        );
+        assert_eq!(
+            signatures,
+            [PathBuf::from("out/ext-1.0.0-pg15.tar.gz.minisig")]
+        );
+    }
+
+    #[test]
+    fn validates_signing_settings() {
+        assert!(validate_signing(None, None).is_ok());
+        assert!(validate_signing(Some(SigningTool::Cosign), None).is_ok());
+        assert!(validate_signing(Some(SigningTool::Minisign), None).is_err());
+        assert!(validate_signing(None, Some(Path::new("minisign.key"))).is_err());
+        assert!(validate_signing(
+            Some(SigningTool::Minisign),
+            Some(Path::new("/nonexistent/minisign.key"))
+        )
+        .is_err());
+    }
+}
@@ -31,11 +31,6 @@ struct TrunkProjectInfo {
     pub repository_link: String,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Extension {
-    pub extension_name: String,
-}
-
 #[derive(Debug)]
 struct ExtractedTestCases {
     sql_files: Vec<PathBuf>,
@@ -53,7 +48,9 @@ async fn extract_sql_and_expected_files(
     github_project: GitHubProject<'_>,
 ) -> Result<ExtractedTestCases> {
     fn check_parent(expected_parent: &str, path: &Path) -> bool {
-        let Some(parent_obtained) = path.parent().and_then(|parent| parent.components().last())
+        let Some(parent_obtained) = path
+            .parent()
+            .and_then(|parent| parent.components().next_back())
         else {
             return false;
         };
@@ -240,7 +237,7 @@ impl<'a> GitHubProject<'a> {
             let name = parts.next()?;
             let subdir = if let Some("tree") = parts.next() {
                 // TODO: join instead of last?
-                parts.last()
+                parts.next_back()
             } else {
                 None
             };
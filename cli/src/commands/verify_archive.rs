@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use clap::{Args, ValueEnum};
+use flate2::read::GzDecoder;
+use log::warn;
+use serde::Serialize;
+use tar::{Archive, EntryType};
+use tokio_task_manager::Task;
+
+use super::encryption::decrypt_artifact;
+use super::SubCommand;
+use crate::manifest::{check_manifest_version, sha256_digest, CaptureCategory, Manifest};
+
+const MANIFEST_PATH: &str = "manifest.json";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Args)]
+pub struct VerifyArchiveCommand {
+    /// The archive to check, as written by trunk build
+    file: PathBuf,
+    /// The SHA-256 the whole archive must have, e.g. the one the registry lists for it
+    #[arg(long = "sha256")]
+    sha256: Option<String>,
+    /// Print the report as text or as JSON
+    #[arg(long = "output-format", value_enum, default_value_t)]
+    output_format: OutputFormat,
+    /// Write the archive's manifest.json to this path, or to stdout with -, instead of checking
+    /// the archive
+    #[arg(
+        long = "dump-manifest",
+        value_name = "PATH",
+        conflicts_with_all = ["sha256", "output_format"]
+    )]
+    dump_manifest: Option<PathBuf>,
+    /// The age identity file to decrypt the archive with, if it was written by
+    /// trunk build --encrypt
+    #[arg(long = "decrypt-key", value_name = "PATH")]
+    decrypt_key: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Mismatch {
+    path: PathBuf,
+    expected: String,
+    actual: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    archive: PathBuf,
+    /// Whether the manifest recorded file digests, so that the contents could be checked
+    digests_checked: bool,
+    files_checked: usize,
+    /// Listed in manifest.json but not in the archive
+    missing_files: Vec<PathBuf>,
+    /// In the archive but not listed in manifest.json
+    unlisted_files: Vec<PathBuf>,
+    /// Files whose contents don't match their digest in manifest.json
+    mismatched_files: Vec<Mismatch>,
+    /// The archive itself, if it doesn't have the digest given with `--sha256`
+    archive_mismatch: Option<Mismatch>,
+}
+
+impl Report {
+    fn problems(&self) -> usize {
+        self.missing_files.len()
+            + self.unlisted_files.len()
+            + self.mismatched_files.len()
+            + usize::from(self.archive_mismatch.is_some())
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!(
+            "Checked {} files of {} against its manifest\n",
+            self.files_checked,
+            self.archive.display()
+        );
+        if let Some(mismatch) = &self.archive_mismatch {
+            text.push_str(&format!(
+                "FAIL {}: expected {}, got {}\n",
+                mismatch.path.display(),
+                mismatch.expected,
+                mismatch.actual
+            ));
+        }
+        for path in &self.missing_files {
+            text.push_str(&format!(
+                "FAIL {}: listed in {MANIFEST_PATH}, but missing from the archive\n",
+                path.display()
+            ));
+        }
+        for path in &self.unlisted_files {
+            text.push_str(&format!(
+                "FAIL {}: in the archive, but not listed in {MANIFEST_PATH}\n",
+                path.display()
+            ));
+        }
+        for mismatch in &self.mismatched_files {
+            text.push_str(&format!(
+                "FAIL {}: expected {}, got {}\n",
+                mismatch.path.display(),
+                mismatch.expected,
+                mismatch.actual
+            ));
+        }
+        if !self.digests_checked {
+            text.push_str(
+                "Note: the manifest has no file digests, so file contents were not checked. \
+                 Build with --file-digests to record them\n",
+            );
+        }
+
+        text
+    }
+}
+
+#[async_trait]
+impl SubCommand for VerifyArchiveCommand {
+    async fn execute(&self, _task: Task) -> Result<(), anyhow::Error> {
+        // An encrypted archive is checked through a decrypted copy, and reported as itself
+        let decrypted = match &self.decrypt_key {
+            Some(key) => {
+                let (_, contents) = decrypt_artifact(&self.file, key)?;
+                let mut decrypted = tempfile::NamedTempFile::new()?;
+                decrypted.write_all(&contents)?;
+                Some(decrypted)
+            }
+            None => None,
+        };
+        let archive = decrypted
+            .as_ref()
+            .map_or(self.file.as_path(), |decrypted| decrypted.path());
+
+        if let Some(destination) = &self.dump_manifest {
+            let manifest = read_manifest_json(archive)?;
+            if destination == Path::new("-") {
+                std::io::stdout().write_all(&manifest)?;
+            } else {
+                fs::write(destination, manifest)
+                    .with_context(|| format!("Failed to write {}", destination.display()))?;
+            }
+            return Ok(());
+        }
+        let mut report = verify_archive(archive, self.sha256.as_deref())?;
+        report.archive = self.file.clone();
+        match self.output_format {
+            OutputFormat::Text => print!("{}", report.text()),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        }
+
+        let problems = report.problems();
+        if problems > 0 {
+            bail!(
+                "{problems} problem(s) found in {}, it does not match its manifest",
+                self.file.display()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// A file in an archive, other than manifest.json
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ArchivedFile {
+    pub size: u64,
+    pub digest: String,
+}
+
+fn open_archive(path: &Path) -> anyhow::Result<Archive<GzDecoder<File>>> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    Ok(Archive::new(GzDecoder::new(file)))
+}
+
+fn missing_manifest(path: &Path) -> anyhow::Error {
+    anyhow!(
+        "{} has no {MANIFEST_PATH}. Archives of old versions of trunk may lack one; rebuild it \
+         with a current trunk, choosing its layout with --format-version",
+        path.display()
+    )
+}
+
+/// The manifest.json of the archive at `path`, as written by the build. The archive is read up
+/// to the manifest, without unpacking the other files
+fn read_manifest_json(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut archive = open_archive(path)?;
+    for entry in archive
+        .entries()
+        .with_context(|| format!("{} is not a gzipped tarball", path.display()))?
+    {
+        let mut entry = entry?;
+        if entry.header().entry_type() == EntryType::Regular
+            && entry.path()?.as_ref() == Path::new(MANIFEST_PATH)
+        {
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            return Ok(contents);
+        }
+    }
+
+    Err(missing_manifest(path))
+}
+
+/// Reads the manifest and every other file in the archive at `path`
+pub(crate) fn read_archive(
+    path: &Path,
+) -> anyhow::Result<(Manifest, BTreeMap<PathBuf, ArchivedFile>)> {
+    let mut archive = open_archive(path)?;
+
+    let mut manifest = None;
+    let mut files = BTreeMap::new();
+    for entry in archive
+        .entries()
+        .with_context(|| format!("{} is not a gzipped tarball", path.display()))?
+    {
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+        let entry_path = entry.path()?.to_path_buf();
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+
+        if entry_path == Path::new(MANIFEST_PATH) {
+            manifest = Some(
+                serde_json::from_slice::<Manifest>(&contents).with_context(|| {
+                    format!("The {MANIFEST_PATH} of {} is not valid", path.display())
+                })?,
+            );
+        } else {
+            let file = ArchivedFile {
+                size: contents.len() as u64,
+                digest: sha256_digest(&contents),
+            };
+            files.insert(entry_path, file);
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| missing_manifest(path))?;
+
+    Ok((manifest, files))
+}
+
+fn verify_archive(path: &Path, expected_sha256: Option<&str>) -> anyhow::Result<Report> {
+    let (manifest, files) = read_archive(path)?;
+    // The files can be checked all the same, but may be laid out differently than expected
+    if let Err(err) = check_manifest_version(manifest.manifest_version) {
+        warn!("{err}");
+    }
+
+    check_archive(path, &manifest, files, expected_sha256)
+}
+
+/// Reads the manifest of the archive at `path`, failing if the archive doesn't match it
+pub(crate) fn read_verified_archive(path: &Path) -> anyhow::Result<Manifest> {
+    let (manifest, files) = read_archive(path)?;
+    let problems = check_archive(path, &manifest, files, None)?.problems();
+    if problems > 0 {
+        bail!(
+            "{problems} problem(s) found in {}, it does not match its manifest",
+            path.display()
+        );
+    }
+
+    Ok(manifest)
+}
+
+fn check_archive(
+    path: &Path,
+    manifest: &Manifest,
+    files: BTreeMap<PathBuf, ArchivedFile>,
+    expected_sha256: Option<&str>,
+) -> anyhow::Result<Report> {
+    let digests: BTreeMap<PathBuf, String> = files
+        .into_iter()
+        .map(|(path, file)| (path, file.digest))
+        .collect();
+
+    let archive_mismatch = match expected_sha256 {
+        Some(expected) => {
+            let actual = sha256_digest(&fs::read(path)?);
+            let expected = expected.trim().to_lowercase();
+            // Registries list the bare hex digest
+            let matches = actual == expected || actual.strip_prefix("sha256:") == Some(&expected);
+            (!matches).then(|| Mismatch {
+                path: path.to_path_buf(),
+                expected,
+                actual,
+            })
+        }
+        None => None,
+    };
+
+    let listed: Vec<&PathBuf> = manifest
+        .files
+        .iter()
+        .flat_map(|files| files.keys())
+        .chain(manifest.included_files.iter().flatten())
+        .chain(
+            manifest
+                .captured_files
+                .iter()
+                .flatten()
+                .filter(|(_, category)| **category == CaptureCategory::Prefix)
+                .map(|(path, _)| path),
+        )
+        .collect();
+    let mut missing_files: Vec<PathBuf> = listed
+        .iter()
+        .filter(|path| !digests.contains_key(**path))
+        .map(|path| path.to_path_buf())
+        .collect();
+    missing_files.sort();
+    let unlisted_files: Vec<PathBuf> = digests
+        .keys()
+        .filter(|path| !listed.contains(path))
+        .cloned()
+        .collect();
+
+    let mismatched_files: Vec<Mismatch> = manifest
+        .file_digests
+        .iter()
+        .flatten()
+        .filter_map(|(path, expected)| {
+            let actual = digests.get(path)?;
+            (actual != expected).then(|| Mismatch {
+                path: path.clone(),
+                expected: expected.clone(),
+                actual: actual.clone(),
+            })
+        })
+        .collect();
+
+    Ok(Report {
+        archive: path.to_path_buf(),
+        digests_checked: manifest.file_digests.is_some(),
+        files_checked: digests.len(),
+        missing_files,
+        unlisted_files,
+        mismatched_files,
+        archive_mismatch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_archive(path: &Path, manifest: &Manifest, files: &[(&str, &str)]) {
+        let encoder =
+            flate2::write::GzEncoder::new(File::create(path).unwrap(), flate2::Compression::fast());
+        let mut archive = tar::Builder::new(encoder);
+        let manifest = serde_json::to_string(manifest).unwrap();
+        for (path, contents) in files.iter().copied().chain([("manifest.json", &*manifest)]) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        archive.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn finds_archives_that_dont_match_their_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ext-1.0.0-pg15.tar.gz");
+        let mut manifest = Manifest::default();
+        for (file, contents) in [("ext.control", "comment = 'ext'\n"), ("ext.so", "elf")] {
+            manifest.add_file(file);
+            manifest.add_digest(file, contents.as_bytes());
+        }
+
+        write_archive(
+            &path,
+            &manifest,
+            &[("ext.control", "comment = 'ext'\n"), ("ext.so", "elf")],
+        );
+        let report = verify_archive(&path, None).unwrap();
+        assert_eq!(report.problems(), 0, "{}", report.text());
+        assert!(report.digests_checked);
+
+        let archive_sha256 = sha256_digest(&fs::read(&path).unwrap());
+        let bare_hex = archive_sha256.strip_prefix("sha256:").unwrap();
+        assert_eq!(verify_archive(&path, Some(bare_hex)).unwrap().problems(), 0);
+        let report = verify_archive(&path, Some("00")).unwrap();
+        assert!(report.archive_mismatch.is_some());
+
+        write_archive(
+            &path,
+            &manifest,
+            &[("ext.control", "comment = 'evil'\n"), ("extra.sql", "")],
+        );
+        let report = verify_archive(&path, None).unwrap();
+        assert_eq!(report.missing_files, [PathBuf::from("ext.so")]);
+        assert_eq!(report.unlisted_files, [PathBuf::from("extra.sql")]);
+        assert_eq!(report.mismatched_files.len(), 1);
+        assert_eq!(report.mismatched_files[0].path, Path::new("ext.control"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Finds the other extensions in the repository of the one being built, for `trunk build --with-deps`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+
+use crate::config;
+use crate::control_file::ControlFile;
+use log::warn;
+
+/// An extension with a Trunk.toml in the repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkspaceExtension {
+    /// `extension_name` from its Trunk.toml, or else `name`
+    pub name: String,
+    pub dir: PathBuf,
+    /// The extensions it requires, from `extension_dependencies` and its control files
+    pub requires: Vec<String>,
+}
+
+/// The root of the repository `path` is in: the nearest directory above it with a `.git`, or
+/// else its parent directory, so that its siblings are found
+pub fn repository_root(path: &Path) -> PathBuf {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .or_else(|| path.parent())
+        .unwrap_or(&path)
+        .to_path_buf()
+}
+
+/// Every extension with a Trunk.toml under `root`, by name. Hidden directories and files ignored by git are skipped
+pub fn discover_extensions(root: &Path) -> anyhow::Result<BTreeMap<String, WorkspaceExtension>> {
+    let mut extensions = BTreeMap::new();
+    for entry in ignore::WalkBuilder::new(root).build() {
+        let entry = entry?;
+        if entry.file_name() != "Trunk.toml" {
+            continue;
+        }
+        let dir = entry.path().parent().unwrap_or(root).to_path_buf();
+        // A Trunk.toml that doesn't parse can't be built, but it shouldn't stop other builds
+        let trunk_toml = match config::parse_trunk_file(entry.path(), File::open(entry.path())?) {
+            Ok(trunk_toml) => trunk_toml,
+            Err(err) => {
+                warn!("Skipping {}: {err:#}", entry.path().display());
+                continue;
+            }
+        };
+        let name = trunk_toml
+            .extension
+            .extension_name
+            .unwrap_or(trunk_toml.extension.name);
+        let requires = extension_requires(
+            &dir,
+            trunk_toml
+                .extension
+                .extension_dependencies
+                .as_deref()
+                .unwrap_or_default(),
+        )?;
+        if let Some(other) = extensions
+            .get(&name)
+            .map(|other: &WorkspaceExtension| &other.dir)
+        {
+            bail!(
+                "Both {} and {} define the extension '{name}'",
+                other.display(),
+                dir.display()
+            );
+        }
+        extensions.insert(
+            name.clone(),
+            WorkspaceExtension {
+                name,
+                dir,
+                requires,
+            },
+        );
+    }
+
+    Ok(extensions)
+}
+
+/// `extension_dependencies` along with the `requires` of the control files in `dir`, in order
+/// and without duplicates
+pub fn extension_requires(
+    dir: &Path,
+    extension_dependencies: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let mut control_files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "control"))
+        .collect();
+    control_files.sort();
+
+    let mut requires = extension_dependencies.to_vec();
+    for path in control_files {
+        let control_file = ControlFile::parse(&fs::read_to_string(&path)?);
+        requires.extend(control_file.requires.unwrap_or_default());
+    }
+    let mut seen = BTreeSet::new();
+    requires.retain(|name| seen.insert(name.clone()));
+
+    Ok(requires)
+}
+
+/// The extensions in `extensions` that `requires` needs, directly or through each other, with
+/// every extension after the ones it requires. Also returns the required extensions that aren't
+/// in the repository, which are left to install from elsewhere. Fails on dependency cycles
+pub fn build_order<'a>(
+    target: &str,
+    requires: &[String],
+    extensions: &'a BTreeMap<String, WorkspaceExtension>,
+) -> anyhow::Result<(Vec<&'a WorkspaceExtension>, Vec<String>)> {
+    struct Visit<'a> {
+        extensions: &'a BTreeMap<String, WorkspaceExtension>,
+        // The extensions being visited, from the target down
+        path: Vec<String>,
+        done: BTreeSet<String>,
+        order: Vec<&'a WorkspaceExtension>,
+        missing: Vec<String>,
+    }
+
+    impl<'a> Visit<'a> {
+        fn visit(&mut self, name: &str) -> anyhow::Result<()> {
+            if let Some(start) = self.path.iter().position(|visiting| visiting == name) {
+                let mut cycle = self.path[start..].to_vec();
+                cycle.push(name.to_string());
+                bail!(
+                    "The extensions depend on each other: {}",
+                    cycle.join(" -> ")
+                );
+            }
+            if !self.done.insert(name.to_string()) {
+                return Ok(());
+            }
+            let Some(extension) = self.extensions.get(name) else {
+                self.missing.push(name.to_string());
+                return Ok(());
+            };
+            self.path.push(name.to_string());
+            for required in &extension.requires {
+                self.visit(required)?;
+            }
+            self.path.pop();
+            self.order.push(extension);
+
+            Ok(())
+        }
+    }
+
+    let mut visit = Visit {
+        extensions,
+        path: vec![target.to_string()],
+        done: BTreeSet::from([target.to_string()]),
+        order: Vec::new(),
+        missing: Vec::new(),
+    };
+    for required in requires {
+        visit.visit(required)?;
+    }
+
+    Ok((visit.order, visit.missing))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let write_extension = |name: &str, dependencies: &str, requires: &str| {
+            let ext_dir = dir.path().join(name);
+            fs::create_dir(&ext_dir).unwrap();
+            fs::write(
+                ext_dir.join("Trunk.toml"),
+                format!(
+                    "[extension]\nname = \"{name}\"\nversion = \"1.0.0\"\nlicense = \"MIT\"\n\
+                     categories = []\nextension_dependencies = [{dependencies}]\n\n[build]\n\
+                     platform = \"linux/amd64\"\n"
+                ),
+            )
+            .unwrap();
+            fs::write(
+                ext_dir.join(format!("{name}.control")),
+                format!("default_version = '1.0.0'\nrequires = '{requires}'\n"),
+            )
+            .unwrap();
+        };
+        write_extension("app", "\"api\"", "hstore, core");
+        write_extension("api", "", "core");
+        write_extension("core", "", "");
+
+        let extensions = discover_extensions(dir.path()).unwrap();
+        assert_eq!(
+            extensions.keys().collect::<Vec<_>>(),
+            ["api", "app", "core"]
+        );
+        assert_eq!(extensions["app"].requires, ["api", "hstore", "core"]);
+
+        let (order, missing) =
+            build_order("app", &extensions["app"].requires, &extensions).unwrap();
+        let order: Vec<&str> = order.iter().map(|ext| ext.name.as_str()).collect();
+        assert_eq!(order, ["core", "api"]);
+        assert_eq!(missing, ["hstore"]);
+
+        write_extension("loop", "\"app\"", "");
+        fs::write(
+            dir.path().join("core").join("core.control"),
+            "requires = 'loop'\n",
+        )
+        .unwrap();
+        let extensions = discover_extensions(dir.path()).unwrap();
+        let err = build_order("app", &extensions["app"].requires, &extensions).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The extensions depend on each other: app -> api -> core -> loop -> app"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use log::warn;
 use serde::{Deserialize, Serialize};
+use std::fs;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 
 use crate::trunk_toml::TrunkToml;
 
@@ -15,6 +17,67 @@ pub struct ExtensionConfiguration {
     recommended_default_value: Option<String>,
 }
 
+/// Fields of the extension's control file, set under `[extension.control]` in Trunk.toml, so
+/// that installers can read them from the manifest
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlFields {
+    pub relocatable: Option<bool>,
+    pub schema: Option<String>,
+    pub module_pathname: Option<String>,
+    pub requires: Option<Vec<String>>,
+}
+
+/// YAML files read instead of Trunk.toml when there is none, in the order they're looked for.
+/// They have the same schema as Trunk.toml
+pub const TRUNK_YAML_FILE_NAMES: [&str; 4] = ["Trunk.yaml", "Trunk.yml", "trunk.yaml", "trunk.yml"];
+
+/// Returns the path of the file in `dir` that trunk reads its settings from: Trunk.toml if it
+/// exists, or else the first of [`TRUNK_YAML_FILE_NAMES`] that does. If none exist, this is the
+/// path of the missing Trunk.toml.
+pub fn find_trunk_file(dir: &Path) -> PathBuf {
+    // Symlinks count even if they are broken, so that resolving them reports the broken link
+    let exists = |path: &Path| fs::symlink_metadata(path).is_ok();
+    let toml = dir.join("Trunk.toml");
+    let yaml = TRUNK_YAML_FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| exists(path));
+
+    match yaml {
+        Some(yaml) if exists(&toml) => {
+            warn!(
+                "Found both Trunk.toml and {}, using Trunk.toml",
+                yaml.file_name().unwrap_or_default().to_string_lossy()
+            );
+            toml
+        }
+        Some(yaml) => yaml,
+        None => toml,
+    }
+}
+
+/// Parses the trunk file at `path`, as YAML if its name ends with `.yaml` or `.yml`, and as
+/// TOML otherwise
+pub fn parse_trunk_file<R: Read>(path: &Path, mut reader: R) -> Result<TrunkToml, anyhow::Error> {
+    let is_yaml = path
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml");
+    if !is_yaml {
+        return parse_trunk_toml(reader);
+    }
+
+    let mut body = String::new();
+    reader.read_to_string(&mut body)?;
+
+    match serde_yaml::from_str(&body) {
+        Ok(toml) => Ok(toml),
+        Err(e) => {
+            warn!("{} is not valid YAML", path.display());
+            Err(e.into())
+        }
+    }
+}
+
 pub fn parse_trunk_toml<R: Read>(mut reader: R) -> Result<TrunkToml, anyhow::Error> {
     let mut body = String::new();
     reader.read_to_string(&mut body)?;
@@ -41,9 +104,61 @@ fn default_priority() -> Option<i32> {
     Some(2147483647)
 }
 
+/// `preload` in `[extension]` of Trunk.toml: whether the shared libraries the extension installs
+/// must be in `shared_preload_libraries`, or the names of those that must
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Preload {
+    All(bool),
+    Libraries(Vec<String>),
+}
+
+impl Preload {
+    /// The libraries to preload, given the names of those the build installed. Named libraries
+    /// are kept even if the build didn't install them
+    pub fn libraries(&self, installed: &[String]) -> Vec<String> {
+        match self {
+            Preload::All(true) => installed.to_vec(),
+            Preload::All(false) => Vec::new(),
+            Preload::Libraries(names) => names
+                .iter()
+                .map(|name| name.trim_end_matches(".so").to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Adds the libraries in `preloaded` to `loadable_libraries`, as libraries that take a restart
+/// to load. Libraries that are already listed are left as they are
+pub fn add_preloaded_libraries(
+    loadable_libraries: Option<Vec<LoadableLibrary>>,
+    preloaded: &[String],
+) -> Option<Vec<LoadableLibrary>> {
+    let mut libraries = loadable_libraries.unwrap_or_default();
+    for name in preloaded {
+        if !libraries
+            .iter()
+            .any(|library| &library.library_name == name)
+        {
+            libraries.push(LoadableLibrary {
+                library_name: name.clone(),
+                requires_restart: true,
+                priority: default_priority(),
+            });
+        }
+    }
+
+    (!libraries.is_empty()).then_some(libraries)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::config::parse_trunk_toml;
+    use crate::config::{
+        add_preloaded_libraries, find_trunk_file, parse_trunk_file, parse_trunk_toml,
+        LoadableLibrary, Preload,
+    };
+    use std::fs;
+    use std::path::Path;
 
     #[test]
     fn test_parse_trunk_toml_valid() {
@@ -100,10 +215,105 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parses_trunk_yaml_like_trunk_toml() {
+        let toml = r#"
+        [extension]
+        name = "pg_cron"
+        version = "1.5.2"
+        license = "PostgreSQL"
+        categories = ["analytics"]
+
+        [build]
+        platform = "linux/amd64"
+        install_command = "cd pg_cron && make install"
+
+        [build.pgrx]
+        profile = "debug"
+
+        [dependencies]
+        apt = ["libpq5"]
+        "#;
+        let yaml = r#"
+extension:
+  name: pg_cron
+  version: 1.5.2
+  license: PostgreSQL
+  categories: [analytics]
+build:
+  platform: linux/amd64
+  install_command: cd pg_cron && make install
+  pgrx:
+    profile: debug
+dependencies:
+  apt: [libpq5]
+"#;
+        let from_toml = parse_trunk_toml(toml.as_bytes()).unwrap();
+        let from_yaml = parse_trunk_file(Path::new("Trunk.yaml"), yaml.as_bytes()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&from_yaml).unwrap(),
+            serde_json::to_value(&from_toml).unwrap()
+        );
+
+        // Fields are checked the same way in either format
+        let missing_license = yaml.replace("  license: PostgreSQL\n", "");
+        assert!(parse_trunk_file(Path::new("trunk.yml"), missing_license.as_bytes()).is_err());
+        // Files that are not named .yaml or .yml are TOML
+        assert!(parse_trunk_file(Path::new("Trunk.toml"), yaml.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn prefers_trunk_toml_over_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(find_trunk_file(dir.path()), dir.path().join("Trunk.toml"));
+
+        fs::write(dir.path().join("trunk.yml"), "").unwrap();
+        assert_eq!(find_trunk_file(dir.path()), dir.path().join("trunk.yml"));
+
+        fs::write(dir.path().join("Trunk.toml"), "").unwrap();
+        assert_eq!(find_trunk_file(dir.path()), dir.path().join("Trunk.toml"));
+    }
+
     #[test]
     fn test_parse_trunk_toml_invalid() {
         let toml = "this is not valid toml";
         let result = parse_trunk_toml(toml.as_bytes());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn preload_adds_loadable_libraries() {
+        let parse = |preload: &str| {
+            let toml = format!(
+                "[extension]\nname = \"pg_cron\"\nversion = \"1.5.2\"\nlicense = \"PostgreSQL\"\n\
+                 categories = []\npreload = {preload}\n[build]\nplatform = \"linux/amd64\"\n"
+            );
+            parse_trunk_toml(toml.as_bytes()).unwrap().extension.preload
+        };
+        assert_eq!(parse("true"), Some(Preload::All(true)));
+        assert_eq!(
+            parse(r#"["pg_cron.so"]"#),
+            Some(Preload::Libraries(vec!["pg_cron.so".to_string()]))
+        );
+
+        let installed = ["pg_cron".to_string(), "pg_cron_worker".to_string()];
+        assert_eq!(Preload::All(true).libraries(&installed), installed);
+        assert!(Preload::All(false).libraries(&installed).is_empty());
+        assert_eq!(
+            Preload::Libraries(vec!["pg_cron.so".to_string()]).libraries(&installed),
+            ["pg_cron"]
+        );
+
+        let listed = vec![LoadableLibrary {
+            library_name: "pg_cron".to_string(),
+            requires_restart: false,
+            priority: Some(1),
+        }];
+        let libraries = add_preloaded_libraries(Some(listed), &installed).unwrap();
+        assert_eq!(libraries.len(), 2);
+        assert!(!libraries[0].requires_restart);
+        assert_eq!(libraries[1].library_name, "pg_cron_worker");
+        assert!(libraries[1].requires_restart);
+        assert!(add_preloaded_libraries(None, &[]).is_none());
+    }
 }
@@ -1,22 +1,36 @@
 use std::ops::Not;
 
+use crate::config::ControlFields;
+
 /// Some information contained in an extension's `control` file.
 ///
 /// Postgres docs: https://www.postgresql.org/docs/current/extend-extensions.html
 #[derive(Debug)]
 pub struct ControlFile {
+    pub default_version: Option<String>,
     pub directory: Option<String>,
     pub module_pathname: Option<String>,
     pub requires: Option<Vec<String>>,
+    pub relocatable: Option<bool>,
+    pub schema: Option<String>,
 }
 
 impl ControlFile {
     pub fn parse(input: &str) -> Self {
+        let mut default_version = None;
         let mut directory = None;
         let mut module_pathname = None;
         let mut requires = None;
+        let mut relocatable = None;
+        let mut schema = None;
 
         for line in input.lines().map(str::trim_start) {
+            if let Some(rhs) = line.strip_prefix("default_version") {
+                let value = strip_value(rhs);
+                default_version = Some(value.to_string());
+                continue;
+            }
+
             if let Some(rhs) = line.strip_prefix("directory") {
                 let value = strip_value(rhs);
                 directory = Some(value.to_string());
@@ -41,18 +55,75 @@ impl ControlFile {
                 );
                 continue;
             }
+
+            if let Some(rhs) = line.strip_prefix("relocatable") {
+                relocatable = strip_value(rhs).parse().ok();
+                continue;
+            }
+
+            if let Some(rhs) = line.strip_prefix("schema") {
+                let value = strip_value(rhs);
+                schema = Some(value.to_string());
+                continue;
+            }
         }
 
         Self {
+            default_version,
             directory,
             module_pathname,
             requires,
+            relocatable,
+            schema,
         }
     }
 
     pub fn dependencies(&self) -> &[String] {
         self.requires.as_deref().unwrap_or(&[])
     }
+
+    /// Describes each field set under `[extension.control]` in Trunk.toml that this control
+    /// file disagrees with
+    pub fn mismatches(&self, expected: &ControlFields) -> Vec<String> {
+        fn compare<T: PartialEq + std::fmt::Debug>(
+            mismatches: &mut Vec<String>,
+            field: &str,
+            expected: &Option<T>,
+            actual: &Option<T>,
+        ) {
+            if let Some(expected) = expected {
+                match actual {
+                    Some(actual) if actual == expected => {}
+                    Some(actual) => mismatches.push(format!(
+                        "{field} is {expected:?} in Trunk.toml, but {actual:?} in the control file"
+                    )),
+                    None => mismatches.push(format!(
+                        "{field} is {expected:?} in Trunk.toml, but not set in the control file"
+                    )),
+                }
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        compare(
+            &mut mismatches,
+            "relocatable",
+            &expected.relocatable,
+            &self.relocatable,
+        );
+        compare(&mut mismatches, "schema", &expected.schema, &self.schema);
+        compare(
+            &mut mismatches,
+            "module_pathname",
+            &expected.module_pathname,
+            &self.module_pathname,
+        );
+        // An empty `requires = ''` is the same as leaving it out
+        let requires = Some(self.dependencies().to_vec());
+        compare(&mut mismatches, "requires", &expected.requires, &requires);
+
+        mismatches
+    }
 }
 
 pub fn strip_value(input: &str) -> &str {
@@ -64,6 +135,7 @@ pub fn strip_value(input: &str) -> &str {
 
 #[cfg(test)]
 mod tests {
+    use crate::config::ControlFields;
     use crate::control_file::ControlFile;
 
     #[test]
@@ -95,6 +167,7 @@ mod tests {
         let control_file = ControlFile::parse(contents);
 
         assert!(control_file.directory.is_none());
+        assert_eq!(control_file.default_version.unwrap(), "0.4.2");
         assert_eq!(control_file.module_pathname.unwrap(), "$libdir/pgmq");
     }
 
@@ -130,4 +203,40 @@ mod tests {
             ["pg_partman", "dep2", "dep3"]
         );
     }
+
+    #[test]
+    fn compares_with_trunk_toml_control_fields() {
+        let contents = r#"
+        default_version = '0.4.2'
+        module_pathname = '$libdir/pgmq'
+        relocatable = false
+        schema = pgmq
+        "#;
+        let control_file = ControlFile::parse(contents);
+        assert_eq!(control_file.relocatable, Some(false));
+        assert_eq!(control_file.schema.as_deref(), Some("pgmq"));
+
+        let matching = ControlFields {
+            relocatable: Some(false),
+            schema: Some("pgmq".to_string()),
+            requires: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(control_file.mismatches(&matching).is_empty());
+
+        let mismatched = ControlFields {
+            relocatable: Some(true),
+            module_pathname: Some("$libdir/pgmq2".to_string()),
+            requires: Some(vec!["pg_partman".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            control_file.mismatches(&mismatched),
+            [
+                "relocatable is true in Trunk.toml, but false in the control file",
+                r#"module_pathname is "$libdir/pgmq2" in Trunk.toml, but "$libdir/pgmq" in the control file"#,
+                r#"requires is ["pg_partman"] in Trunk.toml, but [] in the control file"#,
+            ]
+        );
+    }
 }
@@ -0,0 +1,125 @@
+//! Commands from the `[hooks]` table of Trunk.toml, which `--no-hooks` turns off.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, bail, Context};
+use serde_json::Value;
+
+use crate::manifest::Manifest;
+
+/// Fields of manifest.json that `trunk install` and the registry rely on, which a
+/// `manifest_transform` hook may not drop or change
+const BASE_MANIFEST_FIELDS: [&str; 7] = [
+    "name",
+    "version",
+    "manifest_version",
+    "pg_version",
+    "sys",
+    "architecture",
+    "files",
+];
+
+/// Runs the `manifest_transform` hook from `dir`, passing it `manifest_json` on stdin. Returns
+/// the manifest it writes to stdout, pretty-printed, with the fields trunk models parsed.
+pub fn transform_manifest(
+    command: &str,
+    dir: &Path,
+    manifest_json: &str,
+) -> anyhow::Result<(String, Manifest)> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("Failed to run the manifest_transform hook `{command}`"))?;
+
+    // Written from another thread, so that a hook that writes before it has read all of its
+    // input can't block on a full pipe
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = manifest_json.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // A hook that doesn't read its input closes the pipe early, which isn't an error
+    let _ = writer.join();
+    if !output.status.success() {
+        bail!(
+            "The manifest_transform hook `{command}` failed with {}",
+            output.status
+        );
+    }
+
+    let original: Value = serde_json::from_str(manifest_json)?;
+    let transformed: Value = serde_json::from_slice(&output.stdout)
+        .context("The manifest_transform hook didn't write valid JSON to stdout")?;
+    check_base_fields(&original, &transformed)?;
+    let manifest = serde_json::from_value(transformed.clone())
+        .context("The manifest_transform hook wrote a manifest that trunk can't read")?;
+
+    Ok((serde_json::to_string_pretty(&transformed)?, manifest))
+}
+
+fn check_base_fields(original: &Value, transformed: &Value) -> anyhow::Result<()> {
+    let transformed = transformed
+        .as_object()
+        .ok_or_else(|| anyhow!("The manifest_transform hook must write a JSON object"))?;
+    for field in BASE_MANIFEST_FIELDS {
+        match transformed.get(field) {
+            None => bail!("The manifest_transform hook removed the manifest field '{field}'"),
+            Some(value) if Some(value) != original.get(field) => {
+                bail!("The manifest_transform hook changed the manifest field '{field}', which trunk sets")
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_manifests() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = Manifest {
+            name: "ext".to_string(),
+            extension_version: "1.0.0".to_string(),
+            manifest_version: 2,
+            pg_version: 15,
+            sys: "linux".to_string(),
+            architecture: "x86_64".to_string(),
+            ..Default::default()
+        };
+        let json = serde_json::to_string_pretty(&manifest).unwrap();
+
+        let (transformed, parsed) =
+            transform_manifest(r#"sed '1s/{/{"registry_id": 7,/'"#, dir.path(), &json).unwrap();
+        assert!(transformed.contains("\"registry_id\": 7"), "{transformed}");
+        assert_eq!(parsed.extension_version, "1.0.0");
+
+        let err = transform_manifest("echo not json", dir.path(), &json).unwrap_err();
+        assert!(err.to_string().contains("didn't write valid JSON"), "{err}");
+        let err = transform_manifest(
+            r#"sed 's/"version": "1.0.0"/"version": "2.0.0"/'"#,
+            dir.path(),
+            &json,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("changed the manifest field 'version'"),
+            "{err}"
+        );
+        let err = transform_manifest("cat; exit 3", dir.path(), &json).unwrap_err();
+        assert!(
+            err.to_string().contains("failed with exit status: 3"),
+            "{err}"
+        );
+    }
+}
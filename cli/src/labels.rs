@@ -0,0 +1,103 @@
+//! Provenance labels recorded in the archive's manifest, from `--label`, `--label-file` and
+//! `[build.labels]`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+
+/// Label keys follow Docker's convention, e.g. `org.opencontainers.image.source`
+pub fn validate_label_key(key: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err("the label key is empty".to_string());
+    }
+    if let Some(invalid) = key
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/')))
+    {
+        return Err(format!("the label key '{key}' contains '{invalid}'"));
+    }
+
+    Ok(())
+}
+
+/// Parses a `KEY=VALUE` label, the value may be empty
+pub fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("expected KEY=VALUE, got '{label}'"))?;
+    validate_label_key(key)?;
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Reads a file of `KEY=VALUE` lines, skipping blank lines and `#` comments. Later lines override
+/// earlier ones with the same key.
+pub fn read_label_file(path: &Path) -> Result<BTreeMap<String, String>, anyhow::Error> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read label file {}", path.display()))?;
+
+    let mut labels = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = parse_label(line)
+            .map_err(|err| anyhow::anyhow!("{}:{}: {err}", path.display(), index + 1))?;
+        labels.insert(key, value);
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_labels() {
+        assert_eq!(
+            parse_label("org.opencontainers.image.source=https://github.com/org/ext").unwrap(),
+            (
+                "org.opencontainers.image.source".to_string(),
+                "https://github.com/org/ext".to_string()
+            )
+        );
+        assert_eq!(
+            parse_label("ci.job=").unwrap(),
+            ("ci.job".to_string(), String::new())
+        );
+        assert!(parse_label("no-value").is_err());
+        assert!(parse_label("=value").is_err());
+        assert!(parse_label("ci job=1").is_err());
+    }
+
+    #[test]
+    fn reads_label_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("labels.env");
+        fs::write(
+            &path,
+            "# Written by CI\n\nci.pipeline=42\nci.commit=abc=def\nci.pipeline=43\n",
+        )
+        .unwrap();
+        let labels = read_label_file(&path).unwrap();
+        assert_eq!(
+            labels,
+            BTreeMap::from([
+                ("ci.commit".to_string(), "abc=def".to_string()),
+                ("ci.pipeline".to_string(), "43".to_string()),
+            ])
+        );
+
+        fs::write(&path, "ci.pipeline=42\nbroken line\n").unwrap();
+        let err = read_label_file(&path).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("labels.env:2: expected KEY=VALUE, got 'broken line'"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! The trunk CLI as a library. [`build`] runs the same build as `trunk build`, from
+//! [`BuildSettings`] made with [`BuildSettings::builder`].
+
+pub mod build_log;
+pub mod changelog;
+pub mod commands;
+pub mod config;
+mod control_file;
+mod hooks;
+mod labels;
+pub mod manifest;
+mod retry;
+mod semver;
+mod sql_scripts;
+mod sync_utils;
+pub mod timings;
+pub mod trunk_toml;
+pub mod tui;
+mod v1;
+pub mod warnings;
+
+pub use commands::build::{build, BuildOutput, BuildSettings, BuildSettingsBuilder};
+
+pub fn pg_version_to_str(pg_version: u8) -> &'static str {
+    match pg_version {
+        14 => "14",
+        15 => "15",
+        16 => "16",
+        _ => panic!("Unsupported Postgres version!"),
+    }
+}
+
+pub fn pg_release_for_version(pg_version: u8) -> &'static str {
+    match pg_version {
+        14 => "REL_14_10",
+        15 => "REL_15_3",
+        16 => "REL_16_1",
+        _ => panic!("Unsupported Postgres version!"),
+    }
+}
@@ -1,25 +1,17 @@
-mod commands;
-mod config;
-mod control_file;
-mod manifest;
-mod retry;
-mod semver;
-mod sync_utils;
-mod trunk_toml;
-mod tui;
-mod v1;
-
-use crate::commands::SubCommand;
 use async_trait::async_trait;
 use clap::{Parser, Subcommand};
 use log::error;
 use log::Level;
+use pg_trunk::build_log;
+use pg_trunk::commands;
+use pg_trunk::commands::build::NoExtensionFilesError;
+use pg_trunk::commands::SubCommand;
 
 use colorful::{Color, Colorful, RGB};
+use pg_trunk::tui::{indent, TRUNK_SAND_COLOR};
 use std::io::Write;
 use std::{process::ExitCode, time::Duration};
 use tokio_task_manager::{Task, TaskManager};
-use tui::{indent, TRUNK_SAND_COLOR};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -32,13 +24,19 @@ struct Cli {
 #[derive(Subcommand)]
 enum SubCommands {
     /// Build a PGRX or C based Postgres extension
-    Build(commands::build::BuildCommand),
+    Build(Box<commands::build::BuildCommand>),
     /// Publish a Postgres extension to the Trunk registry
     Publish(commands::publish::PublishCommand),
     /// Install a Postgres extension from the Trunk registry
     Install(commands::install::InstallCommand),
     /// Verify a local install of a Postgres extension by running its regression tests
     Verify(commands::verify::VerifyCommand),
+    /// Remove build artifacts and builder images created by trunk
+    Clean(commands::clean::CleanCommand),
+    /// Check that the environment has what trunk needs to build extensions
+    Doctor(commands::doctor::DoctorCommand),
+    /// Check a built archive against the files and digests in its manifest.json
+    VerifyArchive(commands::verify_archive::VerifyArchiveCommand),
 }
 
 #[async_trait]
@@ -49,6 +47,9 @@ impl SubCommand for SubCommands {
             SubCommands::Publish(cmd) => cmd.execute(task).await,
             SubCommands::Install(cmd) => cmd.execute(task).await,
             SubCommands::Verify(cmd) => cmd.execute(task).await,
+            SubCommands::Clean(cmd) => cmd.execute(task).await,
+            SubCommands::Doctor(cmd) => cmd.execute(task).await,
+            SubCommands::VerifyArchive(cmd) => cmd.execute(task).await,
         }
     }
 }
@@ -68,7 +69,17 @@ fn main() -> ExitCode {
                 Level::Debug => String::from("debug").color(RGB::new(234, 67, 118)),
                 Level::Trace => String::from("trace").color(Color::Green),
             };
-            writeln!(buf, "{}: {}", level_str, record.args())
+            let topic = build_log::topic(record.target());
+            build_log::record(&build_log::tag_each_line(
+                topic,
+                &format!(
+                    "{}: {}\n",
+                    record.level().as_str().to_lowercase(),
+                    record.args()
+                ),
+            ));
+            let line = format!("{}: {}\n", level_str, record.args());
+            write!(buf, "{}", build_log::tag_each_line(topic, &line))
         })
         .try_init()
         .ok();
@@ -82,34 +93,51 @@ fn main() -> ExitCode {
 
     match rt.block_on(async {
         let cli = Cli::parse();
-        let result = cli.command.execute(tm.task()).await;
+        // Dropping the command's future on cancellation runs the cleanup of whatever
+        // it was doing (e.g. stopping the build container, removing partial artifacts).
+        let result = tokio::select! {
+            result = cli.command.execute(tm.task()) => Some(result),
+            _ = shutdown_signal() => {
+                eprintln!("{}build cancelled, cleaning up...", indent(1));
+                None
+            }
+        };
         tm.wait().await;
         result
     }) {
-        Ok(_) => ExitCode::SUCCESS,
-        Err(e) => {
+        Some(Ok(_)) => ExitCode::SUCCESS,
+        Some(Err(e)) => {
             // Any errors returned will get propagated up and gracefully logged to the user here
             print!("{}", indent(1));
             error!("{}", e);
-            ExitCode::from(1)
+            if NoExtensionFilesError::is_cause_of(&e) {
+                ExitCode::from(NoExtensionFilesError::EXIT_CODE)
+            } else {
+                ExitCode::from(1)
+            }
         }
+        // Conventional exit code for a process terminated by SIGINT
+        None => ExitCode::from(130),
     }
 }
 
-pub fn pg_version_to_str(pg_version: u8) -> &'static str {
-    match pg_version {
-        14 => "14",
-        15 => "15",
-        16 => "16",
-        _ => panic!("Unsupported Postgres version!"),
+/// Resolves once SIGINT (Ctrl-C) or, on Unix, SIGTERM is received
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
     }
-}
 
-pub fn pg_release_for_version(pg_version: u8) -> &'static str {
-    match pg_version {
-        14 => "REL_14_10",
-        15 => "REL_15_3",
-        16 => "REL_16_1",
-        _ => panic!("Unsupported Postgres version!"),
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
     }
 }
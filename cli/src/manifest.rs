@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::path::{Path, PathBuf};
 
-use crate::config::{ExtensionConfiguration, LoadableLibrary};
+use crate::changelog::Changelog;
+use crate::config::{ControlFields, ExtensionConfiguration, LoadableLibrary};
 
 /// Packaged file
 #[derive(Serialize, Deserialize, Debug)]
@@ -36,6 +39,27 @@ impl PackagedFile {
     }
 }
 
+/// What an extension is made of, from `kind` in `[extension]` of Trunk.toml
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtensionKind {
+    /// An extension with a control file, enabled with `CREATE EXTENSION`
+    #[default]
+    Extension,
+    /// A loadable module without a control file, such as `auto_explain`, loaded with `LOAD` or
+    /// `shared_preload_libraries`
+    Module,
+}
+
+/// An update script shipped with the extension, such as `ext--1.0--1.1.sql`, which lets
+/// `ALTER EXTENSION ext UPDATE` go from `from` to `to`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UpgradePath {
+    pub extension: String,
+    pub from: String,
+    pub to: String,
+}
+
 /// Package manifest
 #[derive(Serialize, Deserialize, Debug)]
 #[cfg_attr(test, derive(Default))]
@@ -63,12 +87,191 @@ pub struct Manifest {
     pub loadable_libraries: Option<Vec<LoadableLibrary>>,
     #[serde(default = "default_pg_version")]
     pub pg_version: u8,
+    /// Files copied from the build context into the archive's `included/` directory.
+    /// They are kept out of `files` so that installing the archive leaves them alone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub included_files: Option<Vec<PathBuf>>,
+    /// Whether the archive contains a shared library, as opposed to an extension made only of
+    /// SQL scripts. Missing from manifests written before this was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_shared_library: Option<bool>,
+    /// The Cargo profile a pgrx extension was built with, `release` or `debug`.
+    /// Not set for other extensions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_profile: Option<String>,
+    /// Versions of the compilers and tools in the builder image, keyed by tool, e.g.
+    /// `rustc = "rustc 1.75.0 (82e1608df 2023-12-21)"`. Tools that weren't found are left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<BTreeMap<String, String>>,
+    /// The oldest Postgres major version the extension supports, if it declares one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pg_version: Option<u8>,
+    /// The newest Postgres major version the extension supports, if it declares one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pg_version: Option<u8>,
+    /// Digests of the packaged files, such as `sha256:9f86…`, keyed by their path in the archive.
+    /// Only recorded with `--file-digests`, so that consumers can find the files that are
+    /// identical across archives, e.g. the SQL scripts of each platform, without extracting them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_digests: Option<BTreeMap<PathBuf, String>>,
+    /// The `--prefix` each file was installed under, keyed by its path in the archive, for the
+    /// files that were not installed under `pg_config --prefix`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install_prefixes: Option<BTreeMap<PathBuf, String>>,
+    /// The control file fields set under `[extension.control]` in Trunk.toml
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlFields>,
+    /// The commits since the previous tag, recorded with `--changelog-from-git`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<Changelog>,
+    /// The update scripts in the archive, read from their file names
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade_paths: Option<Vec<UpgradePath>>,
+    /// Provenance labels given with `--label`, `--label-file` or `[build.labels]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+    /// The registry categories of `[extension] categories` or `--categories`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub categories: Option<Vec<String>>,
+    /// The architecture the shared libraries were built for, read from their ELF header and named
+    /// the way `--platform` names it, e.g. `arm64`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elf_architecture: Option<String>,
+    /// `[build] cflags` and `cxxflags` of generic builds, which were set as `CFLAGS` and
+    /// `CXXFLAGS` while compiling and installing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cflags: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cxxflags: Option<String>,
+    /// The container runtime the extension was built with and its version, e.g.
+    /// `Docker 24.0.7`, or `unknown` if the runtime didn't report it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container_runtime: Option<String>,
+    /// The image the builder image was built from, e.g. `postgres:16-bookworm`: `--base-image`
+    /// or the `base_image` of the platform, or else the Dockerfile's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_image: Option<String>,
+    /// Whether `--strip` stripped the debug symbols from the packaged shared libraries. Not set
+    /// if it wasn't asked to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stripped: Option<bool>,
+    /// Files packaged because they matched `capture_globs`, keyed by their path in the archive,
+    /// with where they were installed. Those captured from elsewhere than sharedir and pkglibdir
+    /// are under `captured/` and kept out of `files`, so that installing the archive leaves them
+    /// alone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub captured_files: Option<BTreeMap<PathBuf, CaptureCategory>>,
+    /// `module` for loadable modules, which have no control file or SQL scripts. Not set for
+    /// other extensions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<ExtensionKind>,
+    /// The shared libraries of a loadable module, by their path in the archive, which is
+    /// relative to `pg_config --pkglibdir`. Only set for modules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modules: Option<Vec<PathBuf>>,
+}
+
+/// Where a file matched by `capture_globs` was installed
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaptureCategory {
+    /// Under `pg_config --sharedir`, packaged and installed like the extension's other files
+    Sharedir,
+    /// Under `pg_config --pkglibdir`, packaged and installed like the extension's other files
+    Pkglibdir,
+    /// Anywhere else under `pg_config --prefix`, only shipped in the archive
+    Prefix,
+}
+
+impl CaptureCategory {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CaptureCategory::Sharedir => "sharedir",
+            CaptureCategory::Pkglibdir => "pkglibdir",
+            CaptureCategory::Prefix => "prefix",
+        }
+    }
+}
+
+/// The `manifest_version` this version of trunk writes, which says how the archive is laid out.
+/// Version 1 kept control files and SQL scripts at the root of the archive, version 2 keeps them
+/// under their directory in `pg_config --sharedir`. Bump it when older versions of trunk could no
+/// longer read the archive correctly.
+pub const MANIFEST_VERSION: i32 = 2;
+
+/// The oldest `manifest_version` trunk reads, and can still write with `--format-version`
+pub const OLDEST_MANIFEST_VERSION: i32 = 1;
+
+/// Checks that trunk knows the archive layout of `manifest_version`
+pub fn check_manifest_version(manifest_version: i32) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        manifest_version <= MANIFEST_VERSION,
+        "The archive has manifest version {manifest_version}, but this version of trunk only \
+         understands versions {OLDEST_MANIFEST_VERSION} to {MANIFEST_VERSION}. Upgrade trunk to read it"
+    );
+    anyhow::ensure!(
+        manifest_version >= OLDEST_MANIFEST_VERSION,
+        "Invalid manifest version {manifest_version}, versions start at {OLDEST_MANIFEST_VERSION}"
+    );
+
+    Ok(())
 }
 
 const fn default_pg_version() -> u8 {
     15
 }
 
+/// The Postgres major versions an extension supports, from `min_pg_version` and
+/// `max_pg_version` in Trunk.toml. Either end of the range can be left open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SupportedPgVersions {
+    pub min: Option<u8>,
+    pub max: Option<u8>,
+}
+
+impl SupportedPgVersions {
+    /// Major versions have been single numbers since Postgres 10
+    const OLDEST_MAJOR: u8 = 10;
+    const NEWEST_MAJOR: u8 = 99;
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (key, version) in [("min_pg_version", self.min), ("max_pg_version", self.max)] {
+            if let Some(version) = version {
+                anyhow::ensure!(
+                    (Self::OLDEST_MAJOR..=Self::NEWEST_MAJOR).contains(&version),
+                    "{key} must be a Postgres major version from {} to {}, such as 14. Got: {version}",
+                    Self::OLDEST_MAJOR,
+                    Self::NEWEST_MAJOR
+                );
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            anyhow::ensure!(
+                min <= max,
+                "min_pg_version {min} is newer than max_pg_version {max}"
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, pg_version: u8) -> bool {
+        self.min.is_none_or(|min| pg_version >= min) && self.max.is_none_or(|max| pg_version <= max)
+    }
+}
+
+impl fmt::Display for SupportedPgVersions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min == max => write!(f, "{min}"),
+            (Some(min), Some(max)) => write!(f, "{min} to {max}"),
+            (Some(min), None) => write!(f, "{min} and later"),
+            (None, Some(max)) => write!(f, "{max} and earlier"),
+            (None, None) => f.write_str("any version"),
+        }
+    }
+}
+
 impl Manifest {
     pub fn merge(&mut self, other: Self) {
         match &mut self.files {
@@ -81,6 +284,46 @@ impl Manifest {
         }
     }
 
+    pub fn supported_pg_versions(&self) -> SupportedPgVersions {
+        SupportedPgVersions {
+            min: self.min_pg_version,
+            max: self.max_pg_version,
+        }
+    }
+
+    pub fn contains_shared_library(&self) -> bool {
+        self.files
+            .iter()
+            .flatten()
+            .any(|(_, file_kind)| matches!(file_kind, PackagedFile::SharedObject {}))
+    }
+
+    /// The paths of the shared libraries in the archive, sorted
+    pub fn shared_libraries(&self) -> Vec<PathBuf> {
+        let mut libraries: Vec<PathBuf> = self
+            .files
+            .iter()
+            .flatten()
+            .filter(|(_, file_kind)| matches!(file_kind, PackagedFile::SharedObject {}))
+            .map(|(path, _)| path.clone())
+            .collect();
+        libraries.sort();
+
+        libraries
+    }
+
+    /// Whether the archive is a loadable module rather than an extension
+    pub fn is_module(&self) -> bool {
+        self.kind == Some(ExtensionKind::Module)
+    }
+
+    /// Records the digest of `contents`, the file at `path` in the archive
+    pub fn add_digest<P: AsRef<Path>>(&mut self, path: P, contents: &[u8]) {
+        self.file_digests
+            .get_or_insert_with(BTreeMap::new)
+            .insert(path.as_ref().to_owned(), sha256_digest(contents));
+    }
+
     pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> &mut PackagedFile {
         let files = match self.files {
             None => {
@@ -95,13 +338,20 @@ impl Manifest {
     }
 }
 
+/// The SHA-256 digest of `contents`, in the `sha256:<hex>` form OCI registries use
+pub fn sha256_digest(contents: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(contents)))
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
 
     use crate::manifest::PackagedFile;
 
-    use super::Manifest;
+    use super::{
+        check_manifest_version, ExtensionKind, Manifest, SupportedPgVersions, MANIFEST_VERSION,
+    };
 
     #[test]
     fn adds_files_to_manifest() {
@@ -129,6 +379,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn records_file_digests() {
+        let mut manifest = Manifest::default();
+        let serialized = serde_json::to_string(&manifest).unwrap();
+        assert!(!serialized.contains("file_digests"));
+
+        manifest.add_digest("extension/pgmq.control", b"hello");
+        let digests = manifest.file_digests.unwrap();
+        assert_eq!(
+            digests[Path::new("extension/pgmq.control")],
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
     #[test]
     fn merges_manifests() {
         let mut manifest_1 = Manifest::default();
@@ -145,4 +409,79 @@ mod tests {
         assert!(files.contains_key(Path::new("manifest.json")));
         assert!(files.contains_key(Path::new("pgmq.control")));
     }
+
+    #[test]
+    fn included_files_are_optional() {
+        let manifest = Manifest::default();
+        let serialized = serde_json::to_string(&manifest).unwrap();
+        assert!(!serialized.contains("included_files"));
+
+        let manifest: Manifest = serde_json::from_str(&serialized).unwrap();
+        assert!(manifest.included_files.is_none());
+    }
+
+    #[test]
+    fn checks_manifest_versions() {
+        assert!(check_manifest_version(1).is_ok());
+        assert!(check_manifest_version(MANIFEST_VERSION).is_ok());
+        let err = check_manifest_version(MANIFEST_VERSION + 1).unwrap_err();
+        assert!(err.to_string().contains("Upgrade trunk"), "{err}");
+        assert!(check_manifest_version(0).is_err());
+    }
+
+    #[test]
+    fn checks_supported_pg_versions() {
+        let from_14 = SupportedPgVersions {
+            min: Some(14),
+            max: None,
+        };
+        assert!(from_14.validate().is_ok());
+        assert!(!from_14.contains(13));
+        assert!(from_14.contains(16));
+        assert_eq!(from_14.to_string(), "14 and later");
+
+        let only_15 = SupportedPgVersions {
+            min: Some(15),
+            max: Some(15),
+        };
+        assert!(only_15.contains(15) && !only_15.contains(16));
+        assert_eq!(only_15.to_string(), "15");
+        assert!(SupportedPgVersions::default().contains(14));
+
+        for (min, max) in [(Some(16), Some(14)), (Some(9), None), (None, Some(150))] {
+            assert!(SupportedPgVersions { min, max }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn detects_shared_libraries() {
+        let mut manifest = Manifest::default();
+        assert!(!manifest.contains_shared_library());
+
+        manifest.add_file("sql_only.control");
+        manifest.add_file("sql_only--1.0.sql");
+        assert!(!manifest.contains_shared_library());
+
+        manifest.add_file("with_library.so");
+        assert!(manifest.contains_shared_library());
+    }
+
+    #[test]
+    fn records_loadable_modules() {
+        let mut manifest = Manifest::default();
+        manifest.add_file("plugins/b_module.so");
+        manifest.add_file("a_module.so");
+        manifest.add_file("a_module.bc");
+        assert_eq!(
+            manifest.shared_libraries(),
+            [Path::new("a_module.so"), Path::new("plugins/b_module.so")]
+        );
+        assert!(!serde_json::to_string(&manifest).unwrap().contains("kind"));
+
+        manifest.kind = Some(ExtensionKind::Module);
+        let serialized = serde_json::to_string(&manifest).unwrap();
+        assert!(serialized.contains(r#""kind":"module""#), "{serialized}");
+        let manifest: Manifest = serde_json::from_str(&serialized).unwrap();
+        assert!(manifest.is_module());
+    }
 }
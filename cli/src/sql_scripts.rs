@@ -0,0 +1,149 @@
+//! Reads the versions an extension provides from the file names of its SQL scripts.
+//!
+//! Postgres docs: https://www.postgresql.org/docs/current/extend-extensions.html#EXTEND-EXTENSIONS-UPDATES
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::manifest::UpgradePath;
+
+/// A script named after the version it installs, `ext--1.0.sql`, or the versions it
+/// updates between, `ext--1.0--1.1.sql`
+#[derive(Debug, PartialEq, Eq)]
+enum SqlScript {
+    Install { extension: String, version: String },
+    Upgrade(UpgradePath),
+}
+
+impl SqlScript {
+    /// Postgres forbids `--` in extension names and versions, so the name splits unambiguously.
+    /// Other SQL files, such as those loaded by the scripts, are none
+    fn parse(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(".sql")?;
+        let parts: Vec<&str> = stem.split("--").collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return None;
+        }
+
+        match parts[..] {
+            [extension, version] => Some(Self::Install {
+                extension: extension.to_string(),
+                version: version.to_string(),
+            }),
+            [extension, from, to] => Some(Self::Upgrade(UpgradePath {
+                extension: extension.to_string(),
+                from: from.to_string(),
+                to: to.to_string(),
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// The install and update scripts among an extension's files
+#[derive(Debug, Default)]
+pub struct SqlScripts {
+    /// `(extension, version)` of each install script
+    installs: BTreeSet<(String, String)>,
+    pub upgrade_paths: Vec<UpgradePath>,
+}
+
+impl SqlScripts {
+    pub fn from_files<'a>(paths: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut scripts = Self::default();
+        let file_names = paths
+            .into_iter()
+            .filter_map(|path| Path::new(path).file_name()?.to_str());
+        for file_name in file_names {
+            match SqlScript::parse(file_name) {
+                Some(SqlScript::Install { extension, version }) => {
+                    scripts.installs.insert((extension, version));
+                }
+                Some(SqlScript::Upgrade(path)) => scripts.upgrade_paths.push(path),
+                None => {}
+            }
+        }
+        scripts.upgrade_paths.sort();
+
+        scripts
+    }
+
+    /// Whether `CREATE EXTENSION` can install `version`, either from its own install script or
+    /// from an older one followed by update scripts
+    pub fn can_install(&self, extension: &str, version: &str) -> bool {
+        let mut reached = BTreeSet::from([version]);
+        let mut pending = vec![version];
+        while let Some(version) = pending.pop() {
+            if self
+                .installs
+                .contains(&(extension.to_string(), version.to_string()))
+            {
+                return true;
+            }
+            for path in &self.upgrade_paths {
+                if path.extension == extension && path.to == version && reached.insert(&path.from) {
+                    pending.push(&path.from);
+                }
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade(from: &str, to: &str) -> UpgradePath {
+        UpgradePath {
+            extension: "ext".to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_script_names() {
+        assert_eq!(
+            SqlScript::parse("ext--1.0.sql"),
+            Some(SqlScript::Install {
+                extension: "ext".to_string(),
+                version: "1.0".to_string()
+            })
+        );
+        assert_eq!(
+            SqlScript::parse("ext--1.0--1.1.sql"),
+            Some(SqlScript::Upgrade(upgrade("1.0", "1.1")))
+        );
+        assert_eq!(SqlScript::parse("functions.sql"), None);
+        assert_eq!(SqlScript::parse("ext--.sql"), None);
+        assert_eq!(SqlScript::parse("ext--1.0--1.1--1.2.sql"), None);
+        assert_eq!(SqlScript::parse("ext--1.0.control"), None);
+    }
+
+    #[test]
+    fn follows_upgrade_paths_to_installable_versions() {
+        let scripts = SqlScripts::from_files([
+            "extension/ext--1.1--1.2.sql",
+            "extension/ext--1.0.sql",
+            "extension/ext--1.0--1.1.sql",
+            "extension/ext--0.9--1.0.sql",
+            "extension/ext.control",
+        ]);
+        assert_eq!(
+            scripts.upgrade_paths,
+            [
+                upgrade("0.9", "1.0"),
+                upgrade("1.0", "1.1"),
+                upgrade("1.1", "1.2")
+            ]
+        );
+
+        assert!(scripts.can_install("ext", "1.0"));
+        assert!(scripts.can_install("ext", "1.2"));
+        assert!(!scripts.can_install("ext", "0.9"));
+        assert!(!scripts.can_install("ext", "2.0"));
+        assert!(!scripts.can_install("other", "1.0"));
+    }
+}
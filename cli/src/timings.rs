@@ -0,0 +1,154 @@
+//! Wall-clock time spent in each phase of a build, summarized when the build finishes.
+
+use std::fmt::Write;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How many of the slowest Dockerfile steps the summary lists
+const SLOWEST_STEPS: usize = 3;
+
+#[derive(Clone, Debug, Default)]
+pub struct BuildTimings {
+    /// Phases in the order they first finished, such as `image build` or `install`
+    phases: Vec<(&'static str, Duration)>,
+    /// Steps of the Dockerfile, as reported by the classic builder
+    steps: Vec<(String, Duration)>,
+}
+
+impl BuildTimings {
+    /// Adds `duration` to the time spent in `phase`
+    pub fn record(&mut self, phase: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase, duration)),
+        }
+    }
+
+    /// Adds the time since `started` to the time spent in `phase`
+    pub fn record_since(&mut self, phase: &'static str, started: Instant) {
+        self.record(phase, started.elapsed());
+    }
+
+    pub fn record_step(&mut self, step: impl Into<String>, duration: Duration) {
+        self.steps.push((step.into(), duration));
+    }
+
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    /// The time each phase took, slowest first, along with its share of `total`, followed by
+    /// the slowest Dockerfile steps. Phases can overlap: the build context is sent while the
+    /// image builds.
+    pub fn summary(&self, artifact: &str, total: Duration) -> String {
+        let mut phases = self.phases.clone();
+        phases.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        let width = phases
+            .iter()
+            .map(|(phase, _)| phase.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut summary = format!("Build timings for {artifact}:\n");
+        for (phase, duration) in &phases {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                duration.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            let _ = writeln!(
+                summary,
+                "  {phase:<width$}  {:>7.1}s  {share:>3.0}%",
+                duration.as_secs_f64()
+            );
+        }
+        let _ = writeln!(
+            summary,
+            "  {:<width$}  {:>7.1}s",
+            "total",
+            total.as_secs_f64()
+        );
+
+        let mut steps: Vec<&(String, Duration)> = self.steps.iter().collect();
+        steps.sort_by(|(_, a), (_, b)| b.cmp(a));
+        if !steps.is_empty() {
+            summary.push_str("Slowest Dockerfile steps:\n");
+        }
+        for (step, duration) in steps.into_iter().take(SLOWEST_STEPS) {
+            let _ = writeln!(summary, "  {:>7.1}s  {step}", duration.as_secs_f64());
+        }
+
+        summary
+    }
+}
+
+/// A writer that adds up the time spent writing to `inner`, e.g. compressing into a
+/// `GzEncoder`
+pub struct TimedWriter<W> {
+    inner: W,
+    pub elapsed: Duration,
+}
+
+impl<W> TimedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for TimedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let written = self.inner.write(buf);
+        self.elapsed += started.elapsed();
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let started = Instant::now();
+        let flushed = self.inner.flush();
+        self.elapsed += started.elapsed();
+        flushed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_slowest_first() {
+        let mut timings = BuildTimings::default();
+        timings.record("image build", Duration::from_secs(30));
+        timings.record("install", Duration::from_secs(5));
+        timings.record("image build", Duration::from_secs(10));
+        timings.record("compression", Duration::from_secs(1));
+        for (step, seconds) in [
+            ("RUN apt-get", 20),
+            ("COPY . .", 1),
+            ("RUN make", 15),
+            ("ARG X", 0),
+        ] {
+            timings.record_step(step, Duration::from_secs(seconds));
+        }
+
+        let summary = timings.summary("ext-1.0.0-pg15.tar.gz", Duration::from_secs(50));
+        let lines: Vec<&str> = summary.lines().collect();
+        assert_eq!(lines[0], "Build timings for ext-1.0.0-pg15.tar.gz:");
+        assert_eq!(lines[1], "  image build     40.0s   80%");
+        assert_eq!(lines[2], "  install          5.0s   10%");
+        assert_eq!(lines[3], "  compression      1.0s    2%");
+        assert_eq!(lines[4], "  total           50.0s");
+        assert_eq!(lines[5], "Slowest Dockerfile steps:");
+        assert_eq!(lines[6], "     20.0s  RUN apt-get");
+        assert_eq!(lines[7], "     15.0s  RUN make");
+        assert_eq!(lines.len(), 9);
+    }
+}
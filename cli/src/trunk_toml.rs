@@ -1,10 +1,15 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use serde::{Deserialize, Serialize};
 
 use glob::PatternError;
 
-use crate::config::{ExtensionConfiguration, LoadableLibrary};
+use crate::commands::build::CargoProfile;
+use crate::config::{ControlFields, ExtensionConfiguration, LoadableLibrary, Preload};
+use crate::manifest::ExtensionKind;
 
 pub type SystemDependencies = HashMap<String, Vec<String>>;
 
@@ -14,6 +19,232 @@ pub struct TrunkToml {
     pub extension: TomlExtensionData,
     pub build: TomlBuildInfo,
     pub dependencies: Option<SystemDependencies>,
+    pub publish: Option<TomlPublishInfo>,
+    #[serde(default)]
+    pub hooks: TomlHooks,
+    /// Variants of the settings selected with `--profile-name`, keyed by profile name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profile: BTreeMap<String, TomlProfile>,
+}
+
+/// A `[profile.<name>]` table, whose `extension` and `build` values are merged over
+/// `[extension]` and `[build]` when the profile is selected
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct TomlProfile {
+    pub extension: Option<toml::Table>,
+    pub build: Option<toml::Table>,
+}
+
+impl TrunkToml {
+    /// Merges `[profile.<name>]` over `[extension]` and `[build]`, with nested tables merged
+    /// key by key. Returns the Trunk.toml keys the profile set, e.g. `build.install_command`.
+    /// Fails if there is no such profile, or if it sets keys that `[extension]` and `[build]`
+    /// don't have
+    pub fn apply_profile(self, name: &str) -> Result<(TrunkToml, Vec<String>), anyhow::Error> {
+        let Some(profile) = self.profile.get(name).cloned() else {
+            let available: Vec<&str> = self.profile.keys().map(String::as_str).collect();
+            if available.is_empty() {
+                anyhow::bail!(
+                    "Unknown profile '{name}', Trunk.toml defines no [profile.<name>] tables"
+                );
+            }
+            anyhow::bail!(
+                "Unknown profile '{name}'. The profiles in Trunk.toml are: {}",
+                available.join(", ")
+            );
+        };
+
+        let mut merged = toml::Table::try_from(&self)?;
+        let mut keys = Vec::new();
+        for (section, overrides, known) in [
+            (
+                "extension",
+                profile.extension,
+                serde_json::to_value(&self.extension)?,
+            ),
+            ("build", profile.build, serde_json::to_value(&self.build)?),
+        ] {
+            let Some(overrides) = overrides else {
+                continue;
+            };
+            let unknown: Vec<String> = overrides
+                .keys()
+                .filter(|key| known.get(key.as_str()).is_none())
+                .map(|key| format!("profile.{name}.{section}.{key}"))
+                .collect();
+            if !unknown.is_empty() {
+                anyhow::bail!(
+                    "Unknown {} in Trunk.toml: {}. Profiles can only set keys of [{section}]",
+                    if unknown.len() == 1 { "key" } else { "keys" },
+                    unknown.join(", ")
+                );
+            }
+            let base = merged
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let toml::Value::Table(base) = base {
+                merge_tables(base, overrides, section, &mut keys);
+            }
+        }
+
+        let merged = toml::Value::Table(merged)
+            .try_into()
+            .map_err(|err| anyhow::anyhow!("Invalid [profile.{name}] in Trunk.toml: {err}"))?;
+
+        Ok((merged, keys))
+    }
+
+    /// Writes the `--config-override` values over Trunk.toml. Returns the Trunk.toml keys they
+    /// set, e.g. `build.cpus`. Fails if a key isn't one Trunk.toml has, or its value isn't one
+    /// the key takes
+    pub fn apply_overrides(
+        self,
+        overrides: &[ConfigOverride],
+    ) -> Result<(TrunkToml, Vec<String>), anyhow::Error> {
+        // Profiles are applied before the overrides, so overriding them would do nothing
+        let mut known = serde_json::to_value(&self)?;
+        if let Some(known) = known.as_object_mut() {
+            known.remove("profile");
+        }
+
+        let mut merged = toml::Table::try_from(&self)?;
+        for config_override in overrides {
+            if let Some(unknown) = unknown_key(&known, &config_override.table, "") {
+                anyhow::bail!(
+                    "Unknown key in --config-override {}: Trunk.toml has no {unknown}",
+                    config_override.key
+                );
+            }
+            merge_tables(
+                &mut merged,
+                config_override.table.clone(),
+                "",
+                &mut Vec::new(),
+            );
+            toml::Value::Table(merged.clone())
+                .try_into::<TrunkToml>()
+                .map_err(|err| {
+                    anyhow::anyhow!("Invalid --config-override {}: {err}", config_override.key)
+                })?;
+        }
+
+        let merged = toml::Value::Table(merged).try_into()?;
+        let keys = overrides
+            .iter()
+            .map(|config_override| config_override.key.clone())
+            .collect();
+
+        Ok((merged, keys))
+    }
+}
+
+/// Writes the values of `overrides` over those of `base`, the table at `path`, merging tables
+/// that are in both. Adds the keys of the values written to `keys`
+fn merge_tables(
+    base: &mut toml::Table,
+    overrides: toml::Table,
+    path: &str,
+    keys: &mut Vec<String>,
+) {
+    for (key, value) in overrides {
+        let key_path = format!("{path}.{key}");
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => {
+                merge_tables(base, value, &key_path, keys)
+            }
+            (_, value) => {
+                base.insert(key, value);
+                keys.push(key_path);
+            }
+        }
+    }
+}
+
+/// A `--config-override` of one Trunk.toml key, e.g. `build.cpus=2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigOverride {
+    /// The dotted path of the key, e.g. `build.platforms."linux/arm64".base_image`
+    pub key: String,
+    /// The key's value, nested in tables along its path
+    pub table: toml::Table,
+}
+
+impl std::str::FromStr for ConfigOverride {
+    type Err = String;
+
+    /// Values are parsed as TOML, such as `true`, `4` or `"text"`. Anything else is taken as a
+    /// string, so that paths and image names don't need quoting
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, such as build.cpus=2. Got: {s}"))?;
+        let key = key.trim();
+        let value = value.trim();
+        let parse = |value: &str| format!("{key} = {value}").parse::<toml::Table>();
+        let table = parse(value)
+            .or_else(|_| parse(&toml::Value::String(value.to_string()).to_string()))
+            .map_err(|_| {
+                format!("{key} is not a dotted path of Trunk.toml keys, such as build.cpus")
+            })?;
+
+        // Spelled the way Trunk.toml keys are shown elsewhere, whatever the quoting of `key`
+        let mut path = Vec::new();
+        let mut nested = &table;
+        while let Some((key, value)) = nested.iter().next() {
+            if key
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '_' || ch == '-')
+            {
+                path.push(key.clone());
+            } else {
+                path.push(format!("\"{key}\""));
+            }
+            match value {
+                toml::Value::Table(value) if value.len() == 1 => nested = value,
+                _ => break,
+            }
+        }
+
+        Ok(ConfigOverride {
+            key: path.join("."),
+            table,
+        })
+    }
+}
+
+/// Tables of Trunk.toml keyed by names of the user's choosing, so keys under them aren't checked
+/// against the schema
+const TOML_MAPS: [&str; 3] = ["dependencies", "build.labels", "build.platforms"];
+
+/// The first key of `overrides`, the table at `path`, that `known` doesn't have
+fn unknown_key(known: &serde_json::Value, overrides: &toml::Table, path: &str) -> Option<String> {
+    for (key, value) in overrides {
+        let key_path = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        let Some(known) = known.get(key) else {
+            return Some(key_path);
+        };
+        if let (false, toml::Value::Table(value)) = (TOML_MAPS.contains(&key_path.as_str()), value)
+        {
+            if let Some(unknown) = unknown_key(known, value, &key_path) {
+                return Some(unknown);
+            }
+        }
+    }
+
+    None
+}
+
+/// The `[hooks]` table, commands run on the host during the build unless `--no-hooks` is given
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TomlHooks {
+    /// Reads the generated manifest.json on stdin and writes the manifest to package on stdout,
+    /// e.g. to add fields a registry requires
+    pub manifest_transform: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,26 +264,348 @@ pub struct TomlExtensionData {
     pub preload_libraries: Option<Vec<String>>,
     pub configurations: Option<Vec<ExtensionConfiguration>>,
     pub loadable_libraries: Option<Vec<LoadableLibrary>>,
+    /// Whether the extension's libraries must be in `shared_preload_libraries`, or which must
+    pub preload: Option<Preload>,
+    /// `extension` by default, or `module` for a loadable module without a control file
+    pub kind: Option<ExtensionKind>,
+    /// Fields of the control file to record in the manifest, checked against the control file
+    /// the build installs
+    pub control: Option<ControlFields>,
+    /// The oldest Postgres major version the extension supports, e.g. 14
+    pub min_pg_version: Option<u8>,
+    /// The newest Postgres major version the extension supports
+    pub max_pg_version: Option<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TomlBuildInfo {
     pub postgres_version: Option<String>,
+    /// Fail builds that don't give `--output-path`, instead of writing to `.trunk`.
+    /// See `--no-default-output`
+    pub require_explicit_output: Option<bool>,
     pub platform: String,
     /// List of globs to package extra files into the archive.
     /// This is useful if you need to package in a file that is not a .sql, .so, .bc, and so on.
     ///
     /// Example:
     ///
-    /// ```
+    /// ```toml
     /// include = ["*.data"]
     /// ```
     pub include: Option<Vec<String>>,
+    /// Files from the build context, such as a README, to ship in the archive under `included/`.
+    /// Unlike `include`, these are not produced by the install step and are not installed.
+    ///
+    /// Example:
+    ///
+    /// ```toml
+    /// include_files = ["LICENSE", "docs/README.md"]
+    /// ```
+    pub include_files: Option<Vec<String>>,
+    /// Globs, relative to `pg_config --prefix`, of installed files to package that Trunk doesn't
+    /// look for, such as sample configs outside sharedir and pkglibdir.
+    ///
+    /// Example:
+    ///
+    /// ```toml
+    /// capture_globs = ["share/doc/postgresql/extension/*.example"]
+    /// ```
+    pub capture_globs: Option<Vec<String>>,
+    /// SQL file, relative to the directory containing Trunk.toml, run against the installed archive, see
+    /// `--integration-test`
+    pub integration_test: Option<String>,
     pub dockerfile: Option<String>,
+    /// Bundled Dockerfile to build with, see `--builder`
+    pub builder: Option<String>,
+    /// Image to build on instead of the builder's default, see `--base-image`
+    pub base_image: Option<String>,
+    /// Where the install command puts shared libraries, see `--lib-dir`
+    pub lib_dir: Option<String>,
+    /// Where the install command puts SQL scripts, see `--sql-dir`
+    pub sql_dir: Option<String>,
+    /// Where the install command puts the control file, see `--control-dir`
+    pub control_dir: Option<String>,
+    /// Other prefixes the install command installs to, see `--prefix`
+    pub prefix: Option<Vec<String>>,
+    /// Number of CPUs the build can use, see `--cpus`
+    pub cpus: Option<f64>,
+    /// Memory limit of the build, such as `2g`, see `--memory`
+    pub memory: Option<String>,
+    /// How long a single build step may run, such as `10m`, see `--step-timeout`
+    pub step_timeout: Option<String>,
+    /// Labels recorded in the archive's manifest, see `--label`
+    pub labels: Option<BTreeMap<String, String>>,
+    /// Overrides applied when building for a given platform, keyed by platform.
+    ///
+    /// Example:
+    ///
+    /// ```toml
+    /// [build.platforms."linux/arm64"]
+    /// install_command = "make install libdir=/usr/lib/aarch64-linux-gnu"
+    /// ```
+    pub platforms: Option<BTreeMap<String, TomlPlatformBuildInfo>>,
+    /// Command run before `build_command`, in its own image layer, see `--configure-command`
+    pub configure_command: Option<String>,
+    /// Command that compiles the extension, in its own image layer, see `--build-command`
+    pub build_command: Option<String>,
     pub install_command: Option<String>,
+    /// Command run in the build container right before the install command, see `--before-install`
+    pub before_install: Option<String>,
+    /// Command run in the build container right after the install command, see `--after-install`
+    pub after_install: Option<String>,
+    /// `CFLAGS` of the compile and install steps of generic builds, see `--cflags`
+    pub cflags: Option<String>,
+    /// `CXXFLAGS` of the compile and install steps of generic builds, see `--cxxflags`
+    pub cxxflags: Option<String>,
+    /// `user[:group]` the install command runs as instead of root, see `--user`
+    pub user: Option<String>,
+    /// Command line the install command is passed to, instead of `/bin/sh -c`, see `--entrypoint`
+    pub entrypoint: Option<String>,
+    /// Install command used when `install_command` is not set, in place of `make install`.
+    /// Useful for sharing a Trunk.toml template across projects with the same build convention.
+    pub default_install_command: Option<String>,
+    /// Directory, relative to the build context, that contains the extension's sources.
+    pub extension_dir: Option<String>,
+    /// Settings that only apply to pgrx extensions
+    #[serde(default)]
+    pub pgrx: TomlPgrxBuildInfo,
+}
+
+/// The `[build.pgrx]` table
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TomlPgrxBuildInfo {
+    /// Cargo features to enable, see `--cargo-features`
+    pub features: Option<Vec<String>>,
+    pub no_default_features: Option<bool>,
+    pub all_features: Option<bool>,
+    /// `release` or `debug`, see `--profile`
+    pub profile: Option<CargoProfile>,
+    /// Rust toolchain to build with, such as `1.79.0` or `nightly-2024-06-01`, see `--rust-toolchain`
+    pub rust_toolchain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TomlPublishInfo {
+    /// Docker-style `config.json` with the registry credentials used to pull base images,
+    /// relative to the Trunk.toml file. See `--registry-auth-file`
+    pub auth_file: Option<String>,
+}
+
+/// The `[build]` values that can be overridden for a single platform
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TomlPlatformBuildInfo {
+    pub include: Option<Vec<String>>,
+    pub include_files: Option<Vec<String>>,
+    pub dockerfile: Option<String>,
+    pub builder: Option<String>,
+    pub configure_command: Option<String>,
+    pub build_command: Option<String>,
+    pub install_command: Option<String>,
+    pub before_install: Option<String>,
+    pub after_install: Option<String>,
+    pub default_install_command: Option<String>,
+    pub extension_dir: Option<String>,
+    pub base_image: Option<String>,
+    pub lib_dir: Option<String>,
+    pub sql_dir: Option<String>,
+    pub control_dir: Option<String>,
+}
+
+const KNOWN_ARCHITECTURES: [&str; 8] = [
+    "amd64", "arm64", "arm", "386", "ppc64le", "s390x", "riscv64", "mips64le",
+];
+
+/// Checks that `platform` looks like a Docker platform, such as `linux/amd64` or `linux/arm/v7`
+pub fn validate_platform(platform: &str) -> Result<(), anyhow::Error> {
+    let mut parts = platform.split('/');
+    let (os, arch, variant) = (parts.next(), parts.next(), parts.next());
+
+    let is_known = os == Some("linux")
+        && arch.is_some_and(|arch| KNOWN_ARCHITECTURES.contains(&arch))
+        && variant.is_none_or(|variant| !variant.is_empty())
+        && parts.next().is_none();
+
+    if !is_known {
+        anyhow::bail!(
+            "Unrecognized platform '{platform}' in [build.platforms]. Expected a platform such as linux/amd64 or linux/arm64"
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that `image`, set by `key`, is an image reference Docker would accept, such as
+/// `postgres:16-bookworm`, `quay.io/coredb/c-builder:pg16` or `ubuntu@sha256:<digest>`
+pub fn validate_base_image(image: &str, key: &str) -> Result<(), anyhow::Error> {
+    let (name, digest) = match image.split_once('@') {
+        Some((name, digest)) => (name, Some(digest)),
+        None => (image, None),
+    };
+    // A colon after the last slash starts the tag, one before it is a registry port
+    let (repository, tag) = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => (repository, Some(tag)),
+        _ => (name, None),
+    };
+    let (registry, path) = match repository.split_once('/') {
+        Some((host, path)) if host.contains(['.', ':']) || host == "localhost" => {
+            (Some(host), path)
+        }
+        _ => (None, repository),
+    };
+
+    let is_valid_registry = registry.is_none_or(|host| {
+        !host.is_empty()
+            && host
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '-' | ':'))
+    });
+    let is_valid_path = path.split('/').all(|component| {
+        !component.is_empty()
+            && component.starts_with(|ch: char| ch.is_ascii_alphanumeric())
+            && component.chars().all(|ch| {
+                ch.is_ascii_lowercase() || ch.is_ascii_digit() || matches!(ch, '.' | '_' | '-')
+            })
+    });
+    let is_valid_tag = tag.is_none_or(|tag| {
+        tag.len() <= 128
+            && !tag.starts_with(['.', '-'])
+            && !tag.is_empty()
+            && tag
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '.' | '_' | '-'))
+    });
+    let is_valid_digest = digest.is_none_or(|digest| {
+        digest.split_once(':').is_some_and(|(algorithm, hex)| {
+            !algorithm.is_empty()
+                && algorithm.chars().all(|ch| ch.is_ascii_alphanumeric())
+                && hex.len() >= 32
+                && hex.chars().all(|ch| ch.is_ascii_hexdigit())
+        })
+    });
+
+    if !(is_valid_registry && is_valid_path && is_valid_tag && is_valid_digest) {
+        anyhow::bail!(
+            "Invalid base image '{image}' in {key}. Expected an image reference such as \
+             postgres:16-bookworm or quay.io/coredb/c-builder:pg16"
+        );
+    }
+
+    Ok(())
 }
 
 impl TomlBuildInfo {
+    /// Replaces `[build]` values with those set in `[build.platforms."<platform>"]`, if any.
+    /// Returns the Trunk.toml keys of the values that were replaced.
+    pub fn apply_platform_overrides(&mut self, platform: &str) -> Vec<(&'static str, String)> {
+        let Some(overrides) = self
+            .platforms
+            .as_ref()
+            .and_then(|platforms| platforms.get(platform))
+            .cloned()
+        else {
+            return Vec::new();
+        };
+
+        fn apply<T>(
+            overridden: &mut Vec<(&'static str, String)>,
+            platform: &str,
+            field: &'static str,
+            target: &mut Option<T>,
+            value: Option<T>,
+        ) {
+            if value.is_some() {
+                *target = value;
+                overridden.push((field, format!("build.platforms.\"{platform}\".{field}")));
+            }
+        }
+
+        let mut overridden = Vec::new();
+        let o = &mut overridden;
+        apply(o, platform, "include", &mut self.include, overrides.include);
+        apply(
+            o,
+            platform,
+            "include_files",
+            &mut self.include_files,
+            overrides.include_files,
+        );
+        apply(
+            o,
+            platform,
+            "dockerfile",
+            &mut self.dockerfile,
+            overrides.dockerfile,
+        );
+        apply(o, platform, "builder", &mut self.builder, overrides.builder);
+        apply(
+            o,
+            platform,
+            "configure_command",
+            &mut self.configure_command,
+            overrides.configure_command,
+        );
+        apply(
+            o,
+            platform,
+            "build_command",
+            &mut self.build_command,
+            overrides.build_command,
+        );
+        apply(
+            o,
+            platform,
+            "install_command",
+            &mut self.install_command,
+            overrides.install_command,
+        );
+        apply(
+            o,
+            platform,
+            "before_install",
+            &mut self.before_install,
+            overrides.before_install,
+        );
+        apply(
+            o,
+            platform,
+            "after_install",
+            &mut self.after_install,
+            overrides.after_install,
+        );
+        apply(
+            o,
+            platform,
+            "default_install_command",
+            &mut self.default_install_command,
+            overrides.default_install_command,
+        );
+        apply(
+            o,
+            platform,
+            "extension_dir",
+            &mut self.extension_dir,
+            overrides.extension_dir,
+        );
+        apply(
+            o,
+            platform,
+            "base_image",
+            &mut self.base_image,
+            overrides.base_image,
+        );
+        apply(o, platform, "lib_dir", &mut self.lib_dir, overrides.lib_dir);
+        apply(o, platform, "sql_dir", &mut self.sql_dir, overrides.sql_dir);
+        apply(
+            o,
+            platform,
+            "control_dir",
+            &mut self.control_dir,
+            overrides.control_dir,
+        );
+
+        overridden
+    }
+
     pub fn build_glob_patterns(&self) -> Result<Vec<glob::Pattern>, PatternError> {
         let Some(patterns) = &self.include else {
             return Ok(Vec::new());
@@ -65,6 +618,234 @@ impl TomlBuildInfo {
     }
 }
 
+/// Where a resolved setting got its value from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// Set through a command-line flag
+    Cli,
+    /// Set through the given command-line flag, rather than the setting's own
+    CliFlag(&'static str),
+    /// Set through the given environment variable
+    Env(&'static str),
+    /// Set in Trunk.toml
+    TrunkToml,
+    /// Read from `[package]` in the extension's Cargo.toml
+    CargoToml,
+    /// Neither was set, so a built-in default was used
+    Default,
+}
+
+/// A setting's value, along with where it came from
+#[derive(Clone, Debug)]
+pub struct Resolved<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Resolved<T> {
+    pub fn new(value: T, source: Source) -> Self {
+        Self { value, source }
+    }
+}
+
+/// Records where each of the resolved settings came from, keyed by setting name
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    sources: BTreeMap<&'static str, Source>,
+    /// Trunk.toml keys for settings that were not read from their usual key
+    toml_keys: BTreeMap<&'static str, String>,
+    /// With `--profile-name`, the profile and the Trunk.toml keys it set, e.g. `build.user`
+    profile: Option<(String, Vec<String>)>,
+    /// The Trunk.toml keys set with `--config-override`
+    override_keys: Vec<String>,
+}
+
+impl Sources {
+    /// Records the source of `resolved` under `key`, returning its value
+    pub fn track<T>(&mut self, key: &'static str, resolved: Option<Resolved<T>>) -> Option<T> {
+        resolved.map(|resolved| {
+            self.sources.insert(key, resolved.source);
+            resolved.value
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Source> {
+        self.sources.get(key).copied()
+    }
+
+    /// Records that the setting `key` is read from `toml_key` in Trunk.toml
+    pub fn set_toml_key(&mut self, key: &'static str, toml_key: String) {
+        self.toml_keys.insert(key, toml_key);
+    }
+
+    pub fn toml_key(&self, key: &str) -> Option<&str> {
+        self.toml_keys.get(key).map(String::as_str)
+    }
+
+    /// Records that the Trunk.toml keys `toml_keys` were set by the profile `profile`
+    pub fn set_profile_keys(&mut self, profile: &str, toml_keys: Vec<String>) {
+        self.profile = Some((profile.to_string(), toml_keys));
+    }
+
+    /// Records that the Trunk.toml keys `toml_keys` were set with `--config-override`
+    pub fn set_override_keys(&mut self, toml_keys: Vec<String>) {
+        self.override_keys = toml_keys;
+    }
+
+    /// The key set with `--config-override` that the value of the Trunk.toml key `toml_key` was
+    /// read from, if one set it or part of it
+    pub fn override_key(&self, toml_key: &str) -> Option<&str> {
+        self.override_keys
+            .iter()
+            .find(|key| within(toml_key, key) || within(key, toml_key))
+            .map(String::as_str)
+    }
+
+    /// The key in the selected profile that the value of the Trunk.toml key `toml_key` was
+    /// read from, e.g. `profile.prod.build.user` for `build.user`, if the profile set it or
+    /// part of it
+    pub fn profile_key(&self, toml_key: &str) -> Option<String> {
+        let (profile, keys) = self.profile.as_ref()?;
+        keys.iter()
+            .any(|key| within(toml_key, key) || within(key, toml_key))
+            .then(|| format!("profile.{profile}.{toml_key}"))
+    }
+}
+
+/// Whether the Trunk.toml key `key` is `table` or one of the keys under it
+fn within(key: &str, table: &str) -> bool {
+    key.strip_prefix(table)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// Resolves a boolean flag that can also be enabled in Trunk.toml. A flag that was not given
+/// can't be told apart from `false`, so it falls back to Trunk.toml, then to `false`.
+pub(crate) fn resolve_trunk_flag<F: FnOnce(&TrunkToml) -> &Option<bool>>(
+    set_in_cli: bool,
+    extract: F,
+    maybe_toml: &Option<TrunkToml>,
+) -> Option<Resolved<bool>> {
+    resolve_cli_or_trunk_opt(&set_in_cli.then_some(true), extract, maybe_toml)
+        .or(Some(Resolved::new(false, Source::Default)))
+}
+
+/// Resolves a flag that has a default value. Such flags can't tell whether they were given,
+/// so a value equal to the default is attributed to the default.
+pub(crate) fn resolve_flag<T: PartialEq>(value: T, default: T) -> Resolved<T> {
+    if value == default {
+        Resolved::new(value, Source::Default)
+    } else {
+        Resolved::new(value, Source::Cli)
+    }
+}
+
+/// A setting that can be read from a `TRUNK_*` environment variable
+pub(crate) trait FromEnv: Sized {
+    fn from_env(value: String) -> Self;
+}
+
+impl FromEnv for String {
+    fn from_env(value: String) -> Self {
+        value
+    }
+}
+
+impl FromEnv for PathBuf {
+    fn from_env(value: String) -> Self {
+        value.into()
+    }
+}
+
+/// Lists are comma-separated, e.g. `TRUNK_EXTENSION_DEPENDENCIES=pg_partman,pg_cron`
+impl FromEnv for Vec<String> {
+    fn from_env(value: String) -> Self {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(ToOwned::to_owned)
+            .collect()
+    }
+}
+
+/// The value of `env_var`, if it is set and not empty
+pub(crate) fn resolve_env<T: FromEnv>(env_var: &'static str) -> Option<Resolved<T>> {
+    std::env::var(env_var)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| Resolved::new(T::from_env(value), Source::Env(env_var)))
+}
+
+/// Use the value supplied through the command-line, if present.
+/// If not, fallback to the value of `env_var`, then to the value specified in the Trunk.toml.
+/// The returned value records which of the three it came from.
+pub(crate) fn resolve_cli_env_or_trunk<T: Clone + FromEnv, F: FnOnce(&TrunkToml) -> &T>(
+    set_in_cli: &Option<T>,
+    env_var: &'static str,
+    extract: F,
+    maybe_toml: &Option<TrunkToml>,
+) -> Option<Resolved<T>> {
+    if let Some(value) = set_in_cli {
+        return Some(Resolved::new(value.clone(), Source::Cli));
+    }
+
+    resolve_env(env_var).or_else(|| resolve_cli_or_trunk(&None, extract, maybe_toml))
+}
+
+/// Use the value supplied through the command-line, if present.
+/// If not, fallback to the value of `env_var`, then to the value specified in the Trunk.toml, if present.
+/// The returned value records which of the three it came from.
+pub(crate) fn resolve_cli_env_or_trunk_opt<
+    T: Clone + FromEnv,
+    F: FnOnce(&TrunkToml) -> &Option<T>,
+>(
+    set_in_cli: &Option<T>,
+    env_var: &'static str,
+    extract: F,
+    maybe_toml: &Option<TrunkToml>,
+) -> Option<Resolved<T>> {
+    if let Some(value) = set_in_cli {
+        return Some(Resolved::new(value.clone(), Source::Cli));
+    }
+
+    resolve_env(env_var).or_else(|| resolve_cli_or_trunk_opt(&None, extract, maybe_toml))
+}
+
+/// Use the value supplied through the command-line, if present.
+/// If not, fallback to the value specified in the Trunk.toml.
+/// The returned value records which of the two it came from.
+pub(crate) fn resolve_cli_or_trunk<T: Clone, F: FnOnce(&TrunkToml) -> &T>(
+    set_in_cli: &Option<T>,
+    extract: F,
+    maybe_toml: &Option<TrunkToml>,
+) -> Option<Resolved<T>> {
+    if let Some(value) = set_in_cli {
+        return Some(Resolved::new(value.clone(), Source::Cli));
+    }
+
+    maybe_toml
+        .as_ref()
+        .map(|toml| Resolved::new(extract(toml).clone(), Source::TrunkToml))
+}
+
+/// Use the value supplied through the command-line, if present.
+/// If not, fallback to the value specified in the Trunk.toml, if present.
+/// The returned value records which of the two it came from.
+pub(crate) fn resolve_cli_or_trunk_opt<T: Clone, F: FnOnce(&TrunkToml) -> &Option<T>>(
+    set_in_cli: &Option<T>,
+    extract: F,
+    maybe_toml: &Option<TrunkToml>,
+) -> Option<Resolved<T>> {
+    if let Some(value) = set_in_cli {
+        return Some(Resolved::new(value.clone(), Source::Cli));
+    }
+
+    maybe_toml
+        .as_ref()
+        .and_then(|toml| extract(toml).as_ref())
+        .map(|value| Resolved::new(value.clone(), Source::TrunkToml))
+}
+
 /// Use the value supplied through the command-line, if present.
 /// If not, fallback to the value specified in the Trunk.toml
 pub(crate) fn cli_or_trunk<T: Clone, F: FnOnce(&TrunkToml) -> &T>(
@@ -72,10 +853,7 @@ pub(crate) fn cli_or_trunk<T: Clone, F: FnOnce(&TrunkToml) -> &T>(
     extract: F,
     maybe_toml: &Option<TrunkToml>,
 ) -> Option<T> {
-    set_in_cli
-        .as_ref()
-        .or_else(|| maybe_toml.as_ref().map(extract))
-        .cloned()
+    resolve_cli_or_trunk(set_in_cli, extract, maybe_toml).map(|resolved| resolved.value)
 }
 
 /// Use the value supplied through the command-line, if present.
@@ -85,8 +863,328 @@ pub(crate) fn cli_or_trunk_opt<T: Clone, F: FnOnce(&TrunkToml) -> &Option<T>>(
     extract: F,
     maybe_toml: &Option<TrunkToml>,
 ) -> Option<T> {
-    set_in_cli
-        .as_ref()
-        .or_else(|| maybe_toml.as_ref().map(extract).and_then(Option::as_ref))
-        .cloned()
+    resolve_cli_or_trunk_opt(set_in_cli, extract, maybe_toml).map(|resolved| resolved.value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_trunk_toml;
+
+    fn trunk_toml() -> Option<TrunkToml> {
+        let toml = r#"
+        [extension]
+        name = "pg_cron"
+        version = "1.5.2"
+        license = "PostgreSQL"
+        categories = []
+
+        [build]
+        platform = "linux/amd64"
+        install_command = "make install"
+        "#;
+
+        Some(parse_trunk_toml(toml.as_bytes()).unwrap())
+    }
+
+    #[test]
+    fn cli_takes_precedence_over_trunk_toml() {
+        let resolved = resolve_cli_or_trunk(
+            &Some("from_cli".to_string()),
+            |toml| &toml.extension.name,
+            &trunk_toml(),
+        )
+        .unwrap();
+
+        assert_eq!(resolved.value, "from_cli");
+        assert_eq!(resolved.source, Source::Cli);
+    }
+
+    #[test]
+    fn falls_back_to_trunk_toml() {
+        let toml = trunk_toml();
+
+        let name = resolve_cli_or_trunk(&None, |toml| &toml.extension.name, &toml).unwrap();
+        assert_eq!(name.value, "pg_cron");
+        assert_eq!(name.source, Source::TrunkToml);
+
+        let install_command =
+            resolve_cli_or_trunk_opt(&None, |toml| &toml.build.install_command, &toml).unwrap();
+        assert_eq!(install_command.value, "make install");
+        assert_eq!(install_command.source, Source::TrunkToml);
+
+        let dockerfile = resolve_cli_or_trunk_opt(&None, |toml| &toml.build.dockerfile, &toml);
+        assert!(dockerfile.is_none());
+    }
+
+    // Each test uses its own variable, since tests run concurrently in the same process
+    #[test]
+    fn env_takes_precedence_over_trunk_toml() {
+        std::env::set_var("TRUNK_TEST_ENV_NAME", "from_env");
+
+        let resolved = resolve_cli_env_or_trunk(
+            &None,
+            "TRUNK_TEST_ENV_NAME",
+            |toml| &toml.extension.name,
+            &trunk_toml(),
+        )
+        .unwrap();
+        assert_eq!(resolved.value, "from_env");
+        assert_eq!(resolved.source, Source::Env("TRUNK_TEST_ENV_NAME"));
+
+        let resolved = resolve_cli_env_or_trunk(
+            &Some("from_cli".to_string()),
+            "TRUNK_TEST_ENV_NAME",
+            |toml| &toml.extension.name,
+            &trunk_toml(),
+        )
+        .unwrap();
+        assert_eq!(resolved.value, "from_cli");
+        assert_eq!(resolved.source, Source::Cli);
+    }
+
+    #[test]
+    fn empty_env_falls_back_to_trunk_toml() {
+        std::env::set_var("TRUNK_TEST_EMPTY_INSTALL_COMMAND", "");
+
+        let resolved = resolve_cli_env_or_trunk_opt(
+            &None,
+            "TRUNK_TEST_EMPTY_INSTALL_COMMAND",
+            |toml| &toml.build.install_command,
+            &trunk_toml(),
+        )
+        .unwrap();
+        assert_eq!(resolved.value, "make install");
+        assert_eq!(resolved.source, Source::TrunkToml);
+    }
+
+    #[test]
+    fn env_lists_are_comma_separated() {
+        std::env::set_var("TRUNK_TEST_EXTENSION_DEPENDENCIES", "pg_partman, pg_cron,");
+
+        let resolved = resolve_env::<Vec<String>>("TRUNK_TEST_EXTENSION_DEPENDENCIES").unwrap();
+        assert_eq!(resolved.value, vec!["pg_partman", "pg_cron"]);
+    }
+
+    #[test]
+    fn platform_overrides_replace_build_values() {
+        let toml = r#"
+        [extension]
+        name = "pg_cron"
+        version = "1.5.2"
+        license = "PostgreSQL"
+        categories = []
+
+        [build]
+        platform = "linux/amd64"
+        install_command = "make install"
+        dockerfile = "Dockerfile"
+
+        [build.platforms."linux/arm64"]
+        install_command = "make install libdir=/usr/lib/aarch64-linux-gnu"
+        "#;
+        let mut toml = parse_trunk_toml(toml.as_bytes()).unwrap();
+
+        assert!(toml
+            .build
+            .apply_platform_overrides("linux/amd64")
+            .is_empty());
+        assert_eq!(toml.build.install_command.as_deref(), Some("make install"));
+
+        let overridden = toml.build.apply_platform_overrides("linux/arm64");
+        assert_eq!(
+            overridden,
+            vec![(
+                "install_command",
+                r#"build.platforms."linux/arm64".install_command"#.to_string()
+            )]
+        );
+        assert_eq!(
+            toml.build.install_command.as_deref(),
+            Some("make install libdir=/usr/lib/aarch64-linux-gnu")
+        );
+        // Values that are not overridden are kept
+        assert_eq!(toml.build.dockerfile.as_deref(), Some("Dockerfile"));
+    }
+
+    #[test]
+    fn profiles_are_merged_over_the_base() {
+        let profiles = r#"
+        [extension]
+        name = "pg_cron"
+        version = "1.5.2"
+        license = "PostgreSQL"
+        categories = []
+
+        [build]
+        platform = "linux/amd64"
+        install_command = "make install"
+        labels = { team = "db" }
+
+        [build.pgrx]
+        profile = "release"
+
+        [profile.dev.build]
+        install_command = "make install DEBUG=1"
+        labels = { stage = "dev" }
+
+        [profile.prod.extension]
+        version = "1.5.2-prod"
+        "#;
+        let parse =
+            |extra: &str| parse_trunk_toml(format!("{profiles}{extra}").as_bytes()).unwrap();
+
+        let (toml, keys) = parse("").apply_profile("dev").unwrap();
+        assert_eq!(keys, ["build.install_command", "build.labels.stage"]);
+        assert_eq!(
+            toml.build.install_command.as_deref(),
+            Some("make install DEBUG=1")
+        );
+        // Tables are merged key by key
+        let labels = toml.build.labels.unwrap();
+        assert_eq!(labels["team"], "db");
+        assert_eq!(labels["stage"], "dev");
+        assert_eq!(toml.extension.version, "1.5.2");
+
+        let mut sources = Sources::default();
+        sources.set_profile_keys("dev", keys);
+        assert_eq!(
+            sources.profile_key("build.labels").as_deref(),
+            Some("profile.dev.build.labels")
+        );
+        assert_eq!(sources.profile_key("build.install"), None);
+        assert_eq!(sources.profile_key("build.platform"), None);
+
+        let (toml, _) = parse("").apply_profile("prod").unwrap();
+        assert_eq!(toml.extension.version, "1.5.2-prod");
+        assert_eq!(toml.build.install_command.as_deref(), Some("make install"));
+
+        let err = parse("").apply_profile("staging").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown profile 'staging'. The profiles in Trunk.toml are: dev, prod"
+        );
+        let err = parse("\n[profile.qa.build]\ninstal_command = \"make\"\n")
+            .apply_profile("qa")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown key in Trunk.toml: profile.qa.build.instal_command. Profiles can only set keys of [build]"
+        );
+        let err = parse("\n[profile.qa.build]\nplatform = 1\n")
+            .apply_profile("qa")
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid [profile.qa] in Trunk.toml"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn validates_platforms() {
+        assert!(validate_platform("linux/amd64").is_ok());
+        assert!(validate_platform("linux/arm64").is_ok());
+        assert!(validate_platform("linux/arm/v7").is_ok());
+
+        assert!(validate_platform("linux").is_err());
+        assert!(validate_platform("linux/aarch64").is_err());
+        assert!(validate_platform("darwin/arm64").is_err());
+        assert!(validate_platform("linux/arm/v7/extra").is_err());
+    }
+
+    #[test]
+    fn validates_base_images() {
+        let validate = |image: &str| validate_base_image(image, "build.base_image");
+        assert!(validate("postgres:16-bookworm").is_ok());
+        assert!(validate("quay.io/coredb/c-builder:pg16").is_ok());
+        assert!(validate("localhost:5000/builder").is_ok());
+        assert!(validate(&format!("ubuntu@sha256:{}", "a".repeat(64))).is_ok());
+
+        assert!(validate("").is_err());
+        assert!(validate("Postgres:16").is_err());
+        assert!(validate("postgres 16").is_err());
+        assert!(validate("postgres:").is_err());
+        assert!(validate("postgres@sha256:xyz").is_err());
+        assert_eq!(
+            validate_base_image("-rm", "build.platforms.\"linux/arm64\".base_image")
+                .unwrap_err()
+                .to_string(),
+            "Invalid base image '-rm' in build.platforms.\"linux/arm64\".base_image. Expected an \
+             image reference such as postgres:16-bookworm or quay.io/coredb/c-builder:pg16"
+        );
+    }
+
+    #[test]
+    fn sources_are_tracked() {
+        let mut sources = Sources::default();
+
+        let name = sources.track(
+            "name",
+            resolve_cli_or_trunk(&None, |toml| &toml.extension.name, &trunk_toml()),
+        );
+        let dockerfile = sources.track::<String>("dockerfile", None);
+
+        assert_eq!(name.as_deref(), Some("pg_cron"));
+        assert!(dockerfile.is_none());
+        assert_eq!(sources.get("name"), Some(Source::TrunkToml));
+        assert_eq!(sources.get("dockerfile"), None);
+    }
+
+    #[test]
+    fn config_overrides_are_merged_over_trunk_toml() {
+        let parse = |s: &str| s.parse::<ConfigOverride>();
+        let config_override =
+            parse(r#"build.platforms."linux/arm64".base_image=debian:12"#).unwrap();
+        assert_eq!(
+            config_override.key,
+            r#"build.platforms."linux/arm64".base_image"#
+        );
+        assert_eq!(parse("build.cpus = 2").unwrap().key, "build.cpus");
+        assert!(parse("build.cpus").is_err());
+        assert!(parse("build..cpus=2").is_err());
+
+        let overrides = [
+            parse("build.cpus=2").unwrap(),
+            parse("build.install_command=make install DEBUG=1").unwrap(),
+            parse(r#"build.labels.stage="dev""#).unwrap(),
+        ];
+        let (toml, keys) = trunk_toml().unwrap().apply_overrides(&overrides).unwrap();
+        assert_eq!(
+            keys,
+            ["build.cpus", "build.install_command", "build.labels.stage"]
+        );
+        assert_eq!(toml.build.cpus, Some(2.0));
+        assert_eq!(
+            toml.build.install_command.as_deref(),
+            Some("make install DEBUG=1")
+        );
+        assert_eq!(toml.build.labels.unwrap()["stage"], "dev");
+
+        let mut sources = Sources::default();
+        sources.set_override_keys(keys);
+        assert_eq!(
+            sources.override_key("build.labels"),
+            Some("build.labels.stage")
+        );
+        assert_eq!(sources.override_key("build.install"), None);
+
+        let err = trunk_toml()
+            .unwrap()
+            .apply_overrides(&[parse("build.instal_command=make").unwrap()])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown key in --config-override build.instal_command: Trunk.toml has no build.instal_command"
+        );
+        let err = trunk_toml()
+            .unwrap()
+            .apply_overrides(&[parse("build.cpus=[1]").unwrap()])
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid --config-override build.cpus"),
+            "{err}"
+        );
+    }
 }
@@ -1,6 +1,7 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[allow(dead_code)]
 pub struct TrunkProjectView {
     pub name: String,
     pub version: String,
@@ -9,12 +10,13 @@ pub struct TrunkProjectView {
     pub extensions: Vec<Extension>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Extension {
     pub extension_name: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[allow(dead_code)]
 pub struct Download {
     pub link: String,
     pub pg_version: u8,
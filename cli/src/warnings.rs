@@ -0,0 +1,122 @@
+//! Categories of the warnings `trunk build` prints, so that `--fail-on-warn` can make some of
+//! them fail the build.
+
+use std::fmt;
+
+/// A kind of warning that `--fail-on-warn` can turn into an error
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WarningCategory {
+    /// No Trunk.toml was found in --path
+    MissingTrunkToml,
+    /// No install command was set, so `make install` is guessed
+    MissingInstallCommand,
+    /// A setting doesn't apply to the kind of build and is ignored
+    IgnoredSetting,
+    /// --pg-version is outside the versions the extension supports
+    UnsupportedPgVersion,
+    /// A custom Dockerfile looks like it won't build
+    Dockerfile,
+    /// The installed control file disagrees with `[extension.control]`
+    ControlMismatch,
+    /// A control file's `default_version` has no install script
+    MissingInstallScript,
+    /// A shared library was installed without bitcode for JIT
+    MissingBitcode,
+    /// A category to record in the manifest isn't one of the registry's
+    UnknownCategory,
+    /// A `capture_globs` pattern matched none of the installed files
+    UnmatchedCaptureGlob,
+    /// `preload` in Trunk.toml disagrees with the libraries the build installed
+    PreloadMismatch,
+}
+
+impl fmt::Display for WarningCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no category is skipped");
+        f.write_str(value.get_name())
+    }
+}
+
+impl WarningCategory {
+    /// The topic the warning is tagged with in the build output, see `build_log::topic`
+    fn topic(self) -> &'static str {
+        match self {
+            WarningCategory::MissingTrunkToml
+            | WarningCategory::IgnoredSetting
+            | WarningCategory::UnsupportedPgVersion
+            | WarningCategory::UnknownCategory => "config",
+            WarningCategory::MissingInstallCommand
+            | WarningCategory::Dockerfile
+            | WarningCategory::ControlMismatch
+            | WarningCategory::MissingInstallScript
+            | WarningCategory::MissingBitcode
+            | WarningCategory::UnmatchedCaptureGlob
+            | WarningCategory::PreloadMismatch => "build",
+        }
+    }
+}
+
+/// A warning whose category was given to `--fail-on-warn`
+#[derive(thiserror::Error, Debug)]
+#[error("{message}\nThis warning fails the build, as --fail-on-warn includes {category}")]
+pub struct FatalWarningError {
+    pub category: WarningCategory,
+    pub message: String,
+}
+
+/// Fails with the warning if its category is in `fail_on_warn`, so that the caller only prints
+/// the warnings that don't
+pub fn check_warning(
+    fail_on_warn: &[WarningCategory],
+    category: WarningCategory,
+    message: &str,
+) -> Result<(), FatalWarningError> {
+    if fail_on_warn.contains(&category) {
+        return Err(FatalWarningError {
+            category,
+            message: message.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Logs the warning, or fails with it if its category is in `fail_on_warn`
+pub fn warn_or_fail(
+    fail_on_warn: &[WarningCategory],
+    category: WarningCategory,
+    message: &str,
+) -> Result<(), FatalWarningError> {
+    check_warning(fail_on_warn, category, message)?;
+    log::warn!(target: category.topic(), "{message}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fails_on_the_given_categories() {
+        let fail_on_warn = [WarningCategory::MissingInstallCommand];
+        assert!(check_warning(
+            &fail_on_warn,
+            WarningCategory::MissingTrunkToml,
+            "Trunk.toml not found"
+        )
+        .is_ok());
+
+        let err = check_warning(
+            &fail_on_warn,
+            WarningCategory::MissingInstallCommand,
+            "Install command is not specified",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Install command is not specified\nThis warning fails the build, as --fail-on-warn includes missing-install-command"
+        );
+    }
+}
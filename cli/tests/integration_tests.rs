@@ -629,13 +629,15 @@ fn build_pgrx_with_trunk_toml_bad_name() -> Result<(), Box<dyn std::error::Error
     trunkfile_path.push("test_trunk_toml_dirs");
     trunkfile_path.push("pgrx_with_trunk_toml_bad_name");
 
+    // A mismatch that only comes from Trunk.toml is warned about, and Cargo.toml is used
     let mut cmd = Command::cargo_bin(CARGO_BIN)?;
     cmd.arg("build");
     cmd.arg("--path");
     cmd.arg(trunkfile_path.as_os_str());
     cmd.arg("--output-path");
     cmd.arg(output_dir);
-    cmd.assert().code(1);
+    cmd.assert()
+        .stderr(predicate::str::contains("does not match Cargo.toml name"));
 
     Ok(())
 }
@@ -651,13 +653,16 @@ fn build_pgrx_with_trunk_toml_bad_version() -> Result<(), Box<dyn std::error::Er
     trunkfile_path.push("test_trunk_toml_dirs");
     trunkfile_path.push("pgrx_with_trunk_toml_bad_version");
 
+    // A mismatch that only comes from Trunk.toml is warned about, and Cargo.toml is used
     let mut cmd = Command::cargo_bin(CARGO_BIN)?;
     cmd.arg("build");
     cmd.arg("--path");
     cmd.arg(trunkfile_path.as_os_str());
     cmd.arg("--output-path");
     cmd.arg(output_dir);
-    cmd.assert().code(1);
+    cmd.assert().stderr(predicate::str::contains(
+        "does not match Cargo.toml version",
+    ));
 
     Ok(())
 }
@@ -785,6 +790,54 @@ fn build_pg_unit() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn build_bitcode_extension() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_bitcode_ext_")?;
+    let output_dir = tmp_dir.path();
+    let tarball = &output_dir.join("bitcode_ext-1.0.0-pg15.tar.gz");
+
+    let mut extension_path = std::path::PathBuf::from(file!());
+    extension_path.pop();
+    extension_path.push("test_bitcode_extension");
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(extension_path.as_os_str());
+    cmd.arg("--output-path");
+    cmd.arg(output_dir);
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains("without bitcode").not());
+    assert!(tarball.exists());
+
+    // PGXS installs the bitcode JIT inlines under pkglibdir/bitcode
+    let output = Command::new("tar")
+        .arg("-tvf")
+        .arg(tarball)
+        .output()
+        .expect("failed to run tar command");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("bitcode/bitcode_ext.index.bc"));
+    assert!(stdout.contains("bitcode/bitcode_ext/bitcode_ext.bc"));
+
+    let _extract = Command::new("tar")
+        .arg("-xf")
+        .arg(tarball)
+        .arg("-C")
+        .arg(output_dir)
+        .output()
+        .expect("failed to run tar command");
+    let manifest: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(output_dir.join("manifest.json"))?)?;
+    assert_eq!(
+        manifest["files"]["bitcode/bitcode_ext.index.bc"]["type"],
+        "bitcode"
+    );
+
+    Ok(())
+}
+
 // Build and install postgis
 #[test]
 fn build_install_postgis() -> Result<(), Box<dyn std::error::Error>> {
@@ -888,6 +941,1079 @@ fn install_extension_dependency_resolution() -> Result<(), Box<dyn std::error::E
     Ok(())
 }
 
+#[test]
+fn build_with_local_base_image() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_local_base_image_")?;
+    let output_dir = tmp_dir.path();
+    let tarball = &output_dir.join("local_base_image-0.1.0-pg15.tar.gz");
+
+    let mut extension_path = std::path::PathBuf::from(file!());
+    extension_path.pop(); // Remove the file name from the path
+    extension_path.push("test_local_base_image");
+
+    // Tag the default builder image under a name that only exists locally
+    let local_image = "trunk-test-local-base:latest";
+    let status = Command::new("docker")
+        .args(["pull", "quay.io/coredb/c-builder:pg15"])
+        .status()?;
+    assert!(status.success());
+    let status = Command::new("docker")
+        .args(["tag", "quay.io/coredb/c-builder:pg15", local_image])
+        .status()?;
+    assert!(status.success());
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(extension_path.as_os_str());
+    cmd.arg("--output-path");
+    cmd.arg(output_dir);
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--name");
+    cmd.arg("local_base_image");
+    cmd.arg("--base-image");
+    cmd.arg(local_image);
+    cmd.arg("--pull");
+    cmd.arg("never");
+    let result = cmd.assert();
+
+    let _ = Command::new("docker").args(["rmi", local_image]).status();
+
+    result.code(0).stdout(predicate::str::contains(format!(
+        "Using base image {local_image}"
+    )));
+    assert!(tarball.exists());
+
+    // An image that is not present locally fails before building when pulling is disabled
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(extension_path.as_os_str());
+    cmd.arg("--output-path");
+    cmd.arg(output_dir);
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--name");
+    cmd.arg("local_base_image");
+    cmd.arg("--base-image");
+    cmd.arg(local_image);
+    cmd.arg("--pull");
+    cmd.arg("never");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "Image {local_image} not present locally and pull=never"
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn build_explain() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    // Settings from the environment would change the output
+    for (env_var, _) in std::env::vars().filter(|(key, _)| key.starts_with("TRUNK_")) {
+        cmd.env_remove(env_var);
+    }
+    cmd.env_remove("TMPDIR");
+    cmd.env("TRUNK_INSTALL_COMMAND", "make install");
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg("tests/test_postgresql_unit");
+    cmd.arg("--pull");
+    cmd.arg("never");
+
+    let expected = r#"path = "tests/test_postgresql_unit"  # flag --path
+source_tarball = null  # not set
+context_from_stdin = false  # default
+temp_dir = null  # not set
+context_exclude = []  # default
+context_include = []  # default
+output_path = "tests/test_postgresql_unit/.trunk"  # default
+output_layout = "flat"  # default
+require_explicit_output = false  # default
+name = "postgresql_unit"  # Trunk.toml extension.name
+version = "7.0.0"  # Trunk.toml extension.version
+extension_name = null  # not set
+extension_dependencies = null  # not set
+configurations = null  # not set
+loadable_libraries = null  # not set
+preload = null  # not set
+kind = "extension"  # default
+control = null  # not set
+system_dependencies = {"apt":["libc6"]}  # Trunk.toml dependencies
+include = ["*.data"]  # Trunk.toml build.include
+include_files = []  # not set
+capture_globs = []  # not set
+platform = "linux/amd64"  # Trunk.toml build.platform
+dockerfile_path = "tests/test_postgresql_unit/Dockerfile"  # Trunk.toml build.dockerfile
+builder = null  # not set
+target = null  # not set
+configure_command = null  # not set
+build_command = null  # not set
+cflags = null  # not set
+cxxflags = null  # not set
+install_command = "make install"  # environment variable TRUNK_INSTALL_COMMAND
+before_install = null  # not set
+after_install = null  # not set
+install_user = null  # not set
+check_install_writes = false  # default
+writable_paths = []  # default
+entrypoint = null  # not set
+extension_dir = null  # not set
+cargo_manifest = null  # not set
+lib_dir = null  # not set
+sql_dir = null  # not set
+control_dir = null  # not set
+install_prefixes = []  # not set
+base_image = null  # not set
+artifact_suffix = ".tar.gz"  # default
+artifact_mode = "0644"  # default
+format_version = 2  # default
+sign = null  # not set
+sign_key = null  # not set
+encrypt = false  # default
+encrypt_key = null  # not set
+compare = null  # not set
+report = null  # not set
+resume = false  # default
+fail_if_exists = false  # default
+buildkit = false  # default
+cpus = null  # not set
+memory = null  # not set
+step_timeout = null  # not set
+fail_on_warn = []  # default
+pull = "never"  # flag --pull
+offline = false  # default
+no_install = false  # default
+shell_in = false  # default
+skip_platform_check = false  # default
+allow_arch_mismatch = false  # default
+no_version_check = false  # default
+registry_auth_file = null  # not set
+allow_missing_control = false  # default
+file_digests = false  # default
+strip = false  # default
+keep_debug = false  # default
+changelog_from_git = false  # default
+labels = {}  # default
+label_file = null  # not set
+categories = ["analytics"]  # Trunk.toml extension.categories
+manifest_transform = null  # not set
+no_hooks = false  # default
+allow_unusual_name = false  # default
+cargo_features = []  # not set
+no_default_features = false  # default
+all_features = false  # default
+profile = null  # not set
+rust_toolchain = null  # not set
+force_pgrx = false  # default
+should_test = false  # default
+integration_test = null  # not set
+no_integration_test = false  # default
+pg_version = 15  # default
+min_pg_version = null  # not set
+max_pg_version = null  # not set
+"#;
+    cmd.assert().code(0).stdout(expected);
+
+    Ok(())
+}
+
+const DOCKERFILE_ONLY_TRUNK_TOML: &str = r#"[extension]
+name = "anchored"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+postgres_version = "15"
+platform = "linux/amd64"
+dockerfile = "Dockerfile"
+install_command = "make install"
+"#;
+
+#[test]
+fn build_relative_path_resolves_dockerfile_from_trunk_toml(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_relative_path_")?;
+    fs::create_dir_all(tmp_dir.path().join("ext"))?;
+    fs::write(
+        tmp_dir.path().join("ext/Trunk.toml"),
+        DOCKERFILE_ONLY_TRUNK_TOML,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.current_dir(tmp_dir.path());
+    cmd.env_remove("TRUNK_DOCKERFILE");
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg("./ext");
+    cmd.assert().code(0).stdout(predicate::str::contains(
+        r#"dockerfile_path = "./ext/Dockerfile"  # Trunk.toml build.dockerfile"#,
+    ));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn build_symlinked_trunk_toml_resolves_dockerfile_from_target(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_symlinked_trunk_toml_")?;
+    fs::create_dir_all(tmp_dir.path().join("config"))?;
+    fs::create_dir_all(tmp_dir.path().join("ext"))?;
+    fs::write(
+        tmp_dir.path().join("config/Trunk.toml"),
+        DOCKERFILE_ONLY_TRUNK_TOML,
+    )?;
+    std::os::unix::fs::symlink(
+        "../config/Trunk.toml",
+        tmp_dir.path().join("ext/Trunk.toml"),
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.current_dir(tmp_dir.path());
+    cmd.env_remove("TRUNK_DOCKERFILE");
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg("ext");
+    cmd.assert().code(0).stdout(predicate::str::contains(
+        r#"dockerfile_path = "ext/../config/Dockerfile"  # Trunk.toml build.dockerfile"#,
+    ));
+
+    // A dangling symlink is an error rather than a missing Trunk.toml
+    fs::remove_file(tmp_dir.path().join("config/Trunk.toml"))?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.current_dir(tmp_dir.path());
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg("ext");
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains("which does not exist"));
+
+    Ok(())
+}
+
+#[test]
+fn build_rejects_unusual_extension_name() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_unusual_name_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--name");
+    cmd.arg("my-ext");
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains("Consider naming it 'my_ext'"));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--name");
+    cmd.arg("my-ext");
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--allow-unusual-name");
+    cmd.assert().code(0).stdout(predicate::str::contains(
+        "allow_unusual_name = true  # flag --allow-unusual-name",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_registry_auth_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_registry_auth_")?;
+    std::fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "my_ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+
+[publish]
+auth_file = "ci/config.json"
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains(format!(
+            "Registry auth file {} does not exist",
+            tmp_dir.path().join("ci/config.json").display()
+        )));
+
+    std::fs::create_dir(tmp_dir.path().join("ci"))?;
+    std::fs::write(
+        tmp_dir.path().join("ci/config.json"),
+        r#"{"auths": {"quay.io": {"auth": "cm9ib3Q6czNjcmV0"}}}"#,
+    )?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains(
+            "config.json\"  # Trunk.toml publish.auth_file",
+        ))
+        .stdout(predicate::str::contains("s3cret").not());
+
+    Ok(())
+}
+
+#[test]
+fn build_target_requires_custom_dockerfile() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_target_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--name");
+    cmd.arg("my_ext");
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--target");
+    cmd.arg("trunk");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "--target requires a custom --dockerfile",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_selects_bundled_builder() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--list-builders");
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains("generic  "))
+        .stdout(predicate::str::contains("(default)"))
+        .stdout(predicate::str::contains("debian  "));
+
+    let tmp_dir = TempDir::with_prefix("test_builder_")?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--builder");
+    cmd.arg("alpine");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "Unknown builder 'alpine'. The bundled builders are: generic, debian",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_force_pgrx_requires_cargo_toml() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_force_pgrx_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--force-pgrx");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "--force-pgrx requires a Cargo.toml, but there is none at",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_shell_in_requires_no_install() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--shell-in");
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("--no-install"));
+
+    Ok(())
+}
+
+#[test]
+fn build_minisign_requires_sign_key() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_sign_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--name");
+    cmd.arg("my_ext");
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--sign");
+    cmd.arg("minisign");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "--sign minisign requires --sign-key",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_encrypt_requires_encrypt_key() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_encrypt_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--name");
+    cmd.arg("my_ext");
+    cmd.arg("--version");
+    cmd.arg("0.1.0");
+    cmd.arg("--encrypt");
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains("--encrypt requires --encrypt-key"));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("install");
+    cmd.arg("my_ext");
+    cmd.arg("--decrypt-key");
+    cmd.arg(tmp_dir.path().join("identity.txt"));
+    cmd.assert()
+        .code(2)
+        .stderr(predicate::str::contains("--file"));
+
+    Ok(())
+}
+
+#[test]
+fn build_module_kind() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_module_kind_")?;
+    let trunk_toml = r#"[extension]
+name = "my_module"
+version = "0.1.0"
+license = "MIT"
+categories = []
+kind = "module"
+
+[build]
+platform = "linux/amd64"
+"#;
+    std::fs::write(tmp_dir.path().join("Trunk.toml"), trunk_toml)?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.assert().success().stdout(predicate::str::contains(
+        "kind = \"module\"  # Trunk.toml extension.kind",
+    ));
+
+    std::fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        format!("{trunk_toml}\n[extension.control]\nrelocatable = true\n"),
+    )?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "[extension.control] can't be set with kind = \"module\"",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_require_explicit_output() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_require_explicit_output_")?;
+    std::fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "my_ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+require_explicit_output = true
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.env_remove("TRUNK_OUTPUT_PATH");
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "No output path was given, and require_explicit_output is set in Trunk.toml",
+    ));
+
+    let output_path = tmp_dir.path().join("artifacts");
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.env_remove("TRUNK_OUTPUT_PATH");
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.arg("--output-path").arg(&output_path);
+    cmd.assert().code(0).stdout(predicate::str::contains(
+        "require_explicit_output = true  # Trunk.toml build.require_explicit_output",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_pgrx_settings_from_trunk_toml() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_cargo_features_")?;
+    std::fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "my_ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+
+[build.pgrx]
+features = ["pg_test"]
+no_default_features = true
+profile = "debug"
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains(
+            "cargo_features = [\"pg_test\"]  # Trunk.toml build.pgrx.features",
+        ))
+        .stdout(predicate::str::contains(
+            "no_default_features = true  # Trunk.toml build.pgrx.no_default_features",
+        ))
+        .stdout(predicate::str::contains(
+            "profile = \"debug\"  # Trunk.toml build.pgrx.profile",
+        ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.arg("--all-features");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "--all-features and --no-default-features cannot be used together",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_from_source_tarball() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_source_tarball_")?;
+    let sources = tmp_dir.path().join("my_ext-0.1.0");
+    std::fs::create_dir(&sources)?;
+    std::fs::write(
+        sources.join("Trunk.toml"),
+        r#"[extension]
+name = "my_ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+"#,
+    )?;
+    let tarball = tmp_dir.path().join("dist.tar.gz");
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(tmp_dir.path())
+        .arg("my_ext-0.1.0")
+        .status()?;
+    assert!(status.success());
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--source-tarball");
+    cmd.arg(&tarball);
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains(
+            "/my_ext-0.1.0\"  # flag --source-tarball",
+        ))
+        .stdout(predicate::str::contains(
+            "output_path = \"./.trunk\"  # default",
+        ))
+        .stdout(predicate::str::contains(
+            "name = \"my_ext\"  # Trunk.toml extension.name",
+        ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build");
+    cmd.arg("--explain");
+    cmd.arg("--source-tarball");
+    cmd.arg(tmp_dir.path().join("missing.tar.gz"));
+    cmd.assert()
+        .code(1)
+        .stderr(predicate::str::contains("Failed to open source tarball"));
+
+    Ok(())
+}
+
+#[test]
+fn build_context_from_stdin() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_stdin_context_")?;
+    let sources = tmp_dir.path().join("my_ext");
+    std::fs::create_dir(&sources)?;
+    std::fs::write(sources.join("Makefile"), "install:\n")?;
+    let tarball = tmp_dir.path().join("context.tar.gz");
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(tmp_dir.path())
+        .arg("my_ext")
+        .status()?;
+    assert!(status.success());
+
+    // Without a Trunk.toml, the name and version come from flags
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--context-from-stdin")
+        .arg("--name")
+        .arg("my_ext")
+        .arg("--version")
+        .arg("0.1.0")
+        .stdin(fs::File::open(&tarball)?);
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains(
+            "/my_ext\"  # flag --context-from-stdin",
+        ))
+        .stdout(predicate::str::contains("name = \"my_ext\"  # flag --name"));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--context-from-stdin")
+        .arg("--path")
+        .arg(&sources);
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "'--context-from-stdin' cannot be used with '--path <PATH>'",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_settings_builder() -> Result<(), Box<dyn std::error::Error>> {
+    use pg_trunk::commands::build::PullPolicy;
+    use pg_trunk::BuildSettings;
+
+    let settings = BuildSettings::builder("tests/test_postgresql_unit")
+        .name("postgresql_unit")
+        .version("7.0.0")
+        .offline(true)
+        .build()?;
+    assert_eq!(settings.output_path, "tests/test_postgresql_unit/.trunk");
+    assert_eq!(settings.artifact_suffix, ".tar.gz");
+    assert_eq!(settings.pull, PullPolicy::Never);
+    assert_eq!(settings.pg_version, 15);
+
+    // The builder checks its settings like `trunk build` checks its flags
+    let result = BuildSettings::builder("tests/test_postgresql_unit")
+        .offline(true)
+        .pull(PullPolicy::Always)
+        .build();
+    assert!(result.is_err());
+    let result = BuildSettings::builder("tests/test_postgresql_unit")
+        .included_files(vec!["missing.txt".to_string()])
+        .build();
+    assert!(result.is_err());
+    let result = BuildSettings::builder("tests/test_postgresql_unit")
+        .keep_debug(true)
+        .build();
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn install_refuses_unsupported_pg_version() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_min_pg_version_")?;
+    let manifest = r#"{
+        "name": "pg16_only",
+        "extension_name": "pg16_only",
+        "extension_dependencies": null,
+        "dependencies": null,
+        "version": "1.0.0",
+        "manifest_version": 2,
+        "sys": "linux",
+        "architecture": "x86_64",
+        "files": {"pg16_only.control": {"type": "control-file"}},
+        "configurations": null,
+        "loadable_libraries": null,
+        "pg_version": 16,
+        "min_pg_version": 16
+    }"#;
+    let archive_path = tmp_dir.path().join("pg16_only-1.0.0-pg16.tar.gz");
+    let encoder = flate2::write::GzEncoder::new(
+        fs::File::create(&archive_path)?,
+        flate2::Compression::fast(),
+    );
+    let mut archive = tar::Builder::new(encoder);
+    for (path, contents) in [
+        ("manifest.json", manifest),
+        ("pg16_only.control", "default_version = '1.0.0'\n"),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, contents.as_bytes())?;
+    }
+    archive.into_inner()?.finish()?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("install");
+    cmd.arg("--pg-version");
+    cmd.arg("15");
+    cmd.arg("--file");
+    cmd.arg(&archive_path);
+    cmd.arg("pg16_only");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "pg16_only 1.0.0 supports PostgreSQL 16 and later, it cannot be installed on PostgreSQL 15",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn install_refuses_newer_manifest_version() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_manifest_version_")?;
+    let manifest = r#"{
+        "name": "from_the_future",
+        "extension_name": "from_the_future",
+        "extension_dependencies": null,
+        "dependencies": null,
+        "version": "1.0.0",
+        "manifest_version": 99,
+        "sys": "linux",
+        "architecture": "x86_64",
+        "files": {"extension/from_the_future.control": {"type": "control-file"}},
+        "configurations": null,
+        "loadable_libraries": null,
+        "pg_version": 15
+    }"#;
+    let archive_path = tmp_dir.path().join("from_the_future-1.0.0-pg15.tar.gz");
+    let encoder = flate2::write::GzEncoder::new(
+        fs::File::create(&archive_path)?,
+        flate2::Compression::fast(),
+    );
+    let mut archive = tar::Builder::new(encoder);
+    for (path, contents) in [
+        ("manifest.json", manifest),
+        (
+            "extension/from_the_future.control",
+            "default_version = '1.0.0'\n",
+        ),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, contents.as_bytes())?;
+    }
+    archive.into_inner()?.finish()?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("install");
+    cmd.arg("--pg-version");
+    cmd.arg("15");
+    cmd.arg("--file");
+    cmd.arg(&archive_path);
+    cmd.arg("from_the_future");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "The archive has manifest version 99, but this version of trunk only understands versions 1 to 2",
+    ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg("tests/test_postgresql_unit")
+        .arg("--format-version")
+        .arg("3");
+    cmd.assert().code(1).stderr(predicate::str::contains(
+        "--format-version must be a manifest version from 1 to 2. Got: 3",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn verify_archive_reports_unlisted_files() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_verify_archive_")?;
+    let manifest = r#"{
+        "name": "checked",
+        "extension_name": "checked",
+        "extension_dependencies": null,
+        "dependencies": null,
+        "version": "1.0.0",
+        "manifest_version": 2,
+        "sys": "linux",
+        "architecture": "x86_64",
+        "files": {"checked.control": {"type": "control-file"}},
+        "configurations": null,
+        "loadable_libraries": null,
+        "pg_version": 15
+    }"#;
+    let write_archive = |path: &Path, files: &[(&str, &str)]| -> std::io::Result<()> {
+        let encoder =
+            flate2::write::GzEncoder::new(fs::File::create(path)?, flate2::Compression::fast());
+        let mut archive = tar::Builder::new(encoder);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        archive.into_inner()?.finish()?;
+        Ok(())
+    };
+
+    let good = tmp_dir.path().join("checked-1.0.0-pg15.tar.gz");
+    write_archive(
+        &good,
+        &[
+            ("manifest.json", manifest),
+            ("checked.control", "default_version = '1.0.0'\n"),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("verify-archive").arg(&good);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("Checked 1 files"))
+        .stdout(predicate::str::contains("Build with --file-digests"));
+
+    let tampered = tmp_dir.path().join("tampered-1.0.0-pg15.tar.gz");
+    write_archive(
+        &tampered,
+        &[
+            ("manifest.json", manifest),
+            ("checked.control", "default_version = '1.0.0'\n"),
+            ("extra.so", "elf"),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("verify-archive")
+        .arg(&tampered)
+        .args(["--output-format", "json"]);
+    let output = cmd.output()?;
+    assert_eq!(output.status.code(), Some(1));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["unlisted_files"], serde_json::json!(["extra.so"]));
+    assert_eq!(report["digests_checked"], false);
+
+    Ok(())
+}
+
+#[test]
+fn verify_archive_dumps_the_manifest() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_dump_manifest_")?;
+    let manifest = r#"{"name": "dumped", "version": "1.0.0", "manifest_version": 2}"#;
+    let write_archive = |path: &Path, files: &[(&str, &str)]| -> std::io::Result<()> {
+        let encoder =
+            flate2::write::GzEncoder::new(fs::File::create(path)?, flate2::Compression::fast());
+        let mut archive = tar::Builder::new(encoder);
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            archive.append_data(&mut header, path, contents.as_bytes())?;
+        }
+        archive.into_inner()?.finish()?;
+        Ok(())
+    };
+
+    let archive = tmp_dir.path().join("dumped-1.0.0-pg15.tar.gz");
+    write_archive(
+        &archive,
+        &[
+            ("dumped.control", "default_version = '1.0.0'\n"),
+            ("manifest.json", manifest),
+        ],
+    )?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("verify-archive")
+        .arg(&archive)
+        .args(["--dump-manifest", "-"]);
+    cmd.assert().success().stdout(manifest);
+
+    let destination = tmp_dir.path().join("manifest.json");
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("verify-archive")
+        .arg(&archive)
+        .arg("--dump-manifest")
+        .arg(&destination);
+    cmd.assert().success().stdout("");
+    assert_eq!(fs::read_to_string(&destination)?, manifest);
+
+    let old = tmp_dir.path().join("old-1.0.0-pg15.tar.gz");
+    write_archive(&old, &[("old.control", "default_version = '1.0.0'\n")])?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("verify-archive")
+        .arg(&old)
+        .args(["--dump-manifest", "-"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "{} has no manifest.json",
+            old.display()
+        )))
+        .stderr(predicate::str::contains("--format-version"));
+
+    Ok(())
+}
+
+#[test]
+fn clean_artifacts() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_clean_")?;
+    let output_dir = tmp_dir.path().join(".trunk");
+    fs::create_dir_all(&output_dir)?;
+    fs::write(output_dir.join("my_extension-0.0.0-pg15.tar.gz"), [0; 2048])?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("clean");
+    cmd.arg("--artifacts");
+    cmd.arg("--path");
+    cmd.arg(tmp_dir.path());
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains("Freed 2.0 KiB"));
+
+    assert!(!output_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn clean_refuses_foreign_output_path() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_clean_foreign_")?;
+    let output_dir = tmp_dir.path().join("dist");
+    fs::create_dir_all(&output_dir)?;
+    fs::write(output_dir.join("my_extension-0.0.0-pg15.tar.gz"), [0; 2048])?;
+    fs::write(output_dir.join("notes.txt"), "not trunk's")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("clean");
+    cmd.arg("--artifacts");
+    cmd.arg("--output-path");
+    cmd.arg(&output_dir);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "notes.txt is not a file trunk writes",
+        ))
+        .stderr(predicate::str::contains("--force"));
+    assert!(output_dir.join("my_extension-0.0.0-pg15.tar.gz").exists());
+    assert!(output_dir.join("notes.txt").exists());
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("clean");
+    cmd.arg("--artifacts");
+    cmd.arg("--force");
+    cmd.arg("--output-path");
+    cmd.arg(&output_dir);
+    cmd.assert()
+        .code(0)
+        .stdout(predicate::str::contains("Freed"));
+    assert!(!output_dir.exists());
+
+    Ok(())
+}
+
+#[test]
+fn clean_refuses_current_directory() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_clean_cwd_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.current_dir(tmp_dir.path());
+    cmd.arg("clean");
+    cmd.arg("--artifacts");
+    cmd.arg("--output-path");
+    cmd.arg(".");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Refusing to remove"));
+
+    assert!(tmp_dir.path().exists());
+
+    Ok(())
+}
+
+#[test]
+fn doctor_fails_on_unwritable_output_path() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_doctor_")?;
+    let file = tmp_dir.path().join("not_a_dir");
+    fs::write(&file, "")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("doctor");
+    cmd.arg("--output-path");
+    cmd.arg(file.join(".trunk"));
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("[FAIL] output path"))
+        .stderr(predicate::str::contains("required check(s) failed"));
+
+    Ok(())
+}
+
 fn pg_config_path(opt: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
     // Get output from pg_config
     let output = Command::new("pg_config")
@@ -901,3 +2027,1064 @@ fn pg_config_path(opt: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
     // Trim the output string and create a Path
     Ok(Path::new(cfg.trim()).to_owned())
 }
+
+#[test]
+fn build_print_settings_toml() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_print_settings_toml_")?;
+    std::fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "my_ext"
+version = "0.1.0"
+license = "MIT"
+categories = ["analytics"]
+
+[build]
+platform = "linux/amd64"
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    for (env_var, _) in std::env::vars().filter(|(key, _)| key.starts_with("TRUNK_")) {
+        cmd.env_remove(env_var);
+    }
+    cmd.arg("build")
+        .arg("--print-settings-toml")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.args(["--install-command", "make install PREFIX=/usr"]);
+    cmd.args(["--memory", "2g", "--cpus", "2"]);
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    let settings_toml = String::from_utf8(output.stdout)?;
+    assert!(
+        settings_toml.contains(r#"license = "MIT""#),
+        "{settings_toml}"
+    );
+    assert!(
+        settings_toml.contains(r#"memory = "2g""#),
+        "{settings_toml}"
+    );
+    assert!(!settings_toml.contains("path ="), "{settings_toml}");
+
+    // Building from the printed Trunk.toml alone resolves the same settings
+    std::fs::write(tmp_dir.path().join("Trunk.toml"), settings_toml)?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    for (env_var, _) in std::env::vars().filter(|(key, _)| key.starts_with("TRUNK_")) {
+        cmd.env_remove(env_var);
+    }
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            r#"install_command = "make install PREFIX=/usr"  # Trunk.toml build.install_command"#,
+        ))
+        .stdout(predicate::str::contains(
+            "memory = 2147483648  # Trunk.toml build.memory",
+        ))
+        .stdout(predicate::str::contains(
+            "cpus = 2.0  # Trunk.toml build.cpus",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_fail_on_warn() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_fail_on_warn_")?;
+
+    // Warnings of other categories are only printed
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--fail-on-warn")
+        .arg("missing-install-command");
+    cmd.assert().success();
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--fail-on-warn")
+        .arg("missing-install-command,missing-trunk-toml");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Trunk.toml not found\ntrunk: build: This warning fails the build, as --fail-on-warn includes missing-trunk-toml",
+    ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build").arg("--fail-on-warn").arg("everything");
+    cmd.assert().code(2).stderr(predicate::str::contains(
+        "possible values: missing-trunk-toml",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_log_prefix() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_log_prefix_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path());
+    cmd.assert()
+        .success()
+        .stderr(predicate::str::is_match(
+            r"(?m)^trunk: config: .*warn.*: Trunk.toml not found$",
+        )?)
+        // What --explain prints is left untagged, to be parsed
+        .stdout(predicate::str::is_match(
+            r#"(?m)^pg_version = 15  # default$"#,
+        )?);
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--log-prefix")
+        .arg("[ci] trunk/");
+    cmd.assert().success().stderr(predicate::str::is_match(
+        r"(?m)^\[ci\] trunk/config: .*warn.*: Trunk.toml not found$",
+    )?);
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--log-prefix")
+        .arg("");
+    cmd.assert().success().stderr(
+        predicate::str::contains("Trunk.toml not found")
+            .and(predicate::str::contains("config:").not()),
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_label_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_label_file_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        ext_trunk_toml(
+            r#"
+[build.labels]
+"ci.commit" = "from-trunk-toml"
+"ci.runner" = "docker"
+"#,
+        ),
+    )?;
+    let label_file = tmp_dir.path().join("labels.env");
+    fs::write(
+        &label_file,
+        "# written by CI\nci.commit=3f2a9c1\n\nci.pipeline=1234\n",
+    )?;
+    let label_file_arg = label_file.to_string_lossy();
+
+    explain(tmp_dir.path(), &[])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"labels = {"ci.commit":"from-trunk-toml","ci.runner":"docker"}  # Trunk.toml build.labels"#,
+        ));
+    // The label file overrides Trunk.toml, and is named as the source
+    explain(tmp_dir.path(), &["--label-file", &label_file_arg])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"labels = {"ci.commit":"3f2a9c1","ci.pipeline":"1234","ci.runner":"docker"}  # flag --label-file"#,
+        ));
+    explain(
+        tmp_dir.path(),
+        &["--label-file", &label_file_arg, "--label", "ci.pipeline=5678"],
+    )?
+    .success()
+    .stdout(predicate::str::contains(
+        r#"labels = {"ci.commit":"3f2a9c1","ci.pipeline":"5678","ci.runner":"docker"}  # flag --label"#,
+    ));
+
+    fs::write(&label_file, "ci.commit=3f2a9c1\nci.pipeline\n")?;
+    explain(tmp_dir.path(), &["--label-file", &label_file_arg])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "labels.env:2: expected KEY=VALUE, got 'ci.pipeline'",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_compiler_flags() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_compiler_flags_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+cxxflags = "-D_GLIBCXX_USE_CXX11_ABI=0"
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--cflags")
+        .arg("-fno-strict-aliasing");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "cflags = \"-fno-strict-aliasing\"  # flag --cflags\n",
+        ))
+        .stdout(predicate::str::contains(
+            "cxxflags = \"-D_GLIBCXX_USE_CXX11_ABI=0\"  # Trunk.toml build.cxxflags\n",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_resume_skips_intact_archives() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_resume_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+"#,
+    )?;
+    let output_dir = tmp_dir.path().join("out");
+    fs::create_dir(&output_dir)?;
+
+    // What an earlier run of the build matrix left behind
+    let manifest = r#"{"name": "ext", "extension_name": "ext", "extension_dependencies": null,
+        "dependencies": null, "version": "0.1.0", "manifest_version": 2, "sys": "linux",
+        "architecture": "x86_64", "files": {"extension/ext.control": {"type": "control-file"}},
+        "configurations": null, "loadable_libraries": null, "pg_version": 16}"#;
+    let archive = fs::File::create(output_dir.join("ext-0.1.0-pg16.tar.gz"))?;
+    let mut archive = tar::Builder::new(flate2::write::GzEncoder::new(
+        archive,
+        flate2::Compression::fast(),
+    ));
+    for (path, contents) in [
+        ("extension/ext.control", "default_version = '0.1.0'\n"),
+        ("manifest.json", manifest),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, contents.as_bytes())?;
+    }
+    archive.into_inner()?.finish()?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--output-path")
+        .arg(&output_dir)
+        .arg("--pg-version")
+        .arg("16")
+        .arg("--resume")
+        .arg("--report")
+        .arg(tmp_dir.path().join("report.json"));
+    cmd.assert().success().stdout(predicate::str::contains(
+        "ext-0.1.0-pg16.tar.gz from an earlier build is intact (--resume)",
+    ));
+
+    // The report covers the archive that was kept
+    let report: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(tmp_dir.path().join("report.json"))?)?;
+    assert_eq!(report["version"], "0.1.0");
+    assert_eq!(report["pg_version"], 16);
+    assert!(
+        report["files"]["extension/ext.control"]
+            .as_str()
+            .is_some_and(|digest| digest.starts_with("sha256:")),
+        "{report}"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_print_install_command() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_print_install_command_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--print-install-command")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--install-command")
+        .arg("make install PG_CONFIG=/usr/lib/postgresql/15/bin/pg_config")
+        .arg("--pg-version")
+        .arg("16");
+    cmd.assert()
+        .success()
+        .stdout("/bin/sh -c 'make install PG_CONFIG=/usr/lib/postgresql/16/bin/pg_config'\n");
+
+    // The install hooks run around the install command, the same way
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--print-install-command")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--before-install")
+        .arg("mkdir -p /opt/fake")
+        .arg("--after-install")
+        .arg("chmod -R a+r /usr/share/postgresql")
+        .arg("--entrypoint")
+        .arg("/bin/bash -lc");
+    cmd.assert().success().stdout(
+        "/bin/bash -lc 'mkdir -p /opt/fake'\n\
+         /bin/bash -lc 'make install'\n\
+         /bin/bash -lc 'chmod -R a+r /usr/share/postgresql'\n",
+    );
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--print-install-command")
+        .arg("--path")
+        .arg("./tests/test_pgrx_extension")
+        .arg("--cargo-features")
+        .arg("pg_test");
+    cmd.assert().success().stdout(
+        "cargo pgrx package --features pg_test\n\
+         cp --verbose -R target/release/test_pgrx_extension-pg15/usr /\n",
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_with_deps_rejects_cycles() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_with_deps_")?;
+    for (name, requires) in [("app", "api"), ("api", "core"), ("core", "app")] {
+        let ext_dir = tmp_dir.path().join(name);
+        fs::create_dir(&ext_dir)?;
+        fs::write(
+            ext_dir.join("Trunk.toml"),
+            format!(
+                r#"[extension]
+name = "{name}"
+version = "1.0.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+"#
+            ),
+        )?;
+        fs::write(
+            ext_dir.join(format!("{name}.control")),
+            format!("default_version = '1.0.0'\nrequires = 'plpgsql, {requires}'\n"),
+        )?;
+    }
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--with-deps")
+        .arg("--path")
+        .arg(tmp_dir.path().join("app"));
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "The extensions depend on each other: app -> api -> core -> app",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_fail_if_exists() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_fail_if_exists_")?;
+    let output_dir = tmp_dir.path().join("out");
+    fs::create_dir(&output_dir)?;
+    fs::write(output_dir.join("ext-1.0.0-pg15.tar.gz"), "released")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--name")
+        .arg("ext")
+        .arg("--version")
+        .arg("1.0.0")
+        .arg("--output-path")
+        .arg(&output_dir)
+        .arg("--fail-if-exists");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "ext-1.0.0-pg15.tar.gz already exists, and --fail-if-exists forbids overwriting it",
+    ));
+    assert_eq!(
+        fs::read_to_string(output_dir.join("ext-1.0.0-pg15.tar.gz"))?,
+        "released"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn build_unknown_category() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_unknown_category_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = ["analytics", "geo"]
+
+[build]
+platform = "linux/amd64"
+"#,
+    )?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--fail-on-warn")
+        .arg("unknown-category");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "'geo' is not a category the registry knows, expected one of: analytics,",
+    ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--categories")
+        .arg("search,security")
+        .arg("--explain");
+    cmd.assert().success().stdout(predicate::str::contains(
+        r#"categories = ["search","security"]  # flag --categories"#,
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_profile_name() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_profile_name_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+install_command = "make install"
+
+[profile.prod.build]
+install_command = "make install OPTIMIZE=1"
+user = "postgres"
+
+[profile.dev.build]
+install_command = "make install DEBUG=1"
+"#,
+    )?;
+    let explain = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--explain")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    explain(&["--profile-name", "prod"])?.success().stdout(
+        predicate::str::contains(
+            r#"install_command = "make install OPTIMIZE=1"  # Trunk.toml profile.prod.build.install_command"#,
+        )
+        .and(predicate::str::contains(
+            r#"install_user = "postgres"  # Trunk.toml profile.prod.build.user"#,
+        ))
+        .and(predicate::str::contains(
+            r#"platform = "linux/amd64"  # Trunk.toml build.platform"#,
+        )),
+    );
+    // Flags take precedence over the profile
+    explain(&["--profile-name", "prod", "--user", "nobody"])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"install_user = "nobody"  # flag --user"#,
+        ));
+    explain(&[])?.success().stdout(predicate::str::contains(
+        r#"install_command = "make install"  # Trunk.toml build.install_command"#,
+    ));
+    explain(&["--profile-name", "staging"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Unknown profile 'staging'. The profiles in Trunk.toml are: dev, prod",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_config_override() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_config_override_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+install_command = "make install"
+
+[build.platforms."linux/amd64"]
+configure_command = "./configure --amd64"
+
+[profile.prod.build]
+install_command = "make install OPTIMIZE=1"
+"#,
+    )?;
+    let explain = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.env_remove("TRUNK_CPUS")
+            .arg("build")
+            .arg("--explain")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    // Overrides take precedence over profiles and platform overrides
+    explain(&[
+        "--profile-name",
+        "prod",
+        "--config-override",
+        "build.install_command=make install DEBUG=1",
+        "--config-override",
+        "build.configure_command=./configure",
+        "--config-override",
+        "build.cpus=2",
+    ])?
+    .success()
+    .stdout(
+        predicate::str::contains(
+            r#"install_command = "make install DEBUG=1"  # flag --config-override build.install_command"#,
+        )
+        .and(predicate::str::contains(
+            r#"configure_command = "./configure"  # flag --config-override build.configure_command"#,
+        ))
+        .and(predicate::str::contains(
+            "cpus = 2.0  # flag --config-override build.cpus",
+        )),
+    );
+    explain(&["--config-override", "build.cpus=2", "--cpus", "4"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "--config-override build.cpus sets cpus, but --cpus was given too. Only give one",
+        ));
+    explain(&["--config-override", "build.no_cache=true"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Unknown key in --config-override build.no_cache: Trunk.toml has no build.no_cache",
+        ));
+    explain(&["--config-override", "build.cpus=many"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid --config-override build.cpus",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_capture_globs() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_capture_globs_")?;
+    let explain = |capture_globs: &str| -> Result<_, Box<dyn std::error::Error>> {
+        fs::write(
+            tmp_dir.path().join("Trunk.toml"),
+            format!(
+                "[extension]\nname = \"ext\"\nversion = \"0.1.0\"\nlicense = \"MIT\"\n\
+                 categories = []\n\n[build]\nplatform = \"linux/amd64\"\n\
+                 capture_globs = {capture_globs}\n"
+            ),
+        )?;
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--explain")
+            .arg("--path")
+            .arg(tmp_dir.path());
+        Ok(cmd.assert())
+    };
+
+    explain(r#"["share/doc/ext/*.example", "etc/**/*.conf"]"#)?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"capture_globs = ["share/doc/ext/*.example","etc/**/*.conf"]  # Trunk.toml build.capture_globs"#,
+        ));
+    explain(r#"["/etc/ext.conf"]"#)?
+        .failure()
+        .stderr(predicate::str::contains(
+            "capture_globs entries must be globs relative to pg_config --prefix, without '..' components. Got: /etc/ext.conf",
+        ));
+    explain(r#"["share/doc/[ext"]"#)?
+        .failure()
+        .stderr(predicate::str::contains(
+            "Invalid glob in capture_globs in Trunk.toml: share/doc/[ext",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_output_layout() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_output_layout_")?;
+    fs::write(tmp_dir.path().join("Makefile"), "install:\n")?;
+    let explain = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--explain")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    explain(&["--output-layout", "registry", "--platform", "linux/arm64"])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"output_layout = "registry"  # flag --output-layout"#,
+        ));
+    explain(&["--output-layout", "nested"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "--output-layout nested puts the platform in the path of the archive, so it requires \
+             --platform, or platform in Trunk.toml",
+        ));
+    explain(&["--output-layout", "tree"])?
+        .failure()
+        .stderr(predicate::str::contains("invalid value 'tree'"));
+
+    Ok(())
+}
+
+/// A Trunk.toml for an extension named `ext`, built for linux/amd64, with `build` appended to
+/// its `[build]` table
+fn ext_trunk_toml(build: &str) -> String {
+    format!(
+        r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+{build}"#
+    )
+}
+
+/// Runs `trunk build --explain` on the extension at `path` with `args`
+fn explain(
+    path: &Path,
+    args: &[&str],
+) -> Result<assert_cmd::assert::Assert, Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--explain")
+        .arg("--path")
+        .arg(path)
+        .args(args);
+
+    Ok(cmd.assert())
+}
+
+#[test]
+fn build_rejects_unsupported_pg_version() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_unsupported_pg_version_")?;
+
+    for flag in ["--explain", "--print-install-command"] {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(["--pg-version", "99"])
+            .arg(flag);
+        cmd.assert().failure().stderr(predicate::str::contains(
+            "--pg-version must be 14, 15 or 16. Got: 99",
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn build_extension_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_extension_dir_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        ext_trunk_toml("extension_dir = \"src/ext\"\n"),
+    )?;
+
+    explain(tmp_dir.path(), &[])?
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "--extension-dir {} does not exist or is not a directory",
+            tmp_dir.path().join("src/ext").display()
+        )));
+    fs::create_dir_all(tmp_dir.path().join("src/ext"))?;
+    fs::create_dir_all(tmp_dir.path().join("other"))?;
+    explain(tmp_dir.path(), &[])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"extension_dir = "src/ext"  # Trunk.toml build.extension_dir"#,
+        ));
+    explain(tmp_dir.path(), &["--extension-dir", "other"])?
+        .success()
+        .stdout(predicate::str::contains(
+            r#"extension_dir = "other"  # flag --extension-dir"#,
+        ));
+    explain(tmp_dir.path(), &["--extension-dir", "../other"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "--extension-dir must be a path relative to --path, without '..' components. Got: ../other",
+        ));
+
+    Ok(())
+}
+
+#[test]
+fn build_integration_test() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_integration_test_")?;
+    fs::write(
+        tmp_dir.path().join("Trunk.toml"),
+        ext_trunk_toml("integration_test = \"test/smoke.sql\"\n"),
+    )?;
+    let explain = |args: &[&str]| explain(tmp_dir.path(), args);
+
+    explain(&[])?
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "The integration test {} does not exist or is not a file",
+            tmp_dir.path().join("test/smoke.sql").display()
+        )));
+    fs::create_dir(tmp_dir.path().join("test"))?;
+    fs::write(
+        tmp_dir.path().join("test/smoke.sql"),
+        "CREATE EXTENSION ext;\n",
+    )?;
+    explain(&[])?
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "integration_test = {:?}  # Trunk.toml build.integration_test",
+            tmp_dir.path().join("test/smoke.sql").display().to_string()
+        )));
+    explain(&["--no-integration-test"])?.success().stdout(
+        predicate::str::contains("integration_test = null  # not set").and(
+            predicate::str::contains("no_integration_test = true  # flag --no-integration-test"),
+        ),
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn build_integration_test_resolves_from_trunk_toml() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_integration_test_trunk_toml_")?;
+    let ext_dir = tmp_dir.path().join("ext");
+    let other_dir = tmp_dir.path().join("other");
+    fs::create_dir_all(&ext_dir)?;
+    fs::create_dir_all(other_dir.join("test"))?;
+    fs::write(
+        other_dir.join("Trunk.toml"),
+        ext_trunk_toml("integration_test = \"test/smoke.sql\"\n"),
+    )?;
+    fs::write(other_dir.join("test/smoke.sql"), "CREATE EXTENSION ext;\n")?;
+    // The Trunk.toml read is outside --path, and the SQL file is next to it, not under --path
+    std::os::unix::fs::symlink("../other/Trunk.toml", ext_dir.join("Trunk.toml"))?;
+
+    explain(&ext_dir, &[])?
+        .success()
+        .stdout(predicate::str::contains(format!(
+            "integration_test = {:?}  # Trunk.toml build.integration_test",
+            ext_dir
+                .join("../other/test/smoke.sql")
+                .display()
+                .to_string()
+        )));
+
+    Ok(())
+}
+
+#[test]
+fn build_platform_base_image() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_platform_base_image_")?;
+    let write_trunk_toml = |arm64_image: &str| {
+        fs::write(
+            tmp_dir.path().join("Trunk.toml"),
+            format!(
+                r#"[extension]
+name = "ext"
+version = "0.1.0"
+license = "MIT"
+categories = []
+
+[build]
+platform = "linux/amd64"
+base_image = "postgres:16-bookworm"
+
+[build.platforms."linux/arm64"]
+base_image = "{arm64_image}"
+"#
+            ),
+        )
+    };
+
+    // Every platform's base image is checked, not only the one being built
+    write_trunk_toml("Arm64/Postgres:16")?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--explain");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        r#"Invalid base image 'Arm64/Postgres:16' in build.platforms."linux/arm64".base_image"#,
+    ));
+
+    write_trunk_toml("arm64v8/postgres:16-bookworm")?;
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--explain");
+    cmd.assert().success().stdout(predicate::str::contains(
+        r#"base_image = "postgres:16-bookworm"  # Trunk.toml build.base_image"#,
+    ));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--platform")
+        .arg("linux/arm64")
+        .arg("--explain");
+    cmd.assert().success().stdout(predicate::str::contains(
+        r#"base_image = "arm64v8/postgres:16-bookworm"  # Trunk.toml build.platforms."linux/arm64".base_image"#,
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn build_versions_keep_going() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_versions_")?;
+    fs::write(tmp_dir.path().join("Makefile"), "install:\n")?;
+    for args in [
+        &["init", "--quiet"][..],
+        &["add", "Makefile"],
+        &[
+            "-c",
+            "user.name=trunk",
+            "-c",
+            "user.email=trunk@example.com",
+            "commit",
+            "--quiet",
+            "--message",
+            "Add a Makefile",
+        ],
+    ] {
+        assert!(Command::new("git")
+            .arg("-C")
+            .arg(tmp_dir.path())
+            .args(args)
+            .status()?
+            .success());
+    }
+    let build = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(["--name", "my_ext"])
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    // Every ref is tried, and the build fails at the end
+    build(&["--versions", "v0.1.0,v0.2.0", "--keep-going"])?
+        .code(1)
+        .stdout(predicate::str::contains("Built 0 of 2 versions:"))
+        .stdout(predicate::str::contains(
+            "v0.2.0  FAILED: git archive v0.2.0 failed",
+        ))
+        .stderr(predicate::str::contains(
+            "2 of 2 versions failed to build: v0.1.0, v0.2.0",
+        ));
+    // Without --keep-going, the first that fails stops the build
+    build(&["--versions", "v0.1.0,v0.2.0"])?
+        .code(1)
+        .stdout(predicate::str::contains("Building v0.2.0").not())
+        .stderr(predicate::str::contains(
+            "Failed to build v0.1.0: git archive v0.1.0 failed",
+        ));
+    build(&["--keep-going"])?
+        .code(2)
+        .stderr(predicate::str::contains("--versions"));
+
+    Ok(())
+}
+
+#[test]
+fn build_context_exclude() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_context_exclude_")?;
+    fs::write(tmp_dir.path().join("Makefile"), "install:\n")?;
+    fs::create_dir(tmp_dir.path().join("fixtures"))?;
+    let build = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--path")
+            .arg(tmp_dir.path())
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    build(&[
+        "--explain",
+        "--context-exclude",
+        "fixtures",
+        "--context-include",
+        "fixtures/*.sql",
+    ])?
+    .success()
+    .stdout(predicate::str::contains(
+        r#"context_exclude = ["fixtures"]  # flag --context-exclude"#,
+    ))
+    .stdout(predicate::str::contains(
+        r#"context_include = ["fixtures/*.sql"]  # flag --context-include"#,
+    ));
+    build(&["--explain", "--context-exclude", "../sibling"])?
+        .failure()
+        .stderr(predicate::str::contains(
+            "--context-exclude must be a glob relative to the build context, without '..' components. Got: ../sibling",
+        ));
+
+    // Excluded files aren't part of what is built
+    let checksum = || -> Result<_, Box<dyn std::error::Error>> {
+        let output = build(&["--print-context-checksum", "--context-exclude", "fixtures"])?
+            .success()
+            .get_output()
+            .stdout
+            .clone();
+        Ok(String::from_utf8(output)?)
+    };
+    let before = checksum()?;
+    fs::write(tmp_dir.path().join("fixtures/large.csv"), "1,2,3\n")?;
+    assert_eq!(checksum()?, before);
+
+    Ok(())
+}
+
+#[test]
+fn build_print_context_checksum() -> Result<(), Box<dyn std::error::Error>> {
+    let checksum = |path: &Path, pg_version: &str| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+        cmd.arg("build")
+            .arg("--print-context-checksum")
+            .arg("--path")
+            .arg(path)
+            .arg("--pg-version")
+            .arg(pg_version);
+        let output = cmd.assert().success().get_output().stdout.clone();
+        Ok(String::from_utf8(output)?.trim_end().to_string())
+    };
+
+    let first = TempDir::with_prefix("test_context_checksum_")?;
+    let second = TempDir::with_prefix("test_context_checksum_")?;
+    for dir in [&first, &second] {
+        fs::write(dir.path().join("Makefile"), "install:\n")?;
+    }
+    let checksum_a = checksum(first.path(), "15")?;
+    assert_eq!(checksum_a.len(), 64, "{checksum_a}");
+    // The same sources elsewhere give the same checksum
+    assert_eq!(checksum(second.path(), "15")?, checksum_a);
+    assert_ne!(checksum(first.path(), "16")?, checksum_a);
+    fs::write(
+        second.path().join("ext.control"),
+        "default_version = '1.0'\n",
+    )?;
+    assert_ne!(checksum(second.path(), "15")?, checksum_a);
+
+    Ok(())
+}
+
+#[test]
+fn build_check_install_writes() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp_dir = TempDir::with_prefix("test_check_install_writes_")?;
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--check-install-writes")
+        .arg("--writable-path")
+        .arg("/root/.cache")
+        .arg("--explain");
+    cmd.assert().success().stdout(
+        predicate::str::contains("check_install_writes = true  # flag --check-install-writes").and(
+            predicate::str::contains(
+                r#"writable_paths = ["/root/.cache"]  # flag --writable-path"#,
+            ),
+        ),
+    );
+
+    // --writable-path only widens what --check-install-writes allows
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--writable-path")
+        .arg("/root/.cache")
+        .arg("--explain");
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--check-install-writes"));
+
+    let mut cmd = Command::cargo_bin(CARGO_BIN)?;
+    cmd.arg("build")
+        .arg("--path")
+        .arg(tmp_dir.path())
+        .arg("--check-install-writes")
+        .arg("--writable-path")
+        .arg("cache")
+        .arg("--explain");
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--writable-path must be an absolute path without '..'. Got: cache",
+    ));
+
+    Ok(())
+}
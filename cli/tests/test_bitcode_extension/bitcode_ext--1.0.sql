@@ -0,0 +1,3 @@
+CREATE FUNCTION bitcode_ext_add(integer, integer) RETURNS integer
+AS 'MODULE_PATHNAME', 'bitcode_ext_add'
+LANGUAGE C IMMUTABLE STRICT;
@@ -0,0 +1,12 @@
+#include "postgres.h"
+#include "fmgr.h"
+
+PG_MODULE_MAGIC;
+
+PG_FUNCTION_INFO_V1(bitcode_ext_add);
+
+Datum
+bitcode_ext_add(PG_FUNCTION_ARGS)
+{
+    PG_RETURN_INT32(PG_GETARG_INT32(0) + PG_GETARG_INT32(1));
+}
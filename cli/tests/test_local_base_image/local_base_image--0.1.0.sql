@@ -0,0 +1,3 @@
+CREATE FUNCTION local_base_image() RETURNS text
+    LANGUAGE sql IMMUTABLE
+    AS $$ SELECT 'built on a local base image' $$;